@@ -1,30 +1,45 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
 use async_trait::async_trait;
 use bhwi::{
-    Interpreter,
+    Interpreter, RawCommand,
     bitbox::{
-        BitBoxCommand, BitBoxInterpreter, BitBoxResponse,
+        BackupInfo, BitBoxCommand, BitBoxInterpreter, BitBoxResponse,
+        bootloader::{
+            BootloaderCommand, BootloaderHashes, BootloaderInterpreter, BootloaderResponse,
+            BootloaderVersions, SignedFirmware, UpgradeProgress,
+        },
         error::BitBoxError,
-        noise::{NoiseConfigData, NoiseState, PairingCodeHook},
+        noise::{NoiseConfigData, NoiseState},
     },
     bitcoin::Network,
     common,
 };
 
-use crate::{HttpClient, Transport};
+use crate::{CommandControl, HttpClient, Transport, abort, control::Interrupt};
 
 /// Async BitBox02 client. Holds the noise-encryption state that persists across
 /// interpreter invocations. The caller is expected to:
 ///
 /// 1. Construct with `BitBox::new(transport, load_persisted_config())`.
 /// 2. Call `HWI::unlock(&mut bb, network).await?` to drive the handshake and pair.
-///    While `pairing_code()` returns `Some`, the CLI/user must confirm the code on the
-///    device; the `HWI::unlock` future resolves once the device replies.
-/// 3. Persist `bb.noise_config_data()` externally for future sessions.
+///    On a first-time pair the code to confirm on the device is raised as
+///    [`common::Event::PairingCodeAvailable`] to the listener of
+///    [`CommandControl::with_events`]; the `HWI::unlock` future resolves once the device
+///    replies.
+/// 3. Persist `bb.noise_config_data()` externally for future sessions, or install a
+///    [`PairingStore`] with [`BitBox::with_pairing_store`] to do it after every unlock.
 /// 4. Issue further HWI calls (`get_master_fingerprint`, ...).
 pub struct BitBox<T> {
     pub transport: T,
     pub network: Network,
     noise: NoiseState,
+    pairing_store: Option<Box<dyn PairingStore>>,
+    control: CommandControl,
+    pending_reply: bool,
 }
 
 impl<T> BitBox<T> {
@@ -36,9 +51,23 @@ impl<T> BitBox<T> {
             transport,
             network: Network::Bitcoin,
             noise: NoiseState::new(pairing_data),
+            pairing_store: None,
+            control: CommandControl::default(),
+            pending_reply: false,
         }
     }
 
+    /// Load the pairing data persisted by `store`, if any, and write the updated data back
+    /// to it after every successful unlock. Already-paired devices then skip the on-screen
+    /// pairing-code confirmation.
+    pub fn with_pairing_store(mut self, store: Box<dyn PairingStore>) -> Result<Self, io::Error> {
+        if let Some(data) = store.load()? {
+            self.noise = NoiseState::new(Some(data));
+        }
+        self.pairing_store = Some(store);
+        Ok(self)
+    }
+
     /// Set the network used for xpub encoding, address/coin selection and signing.
     pub fn with_network(mut self, network: Network) -> Self {
         self.network = network;
@@ -51,17 +80,6 @@ impl<T> BitBox<T> {
         self.noise.pairing_code()
     }
 
-    /// Install a hook that fires the moment the pairing code becomes available during a
-    /// first-time pair — i.e. right before the interpreter blocks on the device's
-    /// verification response. The hook runs synchronously inside `HWI::unlock`, so it must
-    /// be non-blocking. Typical uses: `eprintln!`, `log::info!`, or sending on a channel.
-    ///
-    /// If the device is already paired (matching `NoiseConfigData.device_static_pubkeys`),
-    /// the hook is never called.
-    pub fn set_pairing_code_hook(&mut self, hook: PairingCodeHook) {
-        self.noise.set_pairing_code_hook(hook);
-    }
-
     /// Snapshot the noise-pairing state so the caller can persist it externally.
     pub fn noise_config_data(&self) -> NoiseConfigData {
         self.noise.data().clone()
@@ -72,17 +90,6 @@ impl<T> BitBox<T> {
     }
 }
 
-/// Feeds a `BitBoxCommand` straight into the interpreter, bypassing `common::Command`. Used
-/// for BitBox-only operations that have no place in the shared HWI command surface.
-struct RawCommand(BitBoxCommand);
-
-impl TryFrom<RawCommand> for BitBoxCommand {
-    type Error = BitBoxError;
-    fn try_from(cmd: RawCommand) -> Result<Self, Self::Error> {
-        Ok(cmd.0)
-    }
-}
-
 impl<T: Transport> BitBox<T> {
     /// Restore the device from the mnemonic currently loaded on it. This is BitBox-specific
     /// and intentionally not part of the shared `HWI` trait; its purpose here is to seed the
@@ -100,28 +107,216 @@ impl<T: Transport> BitBox<T> {
         .map(|_| ())
     }
 
+    /// Backups stored on the inserted microSD card.
+    pub async fn list_backups(&mut self) -> Result<Vec<BackupInfo>, BitBoxError> {
+        match self.run_bitbox(BitBoxCommand::ListBackups).await? {
+            BitBoxResponse::Backups(backups) => Ok(backups),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Id of the microSD backup of the device's seed, or `None` if the card holds none.
+    /// Unless `silent`, the device shows the result.
+    pub async fn check_backup(&mut self, silent: bool) -> Result<Option<String>, BitBoxError> {
+        match self
+            .run_bitbox(BitBoxCommand::CheckBackup { silent })
+            .await?
+        {
+            BitBoxResponse::BackupCheck(id) => Ok(id),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Restore an unseeded device from the microSD backup with `id`, as listed by
+    /// [`BitBox::list_backups`].
+    pub async fn restore_backup(
+        &mut self,
+        id: &str,
+        timestamp: u32,
+        timezone_offset: i32,
+    ) -> Result<(), BitBoxError> {
+        self.run_bitbox(BitBoxCommand::RestoreBackup {
+            id: id.to_string(),
+            timestamp,
+            timezone_offset,
+        })
+        .await
+        .map(|_| ())
+    }
+
+    pub async fn check_sdcard(&mut self) -> Result<bool, BitBoxError> {
+        match self.run_bitbox(BitBoxCommand::CheckSdCard).await? {
+            BitBoxResponse::SdCardInserted(inserted) => Ok(inserted),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Prompt the user to insert a microSD card. Returns once a card is inserted.
+    pub async fn insert_sdcard(&mut self) -> Result<(), BitBoxError> {
+        self.run_bitbox(BitBoxCommand::InsertSdCard)
+            .await
+            .map(|_| ())
+    }
+
+    /// Reboot into the bootloader to upgrade the firmware, once the user confirms on the
+    /// device. The device then reconnects as a bootloader, driven by [`BitBoxBootloader`].
+    pub async fn reboot_to_bootloader(&mut self) -> Result<(), BitBoxError> {
+        self.run_bitbox_inner(BitBoxCommand::RebootToBootloader, true)
+            .await
+            .map(|_| ())
+    }
+
     /// Drive one BitBox-specific command through the interpreter and transport.
     async fn run_bitbox(&mut self, command: BitBoxCommand) -> Result<BitBoxResponse, BitBoxError> {
-        use crate::CommonInterface;
-        let (transport, _http, mut interpreter) = <BitBox<T> as CommonInterface<
-            RawCommand,
+        self.run_bitbox_inner(command, false).await
+    }
+
+    /// With `allow_final_disconnect`, a device resetting instead of answering completes the
+    /// command.
+    async fn run_bitbox_inner(
+        &mut self,
+        command: BitBoxCommand,
+        allow_final_disconnect: bool,
+    ) -> Result<BitBoxResponse, BitBoxError> {
+        if self.pending_reply {
+            return Err(BitBoxError::PendingReply);
+        }
+        let transport = &mut self.transport;
+        let mut interpreter = BitBoxInterpreter::<
+            RawCommand<BitBoxCommand>,
             common::Transmit,
             BitBoxResponse,
             BitBoxError,
-        >>::components(self);
-        let transmit = interpreter.start(RawCommand(command))?;
-        let exchange = transport
-            .exchange(&transmit.payload, transmit.encrypted)
-            .await
-            .map_err(|e| BitBoxError::Transport(format!("{e:?}")))?;
-        let mut next = interpreter.exchange(exchange)?;
+        >::new(&mut self.noise)
+        .with_network(self.network);
+        let control = &self.control;
+        let mut guard = control.guard();
+        let mut next = Some(interpreter.start(RawCommand(command))?);
+        control.forward_events(&mut interpreter);
         while let Some(t) = next {
             // BitBox02 never talks to a pin server; every transmit targets the device.
-            let exchange = transport
-                .exchange(&t.payload, t.encrypted)
-                .await
-                .map_err(|e| BitBoxError::Transport(format!("{e:?}")))?;
+            let exchange = match guard.run(transport.exchange(&t.payload, t.encrypted)).await {
+                Ok(Ok(exchange)) => exchange,
+                Ok(Err(e)) if allow_final_disconnect && transport.is_post_write_disconnect(&e) => {
+                    return Ok(BitBoxResponse::TaskDone);
+                }
+                Ok(Err(e)) => return Err(BitBoxError::Transport(format!("{e:?}"))),
+                Err(interrupt) => {
+                    let pending_reply = &mut self.pending_reply;
+                    let interrupt = abort(
+                        &guard,
+                        transport,
+                        &mut interpreter,
+                        interrupt,
+                        pending_reply,
+                    )
+                    .await;
+                    return Err(match interrupt {
+                        Interrupt::Cancelled => BitBoxError::Cancelled,
+                        Interrupt::Timeout => BitBoxError::Timeout,
+                    });
+                }
+            };
+            next = interpreter.exchange(exchange)?;
+            control.forward_events(&mut interpreter);
+        }
+        interpreter.end()
+    }
+}
+
+/// Async client for the BitBox02 bootloader, which the device runs after
+/// [`BitBox::reboot_to_bootloader`] or when it has no valid firmware. The bootloader needs
+/// no pairing.
+pub struct BitBoxBootloader<T> {
+    pub transport: T,
+}
+
+impl<T: Transport> BitBoxBootloader<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub async fn versions(&mut self) -> Result<BootloaderVersions, BitBoxError> {
+        match self.run(BootloaderCommand::Versions, |_| {}).await? {
+            BootloaderResponse::Versions(versions) => Ok(versions),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Firmware and signing keydata hashes. The device shows the requested ones, and the
+    /// call returns once the user confirmed them.
+    pub async fn hashes(
+        &mut self,
+        display_firmware_hash: bool,
+        display_signing_keydata_hash: bool,
+    ) -> Result<BootloaderHashes, BitBoxError> {
+        let command = BootloaderCommand::Hashes {
+            display_firmware_hash,
+            display_signing_keydata_hash,
+        };
+        match self.run(command, |_| {}).await? {
+            BootloaderResponse::Hashes(hashes) => Ok(hashes),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Show the firmware hash on the device at every boot.
+    pub async fn set_show_firmware_hash(&mut self, enabled: bool) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::SetShowFirmwareHash(enabled), |_| {})
+            .await
+            .map(|_| ())
+    }
+
+    /// Flash `firmware`, then check the hash the bootloader reports for it. `progress` is
+    /// called after every chunk the device accepted. The device keeps running the bootloader
+    /// until [`BitBoxBootloader::reboot`].
+    pub async fn upgrade_firmware(
+        &mut self,
+        firmware: SignedFirmware,
+        progress: impl FnMut(UpgradeProgress),
+    ) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Upgrade(firmware), progress)
+            .await
+            .map(|_| ())
+    }
+
+    /// Erase the firmware, leaving the device in the bootloader.
+    pub async fn erase(&mut self) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Erase, |_| {}).await.map(|_| ())
+    }
+
+    /// Leave the bootloader and boot the firmware.
+    pub async fn reboot(&mut self) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Reboot, |_| {})
+            .await
+            .map(|_| ())
+    }
+
+    async fn run(
+        &mut self,
+        command: BootloaderCommand,
+        mut progress: impl FnMut(UpgradeProgress),
+    ) -> Result<BootloaderResponse, BitBoxError> {
+        let reboot = matches!(command, BootloaderCommand::Reboot);
+        let mut interpreter = BootloaderInterpreter::new();
+        let mut next = Some(interpreter.start(command)?);
+        let mut reported = None;
+        while let Some(t) = next {
+            let exchange = match self.transport.exchange(&t.payload, t.encrypted).await {
+                Ok(exchange) => exchange,
+                // The device may reset before it acknowledges the reboot.
+                Err(e) if reboot && self.transport.is_post_write_disconnect(&e) => {
+                    return Ok(BootloaderResponse::TaskDone);
+                }
+                Err(e) => return Err(BitBoxError::Transport(format!("{e:?}"))),
+            };
             next = interpreter.exchange(exchange)?;
+            if let Some(p) = interpreter.upgrade_progress()
+                && reported != Some(p.written)
+            {
+                reported = Some(p.written);
+                progress(p);
+            }
         }
         interpreter.end()
     }
@@ -138,6 +333,14 @@ where
     type TransportError = F::Error;
     type HttpClientError = BitBoxError;
 
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn pending_reply(&mut self) -> &mut bool {
+        &mut self.pending_reply
+    }
+
     fn components(
         &mut self,
     ) -> (
@@ -156,10 +359,117 @@ where
 
 impl<T> crate::OnUnlock for BitBox<T> {
     fn on_unlock(&mut self, _response: common::Response) -> Result<(), common::Error> {
+        if let Some(store) = &self.pairing_store {
+            store.store(self.noise.data()).map_err(|e| {
+                common::Error::Device(
+                    common::ErrorKind::Transport,
+                    format!("failed to persist BitBox02 pairing: {e}"),
+                )
+            })?;
+        }
         Ok(())
     }
 }
 
+/// Persists the noise-pairing data of a BitBox02 host across sessions.
+pub trait PairingStore {
+    /// Returns `None` if nothing has been persisted yet.
+    fn load(&self) -> Result<Option<NoiseConfigData>, io::Error>;
+    fn store(&self, data: &NoiseConfigData) -> Result<(), io::Error>;
+}
+
+/// File-backed [`PairingStore`] holding the pairing data as JSON, in the same layout as the
+/// BitBoxApp (`app_static_privkey` and `device_static_pubkeys`, hex encoded).
+pub struct FilePairingStore {
+    path: PathBuf,
+}
+
+impl FilePairingStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Store at `$XDG_CONFIG_HOME/bhwi/bitbox02.json`, falling back to `$HOME/.config`.
+    /// Returns `None` if neither variable is set.
+    pub fn from_config_dir() -> Option<Self> {
+        crate::config_dir().map(|dir| Self::new(dir.join("bitbox02.json")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl PairingStore for FilePairingStore {
+    fn load(&self) -> Result<Option<NoiseConfigData>, io::Error> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let value: serde_json::Value = serde_json::from_slice(&contents)?;
+        decode_noise_config(&value)
+            .map(Some)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid pairing data"))
+    }
+
+    fn store(&self, data: &NoiseConfigData) -> Result<(), io::Error> {
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let value = serde_json::json!({
+            "app_static_privkey": data.app_static_privkey.map(hex::encode),
+            "device_static_pubkeys": data
+                .device_static_pubkeys
+                .iter()
+                .map(hex::encode)
+                .collect::<Vec<_>>(),
+        });
+        // Written aside then renamed over the store, so that an interrupted write never
+        // leaves a truncated pairing behind.
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".tmp");
+        let tmp = self.path.with_file_name(name);
+        if let Err(e) = std::fs::remove_file(&tmp)
+            && e.kind() != io::ErrorKind::NotFound
+        {
+            return Err(e);
+        }
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        // The file holds the host's static noise private key.
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let mut file = options.open(&tmp)?;
+        let written = io::Write::write_all(&mut file, &serde_json::to_vec_pretty(&value)?)
+            .and_then(|()| file.sync_all())
+            .and_then(|()| std::fs::rename(&tmp, &self.path));
+        if written.is_err() {
+            let _ = std::fs::remove_file(&tmp);
+        }
+        written
+    }
+}
+
+fn decode_noise_config(value: &serde_json::Value) -> Option<NoiseConfigData> {
+    let app_static_privkey = match value.get("app_static_privkey") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(key) => Some(hex::decode(key.as_str()?).ok()?.try_into().ok()?),
+    };
+    let device_static_pubkeys = match value.get("device_static_pubkeys") {
+        None => Vec::new(),
+        Some(keys) => keys
+            .as_array()?
+            .iter()
+            .map(|key| hex::decode(key.as_str()?).ok())
+            .collect::<Option<Vec<_>>>()?,
+    };
+    Some(NoiseConfigData {
+        app_static_privkey,
+        device_static_pubkeys,
+    })
+}
+
 pub struct DummyClient;
 
 #[async_trait(?Send)]
@@ -169,3 +479,44 @@ impl HttpClient for DummyClient {
         unreachable!("BitBox02 does not use an HTTP client")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_pairing_store_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("bhwi-pairing-{}", std::process::id()));
+        let store = FilePairingStore::new(dir.join("bitbox02.json"));
+        assert_eq!(store.load().unwrap(), None);
+
+        let data = NoiseConfigData {
+            app_static_privkey: Some([7; 32]),
+            device_static_pubkeys: vec![vec![1; 32], vec![2; 32]],
+        };
+        store.store(&data).unwrap();
+        assert_eq!(store.load().unwrap(), Some(data));
+
+        let data = NoiseConfigData {
+            app_static_privkey: Some([8; 32]),
+            device_static_pubkeys: Vec::new(),
+        };
+        store.store(&data).unwrap();
+        assert_eq!(store.load().unwrap(), Some(data));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let metadata = std::fs::metadata(store.path()).unwrap();
+            assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        }
+        assert!(!dir.join("bitbox02.json.tmp").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn invalid_pairing_data_is_rejected() {
+        let value = serde_json::json!({ "app_static_privkey": "00", "device_static_pubkeys": [] });
+        assert_eq!(decode_noise_config(&value), None);
+    }
+}
@@ -1,10 +1,12 @@
-use crate::{HttpClient, Transport};
+use crate::{CommandControl, Error, HttpClient, Transport, run_raw_command};
 use async_trait::async_trait;
 use bhwi::{
-    Interpreter,
+    Interpreter, RawCommand,
+    bitcoin::Psbt,
     coldcard::{
         ColdcardCommand, ColdcardError, ColdcardInterpreter, ColdcardResponse, ColdcardTransmit,
         encrypt::{self, CryptoRngCore},
+        hsm::{AuthMode, HsmStatus, UserAuth},
     },
     common,
 };
@@ -12,6 +14,8 @@ use bhwi::{
 pub struct Coldcard<T> {
     pub transport: T,
     encryption: encrypt::Engine,
+    control: CommandControl,
+    pending_reply: bool,
 }
 
 impl<T> Coldcard<T> {
@@ -19,10 +23,111 @@ impl<T> Coldcard<T> {
         Self {
             transport,
             encryption: encrypt::Engine::new(rng),
+            control: CommandControl::default(),
+            pending_reply: false,
         }
     }
 }
 
+/// HSM mode management. Like every other Coldcard command, these need the encrypted
+/// session set up by `HWI::unlock` first.
+impl<T: Transport> Coldcard<T> {
+    /// Upload `policy`, an HSM policy JSON, and ask to start HSM mode with it. With `None`
+    /// the policy already saved on the device is used. The operator still approves on the
+    /// device; poll `hsm_status` to see when HSM mode is active.
+    pub async fn start_hsm(
+        &mut self,
+        policy: Option<Vec<u8>>,
+    ) -> Result<(), Error<T::Error, ColdcardError>> {
+        self.run_coldcard(ColdcardCommand::StartHsm { policy })
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn hsm_status(&mut self) -> Result<HsmStatus, Error<T::Error, ColdcardError>> {
+        match self.run_coldcard(ColdcardCommand::HsmStatus).await? {
+            ColdcardResponse::HsmStatus(status) => Ok(status),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    /// Create an HSM user and return its secret: the base32 OTP key, or the password when
+    /// the device picked it. An empty `secret` lets the device pick one, and `show_qr`
+    /// displays it on the device instead.
+    pub async fn create_user(
+        &mut self,
+        username: &str,
+        mode: AuthMode,
+        secret: Vec<u8>,
+        show_qr: bool,
+    ) -> Result<String, Error<T::Error, ColdcardError>> {
+        match self
+            .run_coldcard(ColdcardCommand::CreateUser {
+                username: username.to_string(),
+                mode,
+                secret,
+                show_qr,
+            })
+            .await?
+        {
+            ColdcardResponse::UserSecret(secret) => Ok(secret),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    pub async fn delete_user(
+        &mut self,
+        username: &str,
+    ) -> Result<(), Error<T::Error, ColdcardError>> {
+        self.run_coldcard(ColdcardCommand::DeleteUser {
+            username: username.to_string(),
+        })
+        .await
+        .map(|_| ())
+    }
+
+    /// Authenticate `username` for `psbt`, then have the device sign it if the HSM policy
+    /// allows it.
+    pub async fn sign_psbt_as_user(
+        &mut self,
+        psbt: Psbt,
+        username: &str,
+        auth: UserAuth,
+    ) -> Result<Psbt, Error<T::Error, ColdcardError>> {
+        match self
+            .run_coldcard(ColdcardCommand::SignPsbtAsUser {
+                psbt,
+                username: username.to_string(),
+                auth,
+            })
+            .await?
+        {
+            ColdcardResponse::SignedPsbt(psbt) => Ok(psbt),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    /// Drive one Coldcard-specific command through the interpreter and transport.
+    async fn run_coldcard(
+        &mut self,
+        command: ColdcardCommand,
+    ) -> Result<ColdcardResponse, Error<T::Error, ColdcardError>> {
+        let interpreter =
+            ColdcardInterpreter::<_, common::Transmit, ColdcardResponse, ColdcardError>::new(
+                &mut self.encryption,
+            );
+        run_raw_command(
+            &mut self.transport,
+            &self.control,
+            &mut self.pending_reply,
+            interpreter,
+            RawCommand(command),
+            |_| {},
+        )
+        .await
+    }
+}
+
 impl<C, T, R, E, F> crate::CommonInterface<C, T, R, E> for Coldcard<F>
 where
     C: TryInto<ColdcardCommand, Error = ColdcardError>,
@@ -33,6 +138,15 @@ where
 {
     type TransportError = F::Error;
     type HttpClientError = ColdcardError;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn pending_reply(&mut self) -> &mut bool {
+        &mut self.pending_reply
+    }
+
     fn components(
         &mut self,
     ) -> (
@@ -0,0 +1,425 @@
+//! Cancellation, deadlines and events for the commands run by [`crate::HWI`].
+//!
+//! A command waits on the device for as long as the user takes to confirm, and on a hung
+//! transport forever. [`CommandControl`] bounds it: a [`CancellationToken`] aborts it from
+//! elsewhere, and deadlines bound every exchange and the whole command. Timers come from
+//! the caller, as a sleep function of its runtime, so the same code runs on tokio and in
+//! the browser. Meanwhile the events raised by the interpreter, such as a prompt on the
+//! device, go to the caller's listener.
+
+use std::{
+    fmt,
+    future::Future,
+    pin::{Pin, pin},
+    rc::Rc,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+pub use bhwi::EventListener;
+use bhwi::{Interpreter, common::Event};
+use futures::future::{Either, LocalBoxFuture, pending, select};
+
+/// Aborts the commands it is attached to, see [`CommandControl::with_cancellation`].
+///
+/// Clones share the same state, so one can be handed to the task that decides to cancel.
+/// Once cancelled a token stays cancelled.
+#[derive(Clone, Default)]
+pub struct CancellationToken(Arc<Shared>);
+
+#[derive(Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    wakers: Mutex<Vec<Waker>>,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::SeqCst);
+        for waker in self.0.wakers.lock().expect("poisoned").drain(..) {
+            waker.wake();
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Resolves once the token is cancelled.
+    pub fn cancelled(&self) -> Cancelled<'_> {
+        Cancelled(self)
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("CancellationToken")
+            .field(&self.is_cancelled())
+            .finish()
+    }
+}
+
+pub struct Cancelled<'a>(&'a CancellationToken);
+
+impl Future for Cancelled<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0.is_cancelled() {
+            return Poll::Ready(());
+        }
+        let mut wakers = self.0.0.wakers.lock().expect("poisoned");
+        // Checked again under the lock, `cancel` may have drained the wakers meanwhile.
+        if self.0.is_cancelled() {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+/// Sleep of the caller's runtime, such as `tokio::time::sleep` or a `setTimeout` promise.
+pub type Sleep = Rc<dyn Fn(Duration) -> LocalBoxFuture<'static, ()>>;
+
+/// Cancellation, deadlines and event listener applied to every command run on a device.
+///
+/// Deadlines need a timer, see [`CommandControl::with_timer`]; without one they are
+/// ignored. When a command is interrupted the device is asked to abort the request in
+/// flight, see [`crate::Transport::cancel`], and the call fails with
+/// [`crate::Error::Cancelled`] or [`crate::Error::Timeout`]. A device that cannot abort it
+/// fails the following commands with [`crate::Error::PendingReply`].
+#[derive(Clone, Default)]
+pub struct CommandControl {
+    cancellation: Option<CancellationToken>,
+    sleep: Option<Sleep>,
+    exchange_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    events: Option<EventListener>,
+}
+
+impl CommandControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        self.cancellation = Some(token);
+        self
+    }
+
+    pub fn with_timer<F, D>(mut self, sleep: F) -> Self
+    where
+        F: Fn(Duration) -> D + 'static,
+        D: Future<Output = ()> + 'static,
+    {
+        self.sleep = Some(Rc::new(move |duration| Box::pin(sleep(duration))));
+        self
+    }
+
+    /// Bound every exchange with the device or the pin server, user confirmations included.
+    pub fn with_exchange_timeout(mut self, timeout: Duration) -> Self {
+        self.exchange_timeout = Some(timeout);
+        self
+    }
+
+    /// Bound the whole command.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Call `listener` with every event of the commands, see [`EventListener`].
+    pub fn with_events<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&Event) + 'static,
+    {
+        self.events = Some(Rc::new(listener));
+        self
+    }
+
+    /// Hand the events raised by the last call on `intpr` to the listener.
+    pub(crate) fn forward_events<I: Interpreter>(&self, intpr: &mut I) {
+        bhwi::forward_events(self.events.as_ref(), intpr)
+    }
+
+    fn sleep(&self, duration: Option<Duration>) -> LocalBoxFuture<'static, ()> {
+        match (&self.sleep, duration) {
+            (Some(sleep), Some(duration)) => sleep(duration),
+            _ => Box::pin(pending()),
+        }
+    }
+
+    /// Start the overall deadline of a command.
+    pub(crate) fn guard(&self) -> Guard<'_> {
+        Guard {
+            control: self,
+            deadline: self.sleep(self.timeout),
+            expired: false,
+            in_flight: false,
+        }
+    }
+}
+
+impl fmt::Debug for CommandControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandControl")
+            .field("cancellation", &self.cancellation)
+            .field("timer", &self.sleep.is_some())
+            .field("exchange_timeout", &self.exchange_timeout)
+            .field("timeout", &self.timeout)
+            .field("events", &self.events.is_some())
+            .finish()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub(crate) enum Interrupt {
+    #[error("command cancelled")]
+    Cancelled,
+    #[error("command timed out")]
+    Timeout,
+}
+
+/// The deadlines of one running command.
+pub(crate) struct Guard<'a> {
+    control: &'a CommandControl,
+    deadline: LocalBoxFuture<'static, ()>,
+    // The deadline future must not be polled again once it has resolved.
+    expired: bool,
+    in_flight: bool,
+}
+
+impl Guard<'_> {
+    /// Run `future`, one exchange of the command with the device, unless it is interrupted
+    /// first.
+    pub(crate) async fn run<T>(&mut self, future: impl Future<Output = T>) -> Result<T, Interrupt> {
+        self.race(future, true).await
+    }
+
+    /// Run `future`, a step of the command that leaves the device idle like a pin server
+    /// request, unless it is interrupted first. Its interruption leaves nothing in flight.
+    pub(crate) async fn run_off_device<T>(
+        &mut self,
+        future: impl Future<Output = T>,
+    ) -> Result<T, Interrupt> {
+        self.race(future, false).await
+    }
+
+    async fn race<T>(
+        &mut self,
+        future: impl Future<Output = T>,
+        on_device: bool,
+    ) -> Result<T, Interrupt> {
+        let Self {
+            control,
+            deadline,
+            expired,
+            in_flight,
+        } = self;
+        let cancellation = control.cancellation.as_ref();
+        if cancellation.is_some_and(CancellationToken::is_cancelled) {
+            return Err(Interrupt::Cancelled);
+        }
+        if *expired {
+            return Err(Interrupt::Timeout);
+        }
+        let cancelled = async {
+            match cancellation {
+                Some(token) => token.cancelled().await,
+                None => pending().await,
+            }
+        };
+        let timeout = async {
+            if let Either::Right(_) =
+                select(control.sleep(control.exchange_timeout), deadline).await
+            {
+                *expired = true;
+            }
+        };
+        let interrupt = async {
+            match select(pin!(cancelled), pin!(timeout)).await {
+                Either::Left(_) => Interrupt::Cancelled,
+                Either::Right(_) => Interrupt::Timeout,
+            }
+        };
+        match select(pin!(future), pin!(interrupt)).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right((interrupt, _)) => {
+                *in_flight = on_device;
+                Err(interrupt)
+            }
+        }
+    }
+
+    /// Whether the last interrupted exchange had already started, so the device may still
+    /// be working on its request.
+    pub(crate) fn in_flight(&self) -> bool {
+        self.in_flight
+    }
+
+    /// Run `future`, the abort of an interrupted command, within one exchange timeout.
+    pub(crate) async fn bounded<T>(&self, future: impl Future<Output = T>) -> Option<T> {
+        match select(
+            pin!(future),
+            self.control.sleep(self.control.exchange_timeout),
+        )
+        .await
+        {
+            Either::Left((output, _)) => Some(output),
+            Either::Right(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use futures::{channel::oneshot, executor::block_on};
+
+    use super::*;
+
+    /// Timer whose sleeps only end when the test fires them.
+    #[derive(Clone, Default)]
+    struct ManualTimer(Rc<RefCell<Vec<(Duration, oneshot::Sender<()>)>>>);
+
+    impl ManualTimer {
+        fn control(&self) -> CommandControl {
+            let timer = self.clone();
+            CommandControl::new().with_timer(move |duration| {
+                let (fire, fired) = oneshot::channel();
+                timer.0.borrow_mut().push((duration, fire));
+                async move {
+                    let _ = fired.await;
+                }
+            })
+        }
+
+        fn fire(&self, duration: Duration) {
+            let mut sleeps = self.0.borrow_mut();
+            let index = sleeps
+                .iter()
+                .position(|(d, _)| *d == duration)
+                .expect("no such sleep");
+            let _ = sleeps.remove(index).1.send(());
+        }
+    }
+
+    #[test]
+    fn completes_when_not_interrupted() {
+        let control = CommandControl::new().with_cancellation(CancellationToken::new());
+        let mut guard = control.guard();
+        assert_eq!(block_on(guard.run(async { 7 })), Ok(7));
+    }
+
+    #[test]
+    fn cancellation_interrupts_a_pending_exchange() {
+        let token = CancellationToken::new();
+        let control = CommandControl::new().with_cancellation(token.clone());
+        let mut guard = control.guard();
+        let result = block_on(async {
+            // Polled once the exchange is pending.
+            let cancel = async {
+                token.cancel();
+                pending::<()>().await
+            };
+            match select(pin!(guard.run(pending::<()>())), pin!(cancel)).await {
+                Either::Left((result, _)) => result,
+                Either::Right(_) => unreachable!(),
+            }
+        });
+        assert_eq!(result, Err(Interrupt::Cancelled));
+        assert!(guard.in_flight());
+        // A cancelled token refuses the next exchange right away.
+        assert_eq!(block_on(guard.run(async {})), Err(Interrupt::Cancelled));
+        // Before it even starts, then nothing is left in flight.
+        let control = CommandControl::new().with_cancellation(token);
+        let mut guard = control.guard();
+        assert_eq!(block_on(guard.run(async {})), Err(Interrupt::Cancelled));
+        assert!(!guard.in_flight());
+    }
+
+    #[test]
+    fn deadlines_interrupt_a_pending_exchange() {
+        let exchange = Duration::from_secs(1);
+        let overall = Duration::from_secs(10);
+        let timer = ManualTimer::default();
+        let control = timer
+            .control()
+            .with_exchange_timeout(exchange)
+            .with_timeout(overall);
+        let mut guard = control.guard();
+
+        let run = async {
+            let first = guard.run(pending::<()>()).await;
+            let second = guard.run(pending::<()>()).await;
+            (first, second)
+        };
+        // Polled once the first exchange is pending.
+        let fire = async {
+            timer.fire(exchange);
+            timer.fire(overall);
+        };
+        let (results, ()) = block_on(futures::future::join(run, fire));
+        assert_eq!(results, (Err(Interrupt::Timeout), Err(Interrupt::Timeout)));
+        // The overall deadline has passed for good.
+        assert_eq!(block_on(guard.run(async {})), Err(Interrupt::Timeout));
+    }
+
+    /// Interpreter that only raises the events it was given.
+    struct Events(Vec<Event>);
+
+    impl Interpreter for Events {
+        type Command = ();
+        type Transmit = ();
+        type Response = ();
+        type Error = ();
+        fn start(&mut self, _: ()) -> Result<(), ()> {
+            Ok(())
+        }
+        fn exchange(&mut self, _: Vec<u8>) -> Result<Option<()>, ()> {
+            Ok(None)
+        }
+        fn end(self) -> Result<(), ()> {
+            Ok(())
+        }
+        fn poll_event(&mut self) -> Option<Event> {
+            (!self.0.is_empty()).then(|| self.0.remove(0))
+        }
+    }
+
+    #[test]
+    fn forwards_events_in_order() {
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let control = CommandControl::new().with_events({
+            let seen = seen.clone();
+            move |event: &Event| seen.borrow_mut().push(event.clone())
+        });
+        let events = vec![
+            Event::Progress { done: 1, total: 2 },
+            Event::AwaitingUserConfirmation,
+        ];
+        let mut intpr = Events(events.clone());
+        control.forward_events(&mut intpr);
+        assert_eq!(*seen.borrow(), events);
+        assert_eq!(intpr.poll_event(), None);
+    }
+
+    #[test]
+    fn deadlines_without_timer_are_ignored() {
+        let control = CommandControl::new().with_timeout(Duration::ZERO);
+        let mut guard = control.guard();
+        assert_eq!(block_on(guard.run(async { 1 })), Ok(1));
+        assert_eq!(block_on(guard.bounded(async { 2 })), Some(2));
+    }
+}
@@ -0,0 +1,298 @@
+//! Auto-approval of the prompts shown by device emulators.
+//!
+//! Commands that need a user confirmation block until someone presses a button. An
+//! [`EmulatorController`] plays that part on a simulator and [`approve_during`] runs it
+//! alongside a command, so sign, register and display flows complete unattended on every
+//! emulator without device-specific glue in the caller.
+
+use std::{collections::VecDeque, convert::Infallible, fmt::Debug, future::Future, pin::pin};
+
+use async_trait::async_trait;
+use futures::future::{Either, select};
+
+use crate::{HttpClient, Transport};
+
+/// Speculos automation rules accepting the review flows of the Ledger Bitcoin app on the
+/// button based models.
+pub const SPECULOS_APPROVE_ALL: &str = include_str!("speculos_approve.json");
+
+#[async_trait(?Send)]
+pub trait EmulatorController {
+    type Error: Debug;
+
+    /// Sets the emulator up before the command is sent.
+    async fn prepare(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Approves the prompt the emulator may be showing.
+    async fn approve(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Runs `command` while `controller` approves prompts, right away and then every time
+/// `delay` resolves, until the command returns. A failed approval drops the command.
+pub async fn approve_during<C, F, S, D>(
+    controller: &mut C,
+    mut delay: S,
+    command: F,
+) -> Result<F::Output, C::Error>
+where
+    C: EmulatorController + ?Sized,
+    F: Future,
+    S: FnMut() -> D,
+    D: Future<Output = ()>,
+{
+    controller.prepare().await?;
+    let approvals = async {
+        loop {
+            if let Err(error) = controller.approve().await {
+                return error;
+            }
+            delay().await;
+        }
+    };
+    match select(pin!(command), pin!(approvals)).await {
+        Either::Left((output, _)) => Ok(output),
+        Either::Right((error, _)) => Err(error),
+    }
+}
+
+/// Presses keys on the Coldcard simulator with its `XKEY` test command.
+///
+/// `transport` has to be a connection to the simulator socket distinct from the one the
+/// command runs on. The initial keys are pressed one per approval, then the repeated key,
+/// if any, on every following one.
+pub struct ColdcardEmulator<T> {
+    transport: T,
+    keys: VecDeque<u8>,
+    repeat: Option<u8>,
+}
+
+impl<T: Transport> ColdcardEmulator<T> {
+    /// Confirms a single prompt with `y`.
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            keys: VecDeque::from([b'y']),
+            repeat: None,
+        }
+    }
+
+    /// Keeps pressing `key` once the initial keys are pressed, like `1` through the
+    /// screens of a backup.
+    pub fn then_repeat(mut self, key: u8) -> Self {
+        self.repeat = Some(key);
+        self
+    }
+}
+
+#[async_trait(?Send)]
+impl<T: Transport> EmulatorController for ColdcardEmulator<T> {
+    type Error = T::Error;
+
+    async fn approve(&mut self) -> Result<(), Self::Error> {
+        if let Some(key) = self.keys.pop_front().or(self.repeat) {
+            let mut command = b"XKEY".to_vec();
+            command.push(key);
+            self.transport.exchange(&command, false).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpeculosButton {
+    Left,
+    Right,
+    Both,
+}
+
+impl SpeculosButton {
+    fn name(self) -> &'static str {
+        match self {
+            SpeculosButton::Left => "left",
+            SpeculosButton::Right => "right",
+            SpeculosButton::Both => "both",
+        }
+    }
+}
+
+/// Drives Speculos through its REST API, served on port 5000 by default.
+///
+/// Prompts are approved by the automation rules installed when the controller is
+/// prepared, [`SPECULOS_APPROVE_ALL`] unless replaced with
+/// [`SpeculosEmulator::with_automation`]. Buttons can also be pressed directly.
+pub struct SpeculosEmulator<H> {
+    client: H,
+    url: String,
+    automation: String,
+}
+
+impl<H: HttpClient> SpeculosEmulator<H> {
+    pub fn new(client: H, url: impl Into<String>) -> Self {
+        Self {
+            client,
+            url: url.into().trim_end_matches('/').to_string(),
+            automation: SPECULOS_APPROVE_ALL.to_string(),
+        }
+    }
+
+    /// Replaces the automation rules, to reject a prompt for instance.
+    pub fn with_automation(mut self, automation: impl Into<String>) -> Self {
+        self.automation = automation.into();
+        self
+    }
+
+    pub async fn press(&self, button: SpeculosButton) -> Result<(), H::Error> {
+        self.client
+            .request(
+                &format!("{}/button/{}", self.url, button.name()),
+                br#"{"action":"press-and-release"}"#,
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+#[async_trait(?Send)]
+impl<H: HttpClient> EmulatorController for SpeculosEmulator<H> {
+    type Error = H::Error;
+
+    async fn prepare(&mut self) -> Result<(), Self::Error> {
+        self.client
+            .request(
+                &format!("{}/automation", self.url),
+                self.automation.as_bytes(),
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn approve(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// Emulators confirming every prompt themselves: the BitBox02 simulator, and Jade QEMU
+/// images built for unattended CI, the default of Jade's `Dockerfile.qemu`.
+pub struct Unattended;
+
+pub type BitBoxSimulator = Unattended;
+pub type JadeQemu = Unattended;
+
+#[async_trait(?Send)]
+impl EmulatorController for Unattended {
+    type Error = Infallible;
+
+    async fn approve(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, future::poll_fn, task::Poll};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct Recorder {
+        sent: Vec<Vec<u8>>,
+        fail: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for Recorder {
+        type Error = &'static str;
+
+        async fn exchange(&mut self, command: &[u8], _: bool) -> Result<Vec<u8>, Self::Error> {
+            if self.fail {
+                return Err("simulator gone");
+            }
+            self.sent.push(command.to_vec());
+            Ok(Vec::new())
+        }
+    }
+
+    #[derive(Default)]
+    struct Requests(RefCell<Vec<(String, Vec<u8>)>>);
+
+    #[async_trait(?Send)]
+    impl HttpClient for Requests {
+        type Error = Infallible;
+
+        async fn request(&self, url: &str, request: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.0
+                .borrow_mut()
+                .push((url.to_string(), request.to_vec()));
+            Ok(Vec::new())
+        }
+    }
+
+    /// A future pending for `polls` polls.
+    async fn yield_times(mut polls: usize) {
+        poll_fn(|cx| {
+            if polls == 0 {
+                return Poll::Ready(());
+            }
+            polls -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[test]
+    fn coldcard_presses_keys_until_the_command_returns() {
+        let mut controller = ColdcardEmulator::new(Recorder::default()).then_repeat(b'1');
+        let output = block_on(approve_during(&mut controller, || yield_times(1), async {
+            yield_times(5).await;
+            42
+        }))
+        .unwrap();
+        assert_eq!(output, 42);
+        let sent = &controller.transport.sent;
+        assert_eq!(sent[0], b"XKEYy");
+        assert!(sent.len() > 1);
+        assert!(sent[1..].iter().all(|command| command == b"XKEY1"));
+    }
+
+    #[test]
+    fn coldcard_confirms_once_by_default() {
+        let mut controller = ColdcardEmulator::new(Recorder::default());
+        for _ in 0..3 {
+            block_on(controller.approve()).unwrap();
+        }
+        assert_eq!(controller.transport.sent, vec![b"XKEYy".to_vec()]);
+    }
+
+    #[test]
+    fn failed_approval_drops_the_command() {
+        let mut controller = ColdcardEmulator::new(Recorder {
+            fail: true,
+            ..Default::default()
+        });
+        let result = block_on(approve_during(
+            &mut controller,
+            || yield_times(1),
+            futures::future::pending::<()>(),
+        ));
+        assert_eq!(result, Err("simulator gone"));
+    }
+
+    #[test]
+    fn speculos_installs_automation_and_presses_buttons() {
+        let mut controller = SpeculosEmulator::new(Requests::default(), "http://127.0.0.1:5000/");
+        block_on(approve_during(&mut controller, || yield_times(1), async {})).unwrap();
+        block_on(controller.press(SpeculosButton::Both)).unwrap();
+
+        let requests = controller.client.0.borrow();
+        assert_eq!(requests[0].0, "http://127.0.0.1:5000/automation");
+        let rules: serde_json::Value = serde_json::from_slice(&requests[0].1).unwrap();
+        assert_eq!(rules["version"], 1);
+        assert_eq!(requests[1].0, "http://127.0.0.1:5000/button/both");
+        assert_eq!(requests[1].1, br#"{"action":"press-and-release"}"#);
+    }
+}
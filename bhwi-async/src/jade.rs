@@ -1,14 +1,19 @@
-use crate::{HttpClient, Transport};
+use crate::{CommandControl, Error, HttpClient, Transport, run_raw_command};
 use bhwi::{
-    Interpreter,
+    Interpreter, RawCommand,
     bitcoin::Network,
-    jade::{JadeCommand, JadeError, JadeInterpreter, JadeResponse, JadeTransmit},
+    common,
+    jade::{
+        JadeCommand, JadeError, JadeInterpreter, JadeResponse, JadeTransmit, OtaProgress, OtaUpdate,
+    },
 };
 
 pub struct Jade<T, S> {
     pub network: Network,
     pub transport: T,
     pub pinserver: S,
+    control: CommandControl,
+    pending_reply: bool,
 }
 
 impl<T, S> Jade<T, S> {
@@ -17,10 +22,47 @@ impl<T, S> Jade<T, S> {
             network,
             transport,
             pinserver,
+            control: CommandControl::default(),
+            pending_reply: false,
         }
     }
 }
 
+impl<T: Transport, S: HttpClient> Jade<T, S> {
+    /// Upload a compressed firmware image through Jade's OTA protocol. `progress` is called
+    /// after every chunk the device accepted. The device checks the image against
+    /// `update.firmware_hash`, if given, and reboots into the new firmware once it is
+    /// complete.
+    pub async fn update_firmware(
+        &mut self,
+        update: OtaUpdate,
+        mut progress: impl FnMut(OtaProgress),
+    ) -> Result<(), Error<T::Error, S::Error>> {
+        // OTA never involves the pin server, every transmit targets the device.
+        let interpreter =
+            JadeInterpreter::<_, common::Transmit, JadeResponse, JadeError>::default()
+                .with_network(self.network);
+        let mut reported = None;
+        run_raw_command(
+            &mut self.transport,
+            &self.control,
+            &mut self.pending_reply,
+            interpreter,
+            RawCommand(JadeCommand::OtaUpdate(update)),
+            |interpreter| {
+                if let Some(p) = interpreter.ota_progress()
+                    && reported != Some(p.written)
+                {
+                    reported = Some(p.written);
+                    progress(p);
+                }
+            },
+        )
+        .await
+        .map(|_| ())
+    }
+}
+
 impl<C, T, R, E, F, H> crate::CommonInterface<C, T, R, E> for Jade<F, H>
 where
     C: TryInto<JadeCommand, Error = E>,
@@ -32,6 +74,15 @@ where
 {
     type TransportError = F::Error;
     type HttpClientError = H::Error;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn pending_reply(&mut self) -> &mut bool {
+        &mut self.pending_reply
+    }
+
     fn components(
         &mut self,
     ) -> (
@@ -52,3 +103,78 @@ impl<T, S> crate::OnUnlock for Jade<T, S> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use bhwi::jade::api;
+    use futures::{executor::block_on, future::pending};
+
+    use super::*;
+    use crate::HWI;
+
+    /// Transport of a Jade that asks for the pin server on every unlock.
+    #[derive(Default)]
+    struct PinServerRequired {
+        exchanges: usize,
+    }
+
+    #[async_trait(?Send)]
+    impl Transport for PinServerRequired {
+        type Error = std::io::Error;
+        async fn exchange(&mut self, _: &[u8], _: bool) -> Result<Vec<u8>, Self::Error> {
+            self.exchanges += 1;
+            Ok(serde_cbor::to_vec(&api::Response {
+                id: "1".to_string(),
+                seqlen: None,
+                seqnum: None,
+                result: Some(api::AuthUserResponse::PinServerRequired {
+                    http_request: api::PinServerRequest {
+                        params: api::PinServerRequestParams {
+                            urls: api::PinServerUrls::Array(vec![
+                                "https://jadepin.blockstream.com/start_handshake".to_string(),
+                            ]),
+                            method: "POST".to_string(),
+                            accept: "json".to_string(),
+                            data: api::PinParams {
+                                data: "request".to_string(),
+                            },
+                        },
+                        onreply: "pin".to_string(),
+                    },
+                }),
+                error: None,
+            })
+            .unwrap())
+        }
+    }
+
+    /// Pin server that never answers.
+    struct Hung;
+
+    #[async_trait(?Send)]
+    impl HttpClient for Hung {
+        type Error = std::io::Error;
+        async fn request(&self, _: &str, _: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            pending().await
+        }
+    }
+
+    #[test]
+    fn interrupted_pin_server_request_leaves_no_reply_pending() {
+        let mut jade = Jade::new(Network::Testnet, PinServerRequired::default(), Hung);
+        jade.set_control(
+            CommandControl::new()
+                .with_timer(|_| async {})
+                .with_exchange_timeout(Duration::ZERO),
+        );
+        let first = block_on(HWI::unlock(&mut jade, Network::Testnet));
+        assert!(matches!(first, Err(Error::Timeout)));
+        // The device only waits for the server reply, the next command runs.
+        let second = block_on(HWI::unlock(&mut jade, Network::Testnet));
+        assert!(matches!(second, Err(Error::Timeout)));
+        assert_eq!(jade.transport.exchanges, 2);
+    }
+}
@@ -1,17 +1,36 @@
-use crate::{HttpClient, Transport};
+use crate::{CommandControl, HttpClient, Transport};
 use async_trait::async_trait;
 use bhwi::{
     Interpreter,
-    ledger::{LedgerCommand, LedgerError, LedgerInterpreter, LedgerResponse, apdu::ApduCommand},
+    common::{Info, Response},
+    ledger::{
+        AppProtocol, LedgerCommand, LedgerError, LedgerInterpreter, LedgerResponse,
+        apdu::ApduCommand,
+    },
 };
 
 pub struct Ledger<T> {
     pub transport: T,
+    /// Protocol of the open Bitcoin app, detected on unlock.
+    protocol: AppProtocol,
+    control: CommandControl,
+    pending_reply: bool,
 }
 
 impl<T> Ledger<T> {
     pub fn new(transport: T) -> Self {
-        Self { transport }
+        Self {
+            transport,
+            protocol: AppProtocol::default(),
+            control: CommandControl::default(),
+            pending_reply: false,
+        }
+    }
+
+    /// Skip the detection, e.g. when the app version is already known.
+    pub fn with_protocol(mut self, protocol: AppProtocol) -> Self {
+        self.protocol = protocol;
+        self
     }
 }
 
@@ -25,6 +44,15 @@ where
 {
     type TransportError = F::Error;
     type HttpClientError = LedgerError;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn pending_reply(&mut self) -> &mut bool {
+        &mut self.pending_reply
+    }
+
     fn components(
         &mut self,
     ) -> (
@@ -35,13 +63,22 @@ where
         (
             &mut self.transport,
             &DummyClient {},
-            LedgerInterpreter::default(),
+            LedgerInterpreter::default().with_protocol(self.protocol),
         )
     }
 }
 
 impl<T> crate::OnUnlock for Ledger<T> {
-    fn on_unlock(&mut self, _response: bhwi::common::Response) -> Result<(), bhwi::common::Error> {
+    fn on_unlock(&mut self, response: Response) -> Result<(), bhwi::common::Error> {
+        // Unlocking reports the app only when it was already open.
+        if let Response::Info(Info {
+            version,
+            firmware: Some(app_name),
+            ..
+        }) = response
+        {
+            self.protocol = AppProtocol::from_app(&app_name, &version);
+        }
         Ok(())
     }
 }
@@ -54,3 +91,39 @@ impl HttpClient for DummyClient {
         unreachable!("Ledger does not need http client")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::{executor::block_on, future::pending};
+
+    use super::*;
+    use crate::{Error, HWI};
+
+    /// Transport of a device that never answers, like one waiting on its user.
+    struct Unanswered;
+
+    #[async_trait(?Send)]
+    impl Transport for Unanswered {
+        type Error = std::io::Error;
+        async fn exchange(&mut self, _: &[u8], _: bool) -> Result<Vec<u8>, Self::Error> {
+            pending().await
+        }
+    }
+
+    #[test]
+    fn interrupted_prompt_is_not_read_by_the_next_command() {
+        let mut ledger = Ledger::new(Unanswered);
+        ledger.set_control(
+            CommandControl::new()
+                .with_timer(|_| async {})
+                .with_exchange_timeout(Duration::ZERO),
+        );
+        let first = block_on(HWI::get_master_fingerprint(&mut ledger));
+        assert!(matches!(first, Err(Error::Timeout)));
+        // The device still owes the reply to the first command.
+        let second = block_on(HWI::get_master_fingerprint(&mut ledger));
+        assert!(matches!(second, Err(Error::PendingReply)));
+    }
+}
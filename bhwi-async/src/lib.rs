@@ -1,34 +1,60 @@
 #[cfg(feature = "bitbox")]
 pub mod bitbox;
 pub mod coldcard;
+pub mod control;
+#[cfg(feature = "emulators")]
+pub mod emulator;
 pub mod jade;
 pub mod ledger;
+#[cfg(feature = "registry")]
+pub mod registry;
 pub mod transport;
 
 use std::{error::Error as StdError, fmt::Debug, str::FromStr};
 
 use async_trait::async_trait;
+pub use bhwi::common::Attestation;
+pub use bhwi::common::Capabilities;
 pub use bhwi::common::DeviceBackup;
 pub use bhwi::common::DeviceContext;
 pub use bhwi::common::DisplayAddress;
+pub use bhwi::common::ErrorKind;
+pub use bhwi::common::Event;
 pub use bhwi::common::Info;
 pub use bhwi::common::RestoreOptions;
 pub use bhwi::common::SetupOptions;
 pub use bhwi::common::WalletRegistration;
+pub use bhwi::common::Zeroizing;
 use bhwi::miniscript::descriptor::WalletPolicy;
+pub use bhwi::psbt::VersionedPsbt;
 use bhwi::{
     Interpreter,
     bitcoin::{
         Network,
+        address::AddressType,
         bip32::{DerivationPath, Fingerprint, Xpub},
         psbt::Psbt,
         secp256k1::ecdsa::Signature,
     },
     common::{self},
 };
+pub use control::{CancellationToken, CommandControl};
 pub use jade::Jade;
 pub use ledger::Ledger;
 
+/// `$XDG_CONFIG_HOME/bhwi`, falling back to `$HOME/.config/bhwi`. Returns `None` if
+/// neither variable is set.
+#[cfg(any(feature = "bitbox", feature = "registry"))]
+pub(crate) fn config_dir() -> Option<std::path::PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config"))
+        })?;
+    Some(config_dir.join("bhwi"))
+}
+
 #[async_trait(?Send)]
 pub trait Transport {
     type Error: Debug;
@@ -41,6 +67,24 @@ pub trait Transport {
     fn is_post_write_disconnect(&self, _error: &Self::Error) -> bool {
         false
     }
+
+    /// Ask the device to abort the request in flight once its command is cancelled or timed
+    /// out.
+    async fn cancel(&mut self) -> Result<Aborted, Self::Error> {
+        Ok(Aborted::Unsupported)
+    }
+}
+
+/// What became of the request in flight, see [`Transport::cancel`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Aborted {
+    /// The device aborted the request and sent this reply to it.
+    Reply(Vec<u8>),
+    /// The device had no request in flight.
+    Idle,
+    /// The device has no such request: it keeps its prompt up and sends its reply later,
+    /// so it must be reconnected before the next command.
+    Unsupported,
 }
 
 #[async_trait(?Send)]
@@ -52,6 +96,8 @@ pub trait HttpClient {
 #[async_trait(?Send)]
 pub trait HWI {
     type Error: Debug;
+    /// Cancellation and deadlines for the following commands, see [`CommandControl`].
+    fn set_control(&mut self, control: CommandControl);
     async fn backup_device(&mut self) -> Result<DeviceBackup, Self::Error>;
     async fn setup_device(
         &mut self,
@@ -65,8 +111,18 @@ pub trait HWI {
         context: Option<DeviceContext>,
     ) -> Result<bool, Self::Error>;
     async fn toggle_passphrase(&mut self) -> Result<bool, Self::Error>;
+    /// Use `passphrase` as the BIP39 passphrase of the session, or clear it with `None`,
+    /// and return the master fingerprint of the resulting wallet.
+    async fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, Self::Error>;
     async fn unlock(&mut self, network: Network) -> Result<(), Self::Error>;
     async fn get_info(&mut self) -> Result<Info, Self::Error>;
+    /// What the device supports at its firmware version, see [`Info::capabilities`].
+    async fn capabilities(&mut self) -> Result<Capabilities, Self::Error>;
+    /// Challenge the device to prove it is genuine, against the bundled manufacturer roots.
+    async fn verify_attestation(&mut self) -> Result<Attestation, Self::Error>;
     async fn get_master_fingerprint(&mut self) -> Result<Fingerprint, Self::Error>;
     async fn get_extended_pubkey(
         &mut self,
@@ -78,6 +134,14 @@ pub trait HWI {
         message: &[u8],
         path: DerivationPath,
     ) -> Result<(u8, Signature), Self::Error>;
+    /// BIP-322 simple signature of `message` by the `address_type` address of the key at
+    /// `path`. Unsupported on the BitBox02.
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, Self::Error>;
     async fn display_address(
         &mut self,
         address: common::DisplayAddress,
@@ -93,6 +157,12 @@ pub trait HWI {
         psbt: Psbt,
         context: Option<common::DeviceContext>,
     ) -> Result<Psbt, Self::Error>;
+    /// Sign a version 0 or version 2 PSBT through `sign_tx`, returning it in its version.
+    async fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, Self::Error>;
 }
 
 // TODO: this will become a pain to maintain, but we can have a proc-macro
@@ -100,6 +170,7 @@ pub trait HWI {
 // generate the blanket impl which will map the errors to HWIDeviceError
 #[async_trait(?Send)]
 pub trait HWIDevice {
+    fn set_control(&mut self, control: CommandControl);
     async fn backup_device(&mut self) -> Result<DeviceBackup, HWIDeviceError>;
     async fn setup_device(
         &mut self,
@@ -113,8 +184,14 @@ pub trait HWIDevice {
         context: Option<DeviceContext>,
     ) -> Result<bool, HWIDeviceError>;
     async fn toggle_passphrase(&mut self) -> Result<bool, HWIDeviceError>;
+    async fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, HWIDeviceError>;
     async fn unlock(&mut self, network: Network) -> Result<(), HWIDeviceError>;
     async fn get_info(&mut self) -> Result<Info, HWIDeviceError>;
+    async fn capabilities(&mut self) -> Result<Capabilities, HWIDeviceError>;
+    async fn verify_attestation(&mut self) -> Result<Attestation, HWIDeviceError>;
     async fn get_master_fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError>;
     async fn get_extended_pubkey(
         &mut self,
@@ -126,6 +203,12 @@ pub trait HWIDevice {
         message: &[u8],
         path: DerivationPath,
     ) -> Result<(u8, Signature), HWIDeviceError>;
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, HWIDeviceError>;
     async fn display_address(
         &mut self,
         address: common::DisplayAddress,
@@ -141,6 +224,11 @@ pub trait HWIDevice {
         psbt: Psbt,
         context: Option<common::DeviceContext>,
     ) -> Result<Psbt, HWIDeviceError>;
+    async fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, HWIDeviceError>;
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -151,6 +239,19 @@ impl HWIDeviceError {
     pub fn new(error: impl StdError + Send + Sync + 'static) -> Self {
         Self(Box::new(error))
     }
+
+    /// The kind of the interpreter error behind this one, or [`ErrorKind::Transport`] when
+    /// the failure did not come from the interpreter.
+    pub fn kind(&self) -> ErrorKind {
+        let mut error: Option<&(dyn StdError + 'static)> = Some(self.0.as_ref());
+        while let Some(e) = error {
+            if let Some(e) = e.downcast_ref::<common::Error>() {
+                return e.kind();
+            }
+            error = e.source();
+        }
+        ErrorKind::Transport
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -163,6 +264,37 @@ pub enum Error<E, F> {
 
     #[error("interpreter error: {0}")]
     Interpreter(#[from] common::Error),
+
+    #[error("command cancelled")]
+    Cancelled,
+
+    #[error("command timed out")]
+    Timeout,
+
+    /// An interrupted command left the device with a reply still to send, which the next
+    /// command would read as its own.
+    #[error("device still has to reply to an interrupted command, reconnect it")]
+    PendingReply,
+}
+
+impl<E, F> From<control::Interrupt> for Error<E, F> {
+    fn from(interrupt: control::Interrupt) -> Self {
+        match interrupt {
+            control::Interrupt::Cancelled => Error::Cancelled,
+            control::Interrupt::Timeout => Error::Timeout,
+        }
+    }
+}
+
+impl<E, F> Error<E, F> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Transport(_) | Error::HttpClient(_) | Error::Timeout => ErrorKind::Transport,
+            Error::Interpreter(e) => e.kind(),
+            Error::Cancelled => ErrorKind::UserCancelled,
+            Error::PendingReply => ErrorKind::Busy,
+        }
+    }
 }
 
 #[async_trait(?Send)]
@@ -172,6 +304,11 @@ where
         + OnUnlock,
 {
     type Error = Error<D::TransportError, D::HttpClientError>;
+
+    fn set_control(&mut self, control: CommandControl) {
+        *self.control() = control;
+    }
+
     async fn backup_device(&mut self) -> Result<DeviceBackup, Self::Error> {
         if let common::Response::Backup(backup) = run_command(self, common::Command::Backup).await?
         {
@@ -229,6 +366,19 @@ where
         }
     }
 
+    async fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, Self::Error> {
+        if let common::Response::MasterFingerprint(fg) =
+            run_command(self, common::Command::SetPassphrase(passphrase)).await?
+        {
+            Ok(fg)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
     async fn unlock(&mut self, network: Network) -> Result<(), Self::Error> {
         let res = run_command(
             self,
@@ -253,6 +403,22 @@ where
         }
     }
 
+    async fn capabilities(&mut self) -> Result<Capabilities, Self::Error> {
+        let info = HWI::get_info(self).await?;
+        info.capabilities()
+            .ok_or_else(|| common::Error::MissingCommandInfo("device kind").into())
+    }
+
+    async fn verify_attestation(&mut self) -> Result<Attestation, Self::Error> {
+        if let common::Response::Attestation(attestation) =
+            run_command(self, common::Command::VerifyAttestation).await?
+        {
+            Ok(attestation)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
     async fn get_master_fingerprint(&mut self) -> Result<Fingerprint, Self::Error> {
         if let common::Response::MasterFingerprint(fg) =
             run_command(self, common::Command::GetMasterFingerprint).await?
@@ -297,6 +463,28 @@ where
         }
     }
 
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, Self::Error> {
+        if let common::Response::Bip322Signature(signature) = run_command(
+            self,
+            common::Command::SignMessageBip322 {
+                message: message.to_vec(),
+                path,
+                address_type,
+            },
+        )
+        .await?
+        {
+            Ok(signature)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
     async fn display_address(
         &mut self,
         address: common::DisplayAddress,
@@ -346,6 +534,15 @@ where
             Err(common::Error::NoErrorOrResult.into())
         }
     }
+
+    async fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, Self::Error> {
+        let signed = HWI::sign_tx(self, psbt.psbt().clone(), context).await?;
+        Ok(psbt.with_psbt(signed)?)
+    }
 }
 
 #[async_trait(?Send)]
@@ -354,6 +551,10 @@ where
     T: HWI,
     T::Error: StdError + Send + Sync + 'static,
 {
+    fn set_control(&mut self, control: CommandControl) {
+        HWI::set_control(self, control)
+    }
+
     async fn backup_device(&mut self) -> Result<DeviceBackup, HWIDeviceError> {
         HWI::backup_device(self).await.map_err(HWIDeviceError::new)
     }
@@ -388,6 +589,15 @@ where
             .map_err(HWIDeviceError::new)
     }
 
+    async fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, HWIDeviceError> {
+        HWI::set_passphrase(self, passphrase)
+            .await
+            .map_err(HWIDeviceError::new)
+    }
+
     async fn unlock(&mut self, network: Network) -> Result<(), HWIDeviceError> {
         HWI::unlock(self, network)
             .await
@@ -398,6 +608,16 @@ where
         HWI::get_info(self).await.map_err(HWIDeviceError::new)
     }
 
+    async fn capabilities(&mut self) -> Result<Capabilities, HWIDeviceError> {
+        HWI::capabilities(self).await.map_err(HWIDeviceError::new)
+    }
+
+    async fn verify_attestation(&mut self) -> Result<Attestation, HWIDeviceError> {
+        HWI::verify_attestation(self)
+            .await
+            .map_err(HWIDeviceError::new)
+    }
+
     async fn get_master_fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError> {
         HWI::get_master_fingerprint(self)
             .await
@@ -424,6 +644,17 @@ where
             .map_err(HWIDeviceError::new)
     }
 
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, HWIDeviceError> {
+        HWI::sign_message_bip322(self, message, path, address_type)
+            .await
+            .map_err(HWIDeviceError::new)
+    }
+
     async fn display_address(
         &mut self,
         address: DisplayAddress,
@@ -453,6 +684,16 @@ where
             .await
             .map_err(HWIDeviceError::new)
     }
+
+    async fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, HWIDeviceError> {
+        HWI::sign_versioned_tx(self, psbt, context)
+            .await
+            .map_err(HWIDeviceError::new)
+    }
 }
 
 pub trait OnUnlock {
@@ -463,6 +704,14 @@ pub trait CommonInterface<C, T, R, E> {
     type TransportError: Debug;
     type HttpClientError: Debug;
 
+    /// Cancellation and deadlines applied to the commands run on the device.
+    fn control(&mut self) -> &mut CommandControl;
+
+    /// Set once an interrupted command left the device with a reply still to send. Commands
+    /// are then refused with [`Error::PendingReply`] until the device is built again over a
+    /// new connection.
+    fn pending_reply(&mut self) -> &mut bool;
+
     #[allow(clippy::type_complexity)]
     fn components(
         &mut self,
@@ -531,35 +780,168 @@ where
         >,
     C: Into<common::Command>,
 {
-    let (transport, http_client, mut intpr) = device.components();
-    let transmit = intpr.start(command.into())?;
-    let exchange = transport
-        .exchange(&transmit.payload, transmit.encrypted)
+    if *device.pending_reply() {
+        return Err(Error::PendingReply);
+    }
+    let control = device.control().clone();
+    let mut pending_reply = false;
+    let response = {
+        let (transport, http_client, intpr) = device.components();
+        drive_command(
+            &control,
+            transport,
+            http_client,
+            intpr,
+            command.into(),
+            allow_final_disconnect,
+            &mut pending_reply,
+        )
+        .await
+    };
+    *device.pending_reply() = pending_reply;
+    response
+}
+
+async fn drive_command<E, F>(
+    control: &CommandControl,
+    transport: &mut dyn Transport<Error = E>,
+    http_client: &dyn HttpClient<Error = F>,
+    mut intpr: impl Interpreter<
+        Command = common::Command,
+        Transmit = common::Transmit,
+        Response = common::Response,
+        Error = common::Error,
+    >,
+    command: common::Command,
+    allow_final_disconnect: bool,
+    pending_reply: &mut bool,
+) -> Result<common::Response, Error<E, F>> {
+    let mut guard = control.guard();
+    let transmit = intpr.start(command)?;
+    control.forward_events(&mut intpr);
+    let exchange = match guard
+        .run(transport.exchange(&transmit.payload, transmit.encrypted))
         .await
-        .map_err(Error::Transport)?;
+    {
+        Ok(exchange) => exchange.map_err(Error::Transport)?,
+        Err(interrupt) => {
+            return Err(
+                abort(&guard, transport, &mut intpr, interrupt, pending_reply)
+                    .await
+                    .into(),
+            );
+        }
+    };
     let mut transmit = intpr.exchange(exchange)?;
+    control.forward_events(&mut intpr);
     while let Some(t) = &transmit {
         match &t.recipient {
             common::Recipient::PinServer { url } => {
-                let res = http_client
-                    .request(url, &t.payload)
+                // The device waits for the server reply without a request of its own to
+                // abort.
+                let res = match guard
+                    .run_off_device(http_client.request(url, &t.payload))
                     .await
-                    .map_err(Error::HttpClient)?;
+                {
+                    Ok(res) => res.map_err(Error::HttpClient)?,
+                    Err(interrupt) => return Err(interrupt.into()),
+                };
                 transmit = intpr.exchange(res)?;
+                control.forward_events(&mut intpr);
             }
             common::Recipient::Device => {
-                let exchange = match transport.exchange(&t.payload, t.encrypted).await {
-                    Ok(exchange) => exchange,
-                    Err(error)
+                let exchange = match guard.run(transport.exchange(&t.payload, t.encrypted)).await {
+                    Ok(Ok(exchange)) => exchange,
+                    Ok(Err(error))
                         if allow_final_disconnect && transport.is_post_write_disconnect(&error) =>
                     {
                         return Ok(common::Response::DeviceAction(true));
                     }
-                    Err(error) => return Err(Error::Transport(error)),
+                    Ok(Err(error)) => return Err(Error::Transport(error)),
+                    Err(interrupt) => {
+                        return Err(
+                            abort(&guard, transport, &mut intpr, interrupt, pending_reply)
+                                .await
+                                .into(),
+                        );
+                    }
                 };
                 transmit = intpr.exchange(exchange)?;
+                control.forward_events(&mut intpr);
             }
         }
     }
     intpr.end().map_err(|e| e.into())
 }
+
+/// Drive a device's own command, for the operations that have no place in the shared HWI
+/// command surface, through `intpr` and `transport`. `on_exchange` sees the interpreter after
+/// every device reply.
+pub(crate) async fn run_raw_command<I, E, F>(
+    transport: &mut dyn Transport<Error = E>,
+    control: &CommandControl,
+    pending_reply: &mut bool,
+    mut intpr: I,
+    command: I::Command,
+    mut on_exchange: impl FnMut(&I),
+) -> Result<I::Response, Error<E, F>>
+where
+    I: Interpreter<Transmit = common::Transmit>,
+    I::Error: Into<common::Error>,
+{
+    if *pending_reply {
+        return Err(Error::PendingReply);
+    }
+    let mut guard = control.guard();
+    let mut transmit = Some(
+        intpr
+            .start(command)
+            .map_err(|e| Error::Interpreter(e.into()))?,
+    );
+    control.forward_events(&mut intpr);
+    while let Some(t) = transmit {
+        let exchange = match guard.run(transport.exchange(&t.payload, t.encrypted)).await {
+            Ok(exchange) => exchange.map_err(Error::Transport)?,
+            Err(interrupt) => {
+                return Err(
+                    abort(&guard, transport, &mut intpr, interrupt, pending_reply)
+                        .await
+                        .into(),
+                );
+            }
+        };
+        transmit = intpr
+            .exchange(exchange)
+            .map_err(|e| Error::Interpreter(e.into()))?;
+        control.forward_events(&mut intpr);
+        on_exchange(&intpr);
+    }
+    intpr.end().map_err(|e| Error::Interpreter(e.into()))
+}
+
+/// Ask the device to abort the request of an interrupted command. Its reply goes to the
+/// interpreter so that state kept across commands, like the BitBox02 noise channel, stays
+/// in step with the device; the interpreter is dropped with the command. `pending_reply`
+/// is set when the device may still send that reply.
+pub(crate) async fn abort<E, I>(
+    guard: &control::Guard<'_>,
+    transport: &mut dyn Transport<Error = E>,
+    intpr: &mut I,
+    interrupt: control::Interrupt,
+    pending_reply: &mut bool,
+) -> control::Interrupt
+where
+    I: Interpreter,
+{
+    if !guard.in_flight() {
+        return interrupt;
+    }
+    match guard.bounded(transport.cancel()).await {
+        Some(Ok(Aborted::Reply(reply))) => {
+            let _ = intpr.exchange(reply);
+        }
+        Some(Ok(Aborted::Idle)) => {}
+        _ => *pending_reply = true,
+    }
+    interrupt
+}
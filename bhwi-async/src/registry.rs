@@ -0,0 +1,606 @@
+//! Host-side record of the wallet policies registered on each device.
+//!
+//! Registering a wallet yields device-specific state the host has to hand back on every later
+//! use of that wallet: the Ledger HMAC, the BitBox02 policy descriptor, or just the name for
+//! Jade and Coldcard. [`RegistryDevice`] wraps an [`HWIDevice`], records each successful
+//! registration in a [`RegistrationStore`] keyed by (master fingerprint, policy id), and fills
+//! in the [`DeviceContext`] of `sign_tx` and `display_address` from it when the caller does
+//! not provide one.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use async_trait::async_trait;
+use bhwi::{
+    bitcoin::{
+        Network,
+        address::AddressType,
+        bip32::{DerivationPath, Fingerprint, Xpub},
+        hashes::{Hash, sha256},
+        psbt::Psbt,
+        secp256k1::ecdsa::Signature,
+    },
+    common,
+    ledger::{LedgerPolicy, LedgerWalletPolicy, Version},
+    miniscript::descriptor::WalletPolicy,
+    policy::{extract_parts, xpub_origin},
+};
+
+use crate::{
+    Attestation, Capabilities, CommandControl, DeviceBackup, DeviceContext, DisplayAddress,
+    HWIDevice, HWIDeviceError, Info, RestoreOptions, SetupOptions, VersionedPsbt,
+    WalletRegistration, Zeroizing,
+};
+
+/// A wallet policy registered on a device, with the proof of registration if the device
+/// returned one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegisteredWallet {
+    pub name: String,
+    pub policy: WalletPolicy,
+    /// Ledger registration HMAC.
+    pub hmac: Option<[u8; 32]>,
+}
+
+impl RegisteredWallet {
+    pub fn policy_id(&self) -> sha256::Hash {
+        policy_id(&self.policy)
+    }
+
+    /// Whether every input of `psbt` is spent by one of this wallet's keys, judging by the
+    /// key origins of the input derivations.
+    pub fn matches_psbt(&self, psbt: &Psbt) -> bool {
+        let Ok((_, keys)) = extract_parts(&self.policy) else {
+            return false;
+        };
+        let origins: Vec<(Fingerprint, DerivationPath)> = keys
+            .iter()
+            .filter_map(xpub_origin)
+            .filter_map(|(fingerprint, path, _)| Some((fingerprint?, path.unwrap_or_default())))
+            .collect();
+        let is_ours = |(fingerprint, path): &(Fingerprint, DerivationPath)| {
+            origins.iter().any(|(origin_fingerprint, origin_path)| {
+                origin_fingerprint == fingerprint && path.as_ref().starts_with(origin_path.as_ref())
+            })
+        };
+        !psbt.inputs.is_empty()
+            && psbt.inputs.iter().all(|input| {
+                input.bip32_derivation.values().any(is_ours)
+                    || input
+                        .tap_key_origins
+                        .values()
+                        .any(|(_, origin)| is_ours(origin))
+            })
+    }
+}
+
+/// Identifies a wallet policy independently of the name it was registered under: the SHA-256
+/// of its BIP-388 string representation, keys included.
+pub fn policy_id(policy: &WalletPolicy) -> sha256::Hash {
+    sha256::Hash::hash(policy.to_string().as_bytes())
+}
+
+/// Persists the wallets registered on each device, keyed by (master fingerprint, policy id).
+pub trait RegistrationStore {
+    /// Returns every wallet registered on the device with `fingerprint`.
+    fn load(&self, fingerprint: Fingerprint) -> Result<Vec<RegisteredWallet>, io::Error>;
+    /// Records `wallet`, replacing any previous registration of the same policy on the
+    /// device with `fingerprint`.
+    fn store(&self, fingerprint: Fingerprint, wallet: &RegisteredWallet) -> Result<(), io::Error>;
+}
+
+/// [`RegistrationStore`] that lives as long as the process.
+#[derive(Default)]
+pub struct MemoryRegistrationStore {
+    wallets: RefCell<BTreeMap<Fingerprint, Vec<RegisteredWallet>>>,
+}
+
+impl RegistrationStore for MemoryRegistrationStore {
+    fn load(&self, fingerprint: Fingerprint) -> Result<Vec<RegisteredWallet>, io::Error> {
+        Ok(self
+            .wallets
+            .borrow()
+            .get(&fingerprint)
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn store(&self, fingerprint: Fingerprint, wallet: &RegisteredWallet) -> Result<(), io::Error> {
+        let mut wallets = self.wallets.borrow_mut();
+        insert_wallet(wallets.entry(fingerprint).or_default(), wallet);
+        Ok(())
+    }
+}
+
+/// File-backed [`RegistrationStore`] holding the registered wallets as JSON, grouped by
+/// master fingerprint:
+///
+/// ```json
+/// { "f5acc2fd": [{ "name": "...", "descriptor": "...", "hmac": "<hex>" }] }
+/// ```
+pub struct FileRegistrationStore {
+    path: PathBuf,
+}
+
+impl FileRegistrationStore {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Store at `$XDG_CONFIG_HOME/bhwi/wallets.json`, falling back to `$HOME/.config`.
+    /// Returns `None` if neither variable is set.
+    pub fn from_config_dir() -> Option<Self> {
+        crate::config_dir().map(|dir| Self::new(dir.join("wallets.json")))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn read(&self) -> Result<BTreeMap<String, Vec<serde_json::Value>>, io::Error> {
+        let contents = match std::fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e),
+        };
+        Ok(serde_json::from_slice(&contents)?)
+    }
+}
+
+impl RegistrationStore for FileRegistrationStore {
+    fn load(&self, fingerprint: Fingerprint) -> Result<Vec<RegisteredWallet>, io::Error> {
+        self.read()?
+            .remove(&fingerprint.to_string())
+            .unwrap_or_default()
+            .iter()
+            .map(|value| {
+                decode_wallet(value).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid registered wallet")
+                })
+            })
+            .collect()
+    }
+
+    fn store(&self, fingerprint: Fingerprint, wallet: &RegisteredWallet) -> Result<(), io::Error> {
+        let mut entries = self.read()?;
+        let mut wallets = self.load(fingerprint)?;
+        insert_wallet(&mut wallets, wallet);
+        entries.insert(
+            fingerprint.to_string(),
+            wallets.iter().map(encode_wallet).collect(),
+        );
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&self.path, serde_json::to_vec_pretty(&entries)?)
+    }
+}
+
+fn insert_wallet(wallets: &mut Vec<RegisteredWallet>, wallet: &RegisteredWallet) {
+    let id = wallet.policy_id();
+    wallets.retain(|registered| registered.policy_id() != id);
+    wallets.push(wallet.clone());
+}
+
+fn encode_wallet(wallet: &RegisteredWallet) -> serde_json::Value {
+    serde_json::json!({
+        "name": wallet.name,
+        "descriptor": wallet.policy.to_string(),
+        "hmac": wallet.hmac.map(hex::encode),
+    })
+}
+
+fn decode_wallet(value: &serde_json::Value) -> Option<RegisteredWallet> {
+    let hmac = match value.get("hmac") {
+        None | Some(serde_json::Value::Null) => None,
+        Some(hmac) => Some(hex::decode(hmac.as_str()?).ok()?.try_into().ok()?),
+    };
+    Some(RegisteredWallet {
+        name: value.get("name")?.as_str()?.to_string(),
+        policy: WalletPolicy::from_str(value.get("descriptor")?.as_str()?).ok()?,
+        hmac,
+    })
+}
+
+/// The single wallet of `wallets` whose keys spend every input of `psbt`.
+pub fn wallet_spending(wallets: Vec<RegisteredWallet>, psbt: &Psbt) -> Option<RegisteredWallet> {
+    let mut matching = wallets
+        .into_iter()
+        .filter(|wallet| wallet.matches_psbt(psbt));
+    match (matching.next(), matching.next()) {
+        (Some(wallet), None) => Some(wallet),
+        _ => None,
+    }
+}
+
+/// How a device expects a registered wallet to be handed back.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WalletContext {
+    /// `DeviceContext::Ledger` with the policy and its registration HMAC.
+    Ledger,
+    /// `DeviceContext::BitBox` with the policy descriptor.
+    #[cfg(feature = "bitbox")]
+    BitBox,
+    /// The device resolves the wallet by name on its own (Jade, Coldcard).
+    ByName,
+}
+
+impl WalletContext {
+    fn device_context(self, wallet: RegisteredWallet) -> Option<DeviceContext> {
+        match self {
+            WalletContext::Ledger => Some(DeviceContext::Ledger {
+                wallet_policy: LedgerWalletPolicy::new(wallet.name, Version::V2, wallet.policy),
+                wallet_hmac: wallet.hmac,
+            }),
+            #[cfg(feature = "bitbox")]
+            WalletContext::BitBox => Some(DeviceContext::BitBox {
+                policy: wallet.policy,
+            }),
+            WalletContext::ByName => None,
+        }
+    }
+}
+
+/// Wraps a device so that wallet registrations are recorded in a [`RegistrationStore`] and
+/// looked up again by `sign_tx` and `display_address`.
+///
+/// - `register_wallet` stores the wallet once the device reports it complete.
+/// - `display_address` by descriptor without a context uses the wallet registered under
+///   that name.
+/// - `sign_tx` without a context uses the single registered wallet whose keys spend every
+///   input of the PSBT.
+/// - A Ledger context without an HMAC gets the HMAC registered for its policy.
+pub struct RegistryDevice {
+    device: Box<dyn HWIDevice>,
+    context: WalletContext,
+    store: Box<dyn RegistrationStore>,
+    fingerprint: Option<Fingerprint>,
+}
+
+impl RegistryDevice {
+    pub fn new(
+        device: Box<dyn HWIDevice>,
+        context: WalletContext,
+        store: Box<dyn RegistrationStore>,
+    ) -> Self {
+        Self {
+            device,
+            context,
+            store,
+            fingerprint: None,
+        }
+    }
+
+    async fn fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError> {
+        if let Some(fingerprint) = self.fingerprint {
+            return Ok(fingerprint);
+        }
+        let fingerprint = self.device.get_master_fingerprint().await?;
+        self.fingerprint = Some(fingerprint);
+        Ok(fingerprint)
+    }
+
+    async fn registered_wallets(&mut self) -> Result<Vec<RegisteredWallet>, HWIDeviceError> {
+        let fingerprint = self.fingerprint().await?;
+        self.store.load(fingerprint).map_err(store_error)
+    }
+
+    /// Fill in the HMAC of a Ledger context, if missing and the policy was registered.
+    async fn complete_context(
+        &mut self,
+        context: DeviceContext,
+    ) -> Result<DeviceContext, HWIDeviceError> {
+        let DeviceContext::Ledger {
+            wallet_policy:
+                LedgerWalletPolicy {
+                    policy: LedgerPolicy::Miniscript(ref policy),
+                    ..
+                },
+            wallet_hmac: None,
+        } = context
+        else {
+            return Ok(context);
+        };
+        let id = policy_id(policy);
+        let hmac = self
+            .registered_wallets()
+            .await?
+            .into_iter()
+            .find(|wallet| wallet.policy_id() == id)
+            .and_then(|wallet| wallet.hmac);
+        Ok(match context {
+            DeviceContext::Ledger { wallet_policy, .. } => DeviceContext::Ledger {
+                wallet_policy,
+                wallet_hmac: hmac,
+            },
+            context => context,
+        })
+    }
+}
+
+fn store_error(e: io::Error) -> HWIDeviceError {
+    HWIDeviceError::new(common::Error::Device(
+        common::ErrorKind::Transport,
+        format!("wallet registration store error: {e}"),
+    ))
+}
+
+#[async_trait(?Send)]
+impl HWIDevice for RegistryDevice {
+    fn set_control(&mut self, control: CommandControl) {
+        self.device.set_control(control)
+    }
+
+    async fn backup_device(&mut self) -> Result<DeviceBackup, HWIDeviceError> {
+        self.device.backup_device().await
+    }
+
+    async fn setup_device(
+        &mut self,
+        options: SetupOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError> {
+        self.fingerprint = None;
+        self.device.setup_device(options, context).await
+    }
+
+    async fn wipe_device(&mut self) -> Result<bool, HWIDeviceError> {
+        self.fingerprint = None;
+        self.device.wipe_device().await
+    }
+
+    async fn restore_device(
+        &mut self,
+        options: RestoreOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError> {
+        self.fingerprint = None;
+        self.device.restore_device(options, context).await
+    }
+
+    async fn toggle_passphrase(&mut self) -> Result<bool, HWIDeviceError> {
+        self.fingerprint = None;
+        self.device.toggle_passphrase().await
+    }
+
+    async fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, HWIDeviceError> {
+        self.fingerprint = None;
+        let fingerprint = self.device.set_passphrase(passphrase).await?;
+        self.fingerprint = Some(fingerprint);
+        Ok(fingerprint)
+    }
+
+    async fn unlock(&mut self, network: Network) -> Result<(), HWIDeviceError> {
+        // A passphrase entered on unlock changes the master fingerprint.
+        self.fingerprint = None;
+        self.device.unlock(network).await
+    }
+
+    async fn get_info(&mut self) -> Result<Info, HWIDeviceError> {
+        self.device.get_info().await
+    }
+
+    async fn capabilities(&mut self) -> Result<Capabilities, HWIDeviceError> {
+        self.device.capabilities().await
+    }
+
+    async fn verify_attestation(&mut self) -> Result<Attestation, HWIDeviceError> {
+        self.device.verify_attestation().await
+    }
+
+    async fn get_master_fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError> {
+        let fingerprint = self.device.get_master_fingerprint().await?;
+        self.fingerprint = Some(fingerprint);
+        Ok(fingerprint)
+    }
+
+    async fn get_extended_pubkey(
+        &mut self,
+        path: DerivationPath,
+        display: bool,
+    ) -> Result<Xpub, HWIDeviceError> {
+        self.device.get_extended_pubkey(path, display).await
+    }
+
+    async fn sign_message(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+    ) -> Result<(u8, Signature), HWIDeviceError> {
+        self.device.sign_message(message, path).await
+    }
+
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, HWIDeviceError> {
+        self.device
+            .sign_message_bip322(message, path, address_type)
+            .await
+    }
+
+    async fn display_address(
+        &mut self,
+        address: DisplayAddress,
+        context: Option<DeviceContext>,
+    ) -> Result<String, HWIDeviceError> {
+        let context = match (&address, context) {
+            (_, Some(context)) => Some(self.complete_context(context).await?),
+            (
+                DisplayAddress::ByDescriptor {
+                    descriptor_name, ..
+                },
+                None,
+            ) if self.context != WalletContext::ByName => self
+                .registered_wallets()
+                .await?
+                .into_iter()
+                .find(|wallet| &wallet.name == descriptor_name)
+                .and_then(|wallet| self.context.device_context(wallet)),
+            (_, None) => None,
+        };
+        self.device.display_address(address, context).await
+    }
+
+    async fn register_wallet(
+        &mut self,
+        name: &str,
+        policy: &str,
+    ) -> Result<WalletRegistration, HWIDeviceError> {
+        let wallet_policy = WalletPolicy::from_str(policy)
+            .map_err(|e| HWIDeviceError::new(common::Error::Serialization(e.to_string())))?;
+        let registration = self.device.register_wallet(name, policy).await?;
+        if let WalletRegistration::Complete { hmac } = registration {
+            let fingerprint = self.fingerprint().await?;
+            let wallet = RegisteredWallet {
+                name: name.to_string(),
+                policy: wallet_policy,
+                hmac,
+            };
+            self.store
+                .store(fingerprint, &wallet)
+                .map_err(store_error)?;
+        }
+        Ok(registration)
+    }
+
+    async fn sign_tx(
+        &mut self,
+        psbt: Psbt,
+        context: Option<DeviceContext>,
+    ) -> Result<Psbt, HWIDeviceError> {
+        let context = match context {
+            Some(context) => Some(self.complete_context(context).await?),
+            None if self.context != WalletContext::ByName => {
+                wallet_spending(self.registered_wallets().await?, &psbt)
+                    .and_then(|wallet| self.context.device_context(wallet))
+            }
+            None => None,
+        };
+        self.device.sign_tx(psbt, context).await
+    }
+
+    async fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<DeviceContext>,
+    ) -> Result<VersionedPsbt, HWIDeviceError> {
+        let signed = self.sign_tx(psbt.psbt().clone(), context).await?;
+        psbt.with_psbt(signed).map_err(HWIDeviceError::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = "wsh(sortedmulti(2,[f5acc2fd/48'/1'/0'/2']tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP/<0;1>/*,[00000000/48'/1'/0'/2']tpubDDtb2WPYwEWw2WWDV7reLV348iJHw2HmhzvPysKKrJw3hYmvrd4jasyoioVPdKGQqjyaBMEvTn1HvHWDSVqQ6amyyxRZ5YjpPBBGjJ8yu8S/<0;1>/*))";
+
+    fn wallet(name: &str, hmac: Option<[u8; 32]>) -> RegisteredWallet {
+        RegisteredWallet {
+            name: name.to_string(),
+            policy: WalletPolicy::from_str(DESCRIPTOR).unwrap(),
+            hmac,
+        }
+    }
+
+    #[test]
+    fn store_replaces_registration_of_same_policy() {
+        let fingerprint = Fingerprint::from([0xf5, 0xac, 0xc2, 0xfd]);
+        let store = MemoryRegistrationStore::default();
+        store.store(fingerprint, &wallet("first", None)).unwrap();
+        store
+            .store(fingerprint, &wallet("second", Some([1; 32])))
+            .unwrap();
+        assert_eq!(
+            store.load(fingerprint).unwrap(),
+            vec![wallet("second", Some([1; 32]))]
+        );
+        assert!(store.load(Fingerprint::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn file_registration_store_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("bhwi-registry-{}", std::process::id()));
+        let store = FileRegistrationStore::new(dir.join("wallets.json"));
+        let fingerprint = Fingerprint::from([0xf5, 0xac, 0xc2, 0xfd]);
+        assert!(store.load(fingerprint).unwrap().is_empty());
+
+        store
+            .store(fingerprint, &wallet("multisig", Some([7; 32])))
+            .unwrap();
+        store
+            .store(Fingerprint::default(), &wallet("other", None))
+            .unwrap();
+        assert_eq!(
+            store.load(fingerprint).unwrap(),
+            vec![wallet("multisig", Some([7; 32]))]
+        );
+        assert_eq!(
+            store.load(Fingerprint::default()).unwrap(),
+            vec![wallet("other", None)]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn psbt_spent_by(origin: &str) -> Psbt {
+        use bhwi::bitcoin::{
+            Transaction, TxIn, absolute::LockTime, secp256k1::PublicKey, transaction::Version,
+        };
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: Vec::new(),
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        let (fingerprint, path) = origin.split_once('/').unwrap();
+        let pubkey = PublicKey::from_str(
+            "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+        )
+        .unwrap();
+        psbt.inputs[0].bip32_derivation.insert(
+            pubkey,
+            (
+                Fingerprint::from_str(fingerprint).unwrap(),
+                DerivationPath::from_str(path).unwrap(),
+            ),
+        );
+        psbt
+    }
+
+    #[test]
+    fn psbt_is_matched_by_key_origins() {
+        let wallet = wallet("multisig", None);
+        assert!(wallet.matches_psbt(&psbt_spent_by("f5acc2fd/48'/1'/0'/2'/0/3")));
+        assert!(!wallet.matches_psbt(&psbt_spent_by("f5acc2fd/84'/1'/0'/0/3")));
+        assert!(!wallet.matches_psbt(&psbt_spent_by("42b7a5e5/48'/1'/0'/2'/0/3")));
+    }
+
+    #[test]
+    fn ledger_context_carries_hmac() {
+        assert!(matches!(
+            WalletContext::Ledger.device_context(wallet("multisig", Some([7; 32]))),
+            Some(DeviceContext::Ledger {
+                wallet_hmac: Some([7, ..]),
+                ..
+            })
+        ));
+        assert!(
+            WalletContext::ByName
+                .device_context(wallet("multisig", None))
+                .is_none()
+        );
+    }
+}
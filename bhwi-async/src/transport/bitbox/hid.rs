@@ -1,9 +1,10 @@
-use crate::{Transport, transport::Channel};
+use crate::{Aborted, Transport, transport::Channel};
 use async_trait::async_trait;
 
 use bhwi::bitbox::u2f::{MAX_LEN, U2fHid};
 pub use bhwi::bitbox::{
-    BITBOX02_HID_USAGE_PAGE, BITBOX02_PID, BITBOX02_PRODUCT_STRINGS, BITBOX02_VID,
+    BITBOX02_BOOTLOADER_PRODUCT_STRINGS, BITBOX02_HID_USAGE_PAGE, BITBOX02_PID,
+    BITBOX02_PRODUCT_STRINGS, BITBOX02_VID,
 };
 use bhwi::device::DeviceId;
 
@@ -13,11 +14,14 @@ pub const BITBOX02_DEVICE_ID: DeviceId = DeviceId::new(BITBOX02_VID)
 
 /// U2F HID CMD byte for BitBox02 firmware traffic (`0xC1`).
 const FIRMWARE_CMD: u8 = 0x80 + 0x40 + 0x01;
+/// U2F HID CMD byte for BitBox02 bootloader traffic (`0xC3`).
+const BOOTLOADER_CMD: u8 = 0x80 + 0x40 + 0x03;
 const HID_PACKET_LEN: usize = 64;
 
 // HWW-level framing (firmware >= 7.0.0).
 const HWW_REQ_NEW: u8 = 0x00;
 const HWW_REQ_RETRY: u8 = 0x01;
+const HWW_REQ_CANCEL: u8 = 0x02;
 const HWW_RSP_ACK: u8 = 0x00;
 const HWW_RSP_NOTREADY: u8 = 0x01;
 const HWW_RSP_BUSY: u8 = 0x02;
@@ -112,19 +116,10 @@ impl<C: Channel> BitBoxTransportHID<C> {
         self.write_frame(msg).await?;
         self.read_frame().await
     }
-}
-
-#[async_trait(?Send)]
-impl<C: Channel> Transport for BitBoxTransportHID<C> {
-    type Error = BitBoxHIDError;
 
-    async fn exchange(&mut self, request: &[u8], _encrypted: bool) -> Result<Vec<u8>, Self::Error> {
-        let mut framed = Vec::with_capacity(1 + request.len());
-        framed.push(HWW_REQ_NEW);
-        framed.extend_from_slice(request);
-
-        let mut response = self.query_raw(&framed).await?;
-        // Retry loop for BUSY / NOTREADY.
+    /// Send an HWW request and retry while the device is not ready, until it answers.
+    async fn query_hww(&mut self, msg: &[u8]) -> Result<Vec<u8>, BitBoxHIDError> {
+        let mut response = self.query_raw(msg).await?;
         loop {
             match response.first() {
                 Some(&HWW_RSP_ACK) => return Ok(response.split_off(1)),
@@ -137,14 +132,70 @@ impl<C: Channel> Transport for BitBoxTransportHID<C> {
             }
         }
     }
+}
+
+#[async_trait(?Send)]
+impl<C: Channel> Transport for BitBoxTransportHID<C> {
+    type Error = BitBoxHIDError;
+
+    async fn exchange(&mut self, request: &[u8], _encrypted: bool) -> Result<Vec<u8>, Self::Error> {
+        let mut framed = Vec::with_capacity(1 + request.len());
+        framed.push(HWW_REQ_NEW);
+        framed.extend_from_slice(request);
+        self.query_hww(&framed).await
+    }
+
+    /// The firmware aborts the workflow waiting for the user and answers the cancelled
+    /// request, with a user abort error. A device with nothing pending refuses the cancel.
+    async fn cancel(&mut self) -> Result<Aborted, Self::Error> {
+        match self.query_hww(&[HWW_REQ_CANCEL]).await {
+            Ok(response) => Ok(Aborted::Reply(response)),
+            Err(BitBoxHIDError::Nack) => Ok(Aborted::Idle),
+            Err(error) => Err(error),
+        }
+    }
 
     fn is_post_write_disconnect(&self, error: &Self::Error) -> bool {
         matches!(error, BitBoxHIDError::Read(_))
     }
 }
 
+/// Transport to the BitBox02 bootloader. Its requests go unframed, in U2F HID reports
+/// carrying the bootloader command byte.
+pub struct BitBoxBootloaderTransportHID<C> {
+    inner: BitBoxTransportHID<C>,
+}
+
+impl<C> BitBoxBootloaderTransportHID<C> {
+    pub fn new(channel: C) -> Self {
+        Self {
+            inner: BitBoxTransportHID {
+                channel,
+                u2f: U2fHid::new(BOOTLOADER_CMD),
+            },
+        }
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Channel> Transport for BitBoxBootloaderTransportHID<C> {
+    type Error = BitBoxHIDError;
+
+    async fn exchange(&mut self, request: &[u8], _encrypted: bool) -> Result<Vec<u8>, Self::Error> {
+        self.inner.query_raw(request).await
+    }
+
+    fn is_post_write_disconnect(&self, error: &Self::Error) -> bool {
+        self.inner.is_post_write_disconnect(error)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use std::{cell::RefCell, collections::VecDeque};
+
+    use futures::executor::block_on;
+
     use super::*;
 
     struct UnusedChannel;
@@ -175,4 +226,83 @@ mod tests {
         );
         assert!(!transport.is_post_write_disconnect(&BitBoxHIDError::Nack));
     }
+
+    /// Records the HID reports written and replays recorded ones.
+    struct Recorded {
+        written: RefCell<Vec<Vec<u8>>>,
+        replies: VecDeque<Vec<u8>>,
+    }
+
+    #[async_trait(?Send)]
+    impl Channel for Recorded {
+        async fn send(&self, data: &[u8]) -> Result<usize, std::io::Error> {
+            self.written.borrow_mut().push(data.to_vec());
+            Ok(data.len())
+        }
+
+        async fn receive(&mut self, data: &mut [u8]) -> Result<usize, std::io::Error> {
+            let reply = self.replies.pop_front().expect("recorded reply");
+            data.copy_from_slice(&reply);
+            Ok(reply.len())
+        }
+    }
+
+    fn report(bytes: &[u8]) -> Vec<u8> {
+        let mut report = bytes.to_vec();
+        report.resize(HID_PACKET_LEN, 0);
+        report
+    }
+
+    #[test]
+    fn bootloader_requests_are_unframed_u2f_reports() {
+        // Versions query: opcode `v`, then `v`, status 0, firmware version 42 and signing
+        // keydata version 3.
+        let cid = [0xff, 0x00, 0xff, 0x00];
+        let request = [&cid[..], &[BOOTLOADER_CMD, 0x00, 0x01, b'v']].concat();
+        let reply = [
+            &cid[..],
+            &[BOOTLOADER_CMD, 0x00, 0x0a, b'v', 0x00],
+            &[0x2a, 0, 0, 0, 0x03, 0, 0, 0],
+        ]
+        .concat();
+        let mut transport = BitBoxBootloaderTransportHID::new(Recorded {
+            written: RefCell::new(Vec::new()),
+            replies: VecDeque::from([report(&reply)]),
+        });
+
+        let response = block_on(transport.exchange(b"v", false)).unwrap();
+        assert_eq!(response, [b'v', 0x00, 0x2a, 0, 0, 0, 0x03, 0, 0, 0]);
+        assert_eq!(
+            *transport.inner.channel.written.borrow(),
+            vec![report(&request)]
+        );
+    }
+
+    #[test]
+    fn cancel_settles_the_request_in_flight() {
+        let cid = [0xff, 0x00, 0xff, 0x00];
+        let frame = |bytes: &[u8]| {
+            report(&[&cid[..], &[FIRMWARE_CMD, 0x00, bytes.len() as u8], bytes].concat())
+        };
+        // Not ready yet, then the aborted request answers.
+        let mut transport = BitBoxTransportHID::new(Recorded {
+            written: RefCell::new(Vec::new()),
+            replies: VecDeque::from([frame(&[HWW_RSP_NOTREADY]), frame(&[HWW_RSP_ACK, 0xab])]),
+        });
+        assert_eq!(
+            block_on(transport.cancel()).unwrap(),
+            Aborted::Reply(vec![0xab])
+        );
+        assert_eq!(
+            *transport.channel.written.borrow(),
+            vec![frame(&[HWW_REQ_CANCEL]), frame(&[HWW_REQ_RETRY])]
+        );
+
+        // Nothing to cancel.
+        let mut transport = BitBoxTransportHID::new(Recorded {
+            written: RefCell::new(Vec::new()),
+            replies: VecDeque::from([frame(&[HWW_RSP_NACK])]),
+        });
+        assert_eq!(block_on(transport.cancel()).unwrap(), Aborted::Idle);
+    }
 }
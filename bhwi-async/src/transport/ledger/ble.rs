@@ -0,0 +1,283 @@
+//! Ledger APDU framing over Bluetooth Low Energy (Nano X, Stax, Flex).
+//!
+//! The `Channel` is expected to write to the device's write characteristic and to yield one
+//! notification of the notify characteristic per `receive`. Unlike HID there is no channel
+//! id and frames are sized by the negotiated MTU:
+//!
+//! - first frame: `0x05 | seq (u16 BE) | apdu length (u16 BE) | data`
+//! - next frames: `0x05 | seq (u16 BE) | data`
+//!
+//! The MTU is queried from the device with `0x08 0x00 0x00 0x00 0x00`, answered by
+//! `0x08 .. .. .. .. <mtu>`, before the first exchange.
+
+use async_trait::async_trait;
+
+use crate::{Transport, transport::Channel};
+
+const TAG_APDU: u8 = 0x05;
+const TAG_MTU: u8 = 0x08;
+const MTU_REQUEST: [u8; 5] = [TAG_MTU, 0x00, 0x00, 0x00, 0x00];
+
+/// Frame size for the minimal ATT MTU of 23 bytes, used if the device reports less.
+pub const LEDGER_BLE_DEFAULT_MTU: usize = 20;
+/// Largest notification a BLE stack delivers (maximal ATT attribute length).
+const LEDGER_BLE_READ_SIZE: usize = 512;
+
+#[derive(Debug, thiserror::Error)]
+pub enum LedgerBleError {
+    #[error("communication error: {0}")]
+    Comm(&'static str),
+
+    #[error("BLE IO error")]
+    Ble(#[from] std::io::Error),
+}
+
+pub struct LedgerTransportBle<C: Channel> {
+    channel: C,
+    mtu: Option<usize>,
+}
+
+impl<C: Channel> LedgerTransportBle<C> {
+    pub fn new(channel: C) -> Self {
+        Self { channel, mtu: None }
+    }
+
+    /// Skip the MTU negotiation, e.g. when the BLE stack already reports the ATT MTU
+    /// (frame size is the ATT MTU minus 3).
+    pub fn with_mtu(channel: C, att_mtu: usize) -> Self {
+        Self {
+            channel,
+            mtu: Some(att_mtu.saturating_sub(3).max(LEDGER_BLE_DEFAULT_MTU)),
+        }
+    }
+
+    /// Query the frame size from the device. Called by the first exchange if the MTU is not
+    /// known yet.
+    pub async fn negotiate_mtu(&mut self) -> Result<usize, LedgerBleError> {
+        self.send(&MTU_REQUEST).await?;
+        let mut buffer = vec![0u8; LEDGER_BLE_READ_SIZE];
+        let mtu = loop {
+            let size = self.channel.receive(&mut buffer).await?;
+            if size > 0 && buffer[0] == TAG_MTU {
+                if size < 6 {
+                    return Err(LedgerBleError::Comm("Read error. Incomplete MTU response"));
+                }
+                break (buffer[5] as usize).max(LEDGER_BLE_DEFAULT_MTU);
+            }
+        };
+        self.mtu = Some(mtu);
+        Ok(mtu)
+    }
+
+    async fn send(&self, frame: &[u8]) -> Result<(), LedgerBleError> {
+        let size = self.channel.send(frame).await?;
+        if size < frame.len() {
+            return Err(LedgerBleError::Comm(
+                "BLE write error. Could not send whole message",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Split `apdu` into frames of at most `mtu` bytes.
+fn frames(apdu: &[u8], mtu: usize) -> Vec<Vec<u8>> {
+    let mut frames = Vec::new();
+    let mut remaining = apdu;
+    let mut sequence_idx = 0u16;
+    loop {
+        let mut frame = Vec::with_capacity(mtu);
+        frame.push(TAG_APDU);
+        frame.extend_from_slice(&sequence_idx.to_be_bytes());
+        if sequence_idx == 0 {
+            frame.extend_from_slice(&(apdu.len() as u16).to_be_bytes());
+        }
+        let take = remaining.len().min(mtu - frame.len());
+        frame.extend_from_slice(&remaining[..take]);
+        remaining = &remaining[take..];
+        frames.push(frame);
+        if remaining.is_empty() {
+            return frames;
+        }
+        sequence_idx += 1;
+    }
+}
+
+#[async_trait(?Send)]
+impl<C: Channel> Transport for LedgerTransportBle<C> {
+    type Error = LedgerBleError;
+
+    async fn exchange(
+        &mut self,
+        apdu_command: &[u8],
+        _encrypted: bool,
+    ) -> Result<Vec<u8>, Self::Error> {
+        if apdu_command.len() > u16::MAX as usize {
+            return Err(LedgerBleError::Comm("APDU too long"));
+        }
+        let mtu = match self.mtu {
+            Some(mtu) => mtu,
+            None => self.negotiate_mtu().await?,
+        };
+
+        for frame in frames(apdu_command, mtu) {
+            self.send(&frame).await?;
+        }
+
+        let mut apdu_answer: Vec<u8> = Vec::new();
+        let mut buffer = vec![0u8; LEDGER_BLE_READ_SIZE];
+        let mut sequence_idx = 0u16;
+        let mut expected_apdu_len = 0usize;
+
+        loop {
+            let size = self.channel.receive(&mut buffer).await?;
+            let frame = &buffer[..size];
+
+            if (sequence_idx == 0 && size < 5) || size < 3 {
+                return Err(LedgerBleError::Comm("Read error. Incomplete header"));
+            }
+            if frame[0] != TAG_APDU {
+                return Err(LedgerBleError::Comm("Invalid tag"));
+            }
+            if u16::from_be_bytes([frame[1], frame[2]]) != sequence_idx {
+                return Err(LedgerBleError::Comm("Invalid sequence idx"));
+            }
+
+            let data = if sequence_idx == 0 {
+                expected_apdu_len = u16::from_be_bytes([frame[3], frame[4]]) as usize;
+                &frame[5..]
+            } else {
+                &frame[3..]
+            };
+            let missing = expected_apdu_len - apdu_answer.len();
+            apdu_answer.extend_from_slice(&data[..data.len().min(missing)]);
+
+            if apdu_answer.len() >= expected_apdu_len {
+                return Ok(apdu_answer);
+            }
+            sequence_idx = sequence_idx
+                .checked_add(1)
+                .ok_or(LedgerBleError::Comm("Too many frames"))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque};
+
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Replays notifications and records the frames written to the device.
+    struct RecordedChannel {
+        sent: RefCell<Vec<Vec<u8>>>,
+        notifications: VecDeque<Vec<u8>>,
+    }
+
+    impl RecordedChannel {
+        fn new(notifications: &[&[u8]]) -> Self {
+            Self {
+                sent: RefCell::new(Vec::new()),
+                notifications: notifications.iter().map(|n| n.to_vec()).collect(),
+            }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl Channel for RecordedChannel {
+        async fn send(&self, data: &[u8]) -> Result<usize, std::io::Error> {
+            self.sent.borrow_mut().push(data.to_vec());
+            Ok(data.len())
+        }
+
+        async fn receive(&mut self, data: &mut [u8]) -> Result<usize, std::io::Error> {
+            let notification = self
+                .notifications
+                .pop_front()
+                .ok_or(std::io::ErrorKind::UnexpectedEof)?;
+            data[..notification.len()].copy_from_slice(&notification);
+            Ok(notification.len())
+        }
+    }
+
+    // GET_MASTER_FINGERPRINT of the Bitcoin app and its reply.
+    const GET_FINGERPRINT: [u8; 5] = [0xe1, 0x05, 0x00, 0x00, 0x00];
+    const FINGERPRINT_REPLY: [u8; 6] = [0xf5, 0xac, 0xc2, 0xfd, 0x90, 0x00];
+
+    #[test]
+    fn negotiates_mtu_before_first_exchange() {
+        let channel = RecordedChannel::new(&[
+            &[0x08, 0x00, 0x00, 0x00, 0x01, 0x99],
+            &[
+                0x05, 0x00, 0x00, 0x00, 0x06, 0xf5, 0xac, 0xc2, 0xfd, 0x90, 0x00,
+            ],
+        ]);
+        let mut transport = LedgerTransportBle::new(channel);
+        let reply = block_on(transport.exchange(&GET_FINGERPRINT, false)).unwrap();
+        assert_eq!(reply, FINGERPRINT_REPLY);
+        assert_eq!(transport.mtu, Some(0x99));
+        assert_eq!(
+            *transport.channel.sent.borrow(),
+            vec![
+                MTU_REQUEST.to_vec(),
+                vec![0x05, 0x00, 0x00, 0x00, 0x05, 0xe1, 0x05, 0x00, 0x00, 0x00],
+            ]
+        );
+    }
+
+    #[test]
+    fn small_reported_mtu_falls_back_to_default() {
+        let channel = RecordedChannel::new(&[&[0x08, 0x00, 0x00, 0x00, 0x01, 0x10]]);
+        let mut transport = LedgerTransportBle::new(channel);
+        assert_eq!(
+            block_on(transport.negotiate_mtu()).unwrap(),
+            LEDGER_BLE_DEFAULT_MTU
+        );
+    }
+
+    #[test]
+    fn splits_and_reassembles_across_frames() {
+        // 40-byte APDU: 15 bytes in the first 20-byte frame of a 23-byte ATT MTU, then 17
+        // and 8.
+        let apdu: Vec<u8> = (0..40).collect();
+        let mut reply: Vec<u8> = (100..130).collect();
+        reply.extend_from_slice(&[0x90, 0x00]);
+
+        let mut first = vec![0x05, 0x00, 0x00, 0x00, 32];
+        first.extend_from_slice(&reply[..15]);
+        let mut second = vec![0x05, 0x00, 0x01];
+        second.extend_from_slice(&reply[15..]);
+        let channel = RecordedChannel::new(&[&first, &second]);
+
+        let mut transport = LedgerTransportBle::with_mtu(channel, 23);
+        assert_eq!(block_on(transport.exchange(&apdu, false)).unwrap(), reply);
+
+        let sent = transport.channel.sent.borrow();
+        assert_eq!(sent.len(), 3);
+        assert_eq!(sent[0][..5], [0x05, 0x00, 0x00, 0x00, 40]);
+        assert_eq!(sent[0][5..], apdu[..15]);
+        assert_eq!(sent[1][..3], [0x05, 0x00, 0x01]);
+        assert_eq!(sent[1][3..], apdu[15..32]);
+        assert_eq!(sent[2][..3], [0x05, 0x00, 0x02]);
+        assert_eq!(sent[2][3..], apdu[32..]);
+        assert!(
+            sent.iter()
+                .all(|frame| frame.len() <= LEDGER_BLE_DEFAULT_MTU)
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_sequence_frame() {
+        let channel = RecordedChannel::new(&[
+            &[0x05, 0x00, 0x00, 0x00, 0x20, 0x01, 0x02],
+            &[0x05, 0x00, 0x02, 0x03],
+        ]);
+        let mut transport = LedgerTransportBle::with_mtu(channel, 23);
+        assert!(matches!(
+            block_on(transport.exchange(&GET_FINGERPRINT, false)),
+            Err(LedgerBleError::Comm("Invalid sequence idx"))
+        ));
+    }
+}
@@ -1,3 +1,4 @@
+pub mod ble;
 pub mod hid;
 #[cfg(feature = "emulators")]
 pub mod speculos;
@@ -0,0 +1,307 @@
+use bhwi::{
+    Interpreter, RawCommand,
+    bitbox::{
+        BackupInfo, BitBoxCommand, BitBoxInterpreter, BitBoxResponse,
+        bootloader::{
+            BootloaderCommand, BootloaderHashes, BootloaderInterpreter, BootloaderResponse,
+            BootloaderVersions, SignedFirmware, UpgradeProgress,
+        },
+        error::BitBoxError,
+        noise::{NoiseConfigData, NoiseState},
+    },
+    bitcoin::Network,
+    common,
+};
+
+use crate::{CommandControl, HttpClient, Transport};
+
+/// Blocking BitBox02 client. Holds the noise-encryption state that persists across
+/// interpreter invocations. The caller is expected to:
+///
+/// 1. Construct with `BitBox::new(transport, load_persisted_config())`.
+/// 2. Call `HWI::unlock(&mut bb, network)?` to drive the handshake and pair. The call
+///    blocks until the user confirms the pairing code on the device; on a first-time pair
+///    the code is raised as [`common::Event::PairingCodeAvailable`] to the listener of
+///    [`CommandControl::with_events`] beforehand.
+/// 3. Persist `bb.noise_config_data()` externally for future sessions.
+/// 4. Issue further HWI calls (`get_master_fingerprint`, ...).
+pub struct BitBox<T> {
+    pub transport: T,
+    pub network: Network,
+    noise: NoiseState,
+    control: CommandControl,
+}
+
+impl<T> BitBox<T> {
+    /// `pairing_data` is `None` on first pair; on reconnect, pass the previously persisted
+    /// noise config data back in. Defaults to mainnet; use [`BitBox::with_network`] for
+    /// testnet/signet.
+    pub fn new(transport: T, pairing_data: Option<NoiseConfigData>) -> Self {
+        Self {
+            transport,
+            network: Network::Bitcoin,
+            noise: NoiseState::new(pairing_data),
+            control: CommandControl::default(),
+        }
+    }
+
+    /// Set the network used for xpub encoding, address/coin selection and signing.
+    pub fn with_network(mut self, network: Network) -> Self {
+        self.network = network;
+        self
+    }
+
+    /// Pairing code shown on the device screen. Returns `None` once pairing has been confirmed
+    /// (or if the device was already paired from cached data).
+    pub fn pairing_code(&self) -> Option<&str> {
+        self.noise.pairing_code()
+    }
+
+    /// Snapshot the noise-pairing state so the caller can persist it externally.
+    pub fn noise_config_data(&self) -> NoiseConfigData {
+        self.noise.data().clone()
+    }
+
+    pub fn is_paired(&self) -> bool {
+        self.noise.is_paired()
+    }
+}
+
+impl<T: Transport> BitBox<T> {
+    /// Restore the device from the mnemonic currently loaded on it. See the `bhwi-async`
+    /// counterpart; its purpose is to seed the BitBox02 simulator.
+    pub fn restore_from_mnemonic(
+        &mut self,
+        timestamp: u32,
+        timezone_offset: i32,
+    ) -> Result<(), BitBoxError> {
+        self.run_bitbox(BitBoxCommand::RestoreFromMnemonic {
+            timestamp,
+            timezone_offset,
+        })
+        .map(|_| ())
+    }
+
+    /// Backups stored on the inserted microSD card.
+    pub fn list_backups(&mut self) -> Result<Vec<BackupInfo>, BitBoxError> {
+        match self.run_bitbox(BitBoxCommand::ListBackups)? {
+            BitBoxResponse::Backups(backups) => Ok(backups),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Id of the microSD backup of the device's seed, or `None` if the card holds none.
+    /// Unless `silent`, the device shows the result.
+    pub fn check_backup(&mut self, silent: bool) -> Result<Option<String>, BitBoxError> {
+        match self.run_bitbox(BitBoxCommand::CheckBackup { silent })? {
+            BitBoxResponse::BackupCheck(id) => Ok(id),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Restore an unseeded device from the microSD backup with `id`, as listed by
+    /// [`BitBox::list_backups`].
+    pub fn restore_backup(
+        &mut self,
+        id: &str,
+        timestamp: u32,
+        timezone_offset: i32,
+    ) -> Result<(), BitBoxError> {
+        self.run_bitbox(BitBoxCommand::RestoreBackup {
+            id: id.to_string(),
+            timestamp,
+            timezone_offset,
+        })
+        .map(|_| ())
+    }
+
+    pub fn check_sdcard(&mut self) -> Result<bool, BitBoxError> {
+        match self.run_bitbox(BitBoxCommand::CheckSdCard)? {
+            BitBoxResponse::SdCardInserted(inserted) => Ok(inserted),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Prompt the user to insert a microSD card. Returns once a card is inserted.
+    pub fn insert_sdcard(&mut self) -> Result<(), BitBoxError> {
+        self.run_bitbox(BitBoxCommand::InsertSdCard).map(|_| ())
+    }
+
+    /// Reboot into the bootloader to upgrade the firmware, once the user confirms on the
+    /// device. The device then reconnects as a bootloader, driven by [`BitBoxBootloader`].
+    pub fn reboot_to_bootloader(&mut self) -> Result<(), BitBoxError> {
+        self.run_bitbox_inner(BitBoxCommand::RebootToBootloader, true)
+            .map(|_| ())
+    }
+
+    /// Drive one BitBox-specific command through the interpreter and transport.
+    fn run_bitbox(&mut self, command: BitBoxCommand) -> Result<BitBoxResponse, BitBoxError> {
+        self.run_bitbox_inner(command, false)
+    }
+
+    /// With `allow_final_disconnect`, a device resetting instead of answering completes the
+    /// command.
+    fn run_bitbox_inner(
+        &mut self,
+        command: BitBoxCommand,
+        allow_final_disconnect: bool,
+    ) -> Result<BitBoxResponse, BitBoxError> {
+        use crate::CommonInterface;
+        let control = self.control.clone();
+        let (transport, _http, mut interpreter) = <BitBox<T> as CommonInterface<
+            RawCommand<BitBoxCommand>,
+            common::Transmit,
+            BitBoxResponse,
+            BitBoxError,
+        >>::components(self);
+        let mut next = Some(interpreter.start(RawCommand(command))?);
+        control.forward_events(&mut interpreter);
+        while let Some(t) = next {
+            // BitBox02 never talks to a pin server; every transmit targets the device.
+            let exchange = match transport.exchange(&t.payload, t.encrypted) {
+                Ok(exchange) => exchange,
+                Err(e) if allow_final_disconnect && transport.is_post_write_disconnect(&e) => {
+                    return Ok(BitBoxResponse::TaskDone);
+                }
+                Err(e) => return Err(BitBoxError::Transport(format!("{e:?}"))),
+            };
+            next = interpreter.exchange(exchange)?;
+            control.forward_events(&mut interpreter);
+        }
+        interpreter.end()
+    }
+}
+
+/// Blocking client for the BitBox02 bootloader. See the `bhwi-async` counterpart.
+pub struct BitBoxBootloader<T> {
+    pub transport: T,
+}
+
+impl<T: Transport> BitBoxBootloader<T> {
+    pub fn new(transport: T) -> Self {
+        Self { transport }
+    }
+
+    pub fn versions(&mut self) -> Result<BootloaderVersions, BitBoxError> {
+        match self.run(BootloaderCommand::Versions, |_| {})? {
+            BootloaderResponse::Versions(versions) => Ok(versions),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    /// Firmware and signing keydata hashes. The device shows the requested ones, and the
+    /// call returns once the user confirmed them.
+    pub fn hashes(
+        &mut self,
+        display_firmware_hash: bool,
+        display_signing_keydata_hash: bool,
+    ) -> Result<BootloaderHashes, BitBoxError> {
+        let command = BootloaderCommand::Hashes {
+            display_firmware_hash,
+            display_signing_keydata_hash,
+        };
+        match self.run(command, |_| {})? {
+            BootloaderResponse::Hashes(hashes) => Ok(hashes),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+
+    pub fn set_show_firmware_hash(&mut self, enabled: bool) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::SetShowFirmwareHash(enabled), |_| {})
+            .map(|_| ())
+    }
+
+    /// Flash `firmware`, then check the hash the bootloader reports for it. `progress` is
+    /// called after every chunk the device accepted.
+    pub fn upgrade_firmware(
+        &mut self,
+        firmware: SignedFirmware,
+        progress: impl FnMut(UpgradeProgress),
+    ) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Upgrade(firmware), progress)
+            .map(|_| ())
+    }
+
+    pub fn erase(&mut self) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Erase, |_| {}).map(|_| ())
+    }
+
+    pub fn reboot(&mut self) -> Result<(), BitBoxError> {
+        self.run(BootloaderCommand::Reboot, |_| {}).map(|_| ())
+    }
+
+    fn run(
+        &mut self,
+        command: BootloaderCommand,
+        mut progress: impl FnMut(UpgradeProgress),
+    ) -> Result<BootloaderResponse, BitBoxError> {
+        let reboot = matches!(command, BootloaderCommand::Reboot);
+        let mut interpreter = BootloaderInterpreter::new();
+        let mut next = Some(interpreter.start(command)?);
+        let mut reported = None;
+        while let Some(t) = next {
+            let exchange = match self.transport.exchange(&t.payload, t.encrypted) {
+                Ok(exchange) => exchange,
+                // The device may reset before it acknowledges the reboot.
+                Err(e) if reboot && self.transport.is_post_write_disconnect(&e) => {
+                    return Ok(BootloaderResponse::TaskDone);
+                }
+                Err(e) => return Err(BitBoxError::Transport(format!("{e:?}"))),
+            };
+            next = interpreter.exchange(exchange)?;
+            if let Some(p) = interpreter.upgrade_progress()
+                && reported != Some(p.written)
+            {
+                reported = Some(p.written);
+                progress(p);
+            }
+        }
+        interpreter.end()
+    }
+}
+
+impl<C, T, R, E, F> crate::CommonInterface<C, T, R, E> for BitBox<F>
+where
+    C: TryInto<BitBoxCommand, Error = BitBoxError>,
+    T: From<common::Transmit>,
+    R: From<BitBoxResponse>,
+    E: From<BitBoxError>,
+    F: Transport,
+{
+    type TransportError = F::Error;
+    type HttpClientError = BitBoxError;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn components(
+        &mut self,
+    ) -> (
+        &mut dyn Transport<Error = Self::TransportError>,
+        &dyn HttpClient<Error = Self::HttpClientError>,
+        impl Interpreter<Command = C, Transmit = T, Response = R, Error = E>,
+    ) {
+        let network = self.network;
+        (
+            &mut self.transport,
+            &DummyClient,
+            BitBoxInterpreter::new(&mut self.noise).with_network(network),
+        )
+    }
+}
+
+impl<T> crate::OnUnlock for BitBox<T> {
+    fn on_unlock(&mut self, _response: common::Response) -> Result<(), common::Error> {
+        Ok(())
+    }
+}
+
+pub struct DummyClient;
+
+impl HttpClient for DummyClient {
+    type Error = BitBoxError;
+    fn request(&self, _url: &str, _req: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        unreachable!("BitBox02 does not use an HTTP client")
+    }
+}
@@ -0,0 +1,160 @@
+use crate::{CommandControl, Error, HttpClient, Transport, run_raw_command};
+use bhwi::{
+    Interpreter, RawCommand,
+    bitcoin::Psbt,
+    coldcard::{
+        ColdcardCommand, ColdcardError, ColdcardInterpreter, ColdcardResponse, ColdcardTransmit,
+        encrypt::{self, CryptoRngCore},
+        hsm::{AuthMode, HsmStatus, UserAuth},
+    },
+    common,
+};
+
+pub struct Coldcard<T> {
+    pub transport: T,
+    encryption: encrypt::Engine,
+    control: CommandControl,
+}
+
+impl<T> Coldcard<T> {
+    pub fn new(transport: T, rng: &mut impl CryptoRngCore) -> Self {
+        Self {
+            transport,
+            encryption: encrypt::Engine::new(rng),
+            control: CommandControl::default(),
+        }
+    }
+}
+
+/// HSM mode management. Like every other Coldcard command, these need the encrypted
+/// session set up by `HWI::unlock` first.
+impl<T: Transport> Coldcard<T> {
+    /// Upload `policy`, an HSM policy JSON, and ask to start HSM mode with it. With `None`
+    /// the policy already saved on the device is used. The operator still approves on the
+    /// device; poll `hsm_status` to see when HSM mode is active.
+    pub fn start_hsm(
+        &mut self,
+        policy: Option<Vec<u8>>,
+    ) -> Result<(), Error<T::Error, ColdcardError>> {
+        self.run_coldcard(ColdcardCommand::StartHsm { policy })
+            .map(|_| ())
+    }
+
+    pub fn hsm_status(&mut self) -> Result<HsmStatus, Error<T::Error, ColdcardError>> {
+        match self.run_coldcard(ColdcardCommand::HsmStatus)? {
+            ColdcardResponse::HsmStatus(status) => Ok(status),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    /// Create an HSM user and return its secret: the base32 OTP key, or the password when
+    /// the device picked it. An empty `secret` lets the device pick one, and `show_qr`
+    /// displays it on the device instead.
+    pub fn create_user(
+        &mut self,
+        username: &str,
+        mode: AuthMode,
+        secret: Vec<u8>,
+        show_qr: bool,
+    ) -> Result<String, Error<T::Error, ColdcardError>> {
+        match self.run_coldcard(ColdcardCommand::CreateUser {
+            username: username.to_string(),
+            mode,
+            secret,
+            show_qr,
+        })? {
+            ColdcardResponse::UserSecret(secret) => Ok(secret),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    pub fn delete_user(&mut self, username: &str) -> Result<(), Error<T::Error, ColdcardError>> {
+        self.run_coldcard(ColdcardCommand::DeleteUser {
+            username: username.to_string(),
+        })
+        .map(|_| ())
+    }
+
+    /// Authenticate `username` for `psbt`, then have the device sign it if the HSM policy
+    /// allows it.
+    pub fn sign_psbt_as_user(
+        &mut self,
+        psbt: Psbt,
+        username: &str,
+        auth: UserAuth,
+    ) -> Result<Psbt, Error<T::Error, ColdcardError>> {
+        match self.run_coldcard(ColdcardCommand::SignPsbtAsUser {
+            psbt,
+            username: username.to_string(),
+            auth,
+        })? {
+            ColdcardResponse::SignedPsbt(psbt) => Ok(psbt),
+            _ => Err(common::Error::NoErrorOrResult.into()),
+        }
+    }
+
+    /// Drive one Coldcard-specific command through the interpreter and transport.
+    fn run_coldcard(
+        &mut self,
+        command: ColdcardCommand,
+    ) -> Result<ColdcardResponse, Error<T::Error, ColdcardError>> {
+        let interpreter =
+            ColdcardInterpreter::<_, common::Transmit, ColdcardResponse, ColdcardError>::new(
+                &mut self.encryption,
+            );
+        run_raw_command(
+            &mut self.transport,
+            &self.control,
+            interpreter,
+            RawCommand(command),
+            |_| {},
+        )
+    }
+}
+
+impl<C, T, R, E, F> crate::CommonInterface<C, T, R, E> for Coldcard<F>
+where
+    C: TryInto<ColdcardCommand, Error = ColdcardError>,
+    T: From<ColdcardTransmit>,
+    R: From<ColdcardResponse>,
+    E: From<ColdcardError>,
+    F: Transport,
+{
+    type TransportError = F::Error;
+    type HttpClientError = ColdcardError;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn components(
+        &mut self,
+    ) -> (
+        &mut dyn Transport<Error = Self::TransportError>,
+        &dyn HttpClient<Error = Self::HttpClientError>,
+        impl Interpreter<Command = C, Transmit = T, Response = R, Error = E>,
+    ) {
+        (
+            &mut self.transport,
+            &DummyClient {},
+            ColdcardInterpreter::new(&mut self.encryption),
+        )
+    }
+}
+
+impl<T> crate::OnUnlock for Coldcard<T> {
+    fn on_unlock(&mut self, response: bhwi::common::Response) -> Result<(), common::Error> {
+        if let bhwi::common::Response::EncryptionKey(key) = response {
+            self.encryption.ready(key)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct DummyClient;
+impl HttpClient for DummyClient {
+    type Error = ColdcardError;
+    fn request(&self, _url: &str, _req: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        unreachable!("Coldcard does not need http client")
+    }
+}
@@ -0,0 +1,45 @@
+//! Event listener for the commands run by [`crate::HWI`].
+//!
+//! The counterpart of the `bhwi-async` control, set the same way through
+//! [`crate::HWI::set_control`]. A blocking exchange cannot be interrupted from the calling
+//! thread, so there are no cancellation or deadlines here: bound the transport reads
+//! instead.
+
+use std::{fmt, rc::Rc};
+
+pub use bhwi::EventListener;
+use bhwi::{Interpreter, common::Event};
+
+/// Event listener applied to every command run on a device.
+#[derive(Clone, Default)]
+pub struct CommandControl {
+    events: Option<EventListener>,
+}
+
+impl CommandControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Call `listener` with every event of the commands, see [`EventListener`].
+    pub fn with_events<F>(mut self, listener: F) -> Self
+    where
+        F: Fn(&Event) + 'static,
+    {
+        self.events = Some(Rc::new(listener));
+        self
+    }
+
+    /// Hand the events raised by the last call on `intpr` to the listener.
+    pub(crate) fn forward_events<I: Interpreter>(&self, intpr: &mut I) {
+        bhwi::forward_events(self.events.as_ref(), intpr)
+    }
+}
+
+impl fmt::Debug for CommandControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CommandControl")
+            .field("events", &self.events.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,95 @@
+use crate::{CommandControl, Error, HttpClient, Transport, run_raw_command};
+use bhwi::{
+    Interpreter, RawCommand,
+    bitcoin::Network,
+    common,
+    jade::{
+        JadeCommand, JadeError, JadeInterpreter, JadeResponse, JadeTransmit, OtaProgress, OtaUpdate,
+    },
+};
+
+pub struct Jade<T, S> {
+    pub network: Network,
+    pub transport: T,
+    pub pinserver: S,
+    control: CommandControl,
+}
+
+impl<T, S> Jade<T, S> {
+    pub fn new(network: Network, transport: T, pinserver: S) -> Self {
+        Self {
+            network,
+            transport,
+            pinserver,
+            control: CommandControl::default(),
+        }
+    }
+}
+
+impl<T: Transport, S: HttpClient> Jade<T, S> {
+    /// Upload a compressed firmware image through Jade's OTA protocol. See the `bhwi-async`
+    /// counterpart.
+    pub fn update_firmware(
+        &mut self,
+        update: OtaUpdate,
+        mut progress: impl FnMut(OtaProgress),
+    ) -> Result<(), Error<T::Error, S::Error>> {
+        // OTA never involves the pin server, every transmit targets the device.
+        let interpreter =
+            JadeInterpreter::<_, common::Transmit, JadeResponse, JadeError>::default()
+                .with_network(self.network);
+        let mut reported = None;
+        run_raw_command(
+            &mut self.transport,
+            &self.control,
+            interpreter,
+            RawCommand(JadeCommand::OtaUpdate(update)),
+            |interpreter| {
+                if let Some(p) = interpreter.ota_progress()
+                    && reported != Some(p.written)
+                {
+                    reported = Some(p.written);
+                    progress(p);
+                }
+            },
+        )
+        .map(|_| ())
+    }
+}
+
+impl<C, T, R, E, F, H> crate::CommonInterface<C, T, R, E> for Jade<F, H>
+where
+    C: TryInto<JadeCommand, Error = E>,
+    T: From<JadeTransmit>,
+    R: From<JadeResponse>,
+    E: From<JadeError>,
+    F: Transport,
+    H: HttpClient,
+{
+    type TransportError = F::Error;
+    type HttpClientError = H::Error;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn components(
+        &mut self,
+    ) -> (
+        &mut dyn Transport<Error = Self::TransportError>,
+        &dyn HttpClient<Error = Self::HttpClientError>,
+        impl Interpreter<Command = C, Transmit = T, Response = R, Error = E>,
+    ) {
+        (
+            &mut self.transport,
+            &self.pinserver,
+            JadeInterpreter::default().with_network(self.network),
+        )
+    }
+}
+
+impl<T, S> crate::OnUnlock for Jade<T, S> {
+    fn on_unlock(&mut self, _response: bhwi::common::Response) -> Result<(), bhwi::common::Error> {
+        Ok(())
+    }
+}
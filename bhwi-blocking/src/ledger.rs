@@ -0,0 +1,85 @@
+use crate::{CommandControl, HttpClient, Transport};
+use bhwi::{
+    Interpreter,
+    common::{Info, Response},
+    ledger::{
+        AppProtocol, LedgerCommand, LedgerError, LedgerInterpreter, LedgerResponse,
+        apdu::ApduCommand,
+    },
+};
+
+pub struct Ledger<T> {
+    pub transport: T,
+    /// Protocol of the open Bitcoin app, detected on unlock.
+    protocol: AppProtocol,
+    control: CommandControl,
+}
+
+impl<T> Ledger<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            protocol: AppProtocol::default(),
+            control: CommandControl::default(),
+        }
+    }
+
+    /// Skip the detection, e.g. when the app version is already known.
+    pub fn with_protocol(mut self, protocol: AppProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+}
+
+impl<C, T, R, E, F> crate::CommonInterface<C, T, R, E> for Ledger<F>
+where
+    C: TryInto<LedgerCommand, Error = LedgerError>,
+    T: From<ApduCommand>,
+    R: From<LedgerResponse>,
+    E: From<LedgerError>,
+    F: Transport,
+{
+    type TransportError = F::Error;
+    type HttpClientError = LedgerError;
+
+    fn control(&mut self) -> &mut CommandControl {
+        &mut self.control
+    }
+
+    fn components(
+        &mut self,
+    ) -> (
+        &mut dyn Transport<Error = Self::TransportError>,
+        &dyn HttpClient<Error = Self::HttpClientError>,
+        impl Interpreter<Command = C, Transmit = T, Response = R, Error = E>,
+    ) {
+        (
+            &mut self.transport,
+            &DummyClient {},
+            LedgerInterpreter::default().with_protocol(self.protocol),
+        )
+    }
+}
+
+impl<T> crate::OnUnlock for Ledger<T> {
+    fn on_unlock(&mut self, response: Response) -> Result<(), bhwi::common::Error> {
+        // Unlocking reports the app only when it was already open.
+        if let Response::Info(Info {
+            version,
+            firmware: Some(app_name),
+            ..
+        }) = response
+        {
+            self.protocol = AppProtocol::from_app(&app_name, &version);
+        }
+        Ok(())
+    }
+}
+
+pub struct DummyClient;
+impl HttpClient for DummyClient {
+    type Error = LedgerError;
+    fn request(&self, _url: &str, _req: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        unreachable!("Ledger does not need http client")
+    }
+}
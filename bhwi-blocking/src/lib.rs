@@ -0,0 +1,805 @@
+//! Blocking driver for the `bhwi` interpreters.
+//!
+//! Mirrors `bhwi-async` for callers without an async runtime: the same `HWI` surface and
+//! device wrappers, driven by a plain loop over a synchronous [`Transport`] and
+//! [`HttpClient`]. Framing (HID, serial, TCP) is left to the caller's `Transport`.
+
+#[cfg(feature = "bitbox")]
+pub mod bitbox;
+pub mod coldcard;
+pub mod control;
+pub mod jade;
+pub mod ledger;
+
+use std::{error::Error as StdError, fmt::Debug, str::FromStr};
+
+pub use bhwi::common::Attestation;
+pub use bhwi::common::Capabilities;
+pub use bhwi::common::DeviceBackup;
+pub use bhwi::common::DeviceContext;
+pub use bhwi::common::DisplayAddress;
+pub use bhwi::common::ErrorKind;
+pub use bhwi::common::Event;
+pub use bhwi::common::Info;
+pub use bhwi::common::RestoreOptions;
+pub use bhwi::common::SetupOptions;
+pub use bhwi::common::WalletRegistration;
+pub use bhwi::common::Zeroizing;
+use bhwi::miniscript::descriptor::WalletPolicy;
+pub use bhwi::psbt::VersionedPsbt;
+use bhwi::{
+    Interpreter,
+    bitcoin::{
+        Network,
+        address::AddressType,
+        bip32::{DerivationPath, Fingerprint, Xpub},
+        psbt::Psbt,
+        secp256k1::ecdsa::Signature,
+    },
+    common::{self},
+};
+pub use control::CommandControl;
+pub use jade::Jade;
+pub use ledger::Ledger;
+
+pub trait Transport {
+    type Error: Debug;
+    fn exchange(&mut self, command: &[u8], encrypted: bool) -> Result<Vec<u8>, Self::Error>;
+
+    /// Whether an exchange failed while reading after the request was successfully written.
+    ///
+    /// Stateful commands that reboot a device can use this to distinguish an expected
+    /// post-write disconnect from a failure to deliver the command.
+    fn is_post_write_disconnect(&self, _error: &Self::Error) -> bool {
+        false
+    }
+}
+
+pub trait HttpClient {
+    type Error: Debug;
+    fn request(&self, url: &str, request: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+pub trait HWI {
+    type Error: Debug;
+    /// Event listener for the following commands, see [`CommandControl`].
+    fn set_control(&mut self, control: CommandControl);
+    fn backup_device(&mut self) -> Result<DeviceBackup, Self::Error>;
+    fn setup_device(
+        &mut self,
+        options: SetupOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, Self::Error>;
+    fn wipe_device(&mut self) -> Result<bool, Self::Error>;
+    fn restore_device(
+        &mut self,
+        options: RestoreOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, Self::Error>;
+    fn toggle_passphrase(&mut self) -> Result<bool, Self::Error>;
+    /// Use `passphrase` as the BIP39 passphrase of the session, or clear it with `None`,
+    /// and return the master fingerprint of the resulting wallet.
+    fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, Self::Error>;
+    fn unlock(&mut self, network: Network) -> Result<(), Self::Error>;
+    fn get_info(&mut self) -> Result<Info, Self::Error>;
+    /// What the device supports at its firmware version, see [`Info::capabilities`].
+    fn capabilities(&mut self) -> Result<Capabilities, Self::Error>;
+    /// Challenge the device to prove it is genuine, against the bundled manufacturer roots.
+    fn verify_attestation(&mut self) -> Result<Attestation, Self::Error>;
+    fn get_master_fingerprint(&mut self) -> Result<Fingerprint, Self::Error>;
+    fn get_extended_pubkey(
+        &mut self,
+        path: DerivationPath,
+        display: bool,
+    ) -> Result<Xpub, Self::Error>;
+    fn sign_message(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+    ) -> Result<(u8, Signature), Self::Error>;
+    /// BIP-322 simple signature of `message` by the `address_type` address of the key at
+    /// `path`. Unsupported on the BitBox02.
+    fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, Self::Error>;
+    fn display_address(
+        &mut self,
+        address: common::DisplayAddress,
+        context: Option<common::DeviceContext>,
+    ) -> Result<String, Self::Error>;
+    fn register_wallet(
+        &mut self,
+        name: &str,
+        policy: &str,
+    ) -> Result<WalletRegistration, Self::Error>;
+    fn sign_tx(
+        &mut self,
+        psbt: Psbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<Psbt, Self::Error>;
+    /// Sign a version 0 or version 2 PSBT through `sign_tx`, returning it in its version.
+    fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, Self::Error>;
+}
+
+/// Object-safe mirror of [`HWI`] with boxed errors, for holding devices of different kinds
+/// behind `Box<dyn HWIDevice>`.
+pub trait HWIDevice {
+    fn set_control(&mut self, control: CommandControl);
+    fn backup_device(&mut self) -> Result<DeviceBackup, HWIDeviceError>;
+    fn setup_device(
+        &mut self,
+        options: SetupOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError>;
+    fn wipe_device(&mut self) -> Result<bool, HWIDeviceError>;
+    fn restore_device(
+        &mut self,
+        options: RestoreOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError>;
+    fn toggle_passphrase(&mut self) -> Result<bool, HWIDeviceError>;
+    fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, HWIDeviceError>;
+    fn unlock(&mut self, network: Network) -> Result<(), HWIDeviceError>;
+    fn get_info(&mut self) -> Result<Info, HWIDeviceError>;
+    fn capabilities(&mut self) -> Result<Capabilities, HWIDeviceError>;
+    fn verify_attestation(&mut self) -> Result<Attestation, HWIDeviceError>;
+    fn get_master_fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError>;
+    fn get_extended_pubkey(
+        &mut self,
+        path: DerivationPath,
+        display: bool,
+    ) -> Result<Xpub, HWIDeviceError>;
+    fn sign_message(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+    ) -> Result<(u8, Signature), HWIDeviceError>;
+    fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, HWIDeviceError>;
+    fn display_address(
+        &mut self,
+        address: common::DisplayAddress,
+        context: Option<common::DeviceContext>,
+    ) -> Result<String, HWIDeviceError>;
+    fn register_wallet(
+        &mut self,
+        name: &str,
+        policy: &str,
+    ) -> Result<WalletRegistration, HWIDeviceError>;
+    fn sign_tx(
+        &mut self,
+        psbt: Psbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<Psbt, HWIDeviceError>;
+    fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, HWIDeviceError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("hwi device error: {0}")]
+pub struct HWIDeviceError(#[from] Box<dyn StdError + Send + Sync + 'static>);
+
+impl HWIDeviceError {
+    pub fn new(error: impl StdError + Send + Sync + 'static) -> Self {
+        Self(Box::new(error))
+    }
+
+    /// The kind of the interpreter error behind this one, or [`ErrorKind::Transport`] when
+    /// the failure did not come from the interpreter.
+    pub fn kind(&self) -> ErrorKind {
+        let mut error: Option<&(dyn StdError + 'static)> = Some(self.0.as_ref());
+        while let Some(e) = error {
+            if let Some(e) = e.downcast_ref::<common::Error>() {
+                return e.kind();
+            }
+            error = e.source();
+        }
+        ErrorKind::Transport
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error<E, F> {
+    #[error("transport error: {0}")]
+    Transport(E),
+
+    #[error("http client error: {0}")]
+    HttpClient(F),
+
+    #[error("interpreter error: {0}")]
+    Interpreter(#[from] common::Error),
+}
+
+impl<E, F> Error<E, F> {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Transport(_) | Error::HttpClient(_) => ErrorKind::Transport,
+            Error::Interpreter(e) => e.kind(),
+        }
+    }
+}
+
+impl<D> HWI for D
+where
+    D: CommonInterface<common::Command, common::Transmit, common::Response, common::Error>
+        + OnUnlock,
+{
+    type Error = Error<D::TransportError, D::HttpClientError>;
+
+    fn set_control(&mut self, control: CommandControl) {
+        *self.control() = control;
+    }
+
+    fn backup_device(&mut self) -> Result<DeviceBackup, Self::Error> {
+        if let common::Response::Backup(backup) = run_command(self, common::Command::Backup)? {
+            Ok(backup)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn setup_device(
+        &mut self,
+        options: SetupOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, Self::Error> {
+        if let common::Response::DeviceAction(success) =
+            run_command(self, common::Command::Setup(options, context))?
+        {
+            Ok(success)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn wipe_device(&mut self) -> Result<bool, Self::Error> {
+        if let common::Response::DeviceAction(success) =
+            run_command_allowing_final_disconnect(self, common::Command::Wipe)?
+        {
+            Ok(success)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn restore_device(
+        &mut self,
+        options: RestoreOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, Self::Error> {
+        if let common::Response::DeviceAction(success) =
+            run_command(self, common::Command::Restore(options, context))?
+        {
+            Ok(success)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn toggle_passphrase(&mut self) -> Result<bool, Self::Error> {
+        if let common::Response::DeviceAction(success) =
+            run_command(self, common::Command::TogglePassphrase)?
+        {
+            Ok(success)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, Self::Error> {
+        if let common::Response::MasterFingerprint(fg) =
+            run_command(self, common::Command::SetPassphrase(passphrase))?
+        {
+            Ok(fg)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn unlock(&mut self, network: Network) -> Result<(), Self::Error> {
+        let res = run_command(
+            self,
+            common::Command::Unlock {
+                options: common::UnlockOptions {
+                    network: Some(network),
+                },
+            },
+        )?;
+        self.on_unlock(res)?;
+        Ok(())
+    }
+
+    fn get_info(&mut self) -> Result<Info, Self::Error> {
+        if let common::Response::Info(version) = run_command(self, common::Command::GetVersion)? {
+            Ok(version)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn capabilities(&mut self) -> Result<Capabilities, Self::Error> {
+        let info = HWI::get_info(self)?;
+        info.capabilities()
+            .ok_or_else(|| common::Error::MissingCommandInfo("device kind").into())
+    }
+
+    fn verify_attestation(&mut self) -> Result<Attestation, Self::Error> {
+        if let common::Response::Attestation(attestation) =
+            run_command(self, common::Command::VerifyAttestation)?
+        {
+            Ok(attestation)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn get_master_fingerprint(&mut self) -> Result<Fingerprint, Self::Error> {
+        if let common::Response::MasterFingerprint(fg) =
+            run_command(self, common::Command::GetMasterFingerprint)?
+        {
+            Ok(fg)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn get_extended_pubkey(
+        &mut self,
+        path: DerivationPath,
+        display: bool,
+    ) -> Result<Xpub, Self::Error> {
+        if let common::Response::Xpub(xpub) =
+            run_command(self, common::Command::GetXpub { path, display })?
+        {
+            Ok(xpub)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn sign_message(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+    ) -> Result<(u8, Signature), Self::Error> {
+        if let common::Response::Signature(header, signature) = run_command(
+            self,
+            common::Command::SignMessage {
+                message: message.to_vec(),
+                path,
+            },
+        )? {
+            Ok((header, signature))
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, Self::Error> {
+        if let common::Response::Bip322Signature(signature) = run_command(
+            self,
+            common::Command::SignMessageBip322 {
+                message: message.to_vec(),
+                path,
+                address_type,
+            },
+        )? {
+            Ok(signature)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn display_address(
+        &mut self,
+        address: common::DisplayAddress,
+        context: Option<common::DeviceContext>,
+    ) -> Result<String, Self::Error> {
+        if let common::Response::Address(addr) =
+            run_command(self, common::Command::DisplayAddress(address, context))?
+        {
+            Ok(addr)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn register_wallet(
+        &mut self,
+        name: &str,
+        policy: &str,
+    ) -> Result<WalletRegistration, Self::Error> {
+        let wallet_policy = WalletPolicy::from_str(policy)
+            .map_err(|e| common::Error::Serialization(e.to_string()))?;
+        if let common::Response::WalletRegistration(registration) = run_command(
+            self,
+            common::Command::RegisterWallet {
+                name: name.to_string(),
+                policy: wallet_policy,
+            },
+        )? {
+            Ok(registration)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn sign_tx(
+        &mut self,
+        psbt: Psbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<Psbt, Self::Error> {
+        if let common::Response::SignedPsbt(psbt) =
+            run_command(self, common::Command::SignTx(psbt, context))?
+        {
+            Ok(psbt)
+        } else {
+            Err(common::Error::NoErrorOrResult.into())
+        }
+    }
+
+    fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, Self::Error> {
+        let signed = HWI::sign_tx(self, psbt.psbt().clone(), context)?;
+        Ok(psbt.with_psbt(signed)?)
+    }
+}
+
+impl<T> HWIDevice for T
+where
+    T: HWI,
+    T::Error: StdError + Send + Sync + 'static,
+{
+    fn set_control(&mut self, control: CommandControl) {
+        HWI::set_control(self, control)
+    }
+
+    fn backup_device(&mut self) -> Result<DeviceBackup, HWIDeviceError> {
+        HWI::backup_device(self).map_err(HWIDeviceError::new)
+    }
+
+    fn setup_device(
+        &mut self,
+        options: SetupOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError> {
+        HWI::setup_device(self, options, context).map_err(HWIDeviceError::new)
+    }
+
+    fn wipe_device(&mut self) -> Result<bool, HWIDeviceError> {
+        HWI::wipe_device(self).map_err(HWIDeviceError::new)
+    }
+
+    fn restore_device(
+        &mut self,
+        options: RestoreOptions,
+        context: Option<DeviceContext>,
+    ) -> Result<bool, HWIDeviceError> {
+        HWI::restore_device(self, options, context).map_err(HWIDeviceError::new)
+    }
+
+    fn toggle_passphrase(&mut self) -> Result<bool, HWIDeviceError> {
+        HWI::toggle_passphrase(self).map_err(HWIDeviceError::new)
+    }
+
+    fn set_passphrase(
+        &mut self,
+        passphrase: Option<Zeroizing<String>>,
+    ) -> Result<Fingerprint, HWIDeviceError> {
+        HWI::set_passphrase(self, passphrase).map_err(HWIDeviceError::new)
+    }
+
+    fn unlock(&mut self, network: Network) -> Result<(), HWIDeviceError> {
+        HWI::unlock(self, network).map_err(HWIDeviceError::new)
+    }
+
+    fn get_info(&mut self) -> Result<Info, HWIDeviceError> {
+        HWI::get_info(self).map_err(HWIDeviceError::new)
+    }
+
+    fn capabilities(&mut self) -> Result<Capabilities, HWIDeviceError> {
+        HWI::capabilities(self).map_err(HWIDeviceError::new)
+    }
+
+    fn verify_attestation(&mut self) -> Result<Attestation, HWIDeviceError> {
+        HWI::verify_attestation(self).map_err(HWIDeviceError::new)
+    }
+
+    fn get_master_fingerprint(&mut self) -> Result<Fingerprint, HWIDeviceError> {
+        HWI::get_master_fingerprint(self).map_err(HWIDeviceError::new)
+    }
+
+    fn get_extended_pubkey(
+        &mut self,
+        path: DerivationPath,
+        display: bool,
+    ) -> Result<Xpub, HWIDeviceError> {
+        HWI::get_extended_pubkey(self, path, display).map_err(HWIDeviceError::new)
+    }
+
+    fn sign_message(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+    ) -> Result<(u8, Signature), HWIDeviceError> {
+        HWI::sign_message(self, message, path).map_err(HWIDeviceError::new)
+    }
+
+    fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: DerivationPath,
+        address_type: AddressType,
+    ) -> Result<String, HWIDeviceError> {
+        HWI::sign_message_bip322(self, message, path, address_type).map_err(HWIDeviceError::new)
+    }
+
+    fn display_address(
+        &mut self,
+        address: DisplayAddress,
+        context: Option<DeviceContext>,
+    ) -> Result<String, HWIDeviceError> {
+        HWI::display_address(self, address, context).map_err(HWIDeviceError::new)
+    }
+
+    fn register_wallet(
+        &mut self,
+        name: &str,
+        policy: &str,
+    ) -> Result<WalletRegistration, HWIDeviceError> {
+        HWI::register_wallet(self, name, policy).map_err(HWIDeviceError::new)
+    }
+
+    fn sign_tx(
+        &mut self,
+        psbt: Psbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<Psbt, HWIDeviceError> {
+        HWI::sign_tx(self, psbt, context).map_err(HWIDeviceError::new)
+    }
+
+    fn sign_versioned_tx(
+        &mut self,
+        psbt: VersionedPsbt,
+        context: Option<common::DeviceContext>,
+    ) -> Result<VersionedPsbt, HWIDeviceError> {
+        HWI::sign_versioned_tx(self, psbt, context).map_err(HWIDeviceError::new)
+    }
+}
+
+pub trait OnUnlock {
+    fn on_unlock(&mut self, _response: common::Response) -> Result<(), common::Error>;
+}
+
+pub trait CommonInterface<C, T, R, E> {
+    type TransportError: Debug;
+    type HttpClientError: Debug;
+
+    /// Event listener applied to the commands run on the device.
+    fn control(&mut self) -> &mut CommandControl;
+
+    #[allow(clippy::type_complexity)]
+    fn components(
+        &mut self,
+    ) -> (
+        &mut dyn Transport<Error = Self::TransportError>,
+        &dyn HttpClient<Error = Self::HttpClientError>,
+        impl Interpreter<Command = C, Transmit = T, Response = R, Error = E>,
+    );
+}
+
+/// Drive `command` through the device's interpreter until it yields a response, blocking on
+/// every transport and HTTP exchange.
+pub fn run_command<D, C, E, F>(device: &mut D, command: C) -> Result<common::Response, Error<E, F>>
+where
+    E: Debug,
+    F: Debug,
+    D: CommonInterface<
+            common::Command,
+            common::Transmit,
+            common::Response,
+            common::Error,
+            TransportError = E,
+            HttpClientError = F,
+        >,
+    C: Into<common::Command>,
+{
+    run_command_inner(device, command, false)
+}
+
+fn run_command_allowing_final_disconnect<D, C, E, F>(
+    device: &mut D,
+    command: C,
+) -> Result<common::Response, Error<E, F>>
+where
+    E: Debug,
+    F: Debug,
+    D: CommonInterface<
+            common::Command,
+            common::Transmit,
+            common::Response,
+            common::Error,
+            TransportError = E,
+            HttpClientError = F,
+        >,
+    C: Into<common::Command>,
+{
+    run_command_inner(device, command, true)
+}
+
+fn run_command_inner<D, C, E, F>(
+    device: &mut D,
+    command: C,
+    allow_final_disconnect: bool,
+) -> Result<common::Response, Error<E, F>>
+where
+    E: Debug,
+    F: Debug,
+    D: CommonInterface<
+            common::Command,
+            common::Transmit,
+            common::Response,
+            common::Error,
+            TransportError = E,
+            HttpClientError = F,
+        >,
+    C: Into<common::Command>,
+{
+    let control = device.control().clone();
+    let (transport, http_client, mut intpr) = device.components();
+    let transmit = intpr.start(command.into())?;
+    control.forward_events(&mut intpr);
+    let exchange = transport
+        .exchange(&transmit.payload, transmit.encrypted)
+        .map_err(Error::Transport)?;
+    let mut transmit = intpr.exchange(exchange)?;
+    control.forward_events(&mut intpr);
+    while let Some(t) = &transmit {
+        match &t.recipient {
+            common::Recipient::PinServer { url } => {
+                let res = http_client
+                    .request(url, &t.payload)
+                    .map_err(Error::HttpClient)?;
+                transmit = intpr.exchange(res)?;
+                control.forward_events(&mut intpr);
+            }
+            common::Recipient::Device => {
+                let exchange = match transport.exchange(&t.payload, t.encrypted) {
+                    Ok(exchange) => exchange,
+                    Err(error)
+                        if allow_final_disconnect && transport.is_post_write_disconnect(&error) =>
+                    {
+                        return Ok(common::Response::DeviceAction(true));
+                    }
+                    Err(error) => return Err(Error::Transport(error)),
+                };
+                transmit = intpr.exchange(exchange)?;
+                control.forward_events(&mut intpr);
+            }
+        }
+    }
+    intpr.end().map_err(|e| e.into())
+}
+
+/// Drive a device's own command, for the operations that have no place in the shared HWI
+/// command surface, through `intpr` and `transport`. `on_exchange` sees the interpreter after
+/// every device reply.
+pub(crate) fn run_raw_command<I, E, F>(
+    transport: &mut dyn Transport<Error = E>,
+    control: &CommandControl,
+    mut intpr: I,
+    command: I::Command,
+    mut on_exchange: impl FnMut(&I),
+) -> Result<I::Response, Error<E, F>>
+where
+    I: Interpreter<Transmit = common::Transmit>,
+    I::Error: Into<common::Error>,
+{
+    let mut transmit = Some(
+        intpr
+            .start(command)
+            .map_err(|e| Error::Interpreter(e.into()))?,
+    );
+    control.forward_events(&mut intpr);
+    while let Some(t) = transmit {
+        let exchange = transport
+            .exchange(&t.payload, t.encrypted)
+            .map_err(Error::Transport)?;
+        transmit = intpr
+            .exchange(exchange)
+            .map_err(|e| Error::Interpreter(e.into()))?;
+        control.forward_events(&mut intpr);
+        on_exchange(&intpr);
+    }
+    intpr.end().map_err(|e| Error::Interpreter(e.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+
+    use super::*;
+
+    /// Replays canned device responses and records the requests it was sent.
+    #[derive(Default)]
+    struct ReplayTransport {
+        responses: VecDeque<Vec<u8>>,
+        requests: Vec<Vec<u8>>,
+    }
+
+    impl Transport for ReplayTransport {
+        type Error = &'static str;
+        fn exchange(&mut self, command: &[u8], _encrypted: bool) -> Result<Vec<u8>, Self::Error> {
+            self.requests.push(command.to_vec());
+            self.responses.pop_front().ok_or("no response left")
+        }
+    }
+
+    #[test]
+    fn ledger_master_fingerprint() {
+        let transport = ReplayTransport {
+            responses: VecDeque::from([vec![0xf5, 0xac, 0xc2, 0xfd, 0x90, 0x00]]),
+            ..Default::default()
+        };
+        let mut ledger = Ledger::new(transport);
+        let fingerprint = HWI::get_master_fingerprint(&mut ledger).unwrap();
+        assert_eq!(fingerprint, Fingerprint::from([0xf5, 0xac, 0xc2, 0xfd]));
+        assert_eq!(ledger.transport.requests.len(), 1);
+    }
+
+    #[test]
+    fn transport_error_is_surfaced() {
+        let mut ledger = Ledger::new(ReplayTransport::default());
+        assert!(matches!(
+            HWI::get_master_fingerprint(&mut ledger),
+            Err(Error::Transport("no response left"))
+        ));
+    }
+
+    #[test]
+    fn error_kind_reaches_the_caller() {
+        let transport = ReplayTransport {
+            responses: VecDeque::from([vec![0x55, 0x15]]),
+            ..Default::default()
+        };
+        let mut ledger = Ledger::new(transport);
+        let path = DerivationPath::from_str("m/84'/0'/0'").unwrap();
+        let error = HWIDevice::get_extended_pubkey(&mut ledger, path, false).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Locked);
+
+        let mut ledger = Ledger::new(ReplayTransport::default());
+        let error = HWIDevice::get_master_fingerprint(&mut ledger).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Transport);
+    }
+}
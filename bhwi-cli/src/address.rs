@@ -52,24 +52,24 @@ impl DeviceManager {
             } => {
                 // BitBox re-supplies the policy descriptor each time; Ledger needs the
                 // registered policy plus its hmac; Coldcard/Jade resolve by name on-device.
+                // Whatever is omitted is looked up in the wallet registration store.
                 let context = match device.device_type() {
                     DeviceType::BitBox02 => {
-                        let wallet_policy = wallet_descriptor.ok_or_else(|| {
-                            anyhow::anyhow!(
-                                "--wallet-descriptor is required for BitBox descriptor addresses"
-                            )
-                        })?;
-                        Some(DeviceContext::BitBox {
+                        wallet_descriptor.map(|wallet_policy| DeviceContext::BitBox {
                             policy: wallet_policy,
                         })
                     }
                     DeviceType::Ledger => match (hmac, wallet_descriptor) {
-                        (Some(hmac_hex), Some(wallet_policy)) => {
-                            let hmac = hex::decode(&hmac_hex)
-                                .map_err(|e| anyhow::anyhow!("invalid hmac hex: {e}"))?;
-                            let hmac: [u8; 32] = hmac.try_into().map_err(|_| {
-                                anyhow::anyhow!("hmac must be 32 bytes (64 hex chars)")
-                            })?;
+                        (hmac_hex, Some(wallet_policy)) => {
+                            let hmac = hmac_hex
+                                .map(|hmac_hex| {
+                                    let hmac = hex::decode(&hmac_hex)
+                                        .map_err(|e| anyhow::anyhow!("invalid hmac hex: {e}"))?;
+                                    <[u8; 32]>::try_from(hmac).map_err(|_| {
+                                        anyhow::anyhow!("hmac must be 32 bytes (64 hex chars)")
+                                    })
+                                })
+                                .transpose()?;
                             let ledger_policy = LedgerWalletPolicy::new(
                                 descriptor_name.clone(),
                                 Version::V2,
@@ -77,12 +77,12 @@ impl DeviceManager {
                             );
                             Some(DeviceContext::Ledger {
                                 wallet_policy: ledger_policy,
-                                wallet_hmac: Some(hmac),
+                                wallet_hmac: hmac,
                             })
                         }
                         (None, None) => None,
-                        _ => anyhow::bail!(
-                            "both --hmac and --wallet-descriptor must be provided for Ledger descriptor addresses"
+                        (Some(_), None) => anyhow::bail!(
+                            "--hmac requires --wallet-descriptor for Ledger descriptor addresses"
                         ),
                     },
                     _ => None,
@@ -1,25 +1,37 @@
 use anyhow::Result;
+use bhwi::common::CommandKind;
 use bhwi::ledger::{LedgerWalletPolicy, Version};
-use bhwi_async::{DeviceBackup, DeviceContext, RestoreOptions, SetupOptions, WalletRegistration};
+use bhwi::preview::{PreviewOutput, TxPreview};
+use bhwi_async::{
+    Attestation, DeviceBackup, DeviceContext, RestoreOptions, SetupOptions, VersionedPsbt,
+    WalletRegistration,
+    registry::{FileRegistrationStore, RegistrationStore, wallet_spending},
+};
 use bhwi_cli::{
     DeviceManager, DeviceType, OutputFormat,
     address::AddressTarget,
+    bitbox::{
+        BitBoxBootloaderHid, BitBoxDevice, SdCardBackup, SdCardBackupOutcome,
+        signed_firmware_from_file,
+    },
     config::DeviceSelector,
     get_descriptors::GetKeypoolOptions,
-    management::{bitbox_restore_context, bitbox_setup_context},
+    jade::{JadeDevice, firmware_update_from_file},
+    management::{bitbox_restore_context, bitbox_setup_context, read_passphrase},
     udev::{UdevRuleSelection, install_udev_rules},
 };
 
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::Duration;
 
 use bitcoin::base64::prelude::{BASE64_STANDARD, Engine as _};
 use bitcoin::{
-    Network,
+    Address, Network,
     address::AddressType,
-    bip32::{DerivationPath, Fingerprint},
-    psbt::Psbt,
+    bip32::{ChildNumber, DerivationPath, Fingerprint},
 };
+use chrono::DateTime;
 use clap::{Parser, Subcommand, ValueEnum};
 use miniscript::descriptor::{DescriptorType, WalletPolicy};
 
@@ -43,6 +55,9 @@ struct Args {
     /// output formatting
     #[arg(long, short)]
     format: Option<OutputFormat>,
+    /// give up on a device command after this many seconds
+    #[arg(long)]
+    timeout: Option<u64>,
 }
 
 impl Args {
@@ -53,6 +68,7 @@ impl Args {
             device_type: self.device_type,
             device_path: self.device_path.clone(),
             include_emulators: true,
+            timeout: self.timeout.map(Duration::from_secs),
         }
     }
 }
@@ -83,8 +99,13 @@ enum Commands {
         descriptor: String,
     },
     /// Sign a PSBT with the selected device
+    ///
+    /// Without --name and --descriptor, the wallet registered through `register-wallet`
+    /// whose keys spend the inputs is used. A summary of the transaction is printed on
+    /// stderr before it is sent to the device.
     SignPsbt {
-        /// PSBT file in base64 text format
+        /// PSBT file in base64 text format, version 0 or 2. The signed PSBT keeps its
+        /// version.
         #[arg(long)]
         psbt: PathBuf,
         /// Wallet name
@@ -93,7 +114,8 @@ enum Commands {
         /// Miniscript wallet policy descriptor
         #[arg(long, value_parser = clap::value_parser!(WalletPolicy))]
         descriptor: Option<WalletPolicy>,
-        /// HMAC from wallet registration (hex-encoded 64 chars)
+        /// HMAC from wallet registration (hex-encoded 64 chars). Defaults to the one
+        /// recorded when the wallet was registered.
         #[arg(long)]
         hmac: Option<String>,
         /// Output file. Defaults to stdout.
@@ -108,6 +130,13 @@ enum Commands {
         /// BIP32 derivation path (e.g. m/44'/0'/0'/0/0)
         #[arg(long, value_parser = clap::value_parser!(DerivationPath))]
         path: DerivationPath,
+        /// Produce a BIP-322 simple signature instead of a BIP-137 one
+        #[arg(long, default_value_t = false)]
+        bip322: bool,
+        /// Address format signing with --bip322 (p2wpkh, p2tr). Defaults to the one of the
+        /// BIP-84/86 purpose of --path.
+        #[arg(long, requires = "bip322", value_parser = clap::value_parser!(AddressType))]
+        address_format: Option<AddressType>,
         /// Output file. Defaults to stdout.
         #[arg(long, short)]
         output: Option<PathBuf>,
@@ -136,12 +165,12 @@ enum AddressCommands {
         /// Address format for path-based retrieval (p2pkh, p2sh, p2wpkh, p2wsh, p2tr)
         #[arg(long, value_parser = clap::value_parser!(AddressType))]
         address_format: Option<AddressType>,
-        /// HMAC from wallet registration (hex-encoded 64 chars), required for
-        /// Ledger descriptor-based addresses.
+        /// HMAC from wallet registration (hex-encoded 64 chars) for Ledger
+        /// descriptor-based addresses. Defaults to the one recorded at registration.
         #[arg(long)]
         hmac: Option<String>,
-        /// Miniscript wallet policy matching the registered wallet,
-        /// required for Ledger descriptor-based addresses.
+        /// Miniscript wallet policy matching the registered wallet, for Ledger and
+        /// BitBox02 descriptor-based addresses. Defaults to the one recorded at registration.
         #[arg(long, value_parser = clap::value_parser!(WalletPolicy))]
         wallet_descriptor: Option<WalletPolicy>,
     },
@@ -152,8 +181,13 @@ enum DeviceCommands {
     /// List all available devices
     #[command(alias = "enumerate")]
     List,
-    /// Start a backup on the selected device
+    /// Print the commands, script types and features the selected device supports
+    Capabilities,
+    /// Start a backup on the selected device, or manage BitBox02 microSD backups
+    #[command(args_conflicts_with_subcommands = true)]
     Backup {
+        #[command(subcommand)]
+        command: Option<BackupCommands>,
         /// Output file for devices that export encrypted backup bytes
         #[arg(long, short)]
         output: Option<PathBuf>,
@@ -174,6 +208,32 @@ enum DeviceCommands {
     },
     /// Toggle mnemonic-passphrase use on the selected BitBox02
     TogglePassphrase,
+    /// Use a BIP39 passphrase on the selected Coldcard or Jade, read from stdin, and print
+    /// the resulting master fingerprint
+    SetPassphrase {
+        /// Go back to the wallet without passphrase instead of reading one
+        #[arg(long)]
+        clear: bool,
+    },
+    /// Upload a firmware image to the selected Jade over OTA
+    UpdateFirmware {
+        /// Compressed firmware image, as published by Blockstream
+        #[arg(long)]
+        file: PathBuf,
+        /// Uncompressed firmware size. Defaults to the one in the
+        /// `<version>_<config>_<size>_fw.bin` file name.
+        #[arg(long)]
+        firmware_size: Option<u32>,
+        /// Expected SHA-256 of the uncompressed firmware (hex). Defaults to the content of
+        /// `<file>.hash` when present.
+        #[arg(long)]
+        firmware_hash: Option<String>,
+    },
+    /// Upgrade the BitBox02 firmware and inspect it through the bootloader
+    Bootloader {
+        #[command(subcommand)]
+        command: BootloaderCommands,
+    },
     /// Install udev rules for hardware wallet device access
     InstallUdevRules {
         /// Device rule targets to install
@@ -188,6 +248,48 @@ enum DeviceCommands {
     },
 }
 
+#[derive(Debug, Clone, Subcommand)]
+enum BackupCommands {
+    /// List the backups on the BitBox02 microSD card
+    List,
+    /// Check that the microSD card holds a backup of the BitBox02 seed
+    Check {
+        /// Do not show the result on the device
+        #[arg(long)]
+        silent: bool,
+    },
+    /// Restore an unseeded BitBox02 from a microSD backup
+    Restore {
+        /// Backup id, as printed by `device backup list`
+        id: String,
+    },
+}
+
+#[derive(Debug, Clone, Subcommand)]
+enum BootloaderCommands {
+    /// Reboot the BitBox02 into its bootloader, once confirmed on the device
+    Enter,
+    /// Print the bootloader versions and the firmware and signing keydata hashes
+    Info {
+        /// Also show the hashes on the device, until confirmed
+        #[arg(long)]
+        display: bool,
+    },
+    /// Flash a signed firmware, rebooting into the bootloader first if needed
+    Upgrade {
+        /// Signed firmware binary, as published with the BitBox02 firmware releases
+        #[arg(long)]
+        file: PathBuf,
+        /// Stay in the bootloader once the firmware is flashed
+        #[arg(long)]
+        no_reboot: bool,
+    },
+    /// Erase the firmware, leaving the device in the bootloader
+    Erase,
+    /// Leave the bootloader and boot the firmware
+    Reboot,
+}
+
 #[derive(Debug, Clone, Copy, ValueEnum)]
 enum KeypoolAddressFormat {
     P2pkh,
@@ -315,24 +417,43 @@ async fn main() -> Result<()> {
                 } else {
                     Some(device.fingerprint().await?)
                 };
+                // A device too old to answer the challenge is still listed.
+                let attestation = match device.attestation().await {
+                    Ok(attestation) => attestation,
+                    Err(e) => {
+                        eprintln!("Warning: could not check that {name} is genuine: {e}");
+                        None
+                    }
+                };
+                if attestation == Some(Attestation::Counterfeit) {
+                    eprintln!("Warning: {name} failed its attestation check, do not trust it");
+                }
                 match format {
                     Some(OutputFormat::Pretty) => {
                         if i == 0 {
                             println!(
-                                "{:<18} | {:<8} | {:<15} | {:<12} | {:<8}",
-                                "Name", "Emulated", "Fingerprint", "Network", "Version"
+                                "{:<18} | {:<8} | {:<15} | {:<12} | {:<8} | {:<12}",
+                                "Name",
+                                "Emulated",
+                                "Fingerprint",
+                                "Network",
+                                "Version",
+                                "Attestation"
                             );
                         }
-                        println!("{}", "-".repeat(80));
+                        println!("{}", "-".repeat(95));
                         let network = info.networks_string();
                         let fingerprint = fingerprint
                             .map(|fingerprint| fingerprint.to_string())
                             .unwrap_or_else(|| "-".to_owned());
+                        let attestation = attestation
+                            .map(|attestation| attestation.to_string())
+                            .unwrap_or_else(|| "-".to_owned());
                         println!(
-                            "{name:<18} | {is_emulated:<8} | {fingerprint:<15} | {network:<12} | {:<8}",
+                            "{name:<18} | {is_emulated:<8} | {fingerprint:<15} | {network:<12} | {:<8} | {attestation:<12}",
                             info.version
                         );
-                        println!("{}", "-".repeat(80));
+                        println!("{}", "-".repeat(95));
                     }
                     Some(OutputFormat::Json) => {}
                     None => match fingerprint {
@@ -345,7 +466,67 @@ async fn main() -> Result<()> {
                 println!("{}", serde_json::json![devices])
             }
         }
-        Commands::Device(DeviceCommands::Backup { output }) => {
+        Commands::Device(DeviceCommands::Backup {
+            command: Some(command),
+            ..
+        }) => {
+            if dev_man
+                .selector
+                .device_type
+                .is_some_and(|device_type| device_type != DeviceType::BitBox02)
+            {
+                anyhow::bail!("microSD backups are supported only for BitBox02");
+            }
+            let operation = match command {
+                BackupCommands::List => SdCardBackup::List,
+                BackupCommands::Check { silent } => SdCardBackup::Check { silent },
+                BackupCommands::Restore { id } => SdCardBackup::Restore { id },
+            };
+            match BitBoxDevice::sdcard_backup(&dev_man.selector, operation).await? {
+                None => anyhow::bail!("no BitBox02 found"),
+                Some(SdCardBackupOutcome::Backups(backups)) => {
+                    if let Some(OutputFormat::Json) = format {
+                        let backups: Vec<_> = backups
+                            .iter()
+                            .map(|backup| {
+                                serde_json::json!({
+                                    "id": backup.id,
+                                    "name": backup.name,
+                                    "timestamp": backup.timestamp,
+                                })
+                            })
+                            .collect();
+                        println!("{}", serde_json::json!(backups));
+                    } else {
+                        for backup in backups {
+                            let created = DateTime::from_timestamp(backup.timestamp.into(), 0)
+                                .map(|time| time.format("%Y-%m-%d %H:%M:%S UTC").to_string())
+                                .unwrap_or_default();
+                            println!("{}\t{created}\t{}", backup.id, backup.name);
+                        }
+                    }
+                }
+                Some(SdCardBackupOutcome::Checked(None)) => {
+                    anyhow::bail!("no backup on the microSD card matches the BitBox02 seed")
+                }
+                Some(SdCardBackupOutcome::Checked(Some(id))) => {
+                    if let Some(OutputFormat::Json) = format {
+                        println!("{}", serde_json::json!({ "id": id }));
+                    } else {
+                        println!("{id}");
+                    }
+                }
+                Some(SdCardBackupOutcome::Restored) => {
+                    if let Some(OutputFormat::Json) = format {
+                        println!("{}", serde_json::json!({ "success": true }));
+                    }
+                }
+            }
+        }
+        Commands::Device(DeviceCommands::Backup {
+            command: None,
+            output,
+        }) => {
             if let Some(mut d) = dev_man.get_device_with_fingerprint().await? {
                 let backup = d.device().backup_device().await?;
                 match backup {
@@ -435,9 +616,46 @@ async fn main() -> Result<()> {
                 }
             }
         }
+        Commands::Device(DeviceCommands::Capabilities) => {
+            if let Some(mut device) = dev_man.get_device_with_fingerprint().await? {
+                let capabilities = device.device().capabilities().await?;
+                let commands = names(&capabilities.commands);
+                let script_types = names(&capabilities.script_types);
+                let sighash_types = names(&capabilities.sighash_types);
+                let backups = names(&capabilities.backups);
+                if let Some(OutputFormat::Json) = format {
+                    println!(
+                        "{}",
+                        serde_json::json!({
+                            "commands": commands,
+                            "script_types": script_types,
+                            "taproot": capabilities.taproot,
+                            "miniscript": capabilities.miniscript,
+                            "multisig": capabilities.multisig,
+                            "sighash_types": sighash_types,
+                            "backups": backups,
+                        })
+                    );
+                } else {
+                    let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+                    println!("commands: {}", commands.join(", "));
+                    println!("script types: {}", script_types.join(", "));
+                    println!("taproot: {}", yes_no(capabilities.taproot));
+                    println!("miniscript: {}", yes_no(capabilities.miniscript));
+                    println!("multisig: {}", yes_no(capabilities.multisig));
+                    println!("sighash types: {}", sighash_types.join(", "));
+                    println!("backups: {}", backups.join(", "));
+                }
+            }
+        }
         Commands::Device(DeviceCommands::TogglePassphrase) => {
             if let Some(mut device) = dev_man.get_device_with_fingerprint().await? {
-                if device.device_type() != DeviceType::BitBox02 {
+                if !device
+                    .device()
+                    .capabilities()
+                    .await?
+                    .supports(CommandKind::TogglePassphrase)
+                {
                     anyhow::bail!(
                         "device toggle-passphrase is currently supported only for BitBox02"
                     );
@@ -450,6 +668,133 @@ async fn main() -> Result<()> {
                 }
             }
         }
+        Commands::Device(DeviceCommands::SetPassphrase { clear }) => {
+            if let Some(mut device) = dev_man.get_device_with_fingerprint().await? {
+                let passphrase = if clear {
+                    None
+                } else {
+                    Some(read_passphrase()?)
+                };
+                let fingerprint = device.device().set_passphrase(passphrase).await?;
+                if let Some(OutputFormat::Json) = format {
+                    println!(
+                        "{}",
+                        serde_json::json!({ "fingerprint": fingerprint.to_string() })
+                    );
+                } else {
+                    println!("{fingerprint}");
+                }
+            }
+        }
+        Commands::Device(DeviceCommands::UpdateFirmware {
+            file,
+            firmware_size,
+            firmware_hash,
+        }) => {
+            if dev_man
+                .selector
+                .device_type
+                .is_some_and(|device_type| device_type != DeviceType::Jade)
+            {
+                anyhow::bail!("firmware update is currently supported only for Jade");
+            }
+            let update = firmware_update_from_file(&file, firmware_size, firmware_hash.as_deref())?;
+            let updated = JadeDevice::update_firmware(&dev_man.selector, update, |progress| {
+                eprint!(
+                    "\rUploading firmware: {}/{} bytes",
+                    progress.written, progress.total
+                );
+            })
+            .await?;
+            if !updated {
+                anyhow::bail!("no Jade found");
+            }
+            eprintln!();
+            if let Some(OutputFormat::Json) = format {
+                println!("{}", serde_json::json!({ "success": true }));
+            }
+        }
+        Commands::Device(DeviceCommands::Bootloader { command }) => {
+            if dev_man
+                .selector
+                .device_type
+                .is_some_and(|device_type| device_type != DeviceType::BitBox02)
+            {
+                anyhow::bail!("bootloader commands are supported only for BitBox02");
+            }
+            let selector = &dev_man.selector;
+            let acknowledge = match command {
+                BootloaderCommands::Enter => {
+                    if !BitBoxDevice::reboot_to_bootloader(selector).await? {
+                        anyhow::bail!("no BitBox02 found");
+                    }
+                    true
+                }
+                BootloaderCommands::Info { display } => {
+                    let mut bootloader = bitbox_bootloader(selector).await?;
+                    let versions = bootloader.versions().await?;
+                    let hashes = bootloader.hashes(display, display).await?;
+                    if let Some(OutputFormat::Json) = format {
+                        println!(
+                            "{}",
+                            serde_json::json!({
+                                "firmware_version": versions.firmware,
+                                "signing_keydata_version": versions.signing_keydata,
+                                "firmware_hash": hex::encode(hashes.firmware),
+                                "signing_keydata_hash": hex::encode(hashes.signing_keydata),
+                            })
+                        );
+                    } else {
+                        println!("firmware version: {}", versions.firmware);
+                        println!("signing keydata version: {}", versions.signing_keydata);
+                        println!("firmware hash: {}", hex::encode(hashes.firmware));
+                        println!(
+                            "signing keydata hash: {}",
+                            hex::encode(hashes.signing_keydata)
+                        );
+                    }
+                    false
+                }
+                BootloaderCommands::Upgrade { file, no_reboot } => {
+                    let firmware = signed_firmware_from_file(&file)?;
+                    let firmware_hash = hex::encode(firmware.firmware_hash());
+                    let upgraded =
+                        BitBoxDevice::upgrade_firmware(selector, firmware, no_reboot, |progress| {
+                            eprint!(
+                                "\rWriting firmware: {}/{} bytes",
+                                progress.written, progress.total
+                            );
+                        })
+                        .await?;
+                    if !upgraded {
+                        anyhow::bail!("no BitBox02 found");
+                    }
+                    eprintln!();
+                    // The hash the device shows for the new firmware, to compare with the
+                    // one published with the release.
+                    if let Some(OutputFormat::Json) = format {
+                        println!(
+                            "{}",
+                            serde_json::json!({ "success": true, "firmware_hash": firmware_hash })
+                        );
+                    } else {
+                        println!("{firmware_hash}");
+                    }
+                    false
+                }
+                BootloaderCommands::Erase => {
+                    bitbox_bootloader(selector).await?.erase().await?;
+                    true
+                }
+                BootloaderCommands::Reboot => {
+                    bitbox_bootloader(selector).await?.reboot().await?;
+                    true
+                }
+            };
+            if acknowledge && let Some(OutputFormat::Json) = format {
+                println!("{}", serde_json::json!({ "success": true }));
+            }
+        }
         Commands::Device(DeviceCommands::InstallUdevRules {
             targets,
             all,
@@ -516,9 +861,9 @@ async fn main() -> Result<()> {
             output,
         } => {
             let psbt_text = std::fs::read_to_string(psbt)?;
-            let psbt = Psbt::from_str(psbt_text.trim())?;
+            let psbt = VersionedPsbt::from_str(psbt_text.trim())?;
             let hmac = hmac.as_deref().map(parse_hmac).transpose()?;
-            let context = match (name, descriptor, hmac) {
+            let context = match (name, descriptor.clone(), hmac) {
                 (Some(name), Some(policy), hmac) => Some(DeviceContext::Ledger {
                     wallet_policy: LedgerWalletPolicy::new(name, Version::V2, policy),
                     wallet_hmac: hmac,
@@ -532,7 +877,19 @@ async fn main() -> Result<()> {
                 ),
             };
             if let Some(mut d) = dev_man.get_device_with_fingerprint().await? {
-                let signed = d.device().sign_tx(psbt, context).await?;
+                // Without --descriptor, preview under the registered wallet the device
+                // signs with.
+                let registered = match (&descriptor, FileRegistrationStore::from_config_dir()) {
+                    (None, Some(store)) => {
+                        wallet_spending(store.load(d.fingerprint().await?)?, psbt.psbt())
+                            .map(|wallet| wallet.policy)
+                    }
+                    _ => None,
+                };
+                let preview =
+                    TxPreview::new(psbt.psbt(), descriptor.as_ref().or(registered.as_ref()))?;
+                print_preview(&preview, dev_man.selector.network);
+                let signed = d.device().sign_versioned_tx(psbt, context).await?;
                 let signed = signed.to_string();
                 if let Some(output) = output {
                     std::fs::write(output, signed)?;
@@ -544,11 +901,26 @@ async fn main() -> Result<()> {
         Commands::SignMessage {
             message,
             path,
+            bip322,
+            address_format,
             output,
         } => {
+            let bip322_address_type = bip322
+                .then(|| bip322_address_type(&path, address_format))
+                .transpose()?;
             if let Some(mut d) = dev_man.get_device_with_fingerprint().await? {
-                let (header, signature) = d.device().sign_message(message.as_bytes(), path).await?;
-                let signature = message_signature_base64(header, &signature);
+                let signature = match bip322_address_type {
+                    Some(address_type) => {
+                        d.device()
+                            .sign_message_bip322(message.as_bytes(), path, address_type)
+                            .await?
+                    }
+                    None => {
+                        let (header, signature) =
+                            d.device().sign_message(message.as_bytes(), path).await?;
+                        message_signature_base64(header, &signature)
+                    }
+                };
                 let rendered = match format {
                     Some(OutputFormat::Json) => {
                         serde_json::json!({ "signature": signature }).to_string()
@@ -567,6 +939,10 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
+fn names<T: ToString>(items: &[T]) -> Vec<String> {
+    items.iter().map(ToString::to_string).collect()
+}
+
 fn message_signature_base64(
     header: u8,
     signature: &bitcoin::secp256k1::ecdsa::Signature,
@@ -577,6 +953,71 @@ fn message_signature_base64(
     BASE64_STANDARD.encode(payload)
 }
 
+/// Address type of a BIP-322 signature: `address_format` if given, otherwise the one of the
+/// BIP-84 or BIP-86 purpose of `path`.
+fn bip322_address_type(
+    path: &DerivationPath,
+    address_format: Option<AddressType>,
+) -> Result<AddressType> {
+    if let Some(address_format) = address_format {
+        return Ok(address_format);
+    }
+    match path.as_ref().first() {
+        Some(ChildNumber::Hardened { index: 84 }) => Ok(AddressType::P2wpkh),
+        Some(ChildNumber::Hardened { index: 86 }) => Ok(AddressType::P2tr),
+        _ => anyhow::bail!("--address-format is required for non BIP-84/86 paths with --bip322"),
+    }
+}
+
+/// Print on stderr what the transaction spends and pays, to compare with the device screen.
+fn print_preview(preview: &TxPreview, network: Network) {
+    let destination = |output: &PreviewOutput| {
+        Address::from_script(&output.script_pubkey, network)
+            .map(|address| address.to_string())
+            .unwrap_or_else(|_| output.script_pubkey.to_hex_string())
+    };
+    eprintln!("Inputs:");
+    for input in &preview.inputs {
+        match input.amount {
+            Some(amount) => eprintln!("  {} {amount}", input.outpoint),
+            None => eprintln!("  {} (unknown amount)", input.outpoint),
+        }
+    }
+    for (title, outputs) in [
+        ("Recipients", &preview.recipients),
+        ("Change", &preview.change),
+    ] {
+        if outputs.is_empty() {
+            continue;
+        }
+        eprintln!("{title}:");
+        for output in outputs {
+            eprintln!(
+                "  #{} {} {}",
+                output.index,
+                destination(output),
+                output.amount
+            );
+        }
+    }
+    match (preview.fee, preview.fee_rate()) {
+        (Some(fee), Some(rate)) => eprintln!("Fee: {fee} (~{rate:.1} sat/vB)"),
+        (Some(fee), None) => eprintln!("Fee: {fee}"),
+        _ => eprintln!("Fee: unknown, an input amount is missing"),
+    }
+    if let Some(locktime) = preview.locktime {
+        eprintln!("Locktime: {locktime}");
+    }
+    eprintln!("RBF: {}", if preview.rbf { "yes" } else { "no" });
+}
+
+async fn bitbox_bootloader(selector: &DeviceSelector) -> Result<BitBoxBootloaderHid> {
+    BitBoxDevice::bootloader(selector)
+        .await?
+        .map(|(bootloader, _)| bootloader)
+        .ok_or_else(|| anyhow::anyhow!("no BitBox02 bootloader found"))
+}
+
 fn parse_hmac(hmac: &str) -> Result<[u8; 32]> {
     let bytes = hex::decode(hmac)?;
     let hmac: [u8; 32] = bytes
@@ -689,6 +1130,16 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn parses_device_capabilities() {
+        let args = Args::try_parse_from(["bhwi", "device", "capabilities"])
+            .expect("parse device capabilities");
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Capabilities)
+        ));
+    }
+
     #[test]
     fn parses_device_toggle_passphrase() {
         let args = Args::try_parse_from(["bhwi", "device", "toggle-passphrase"])
@@ -699,6 +1150,71 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn parses_device_set_passphrase() {
+        let args = Args::try_parse_from(["bhwi", "device", "set-passphrase"])
+            .expect("parse device set-passphrase");
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::SetPassphrase { clear: false })
+        ));
+        let args = Args::try_parse_from(["bhwi", "device", "set-passphrase", "--clear"])
+            .expect("parse device set-passphrase --clear");
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::SetPassphrase { clear: true })
+        ));
+        assert!(Args::try_parse_from(["bhwi", "device", "set-passphrase", "secret"]).is_err());
+    }
+
+    #[test]
+    fn parses_device_update_firmware() {
+        let args = Args::try_parse_from([
+            "bhwi",
+            "device",
+            "update-firmware",
+            "--file",
+            "1.0.31_ble_1153024_fw.bin",
+        ])
+        .expect("parse device update-firmware");
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::UpdateFirmware {
+                file,
+                firmware_size: None,
+                firmware_hash: None,
+            }) if file == PathBuf::from("1.0.31_ble_1153024_fw.bin")
+        ));
+    }
+
+    #[test]
+    fn parses_device_bootloader() {
+        let args = Args::parse_from([
+            "bhwi",
+            "device",
+            "bootloader",
+            "upgrade",
+            "--file",
+            "firmware-btc.v9.21.0.signed.bin",
+            "--no-reboot",
+        ]);
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Bootloader {
+                command: BootloaderCommands::Upgrade { file, no_reboot: true },
+            }) if file == PathBuf::from("firmware-btc.v9.21.0.signed.bin")
+        ));
+
+        let args = Args::parse_from(["bhwi", "device", "bootloader", "info", "--display"]);
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Bootloader {
+                command: BootloaderCommands::Info { display: true },
+            })
+        ));
+        assert!(Args::try_parse_from(["bhwi", "device", "bootloader"]).is_err());
+    }
+
     #[test]
     fn parses_device_install_udev_rules_all() {
         let args = Args::try_parse_from(["bhwi", "device", "install-udev-rules", "--all"])
@@ -746,18 +1262,51 @@ mod tests {
             Commands::SignMessage {
                 message,
                 path,
+                bip322: false,
+                address_format: None,
                 output: None,
             } if message == "hello" && path.to_string() == "44'/1'/0'/0"
         ));
     }
 
+    #[test]
+    fn bip322_address_type_defaults_to_path_purpose() {
+        let path = |path: &str| DerivationPath::from_str(path).unwrap();
+        assert_eq!(
+            bip322_address_type(&path("m/84'/1'/0'/0/0"), None).unwrap(),
+            AddressType::P2wpkh
+        );
+        assert_eq!(
+            bip322_address_type(&path("m/86'/1'/0'/0/0"), None).unwrap(),
+            AddressType::P2tr
+        );
+        assert_eq!(
+            bip322_address_type(&path("m/44'/1'/0'/0/0"), Some(AddressType::P2tr)).unwrap(),
+            AddressType::P2tr
+        );
+        assert!(bip322_address_type(&path("m/44'/1'/0'/0/0"), None).is_err());
+
+        let error = Args::try_parse_from([
+            "bhwi",
+            "sign-message",
+            "--message",
+            "hello",
+            "--path",
+            "m/84'/1'/0'/0/0",
+            "--address-format",
+            "p2tr",
+        ])
+        .expect_err("--address-format requires --bip322");
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+    }
+
     #[test]
     fn parses_device_backup_with_explicit_output() {
         let args = Args::parse_from(["bhwi", "device", "backup", "--output", "backup.7z"]);
 
         assert!(matches!(
             args.command,
-            Commands::Device(DeviceCommands::Backup { output })
+            Commands::Device(DeviceCommands::Backup { command: None, output })
                 if output.as_deref() == Some(std::path::Path::new("backup.7z"))
         ));
     }
@@ -768,8 +1317,45 @@ mod tests {
 
         assert!(matches!(
             args.command,
-            Commands::Device(DeviceCommands::Backup { output: None })
+            Commands::Device(DeviceCommands::Backup {
+                command: None,
+                output: None
+            })
+        ));
+    }
+
+    #[test]
+    fn parses_device_backup_subcommands() {
+        let args = Args::parse_from(["bhwi", "device", "backup", "list"]);
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Backup {
+                command: Some(BackupCommands::List),
+                ..
+            })
+        ));
+
+        let args = Args::parse_from(["bhwi", "device", "backup", "check", "--silent"]);
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Backup {
+                command: Some(BackupCommands::Check { silent: true }),
+                ..
+            })
         ));
+
+        let args = Args::parse_from(["bhwi", "device", "backup", "restore", "a1b2c3"]);
+        assert!(matches!(
+            args.command,
+            Commands::Device(DeviceCommands::Backup {
+                command: Some(BackupCommands::Restore { id }),
+                ..
+            }) if id == "a1b2c3"
+        ));
+
+        assert!(
+            Args::try_parse_from(["bhwi", "device", "backup", "--output", "x.7z", "list"]).is_err()
+        );
     }
 
     #[test]
@@ -1,17 +1,22 @@
-use std::{io, sync::Arc, time::Duration};
+use std::{io, path::Path, sync::Arc, time::Duration};
 
 use anyhow::Context;
 use anyhow::Result;
 use async_hid::Device as HidDevice;
 use async_hid::HidBackend;
 use async_trait::async_trait;
+use bhwi::bitbox::{
+    BackupInfo,
+    bootloader::{FirmwareEdition, SignedFirmware, UpgradeProgress},
+};
 use bhwi_async::{
-    bitbox::BitBox,
+    HWI, Transport,
+    bitbox::{BitBox, BitBoxBootloader, FilePairingStore},
     transport::{
         Channel, DeviceId,
         bitbox::hid::{
-            BITBOX02_DEVICE_ID, BITBOX02_HID_USAGE_PAGE, BITBOX02_PRODUCT_STRINGS,
-            BitBoxTransportHID,
+            BITBOX02_BOOTLOADER_PRODUCT_STRINGS, BITBOX02_DEVICE_ID, BITBOX02_HID_USAGE_PAGE,
+            BITBOX02_PRODUCT_STRINGS, BitBoxBootloaderTransportHID, BitBoxTransportHID,
         },
     },
 };
@@ -23,26 +28,39 @@ use tokio::{
     sync::Mutex,
 };
 
-use crate::{Device, DeviceEnumerator, DeviceType, config::DeviceSelector, hid::HidChannel};
+use crate::{
+    Device, DeviceEnumerator, DeviceType, config::DeviceSelector, hid::HidChannel,
+    management::timestamp_and_timezone_offset,
+};
 
 pub struct BitBoxDevice;
 
+pub type BitBoxHid = BitBox<BitBoxTransportHID<HidChannel>>;
+pub type BitBoxSimulator = BitBox<BitBoxTransportHID<BitBoxTcpChannel>>;
+pub type BitBoxBootloaderHid = BitBoxBootloader<BitBoxBootloaderTransportHID<HidChannel>>;
+
+/// How long the user has to confirm the reboot into the bootloader.
+const BOOTLOADER_WAIT: Duration = Duration::from_secs(60);
+
+/// microSD backup operation run by [`BitBoxDevice::sdcard_backup`].
+pub enum SdCardBackup {
+    List,
+    Check { silent: bool },
+    Restore { id: String },
+}
+
+pub enum SdCardBackupOutcome {
+    Backups(Vec<BackupInfo>),
+    /// Id of the backup matching the device's seed, if any.
+    Checked(Option<String>),
+    Restored,
+}
+
 impl BitBoxDevice {
     async fn hid_device(hid_dev: HidDevice, network: bitcoin::Network) -> Result<Option<Device>> {
         let path = hid_path(&hid_dev);
         let name = hid_dev.name.clone();
-        // No cached pairing data yet — a filesystem-backed store can be plugged in later.
-        // First-time pairing: the interpreter fires a hook the moment the code is
-        // computed (before it blocks on the device's verification response), so the CLI
-        // can print it while the user confirms on the device.
-        let mut bb = BitBox::new(
-            BitBoxTransportHID::new(HidChannel::new(hid_dev.open().await?)),
-            None,
-        )
-        .with_network(network);
-        bb.set_pairing_code_hook(Box::new(|code| {
-            eprintln!("\nBitBox02 pairing code — confirm on device:\n\n{code}\n");
-        }));
+        let bb = Self::hid_bitbox(hid_dev, network).await?;
         Ok(Some(
             Device::new(
                 &name,
@@ -56,6 +74,25 @@ impl BitBoxDevice {
         ))
     }
 
+    async fn hid_bitbox(hid_dev: HidDevice, network: bitcoin::Network) -> Result<BitBoxHid> {
+        // Pairing data is cached under the XDG config dir so already-paired devices skip
+        // the confirmation. First-time pairing: the code is raised as an event before the
+        // interpreter waits on the device's verification response, and printed by the
+        // command control of the selector while the user confirms on the device.
+        let mut bb = BitBox::new(
+            BitBoxTransportHID::new(HidChannel::new(hid_dev.open().await?)),
+            None,
+        )
+        .with_network(network);
+        if let Some(store) = FilePairingStore::from_config_dir() {
+            let path = store.path().display().to_string();
+            bb = bb
+                .with_pairing_store(Box::new(store))
+                .with_context(|| format!("failed to load BitBox02 pairing data from {path}"))?;
+        }
+        Ok(bb)
+    }
+
     async fn simulator_device(
         path: &str,
         stream: TcpStream,
@@ -63,10 +100,7 @@ impl BitBoxDevice {
     ) -> Result<Device> {
         // The simulator speaks the same U2F-HID framing as real hardware, so the only
         // difference from the HID path is the underlying byte channel (a TCP stream here).
-        // No pairing-code hook: the simulator auto-confirms pairing, so surfacing a code
-        // would only add noise (and stderr) to scripted/emulator runs.
-        let bb = BitBox::new(BitBoxTransportHID::new(BitBoxTcpChannel::new(stream)), None)
-            .with_network(network);
+        let bb = Self::simulator_bitbox(stream, network);
         Device::new(
             "BitBox02 Simulator",
             DeviceType::BitBox02,
@@ -77,6 +111,193 @@ impl BitBoxDevice {
         )
         .await
     }
+
+    fn simulator_bitbox(stream: TcpStream, network: bitcoin::Network) -> BitBoxSimulator {
+        BitBox::new(BitBoxTransportHID::new(BitBoxTcpChannel::new(stream)), None)
+            .with_network(network)
+    }
+
+    /// Run `operation` on the first BitBox02 matching `selector`, prompting for the microSD
+    /// card first if none is inserted. Returns `None` when no BitBox02 is connected.
+    pub async fn sdcard_backup(
+        selector: &DeviceSelector,
+        operation: SdCardBackup,
+    ) -> Result<Option<SdCardBackupOutcome>> {
+        let DeviceId {
+            vid,
+            pid,
+            emulator_path,
+            ..
+        } = BITBOX02_DEVICE_ID;
+        let pid = pid.context("bitbox02 pid not set")?;
+        let hid_devices: Vec<HidDevice> = HidBackend::default().enumerate().await?.collect().await;
+        for dev in hid_devices {
+            if selector.matches(DeviceType::BitBox02, &hid_path(&dev))
+                && is_bitbox_firmware(&dev, vid, pid)
+            {
+                let mut bb = Self::hid_bitbox(dev, selector.network).await?;
+                HWI::set_control(&mut bb, selector.command_control(false));
+                return Ok(Some(sdcard_backup(&mut bb, operation).await?));
+            }
+        }
+        if selector.include_emulators
+            && let Some(path) = emulator_path
+            && (selector.matches(DeviceType::BitBox02, path)
+                || selector.matches(DeviceType::BitBox02, simulator_tcp_addr(path)))
+            && let Ok(stream) = TcpStream::connect(simulator_tcp_addr(path)).await
+        {
+            let mut bb = Self::simulator_bitbox(stream, selector.network);
+            HWI::set_control(&mut bb, selector.command_control(true));
+            return Ok(Some(sdcard_backup(&mut bb, operation).await?));
+        }
+        Ok(None)
+    }
+
+    /// Reboot the first BitBox02 matching `selector` into its bootloader, once the user
+    /// confirms on the device. Returns `false` when no BitBox02 runs its firmware. The
+    /// simulator has no bootloader, so only USB devices are considered.
+    pub async fn reboot_to_bootloader(selector: &DeviceSelector) -> Result<bool> {
+        let (vid, pid) = bitbox02_usb_ids()?;
+        let hid_devices: Vec<HidDevice> = HidBackend::default().enumerate().await?.collect().await;
+        for dev in hid_devices {
+            if selector.matches(DeviceType::BitBox02, &hid_path(&dev))
+                && is_bitbox_firmware(&dev, vid, pid)
+            {
+                let mut bb = Self::hid_bitbox(dev, selector.network).await?;
+                HWI::set_control(&mut bb, selector.command_control(false));
+                HWI::unlock(&mut bb, selector.network).await?;
+                bb.reboot_to_bootloader().await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// The first BitBox02 bootloader matching `selector`, with its edition when known.
+    pub async fn bootloader(
+        selector: &DeviceSelector,
+    ) -> Result<Option<(BitBoxBootloaderHid, Option<FirmwareEdition>)>> {
+        let (vid, pid) = bitbox02_usb_ids()?;
+        let hid_devices: Vec<HidDevice> = HidBackend::default().enumerate().await?.collect().await;
+        for dev in hid_devices {
+            if selector.matches(DeviceType::BitBox02, &hid_path(&dev))
+                && is_bitbox_bootloader(&dev, vid, pid)
+            {
+                let edition = FirmwareEdition::from_bootloader_product(&dev.name);
+                let transport =
+                    BitBoxBootloaderTransportHID::new(HidChannel::new(dev.open().await?));
+                return Ok(Some((BitBoxBootloader::new(transport), edition)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Flash `firmware` on the first BitBox02 matching `selector`, rebooting it into its
+    /// bootloader first if it runs its firmware, then boot the new firmware unless
+    /// `stay_in_bootloader`. Returns `false` when no BitBox02 is connected.
+    pub async fn upgrade_firmware(
+        selector: &DeviceSelector,
+        firmware: SignedFirmware,
+        stay_in_bootloader: bool,
+        progress: impl FnMut(UpgradeProgress),
+    ) -> Result<bool> {
+        let (mut bootloader, edition) = match Self::bootloader(selector).await? {
+            Some(bootloader) => bootloader,
+            None => {
+                if !Self::reboot_to_bootloader(selector).await? {
+                    return Ok(false);
+                }
+                Self::wait_for_bootloader(selector).await?
+            }
+        };
+        // Flashing starts with erasing the current firmware, so an unknown edition is refused
+        // rather than risking a firmware the bootloader would reject.
+        let (Some(firmware_edition), Some(bootloader_edition)) = (firmware.edition(), edition)
+        else {
+            anyhow::bail!("unknown firmware or BitBox02 edition, refusing to flash");
+        };
+        if firmware_edition != bootloader_edition {
+            anyhow::bail!(
+                "the firmware is for the {firmware_edition:?} edition, the BitBox02 is a {bootloader_edition:?} one"
+            );
+        }
+        bootloader.upgrade_firmware(firmware, progress).await?;
+        if !stay_in_bootloader {
+            bootloader.reboot().await?;
+        }
+        Ok(true)
+    }
+
+    async fn wait_for_bootloader(
+        selector: &DeviceSelector,
+    ) -> Result<(BitBoxBootloaderHid, Option<FirmwareEdition>)> {
+        let poll = Duration::from_millis(500);
+        let mut waited = Duration::ZERO;
+        while waited < BOOTLOADER_WAIT {
+            tokio::time::sleep(poll).await;
+            waited += poll;
+            if let Some(bootloader) = Self::bootloader(selector).await? {
+                return Ok(bootloader);
+            }
+        }
+        anyhow::bail!("the BitBox02 did not reboot into its bootloader")
+    }
+}
+
+/// Read a signed BitBox02 firmware binary, as published with the firmware releases.
+pub fn signed_firmware_from_file(file: &Path) -> Result<SignedFirmware> {
+    let binary = std::fs::read(file).with_context(|| format!("reading {}", file.display()))?;
+    Ok(SignedFirmware::parse(&binary)?)
+}
+
+async fn sdcard_backup<T>(
+    bb: &mut BitBox<T>,
+    operation: SdCardBackup,
+) -> Result<SdCardBackupOutcome>
+where
+    T: Transport,
+    T::Error: std::error::Error + Send + Sync + 'static,
+{
+    let network = bb.network;
+    HWI::unlock(bb, network).await?;
+    if !bb.check_sdcard().await? {
+        eprintln!("Insert the microSD card in the BitBox02");
+        bb.insert_sdcard().await?;
+    }
+    Ok(match operation {
+        SdCardBackup::List => SdCardBackupOutcome::Backups(bb.list_backups().await?),
+        SdCardBackup::Check { silent } => {
+            SdCardBackupOutcome::Checked(bb.check_backup(silent).await?)
+        }
+        SdCardBackup::Restore { id } => {
+            let (timestamp, timezone_offset) = timestamp_and_timezone_offset()?;
+            bb.restore_backup(&id, timestamp, timezone_offset).await?;
+            SdCardBackupOutcome::Restored
+        }
+    })
+}
+
+/// A BitBox02 also exposes a FIDO/U2F HID interface (usage page 0xf1d0) and a bootloader;
+/// only the firmware interface speaks the HWW protocol.
+fn is_bitbox_firmware(dev: &HidDevice, vid: u16, pid: u16) -> bool {
+    dev.vendor_id == vid
+        && dev.product_id == pid
+        && dev.usage_page == BITBOX02_HID_USAGE_PAGE
+        && BITBOX02_PRODUCT_STRINGS
+            .iter()
+            .any(|s| dev.name.contains(s))
+}
+
+fn is_bitbox_bootloader(dev: &HidDevice, vid: u16, pid: u16) -> bool {
+    dev.vendor_id == vid
+        && dev.product_id == pid
+        && dev.usage_page == BITBOX02_HID_USAGE_PAGE
+        && BITBOX02_BOOTLOADER_PRODUCT_STRINGS.contains(&dev.name.as_str())
+}
+
+fn bitbox02_usb_ids() -> Result<(u16, u16)> {
+    let DeviceId { vid, pid, .. } = BITBOX02_DEVICE_ID;
+    Ok((vid, pid.context("bitbox02 pid not set")?))
 }
 
 fn simulator_tcp_addr(path: &str) -> &str {
@@ -99,15 +320,9 @@ impl DeviceEnumerator for BitBoxDevice {
             .map(Ok)
             .try_filter_map(|dev| async move {
                 let path = hid_path(&dev);
-                // A BitBox02 also exposes a FIDO/U2F HID interface (usage page 0xf1d0);
-                // only the firmware interface speaks the HWW protocol.
-                let is_bitbox = dev.vendor_id == vid
-                    && dev.product_id == pid
-                    && dev.usage_page == BITBOX02_HID_USAGE_PAGE
-                    && BITBOX02_PRODUCT_STRINGS
-                        .iter()
-                        .any(|s| dev.name.contains(s));
-                if selector.matches(DeviceType::BitBox02, &path) && is_bitbox {
+                if selector.matches(DeviceType::BitBox02, &path)
+                    && is_bitbox_firmware(&dev, vid, pid)
+                {
                     Self::hid_device(dev, selector.network).await
                 } else {
                     Ok(None)
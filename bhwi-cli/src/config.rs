@@ -1,6 +1,9 @@
+use std::time::Duration;
+
+use bhwi_async::CommandControl;
 use bitcoin::{Network, bip32::Fingerprint};
 
-use crate::DeviceType;
+use crate::{DeviceType, print_event};
 
 #[derive(Debug, Clone)]
 pub struct DeviceSelector {
@@ -9,6 +12,8 @@ pub struct DeviceSelector {
     pub device_type: Option<DeviceType>,
     pub device_path: Option<String>,
     pub include_emulators: bool,
+    /// Deadline of every command run on the selected devices.
+    pub timeout: Option<Duration>,
 }
 
 impl Default for DeviceSelector {
@@ -19,6 +24,7 @@ impl Default for DeviceSelector {
             device_type: None,
             device_path: None,
             include_emulators: false,
+            timeout: None,
         }
     }
 }
@@ -31,4 +37,17 @@ impl DeviceSelector {
                 .as_ref()
                 .is_none_or(|target| target == path)
     }
+
+    /// Control of the commands run on a selected device: the deadline, and its events
+    /// printed to stderr. Emulators confirm on their own, so their events are not printed.
+    pub fn command_control(&self, is_emulated: bool) -> CommandControl {
+        let mut control = CommandControl::new();
+        if let Some(timeout) = self.timeout {
+            control = control.with_timer(tokio::time::sleep).with_timeout(timeout);
+        }
+        if !is_emulated {
+            control = control.with_events(print_event);
+        }
+        control
+    }
 }
@@ -12,7 +12,9 @@ use bhwi::{
     common::{MultisigAddressType, MultisigDisplayAddress},
     ledger::{LedgerWalletPolicy, Version, singlesig_wallet_policy},
 };
-use bhwi_async::{DeviceBackup, DeviceContext, DisplayAddress, RestoreOptions, SetupOptions};
+use bhwi_async::{
+    DeviceBackup, DeviceContext, DisplayAddress, RestoreOptions, SetupOptions, VersionedPsbt,
+};
 use bitcoin::{
     Network, NetworkKind, PublicKey, ScriptBuf,
     base64::prelude::{BASE64_STANDARD, Engine as _},
@@ -948,12 +950,18 @@ async fn backup_device(
         }
     }
 
-    let approval =
-        coldcard_emulator_approval(coldcard_emulator_path(&device), ColdcardApproval::Backup);
-    let (backup, approval) = tokio::join!(device.device().backup_device(), approval);
-    if let Err(err) = approval {
-        return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
-    }
+    let backup = with_coldcard_emulator_approval(
+        coldcard_emulator_path(&device),
+        ColdcardApproval::Backup,
+        device.device().backup_device(),
+    )
+    .await;
+    let backup = match backup {
+        Ok(backup) => backup,
+        Err(err) => {
+            return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
+        }
+    };
     match backup {
         Ok(DeviceBackup::Complete) => HwiResponse::Success(HwiSuccessResponse { success: true }),
         Ok(DeviceBackup::File(bytes)) => match write_hwi_backup_file(&bytes) {
@@ -1011,7 +1019,7 @@ async fn sign_tx(selector: DeviceSelector, psbt: String) -> HwiResponse {
         ));
     }
 
-    let parsed = match Psbt::from_str(psbt.trim()) {
+    let parsed = match VersionedPsbt::from_str(psbt.trim()) {
         Ok(psbt) => psbt,
         Err(err) => {
             return HwiResponse::Error(HwiError::new(HwiErrorCode::BadArgument, err.to_string()));
@@ -1037,7 +1045,7 @@ async fn sign_tx(selector: DeviceSelector, psbt: String) -> HwiResponse {
 
     let original = parsed.to_string();
     let context = if device.device_type() == DeviceType::Ledger {
-        match ledger_signing_context(&mut device, &parsed).await {
+        match ledger_signing_context(&mut device, parsed.psbt()).await {
             Ok(Some(context)) => Some(context),
             Ok(None) => {
                 return HwiResponse::SignTx(HwiSignTxResponse {
@@ -1053,7 +1061,7 @@ async fn sign_tx(selector: DeviceSelector, psbt: String) -> HwiResponse {
         None
     };
 
-    match device.device().sign_tx(parsed, context).await {
+    match device.device().sign_versioned_tx(parsed, context).await {
         Ok(signed_psbt) => {
             let signed = signed_psbt.to_string();
             HwiResponse::SignTx(HwiSignTxResponse {
@@ -1098,15 +1106,18 @@ async fn sign_message(
     };
 
     let device_type = device.device_type();
-    let approval =
-        coldcard_emulator_approval(coldcard_emulator_path(&device), ColdcardApproval::Once);
-    let (signature, approval) = tokio::join!(
+    let signature = with_coldcard_emulator_approval(
+        coldcard_emulator_path(&device),
+        ColdcardApproval::Once,
         device.device().sign_message(message.as_bytes(), path),
-        approval
-    );
-    if let Err(err) = approval {
-        return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
-    }
+    )
+    .await;
+    let signature = match signature {
+        Ok(signature) => signature,
+        Err(err) => {
+            return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
+        }
+    };
     match signature {
         Ok((header, signature)) => HwiResponse::SignMessage(HwiSignMessageResponse {
             signature: message_signature_base64(
@@ -1191,13 +1202,18 @@ async fn display_address(
         Err(error) => return HwiResponse::Error(error),
     };
 
-    let approval =
-        coldcard_emulator_approval(coldcard_emulator_path(&device), ColdcardApproval::Once);
-    let (address, approval) =
-        tokio::join!(device.device().display_address(display, None), approval);
-    if let Err(err) = approval {
-        return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
-    }
+    let address = with_coldcard_emulator_approval(
+        coldcard_emulator_path(&device),
+        ColdcardApproval::Once,
+        device.device().display_address(display, None),
+    )
+    .await;
+    let address = match address {
+        Ok(address) => address,
+        Err(err) => {
+            return HwiResponse::Error(HwiError::new(HwiErrorCode::DeviceConnectionError, err));
+        }
+    };
     match address {
         Ok(address) => HwiResponse::DisplayAddress(HwiDisplayAddressResponse { address }),
         Err(err) => display_address_error(err.to_string()),
@@ -1215,68 +1231,58 @@ fn coldcard_emulator_path(device: &Device) -> Option<String> {
         .then(|| device.path().to_string())
 }
 
-async fn coldcard_emulator_approval(
+/// Runs `command` while pressing the keys of `approval` on the Coldcard simulator at
+/// `socket_path`, if the device is one.
+async fn with_coldcard_emulator_approval<F: Future>(
     socket_path: Option<String>,
     approval: ColdcardApproval,
-) -> Result<(), String> {
+    command: F,
+) -> Result<F::Output, String> {
     match socket_path {
-        Some(socket_path) => coldcard_emulator_keypresses(&socket_path, approval).await,
-        None => Ok(()),
+        Some(socket_path) => {
+            coldcard_emulator_approve_during(&socket_path, approval, command).await
+        }
+        None => Ok(command.await),
     }
 }
 
 #[cfg(unix)]
-async fn coldcard_emulator_keypresses(
+async fn coldcard_emulator_approve_during<F: Future>(
     socket_path: &str,
     approval: ColdcardApproval,
-) -> Result<(), String> {
-    use tokio::net::UnixDatagram;
+    command: F,
+) -> Result<F::Output, String> {
+    use bhwi_async::{
+        emulator::{ColdcardEmulator, approve_during},
+        transport::coldcard::hid::ColdcardTransportHID,
+    };
 
-    let client_path = format!(
-        "/tmp/bhwi-hwi-approval-{}-{}.sock",
-        std::process::id(),
-        generate_hwi_socket_id()
-    );
-    let _ = fs::remove_file(&client_path);
-    let socket = UnixDatagram::bind(&client_path).map_err(|err| err.to_string())?;
-    socket.connect(socket_path).map_err(|err| err.to_string())?;
-    send_coldcard_simulator_keypress(&socket, b'y').await?;
-    if matches!(approval, ColdcardApproval::Backup) {
-        for _ in 0..20 {
-            tokio::time::sleep(std::time::Duration::from_millis(250)).await;
-            send_coldcard_simulator_keypress(&socket, b'1').await?;
-        }
-    }
-    drop(socket);
-    let _ = fs::remove_file(client_path);
-    Ok(())
-}
+    use crate::coldcard::emulator::EmulatorClient;
 
-#[cfg(unix)]
-async fn send_coldcard_simulator_keypress(
-    socket: &tokio::net::UnixDatagram,
-    key: u8,
-) -> Result<(), String> {
-    let mut packet = [0u8; 64];
-    packet[0] = 0x80 | 5;
-    packet[1..5].copy_from_slice(b"XKEY");
-    packet[5] = key;
-    socket.send(&packet).await.map_err(|err| err.to_string())?;
-    Ok(())
+    let client = EmulatorClient::new(socket_path)
+        .await
+        .map_err(|err| err.to_string())?;
+    let controller = ColdcardEmulator::new(ColdcardTransportHID::new(client));
+    let mut controller = match approval {
+        ColdcardApproval::Once => controller,
+        ColdcardApproval::Backup => controller.then_repeat(b'1'),
+    };
+    approve_during(
+        &mut controller,
+        || tokio::time::sleep(std::time::Duration::from_millis(250)),
+        command,
+    )
+    .await
+    .map_err(|err| err.to_string())
 }
 
 #[cfg(not(unix))]
-async fn coldcard_emulator_keypresses(
+async fn coldcard_emulator_approve_during<F: Future>(
     _socket_path: &str,
     _approval: ColdcardApproval,
-) -> Result<(), String> {
-    Ok(())
-}
-
-fn generate_hwi_socket_id() -> usize {
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    static SOCKET_COUNTER: AtomicUsize = AtomicUsize::new(0);
-    SOCKET_COUNTER.fetch_add(1, Ordering::Relaxed)
+    command: F,
+) -> Result<F::Output, String> {
+    Ok(command.await)
 }
 
 fn python_hwi_message_header(device_type: DeviceType, header: u8) -> u8 {
@@ -2158,6 +2164,8 @@ fn hwi_descriptor_addr_types(device_type: DeviceType, model: &str) -> Vec<HwiAdd
     types
 }
 
+/// Python HWI's `can_sign_taproot`, kept for output parity: `HWI::capabilities` reports
+/// taproot signing for more devices than Python HWI does.
 fn hwi_can_sign_taproot(device_type: DeviceType, model: &str) -> bool {
     match device_type {
         DeviceType::BitBox02 => false,
@@ -2505,6 +2513,7 @@ fn request_from_cli(args: HwiCli) -> HwiResult<HwiRequest> {
             device_type,
             device_path: args.device_path,
             include_emulators,
+            timeout: None,
         },
         command,
     })
@@ -1,9 +1,10 @@
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use async_trait::async_trait;
+use bhwi::jade::{OtaProgress, OtaUpdate};
 use bhwi_async::{
-    HttpClient, Jade, Transport,
+    Error, HWI, HttpClient, Jade, Transport,
     transport::jade::{CborStream, JADE_DEVICE_IDS, tcp::TcpTransport},
 };
 use bitcoin::Network;
@@ -122,6 +123,108 @@ impl JadeDevice {
     }
 }
 
+impl JadeDevice {
+    /// Upload `update` to the first Jade matching `selector`, unlocking it first if it has
+    /// been set up, as Jade only accepts OTA on a new or unlocked device. Returns `false`
+    /// when no Jade is connected.
+    pub async fn update_firmware(
+        selector: &DeviceSelector,
+        update: OtaUpdate,
+        progress: impl FnMut(OtaProgress),
+    ) -> Result<bool> {
+        for info in available_ports()? {
+            if let SerialPortType::UsbPort(usb) = &info.port_type
+                && selector.matches(DeviceType::Jade, &info.port_name)
+                && !is_macos_dialin(&info.port_name)
+                && Self::valid_usb(usb)
+            {
+                let mut jade = JadeSerialDevice::new(
+                    selector.network,
+                    SerialTransport::new(&info.port_name)?,
+                    PinServerClient::new(),
+                );
+                ota_update(&mut jade, update, progress).await?;
+                return Ok(true);
+            }
+        }
+        if selector.include_emulators
+            && (selector.matches(DeviceType::Jade, DEFAULT_JADE_QEMU_ADDRESS)
+                || selector.matches(DeviceType::Jade, jade_tcp_addr(DEFAULT_JADE_QEMU_ADDRESS)))
+            && let Ok(stream) = TcpStream::connect(jade_tcp_addr(DEFAULT_JADE_QEMU_ADDRESS)).await
+        {
+            let mut jade = JadeQemuDevice::new(
+                selector.network,
+                TcpTransport::new(TcpClient::new(stream)),
+                PinServerClient::new(),
+            );
+            ota_update(&mut jade, update, progress).await?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+}
+
+async fn ota_update<T, S>(
+    jade: &mut Jade<T, S>,
+    update: OtaUpdate,
+    progress: impl FnMut(OtaProgress),
+) -> Result<()>
+where
+    T: Transport,
+    S: HttpClient,
+    Error<T::Error, S::Error>: std::error::Error + Send + Sync + 'static,
+{
+    if jade.get_info().await?.initialized != Some(false) {
+        jade.unlock(jade.network).await?;
+    }
+    jade.update_firmware(update, progress).await?;
+    Ok(())
+}
+
+/// Read a compressed Jade firmware image as published by Blockstream. The uncompressed
+/// size defaults to the one in the `<version>_<config>_<size>_fw.bin` file name and the
+/// expected hash to the content of a sibling `<file>.hash`, if present.
+pub fn firmware_update_from_file(
+    file: &Path,
+    firmware_size: Option<u32>,
+    firmware_hash: Option<&str>,
+) -> Result<OtaUpdate> {
+    let image = std::fs::read(file).with_context(|| format!("reading {}", file.display()))?;
+    let firmware_size = match firmware_size {
+        Some(size) => size,
+        None => firmware_size_from_file_name(file).context(
+            "--firmware-size is required when the file name does not carry the firmware size",
+        )?,
+    };
+    let hash_file = file.with_file_name(format!(
+        "{}.hash",
+        file.file_name().unwrap_or_default().to_string_lossy()
+    ));
+    let firmware_hash = match firmware_hash {
+        Some(hash) => Some(parse_firmware_hash(hash)?),
+        None if hash_file.exists() => Some(parse_firmware_hash(
+            std::fs::read_to_string(&hash_file)?.trim(),
+        )?),
+        None => None,
+    };
+    Ok(OtaUpdate {
+        image,
+        firmware_size,
+        firmware_hash,
+    })
+}
+
+fn firmware_size_from_file_name(file: &Path) -> Option<u32> {
+    let name = file.file_name()?.to_str()?.strip_suffix("_fw.bin")?;
+    name.rsplit('_').next()?.parse().ok()
+}
+
+fn parse_firmware_hash(hash: &str) -> Result<[u8; 32]> {
+    hex::decode(hash)?
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("firmware hash must be 32 bytes / 64 hex characters"))
+}
+
 // serialport-rs lists each macOS serial port under both its callout
 // (`/dev/cu.*`) and dial-in (`/dev/tty.*`) node & skip the dial-in so the same
 // Jade is not opened twice.
@@ -213,3 +316,32 @@ impl CborStream for TcpClient {
         Ok(self.stream.read(buf).await?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn firmware_size_is_read_from_published_file_name() {
+        assert_eq!(
+            firmware_size_from_file_name(Path::new("/tmp/1.0.31_ble_1153024_fw.bin")),
+            Some(1_153_024)
+        );
+        assert_eq!(
+            firmware_size_from_file_name(Path::new("1.0.31_noradio_987136_fw.bin")),
+            Some(987_136)
+        );
+        assert_eq!(firmware_size_from_file_name(Path::new("jade.bin")), None);
+        assert_eq!(
+            firmware_size_from_file_name(Path::new("1.0.31_ble_latest_fw.bin")),
+            None
+        );
+    }
+
+    #[test]
+    fn firmware_hash_must_be_32_bytes() {
+        assert_eq!(parse_firmware_hash(&"ab".repeat(32)).unwrap(), [0xab; 32]);
+        assert!(parse_firmware_hash("abcd").is_err());
+        assert!(parse_firmware_hash("zz").is_err());
+    }
+}
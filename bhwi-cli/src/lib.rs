@@ -1,6 +1,9 @@
 use anyhow::Result;
 use async_trait::async_trait;
-use bhwi_async::HWIDevice;
+use bhwi_async::{
+    Attestation, Event, HWIDevice,
+    registry::{FileRegistrationStore, RegistryDevice, WalletContext},
+};
 use bitcoin::{Network, bip32::Fingerprint};
 use clap::ValueEnum;
 use futures::future::join_all;
@@ -37,6 +40,11 @@ pub struct Device {
     fingerprint: Option<Fingerprint>,
     #[serde(flatten, skip_serializing_if = "Option::is_none")]
     info: Option<Info>,
+    #[serde(
+        serialize_with = "option_attestation",
+        skip_serializing_if = "Option::is_none"
+    )]
+    attestation: Option<Attestation>,
 }
 
 /// Serializable Device Information
@@ -79,6 +87,16 @@ impl Device {
         device: Box<dyn HWIDevice>,
         is_emulated: bool,
     ) -> Result<Self> {
+        // Wallet registrations are remembered under the XDG config dir, so signing and
+        // descriptor addresses can omit the registration details.
+        let device: Box<dyn HWIDevice> = match FileRegistrationStore::from_config_dir() {
+            Some(store) => Box::new(RegistryDevice::new(
+                device,
+                device_type.wallet_context(),
+                Box::new(store),
+            )),
+            None => device,
+        };
         Ok(Self {
             name: name.into(),
             device_type,
@@ -88,6 +106,7 @@ impl Device {
             is_emulated,
             fingerprint: None,
             info: None,
+            attestation: None,
         })
     }
 
@@ -134,6 +153,15 @@ impl Device {
             Ok(info)
         }
     }
+
+    /// Whether the device is genuine, `None` for emulators and devices without an
+    /// attestation key.
+    pub async fn attestation(&mut self) -> Result<Option<Attestation>> {
+        if self.attestation.is_none() && !self.is_emulated && self.device_type.has_attestation() {
+            self.attestation = Some(self.device.verify_attestation().await?);
+        }
+        Ok(self.attestation)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Eq, PartialEq, EnumIter, ValueEnum, Serialize, strum::Display)]
@@ -148,6 +176,19 @@ pub enum DeviceType {
 }
 
 impl DeviceType {
+    pub fn wallet_context(self) -> WalletContext {
+        match self {
+            DeviceType::BitBox02 => WalletContext::BitBox,
+            DeviceType::Ledger => WalletContext::Ledger,
+            DeviceType::Coldcard | DeviceType::Jade => WalletContext::ByName,
+        }
+    }
+
+    /// Whether the device holds a manufacturer attestation key.
+    pub fn has_attestation(self) -> bool {
+        matches!(self, DeviceType::BitBox02 | DeviceType::Jade)
+    }
+
     pub async fn enumerate(self, selector: &DeviceSelector) -> Result<Vec<Device>> {
         Ok(match self {
             DeviceType::BitBox02 => BitBoxDevice::enumerate(selector).await?,
@@ -211,7 +252,30 @@ impl DeviceManager {
         .await
         .into_iter()
         .collect::<Result<Vec<_>>>()?;
-        Ok(res.into_iter().flatten().collect())
+        let mut devices: Vec<Device> = res.into_iter().flatten().collect();
+        for device in &mut devices {
+            let control = self.selector.command_control(device.is_emulated);
+            device.device.set_control(control);
+        }
+        Ok(devices)
+    }
+}
+
+/// Print an event of a running command to stderr, for the user in front of the device.
+pub fn print_event(event: &Event) {
+    match event {
+        Event::Progress { done, total } => {
+            let percent = (done * 100).checked_div(*total).unwrap_or(100);
+            eprint!("\r{percent:>3}% ({done}/{total})");
+            if done >= total {
+                eprintln!();
+            }
+        }
+        Event::AwaitingUserConfirmation => eprintln!("Confirm on the device..."),
+        Event::PairingCodeAvailable(code) => {
+            eprintln!("\nBitBox02 pairing code — confirm on device:\n\n{code}\n")
+        }
+        Event::PinServerRoundTrip => eprintln!("Contacting the Jade pin server..."),
     }
 }
 
@@ -236,3 +300,15 @@ where
         ser.serialize_none()
     }
 }
+
+fn option_attestation<S>(value: &Option<Attestation>, ser: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match value {
+        Some(Attestation::Genuine) => ser.serialize_str("genuine"),
+        Some(Attestation::Counterfeit) => ser.serialize_str("counterfeit"),
+        Some(Attestation::UnknownRoot) => ser.serialize_str("unknown_root"),
+        None => ser.serialize_none(),
+    }
+}
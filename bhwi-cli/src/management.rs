@@ -1,7 +1,9 @@
+use std::io::{self, BufRead, IsTerminal};
+
 use anyhow::{Context, Result};
 use bhwi::{
     bitbox::{ManagementContext, SetupEntropy, SetupMode},
-    common::DeviceContext,
+    common::{DeviceContext, Zeroizing},
 };
 use chrono::Local;
 use rand_core::{OsRng, RngCore};
@@ -34,7 +36,31 @@ pub fn bitbox_restore_context() -> Result<DeviceContext> {
     ))
 }
 
-fn timestamp_and_timezone_offset() -> Result<(u32, i32)> {
+/// Read a BIP39 passphrase from the first line of stdin, prompting without echo when stdin
+/// is a terminal. Passphrases are never taken from the command line, where they would end
+/// up in the shell history and the process list.
+pub fn read_passphrase() -> Result<Zeroizing<String>> {
+    let stdin = io::stdin();
+    if stdin.is_terminal() {
+        return rpassword::prompt_password("Passphrase: ")
+            .map(Zeroizing::new)
+            .context("failed to read the passphrase from the terminal");
+    }
+    read_passphrase_from(stdin.lock())
+}
+
+fn read_passphrase_from(mut reader: impl BufRead) -> Result<Zeroizing<String>> {
+    let mut line = Zeroizing::new(String::new());
+    reader
+        .read_line(&mut line)
+        .context("failed to read the passphrase from stdin")?;
+    let len = line.trim_end_matches(['\r', '\n']).len();
+    line.truncate(len);
+    Ok(line)
+}
+
+/// Current Unix time and UTC offset, in seconds, as BitBox02 management requests take them.
+pub fn timestamp_and_timezone_offset() -> Result<(u32, i32)> {
     let now = Local::now();
     timestamp_and_timezone_offset_from(now.timestamp(), now.offset().local_minus_utc())
 }
@@ -71,6 +97,13 @@ mod tests {
         );
     }
 
+    #[test]
+    fn passphrase_is_the_first_line_without_its_line_ending() {
+        let passphrase = read_passphrase_from(&b"correct horse \r\nbattery"[..]).unwrap();
+        assert_eq!(passphrase.as_str(), "correct horse ");
+        assert!(read_passphrase_from(&b""[..]).unwrap().is_empty());
+    }
+
     #[test]
     fn restore_context_contains_host_time() {
         assert!(matches!(
@@ -0,0 +1,516 @@
+//! UniFFI bindings over the common interpreters.
+//!
+//! The host language performs all I/O: it calls [`Device::start`] with a [`Command`],
+//! delivers each returned [`Transmit`] to its [`Recipient`] (the device over HID, serial or
+//! BLE, or the Jade PIN server over HTTP), feeds the reply back through
+//! [`Device::exchange`] until it returns `None`, then collects the [`Response`] with
+//! [`Device::end`].
+//!
+//! Generate Kotlin or Swift bindings with
+//! `cargo run -p bhwi-ffi --features bindgen --bin uniffi-bindgen -- generate --library <lib> --language kotlin`.
+
+mod types;
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        Arc, Mutex,
+        mpsc::{self, Receiver, Sender},
+    },
+};
+
+use bhwi::{
+    Interpreter,
+    bitbox::noise::{NoiseConfigData, NoiseState},
+    coldcard::encrypt,
+    common,
+    ledger::AppProtocol,
+    psbt::VersionedPsbt,
+};
+use rand_core::OsRng;
+
+pub use types::{Attestation, Command, Event, Network, Recipient, Response, Transmit, Wallet};
+
+uniffi::setup_scaffolding!();
+
+#[derive(Debug, thiserror::Error, uniffi::Error)]
+pub enum Error {
+    #[error("invalid input: {reason}")]
+    InvalidInput { reason: String },
+
+    /// `kind` is one of the snake_case `bhwi::common::ErrorKind` names, such as
+    /// `user_cancelled` or `locked`.
+    #[error("interpreter error: {reason}")]
+    Interpreter { kind: String, reason: String },
+
+    #[error("a command is already in progress")]
+    CommandInProgress,
+
+    #[error("no command in progress")]
+    NoCommandInProgress,
+
+    #[error("device worker stopped")]
+    WorkerStopped,
+}
+
+impl From<common::Error> for Error {
+    fn from(error: common::Error) -> Self {
+        Error::Interpreter {
+            kind: error.kind().to_string(),
+            reason: error.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DeviceKind {
+    BitBox,
+    Coldcard,
+    Jade,
+    Ledger,
+}
+
+/// Noise pairing data of a BitBox02 host, to persist between sessions so an already paired
+/// device skips the pairing-code confirmation.
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct BitBoxPairing {
+    pub app_static_privkey: Option<Vec<u8>>,
+    pub device_static_pubkeys: Vec<Vec<u8>>,
+}
+
+impl From<&NoiseConfigData> for BitBoxPairing {
+    fn from(data: &NoiseConfigData) -> Self {
+        Self {
+            app_static_privkey: data.app_static_privkey.map(|key| key.to_vec()),
+            device_static_pubkeys: data.device_static_pubkeys.clone(),
+        }
+    }
+}
+
+impl TryFrom<BitBoxPairing> for NoiseConfigData {
+    type Error = Error;
+    fn try_from(pairing: BitBoxPairing) -> Result<Self, Self::Error> {
+        let app_static_privkey = pairing
+            .app_static_privkey
+            .map(|key| {
+                <[u8; 32]>::try_from(key).map_err(|_| Error::InvalidInput {
+                    reason: "app static private key must be 32 bytes".to_string(),
+                })
+            })
+            .transpose()?;
+        Ok(NoiseConfigData {
+            app_static_privkey,
+            device_static_pubkeys: pairing.device_static_pubkeys,
+        })
+    }
+}
+
+/// State the host can read while the worker owns the device, e.g. the pairing code to show
+/// while an unlock is still waiting on the device.
+#[derive(Default)]
+struct Shared {
+    pairing_code: Option<String>,
+    pairing: Option<BitBoxPairing>,
+    events: VecDeque<Event>,
+}
+
+enum Request {
+    Start(common::Command),
+    Exchange(Vec<u8>),
+    End,
+}
+
+enum Reply {
+    Transmit(Option<common::Transmit>),
+    Done(Result<common::Response, common::Error>),
+}
+
+struct Channels {
+    requests: Sender<Request>,
+    replies: Receiver<Reply>,
+    in_progress: bool,
+    /// PSBT being signed, to return the signed one in the version the host sent.
+    psbt: Option<VersionedPsbt>,
+}
+
+/// A hardware wallet driven by the host. Coldcard and BitBox02 interpreters borrow
+/// per-connection encryption state, so every device runs its interpreters on a dedicated
+/// worker thread that owns that state for the lifetime of this object.
+#[derive(uniffi::Object)]
+pub struct Device {
+    kind: DeviceKind,
+    channels: Mutex<Channels>,
+    shared: Arc<Mutex<Shared>>,
+}
+
+#[uniffi::export]
+impl Device {
+    #[uniffi::constructor]
+    pub fn ledger() -> Arc<Self> {
+        Self::spawn(DeviceKind::Ledger, |requests, replies, shared| {
+            let mut protocol = AppProtocol::default();
+            while let Some(command) = next_command(&requests) {
+                let Some(result) = drive(
+                    common::LedgerInterpreter::default().with_protocol(protocol),
+                    command,
+                    &requests,
+                    &replies,
+                    &shared,
+                ) else {
+                    return;
+                };
+                // Unlock and GetVersion report the open app, which selects its protocol.
+                if let Ok(common::Response::Info(common::Info {
+                    version,
+                    firmware: Some(app_name),
+                    ..
+                })) = &result
+                {
+                    protocol = AppProtocol::from_app(app_name, version);
+                }
+                if replies.send(Reply::Done(result)).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    #[uniffi::constructor]
+    pub fn jade(network: Network) -> Arc<Self> {
+        Self::spawn(DeviceKind::Jade, move |requests, replies, shared| {
+            while let Some(command) = next_command(&requests) {
+                let Some(result) = drive(
+                    common::JadeInterpreter::default().with_network(network.into()),
+                    command,
+                    &requests,
+                    &replies,
+                    &shared,
+                ) else {
+                    return;
+                };
+                if replies.send(Reply::Done(result)).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    #[uniffi::constructor]
+    pub fn coldcard() -> Arc<Self> {
+        Self::spawn(DeviceKind::Coldcard, |requests, replies, shared| {
+            let mut encryption = encrypt::Engine::new(&mut OsRng);
+            while let Some(command) = next_command(&requests) {
+                let Some(result) = drive(
+                    common::ColdcardInterpreter::new(&mut encryption),
+                    command,
+                    &requests,
+                    &replies,
+                    &shared,
+                ) else {
+                    return;
+                };
+                // Unlocking yields the device's session key, which encrypts every later command.
+                let result = match result {
+                    Ok(common::Response::EncryptionKey(key)) => encryption
+                        .ready(key)
+                        .map(|_| common::Response::TaskDone)
+                        .map_err(common::Error::from),
+                    result => result,
+                };
+                if replies.send(Reply::Done(result)).is_err() {
+                    return;
+                }
+            }
+        })
+    }
+
+    /// `pairing` is the data returned by [`Device::bitbox_pairing`] in a previous session, if
+    /// any.
+    #[uniffi::constructor]
+    pub fn bitbox(network: Network, pairing: Option<BitBoxPairing>) -> Result<Arc<Self>, Error> {
+        let pairing_data = pairing.map(NoiseConfigData::try_from).transpose()?;
+        Ok(Self::spawn(
+            DeviceKind::BitBox,
+            move |requests, replies, shared| {
+                let mut noise = NoiseState::new(pairing_data);
+                while let Some(command) = next_command(&requests) {
+                    let Some(result) = drive(
+                        common::BitBoxInterpreter::new(&mut noise).with_network(network.into()),
+                        command,
+                        &requests,
+                        &replies,
+                        &shared,
+                    ) else {
+                        return;
+                    };
+                    {
+                        let mut shared = shared.lock().expect("shared state");
+                        shared.pairing_code = noise.pairing_code().map(str::to_string);
+                        shared.pairing = Some(BitBoxPairing::from(noise.data()));
+                    }
+                    if replies.send(Reply::Done(result)).is_err() {
+                        return;
+                    }
+                }
+            },
+        ))
+    }
+
+    /// Begin `command`. The returned transmit must be delivered before calling
+    /// [`Device::exchange`].
+    pub fn start(&self, command: Command) -> Result<Transmit, Error> {
+        let psbt = command.psbt()?;
+        let command = command.into_common(self.kind)?;
+        let mut channels = self.channels.lock().expect("device channels");
+        if channels.in_progress {
+            return Err(Error::CommandInProgress);
+        }
+        // Events left over from the previous command are stale.
+        self.shared.lock().expect("shared state").events.clear();
+        channels
+            .requests
+            .send(Request::Start(command))
+            .map_err(|_| Error::WorkerStopped)?;
+        channels.in_progress = true;
+        channels.psbt = psbt;
+        match channels.reply()? {
+            Reply::Transmit(Some(transmit)) => Ok(transmit.into()),
+            Reply::Transmit(None) => Err(Error::WorkerStopped),
+            Reply::Done(result) => {
+                channels.in_progress = false;
+                Err(result
+                    .err()
+                    .map(Error::from)
+                    .unwrap_or(Error::WorkerStopped))
+            }
+        }
+    }
+
+    /// Feed the reply to the last transmit. `None` means the command is complete and its
+    /// response can be collected with [`Device::end`].
+    pub fn exchange(&self, data: Vec<u8>) -> Result<Option<Transmit>, Error> {
+        let mut channels = self.channels.lock().expect("device channels");
+        if !channels.in_progress {
+            return Err(Error::NoCommandInProgress);
+        }
+        channels
+            .requests
+            .send(Request::Exchange(data))
+            .map_err(|_| Error::WorkerStopped)?;
+        match channels.reply()? {
+            Reply::Transmit(transmit) => Ok(transmit.map(Transmit::from)),
+            Reply::Done(result) => {
+                channels.in_progress = false;
+                Err(result
+                    .err()
+                    .map(Error::from)
+                    .unwrap_or(Error::WorkerStopped))
+            }
+        }
+    }
+
+    /// Finish the command in progress. Also aborts a command whose exchange is not complete.
+    pub fn end(&self) -> Result<Response, Error> {
+        let mut channels = self.channels.lock().expect("device channels");
+        if !channels.in_progress {
+            return Err(Error::NoCommandInProgress);
+        }
+        channels
+            .requests
+            .send(Request::End)
+            .map_err(|_| Error::WorkerStopped)?;
+        channels.in_progress = false;
+        let psbt = channels.psbt.take();
+        match channels.reply()? {
+            Reply::Done(result) => Ok(match (result?, psbt) {
+                (common::Response::SignedPsbt(signed), Some(psbt)) => Response::SignedPsbt {
+                    psbt: psbt.with_psbt(signed)?.serialize(),
+                },
+                (response, _) => response.into(),
+            }),
+            Reply::Transmit(_) => Err(Error::WorkerStopped),
+        }
+    }
+
+    /// Next event raised by the running command, such as a prompt on the device. Poll it
+    /// after every `start` and `exchange` to keep the user informed.
+    pub fn poll_event(&self) -> Option<Event> {
+        self.shared.lock().expect("shared state").events.pop_front()
+    }
+
+    /// BitBox02 pairing code to show the user while an unlock waits for confirmation on the
+    /// device. `None` for other devices and once the device is paired.
+    pub fn bitbox_pairing_code(&self) -> Option<String> {
+        self.shared
+            .lock()
+            .expect("shared state")
+            .pairing_code
+            .clone()
+    }
+
+    /// BitBox02 pairing data to persist after a successful unlock.
+    pub fn bitbox_pairing(&self) -> Option<BitBoxPairing> {
+        self.shared.lock().expect("shared state").pairing.clone()
+    }
+}
+
+impl Device {
+    fn spawn<F>(kind: DeviceKind, worker: F) -> Arc<Self>
+    where
+        F: FnOnce(Receiver<Request>, Sender<Reply>, Arc<Mutex<Shared>>) + Send + 'static,
+    {
+        let (requests, worker_requests) = mpsc::channel();
+        let (worker_replies, replies) = mpsc::channel();
+        let shared = Arc::new(Mutex::new(Shared::default()));
+        let worker_shared = shared.clone();
+        // The worker exits once this object, and with it the request sender, is dropped.
+        std::thread::spawn(move || worker(worker_requests, worker_replies, worker_shared));
+        Arc::new(Self {
+            kind,
+            channels: Mutex::new(Channels {
+                requests,
+                replies,
+                in_progress: false,
+                psbt: None,
+            }),
+            shared,
+        })
+    }
+}
+
+impl Channels {
+    fn reply(&self) -> Result<Reply, Error> {
+        self.replies.recv().map_err(|_| Error::WorkerStopped)
+    }
+}
+
+/// Wait for the host to start a command, skipping stray requests from an abandoned one.
+fn next_command(requests: &Receiver<Request>) -> Option<common::Command> {
+    loop {
+        match requests.recv().ok()? {
+            Request::Start(command) => return Some(command),
+            Request::Exchange(_) | Request::End => continue,
+        }
+    }
+}
+
+/// Pump one command through `interpreter`, replying to each host request. Returns the final
+/// result to send back, or `None` if the host side is gone.
+fn drive<I>(
+    mut interpreter: I,
+    command: common::Command,
+    requests: &Receiver<Request>,
+    replies: &Sender<Reply>,
+    shared: &Mutex<Shared>,
+) -> Option<Result<common::Response, common::Error>>
+where
+    I: Interpreter<
+            Command = common::Command,
+            Transmit = common::Transmit,
+            Response = common::Response,
+            Error = common::Error,
+        >,
+{
+    // Events are queued before the reply, so the host sees them once its call returns.
+    let publish = |interpreter: &mut I| {
+        let mut shared = shared.lock().expect("shared state");
+        while let Some(event) = interpreter.poll_event() {
+            if let common::Event::PairingCodeAvailable(code) = &event {
+                shared.pairing_code = Some(code.clone());
+            }
+            shared.events.push_back(event.into());
+        }
+    };
+    let started = interpreter.start(command);
+    publish(&mut interpreter);
+    match started {
+        Ok(transmit) => replies.send(Reply::Transmit(Some(transmit))).ok()?,
+        Err(e) => return Some(Err(e)),
+    }
+    loop {
+        match requests.recv().ok()? {
+            Request::Exchange(data) => {
+                let exchanged = interpreter.exchange(data);
+                publish(&mut interpreter);
+                match exchanged {
+                    Ok(transmit) => replies.send(Reply::Transmit(transmit)).ok()?,
+                    Err(e) => return Some(Err(e)),
+                }
+            }
+            Request::End => return Some(interpreter.end()),
+            // `Device` never starts a command while another one is in progress.
+            Request::Start(_) => return Some(Err(common::Error::Request("command in progress"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ledger_master_fingerprint_roundtrip() {
+        let device = Device::ledger();
+        let transmit = device.start(Command::GetMasterFingerprint).unwrap();
+        assert_eq!(transmit.recipient, Recipient::Device);
+        assert!(!transmit.encrypted);
+
+        assert!(matches!(
+            device.start(Command::GetVersion),
+            Err(Error::CommandInProgress)
+        ));
+
+        let next = device
+            .exchange(vec![0xf5, 0xac, 0xc2, 0xfd, 0x90, 0x00])
+            .unwrap();
+        assert_eq!(next, None);
+        assert_eq!(
+            device.end().unwrap(),
+            Response::MasterFingerprint {
+                fingerprint: "f5acc2fd".to_string()
+            }
+        );
+        assert!(matches!(device.end(), Err(Error::NoCommandInProgress)));
+    }
+
+    #[test]
+    fn interpreter_error_ends_the_command() {
+        let device = Device::ledger();
+        device.start(Command::GetMasterFingerprint).unwrap();
+        // Denied by the user (status word 0x6985), so no fingerprint in the reply.
+        assert!(matches!(
+            device.exchange(vec![0x69, 0x85]),
+            Err(Error::Interpreter { .. })
+        ));
+        assert!(device.start(Command::GetMasterFingerprint).is_ok());
+    }
+
+    #[test]
+    fn events_are_polled_between_exchanges() {
+        let device = Device::ledger();
+        device
+            .start(Command::GetXpub {
+                path: "m/84'/0'/0'".to_string(),
+                display: true,
+            })
+            .unwrap();
+        assert_eq!(device.poll_event(), Some(Event::AwaitingUserConfirmation));
+        assert_eq!(device.poll_event(), None);
+    }
+
+    #[test]
+    fn bitbox_pairing_roundtrip() {
+        let pairing = BitBoxPairing {
+            app_static_privkey: Some(vec![7; 32]),
+            device_static_pubkeys: vec![vec![1; 32]],
+        };
+        let data = NoiseConfigData::try_from(pairing.clone()).unwrap();
+        assert_eq!(BitBoxPairing::from(&data), pairing);
+
+        let invalid = BitBoxPairing {
+            app_static_privkey: Some(vec![7; 31]),
+            device_static_pubkeys: vec![],
+        };
+        assert!(Device::bitbox(Network::Bitcoin, Some(invalid)).is_err());
+    }
+}
@@ -0,0 +1,510 @@
+//! FFI mirrors of the `common` command/response model.
+//!
+//! Rich rust types cross the boundary in their standard encodings: derivation paths, xpubs
+//! and descriptors as strings, PSBTs in their binary serialization, version 0 or 2, and
+//! signatures in compact form.
+
+use std::str::FromStr;
+
+use bhwi::{
+    bitcoin::{self, bip32::DerivationPath},
+    common,
+    ledger::{LedgerWalletPolicy, Version},
+    miniscript::descriptor::WalletPolicy,
+    psbt::VersionedPsbt,
+};
+
+use crate::{DeviceKind, Error};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Network {
+    Bitcoin,
+    Testnet,
+    Signet,
+    Regtest,
+}
+
+impl From<Network> for bitcoin::Network {
+    fn from(network: Network) -> Self {
+        match network {
+            Network::Bitcoin => bitcoin::Network::Bitcoin,
+            Network::Testnet => bitcoin::Network::Testnet,
+            Network::Signet => bitcoin::Network::Signet,
+            Network::Regtest => bitcoin::Network::Regtest,
+        }
+    }
+}
+
+impl Network {
+    fn from_bitcoin(network: bitcoin::Network) -> Option<Self> {
+        match network {
+            bitcoin::Network::Bitcoin => Some(Network::Bitcoin),
+            bitcoin::Network::Testnet => Some(Network::Testnet),
+            bitcoin::Network::Signet => Some(Network::Signet),
+            bitcoin::Network::Regtest => Some(Network::Regtest),
+            _ => None,
+        }
+    }
+}
+
+/// A registered wallet policy. `hmac` is the Ledger registration proof, ignored by other
+/// devices.
+#[derive(Clone, Debug, uniffi::Record)]
+pub struct Wallet {
+    pub name: String,
+    pub descriptor: String,
+    pub hmac: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Debug, uniffi::Enum)]
+pub enum Command {
+    Unlock {
+        network: Network,
+    },
+    GetVersion,
+    GetMasterFingerprint,
+    GetXpub {
+        path: String,
+        display: bool,
+    },
+    DisplayAddressByPath {
+        path: String,
+        display: bool,
+    },
+    DisplayAddressByDescriptor {
+        wallet: Wallet,
+        index: u32,
+        change: bool,
+        display: bool,
+    },
+    RegisterWallet {
+        name: String,
+        descriptor: String,
+    },
+    SignTx {
+        psbt: Vec<u8>,
+        wallet: Option<Wallet>,
+    },
+    SignMessage {
+        message: Vec<u8>,
+        path: String,
+    },
+    /// Sign in the BIP-322 simple format with the `address_type` (`p2wpkh` or `p2tr`)
+    /// address of the key at `path`. Answered with a BIP-322 signature.
+    SignMessageBip322 {
+        message: Vec<u8>,
+        path: String,
+        address_type: String,
+    },
+    Backup,
+    Wipe,
+    TogglePassphrase,
+    /// BIP39 passphrase for the session, `None` to clear it. Answered with the new master
+    /// fingerprint.
+    SetPassphrase {
+        passphrase: Option<String>,
+    },
+    /// Check that the device is genuine. Answered with an attestation.
+    VerifyAttestation,
+}
+
+impl Command {
+    /// The PSBT of a `SignTx`, whose version the signed PSBT is returned in.
+    pub(crate) fn psbt(&self) -> Result<Option<VersionedPsbt>, Error> {
+        match self {
+            Command::SignTx { psbt, .. } => parse_psbt(psbt).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    pub(crate) fn into_common(self, kind: DeviceKind) -> Result<common::Command, Error> {
+        Ok(match self {
+            Command::Unlock { network } => common::Command::Unlock {
+                options: common::UnlockOptions {
+                    network: Some(network.into()),
+                },
+            },
+            Command::GetVersion => common::Command::GetVersion,
+            Command::GetMasterFingerprint => common::Command::GetMasterFingerprint,
+            Command::GetXpub { path, display } => common::Command::GetXpub {
+                path: parse_path(&path)?,
+                display,
+            },
+            Command::DisplayAddressByPath { path, display } => common::Command::DisplayAddress(
+                common::DisplayAddress::ByPath {
+                    path: parse_path(&path)?,
+                    display,
+                    address_format: None,
+                },
+                None,
+            ),
+            Command::DisplayAddressByDescriptor {
+                wallet,
+                index,
+                change,
+                display,
+            } => common::Command::DisplayAddress(
+                common::DisplayAddress::ByDescriptor {
+                    index,
+                    change,
+                    display,
+                    descriptor_name: wallet.name.clone(),
+                },
+                wallet_context(kind, wallet)?,
+            ),
+            Command::RegisterWallet { name, descriptor } => common::Command::RegisterWallet {
+                name,
+                policy: parse_wallet_policy(&descriptor)?,
+            },
+            Command::SignTx { psbt, wallet } => {
+                let psbt = parse_psbt(&psbt)?.into_psbt();
+                let context = match wallet {
+                    Some(wallet) => wallet_context(kind, wallet)?,
+                    None => None,
+                };
+                common::Command::SignTx(psbt, context)
+            }
+            Command::SignMessage { message, path } => common::Command::SignMessage {
+                message,
+                path: parse_path(&path)?,
+            },
+            Command::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => common::Command::SignMessageBip322 {
+                message,
+                path: parse_path(&path)?,
+                address_type: bitcoin::AddressType::from_str(&address_type).map_err(|e| {
+                    Error::InvalidInput {
+                        reason: format!("invalid address type: {e}"),
+                    }
+                })?,
+            },
+            Command::Backup => common::Command::Backup,
+            Command::Wipe => common::Command::Wipe,
+            Command::TogglePassphrase => common::Command::TogglePassphrase,
+            Command::SetPassphrase { passphrase } => {
+                common::Command::SetPassphrase(passphrase.map(common::Zeroizing::new))
+            }
+            Command::VerifyAttestation => common::Command::VerifyAttestation,
+        })
+    }
+}
+
+fn parse_psbt(psbt: &[u8]) -> Result<VersionedPsbt, Error> {
+    VersionedPsbt::deserialize(psbt).map_err(|e| Error::InvalidInput {
+        reason: format!("invalid psbt: {e}"),
+    })
+}
+
+fn parse_path(path: &str) -> Result<DerivationPath, Error> {
+    DerivationPath::from_str(path).map_err(|e| Error::InvalidInput {
+        reason: format!("invalid derivation path: {e}"),
+    })
+}
+
+fn parse_wallet_policy(descriptor: &str) -> Result<WalletPolicy, Error> {
+    WalletPolicy::from_str(descriptor).map_err(|e| Error::InvalidInput {
+        reason: format!("invalid wallet descriptor: {e}"),
+    })
+}
+
+fn wallet_context(
+    kind: DeviceKind,
+    wallet: Wallet,
+) -> Result<Option<common::DeviceContext>, Error> {
+    let policy = parse_wallet_policy(&wallet.descriptor)?;
+    Ok(match kind {
+        DeviceKind::Ledger => {
+            let wallet_hmac = wallet
+                .hmac
+                .map(|hmac| {
+                    <[u8; 32]>::try_from(hmac).map_err(|_| Error::InvalidInput {
+                        reason: "wallet hmac must be 32 bytes".to_string(),
+                    })
+                })
+                .transpose()?;
+            Some(common::DeviceContext::Ledger {
+                wallet_policy: LedgerWalletPolicy::new(wallet.name, Version::V2, policy),
+                wallet_hmac,
+            })
+        }
+        DeviceKind::BitBox => Some(common::DeviceContext::BitBox { policy }),
+        DeviceKind::Coldcard | DeviceKind::Jade => None,
+    })
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Recipient {
+    Device,
+    PinServer { url: String },
+}
+
+/// Bytes the host must deliver to `recipient`, then feed the reply back through
+/// `Device::exchange`.
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct Transmit {
+    pub recipient: Recipient,
+    pub payload: Vec<u8>,
+    /// Whether the payload is already encrypted for the device (BitBox02 noise channel).
+    pub encrypted: bool,
+}
+
+impl From<common::Transmit> for Transmit {
+    fn from(transmit: common::Transmit) -> Self {
+        Self {
+            recipient: match transmit.recipient {
+                common::Recipient::Device => Recipient::Device,
+                common::Recipient::PinServer { url } => Recipient::PinServer { url },
+            },
+            payload: transmit.payload,
+            encrypted: transmit.encrypted,
+        }
+    }
+}
+
+/// Raised while a command runs, read with `Device::poll_event` between exchanges.
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Event {
+    /// `done` of `total` steps of a long transfer or signing.
+    Progress { done: u64, total: u64 },
+    /// The device waits for the user to confirm or reject the command.
+    AwaitingUserConfirmation,
+    /// BitBox02 pairing code to compare with the one on the device screen.
+    PairingCodeAvailable { code: String },
+    /// The next transmit goes to the Jade pin server.
+    PinServerRoundTrip,
+}
+
+impl From<common::Event> for Event {
+    fn from(event: common::Event) -> Self {
+        match event {
+            common::Event::Progress { done, total } => Event::Progress {
+                done: done as u64,
+                total: total as u64,
+            },
+            common::Event::AwaitingUserConfirmation => Event::AwaitingUserConfirmation,
+            common::Event::PairingCodeAvailable(code) => Event::PairingCodeAvailable { code },
+            common::Event::PinServerRoundTrip => Event::PinServerRoundTrip,
+        }
+    }
+}
+
+/// What the device answering `GetVersion` supports. Commands, script types, sighash types
+/// and backup modes are given by name, e.g. `sign_tx`, `p2wpkh`, `SIGHASH_ALL` and `microsd`.
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Record)]
+pub struct Capabilities {
+    pub commands: Vec<String>,
+    pub script_types: Vec<String>,
+    pub taproot: bool,
+    pub miniscript: bool,
+    pub multisig: bool,
+    pub sighash_types: Vec<String>,
+    pub backups: Vec<String>,
+}
+
+impl From<common::Capabilities> for Capabilities {
+    fn from(capabilities: common::Capabilities) -> Self {
+        fn names<T: ToString>(items: &[T]) -> Vec<String> {
+            items.iter().map(ToString::to_string).collect()
+        }
+        Self {
+            commands: names(&capabilities.commands),
+            script_types: names(&capabilities.script_types),
+            taproot: capabilities.taproot,
+            miniscript: capabilities.miniscript,
+            multisig: capabilities.multisig,
+            sighash_types: names(&capabilities.sighash_types),
+            backups: names(&capabilities.backups),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Response {
+    /// `file` is set when the device hands the backup to the host.
+    Backup {
+        file: Option<Vec<u8>>,
+    },
+    DeviceAction {
+        success: bool,
+    },
+    TaskDone,
+    TaskBusy,
+    Info {
+        version: String,
+        networks: Vec<Network>,
+        firmware: Option<String>,
+        initialized: Option<bool>,
+        capabilities: Option<Capabilities>,
+    },
+    MasterFingerprint {
+        fingerprint: String,
+    },
+    Xpub {
+        xpub: String,
+    },
+    /// BIP-137 recovery header and compact signature.
+    Signature {
+        header: u8,
+        signature: Vec<u8>,
+    },
+    /// Base64 BIP-322 simple signature.
+    Bip322Signature {
+        signature: String,
+    },
+    SignedPsbt {
+        psbt: Vec<u8>,
+    },
+    Address {
+        address: String,
+    },
+    /// `hmac` is only returned by Ledger; `pending` is set while the device still awaits
+    /// user confirmation.
+    WalletRegistration {
+        hmac: Option<Vec<u8>>,
+        pending: bool,
+    },
+    Attestation {
+        attestation: Attestation,
+    },
+}
+
+/// Outcome of a genuineness check, see [`common::Attestation`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, uniffi::Enum)]
+pub enum Attestation {
+    Genuine,
+    Counterfeit,
+    UnknownRoot,
+}
+
+impl From<common::Attestation> for Attestation {
+    fn from(attestation: common::Attestation) -> Self {
+        match attestation {
+            common::Attestation::Genuine => Attestation::Genuine,
+            common::Attestation::Counterfeit => Attestation::Counterfeit,
+            common::Attestation::UnknownRoot => Attestation::UnknownRoot,
+        }
+    }
+}
+
+impl From<common::Response> for Response {
+    fn from(response: common::Response) -> Self {
+        match response {
+            common::Response::Backup(common::DeviceBackup::Complete) => {
+                Response::Backup { file: None }
+            }
+            common::Response::Backup(common::DeviceBackup::File(file)) => {
+                Response::Backup { file: Some(file) }
+            }
+            common::Response::DeviceAction(success) => Response::DeviceAction { success },
+            // The Coldcard session key is consumed by the device worker.
+            common::Response::TaskDone | common::Response::EncryptionKey(_) => Response::TaskDone,
+            common::Response::TaskBusy => Response::TaskBusy,
+            common::Response::Info(info) => Response::Info {
+                capabilities: info.capabilities().map(Capabilities::from),
+                version: info.version,
+                networks: info
+                    .networks
+                    .into_iter()
+                    .filter_map(Network::from_bitcoin)
+                    .collect(),
+                firmware: info.firmware,
+                initialized: info.initialized,
+            },
+            common::Response::MasterFingerprint(fingerprint) => Response::MasterFingerprint {
+                fingerprint: fingerprint.to_string(),
+            },
+            common::Response::Xpub(xpub) => Response::Xpub {
+                xpub: xpub.to_string(),
+            },
+            common::Response::Signature(header, signature) => Response::Signature {
+                header,
+                signature: signature.serialize_compact().to_vec(),
+            },
+            common::Response::Bip322Signature(signature) => Response::Bip322Signature { signature },
+            common::Response::SignedPsbt(psbt) => Response::SignedPsbt {
+                psbt: psbt.serialize(),
+            },
+            common::Response::Address(address) => Response::Address { address },
+            common::Response::WalletRegistration(registration) => match registration {
+                common::WalletRegistration::Complete { hmac } => Response::WalletRegistration {
+                    hmac: hmac.map(|hmac| hmac.to_vec()),
+                    pending: false,
+                },
+                common::WalletRegistration::PendingUserConfirmation => {
+                    Response::WalletRegistration {
+                        hmac: None,
+                        pending: true,
+                    }
+                }
+            },
+            common::Response::Attestation(attestation) => Response::Attestation {
+                attestation: attestation.into(),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTOR: &str = "wpkh([f5acc2fd/48'/1'/0'/2']tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP/<0;1>/*)";
+
+    fn wallet(hmac: Option<Vec<u8>>) -> Wallet {
+        Wallet {
+            name: "wallet".to_string(),
+            descriptor: DESCRIPTOR.to_string(),
+            hmac,
+        }
+    }
+
+    #[test]
+    fn wallet_context_depends_on_device() {
+        assert!(matches!(
+            wallet_context(DeviceKind::Ledger, wallet(Some(vec![0; 32]))).unwrap(),
+            Some(common::DeviceContext::Ledger {
+                wallet_hmac: Some(_),
+                ..
+            })
+        ));
+        assert!(matches!(
+            wallet_context(DeviceKind::BitBox, wallet(None)).unwrap(),
+            Some(common::DeviceContext::BitBox { .. })
+        ));
+        assert!(
+            wallet_context(DeviceKind::Jade, wallet(None))
+                .unwrap()
+                .is_none()
+        );
+        assert!(wallet_context(DeviceKind::Ledger, wallet(Some(vec![0; 31]))).is_err());
+    }
+
+    #[test]
+    fn invalid_command_input_is_rejected() {
+        let command = Command::GetXpub {
+            path: "m/84'/x".to_string(),
+            display: false,
+        };
+        assert!(matches!(
+            command.into_common(DeviceKind::Ledger),
+            Err(Error::InvalidInput { .. })
+        ));
+        let command = Command::SignTx {
+            psbt: vec![0x70, 0x73, 0x62, 0x74],
+            wallet: None,
+        };
+        assert!(command.into_common(DeviceKind::Ledger).is_err());
+        let command = Command::SignMessageBip322 {
+            message: b"Hello World".to_vec(),
+            path: "m/44'/1'/0'/0/0".to_string(),
+            address_type: "p2pk".to_string(),
+        };
+        assert!(matches!(
+            command.into_common(DeviceKind::Ledger),
+            Err(Error::InvalidInput { .. })
+        ));
+    }
+}
@@ -3,7 +3,9 @@ pub mod pinserver;
 pub mod webhid;
 pub mod webserial;
 
+use std::fmt::Debug;
 use std::str::FromStr;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use bhwi::bitbox::{BITBOX02_PID, BITBOX02_VID};
@@ -11,14 +13,17 @@ use bhwi::ledger::{LedgerWalletPolicy, Version};
 use bhwi::miniscript::descriptor::WalletPolicy;
 use bhwi::{coldcard::COLDCARD_DEVICE_ID, ledger::LEDGER_DEVICE_ID};
 use bhwi_async::{
-    DeviceContext, DisplayAddress, HWI as AsyncHWI, Jade, Ledger, WalletRegistration,
-    bitbox::BitBox, coldcard::Coldcard, transport::bitbox::hid::BitBoxTransportHID,
+    Attestation, CancellationToken, Capabilities, CommandControl, DeviceContext, DisplayAddress,
+    Event, HWI as AsyncHWI, Jade, Ledger, VersionedPsbt, WalletRegistration, bitbox::BitBox,
+    coldcard::Coldcard, transport::bitbox::hid::BitBoxTransportHID,
     transport::coldcard::hid::ColdcardTransportHID, transport::ledger::hid::LedgerTransportHID,
 };
+use bitcoin::base64::prelude::{BASE64_STANDARD, Engine as _};
 use bitcoin::{Network, address::AddressType, bip32::DerivationPath};
 use log::Level;
 use pinserver::PinServer;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
 use webhid::WebHidDevice;
 use webserial::WebSerialDevice;
 
@@ -31,6 +36,26 @@ pub fn initialize_logging(level: &str) {
     console_log::init_with_level(log_level).expect("error initializing log");
 }
 
+/// Future resolving after `timeout_ms`, from a `setTimeout` promise.
+pub(crate) fn timeout_future(timeout_ms: i32) -> JsFuture {
+    let promise = js_sys::Promise::new(&mut |resolve, _| {
+        let closure = Closure::wrap(Box::new(move || {
+            resolve.call0(&JsValue::UNDEFINED).unwrap();
+        }) as Box<dyn FnMut()>);
+
+        web_sys::window()
+            .unwrap()
+            .set_timeout_with_callback_and_timeout_and_arguments_0(
+                closure.as_ref().unchecked_ref(),
+                timeout_ms,
+            )
+            .unwrap();
+
+        closure.forget(); // Avoid dropping the closure prematurely
+    });
+    JsFuture::from(promise)
+}
+
 fn wallet_registration_js(registration: WalletRegistration) -> Result<JsValue, JsValue> {
     let result = js_sys::Object::new();
     let (status, hmac) = match registration {
@@ -46,8 +71,76 @@ fn wallet_registration_js(registration: WalletRegistration) -> Result<JsValue, J
     Ok(result.into())
 }
 
+fn event_js(event: &Event) -> Result<JsValue, JsValue> {
+    let result = js_sys::Object::new();
+    let kind = match event {
+        Event::Progress { done, total } => {
+            js_sys::Reflect::set(&result, &"done".into(), &(*done as u32).into())?;
+            js_sys::Reflect::set(&result, &"total".into(), &(*total as u32).into())?;
+            "progress"
+        }
+        Event::AwaitingUserConfirmation => "awaiting_user_confirmation",
+        Event::PairingCodeAvailable(code) => {
+            js_sys::Reflect::set(&result, &"code".into(), &JsValue::from_str(code))?;
+            "pairing_code_available"
+        }
+        Event::PinServerRoundTrip => "pin_server_round_trip",
+    };
+    js_sys::Reflect::set(&result, &"type".into(), &JsValue::from_str(kind))?;
+    Ok(result.into())
+}
+
+fn capabilities_js(capabilities: &Capabilities) -> Result<JsValue, JsValue> {
+    fn strings<T: ToString>(items: &[T]) -> JsValue {
+        items
+            .iter()
+            .map(|item| JsValue::from_str(&item.to_string()))
+            .collect::<js_sys::Array>()
+            .into()
+    }
+    let result = js_sys::Object::new();
+    for (key, value) in [
+        ("commands", strings(&capabilities.commands)),
+        ("script_types", strings(&capabilities.script_types)),
+        ("taproot", capabilities.taproot.into()),
+        ("miniscript", capabilities.miniscript.into()),
+        ("multisig", capabilities.multisig.into()),
+        ("sighash_types", strings(&capabilities.sighash_types)),
+        ("backups", strings(&capabilities.backups)),
+    ] {
+        js_sys::Reflect::set(&result, &key.into(), &value)?;
+    }
+    Ok(result.into())
+}
+
+fn parse_wallet_policy(descriptor: &str) -> Result<WalletPolicy, JsValue> {
+    descriptor
+        .parse()
+        .map_err(|e| JsValue::from_str(&format!("Invalid wallet descriptor: {e}")))
+}
+
+fn parse_hmac(hmac_hex: &str) -> Result<[u8; 32], JsValue> {
+    let hmac_bytes =
+        hex::decode(hmac_hex).map_err(|e| JsValue::from_str(&format!("Invalid hmac hex: {e}")))?;
+    hmac_bytes
+        .try_into()
+        .map_err(|_| JsValue::from_str("hmac must be 32 bytes (64 hex chars)"))
+}
+
+/// BIP-137 signature: the recovery header followed by the compact signature, base64 encoded.
+fn message_signature_base64(
+    header: u8,
+    signature: &bitcoin::secp256k1::ecdsa::Signature,
+) -> String {
+    let mut payload = [0u8; 65];
+    payload[0] = header;
+    payload[1..].copy_from_slice(&signature.serialize_compact());
+    BASE64_STANDARD.encode(payload)
+}
+
 #[async_trait(?Send)]
 pub trait HWI {
+    fn set_control(&mut self, control: CommandControl);
     async fn unlock(&mut self, network: &str) -> Result<(), JsValue>;
     async fn get_mfg(&mut self) -> Result<String, JsValue>;
     async fn get_xpub(&mut self, path: &str, display: bool) -> Result<String, JsValue>;
@@ -62,22 +155,57 @@ pub trait HWI {
         policy: &str,
     ) -> Result<WalletRegistration, JsValue>;
     async fn get_info(&mut self) -> Result<JsValue, JsValue>;
+    async fn capabilities(&mut self) -> Result<JsValue, JsValue>;
+    async fn verify_attestation(&mut self) -> Result<String, JsValue>;
+    async fn sign_tx(
+        &mut self,
+        psbt: &str,
+        context: Option<DeviceContext>,
+    ) -> Result<String, JsValue>;
+    async fn sign_message(&mut self, message: &[u8], path: &str) -> Result<String, JsValue>;
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: &str,
+        address_type: AddressType,
+    ) -> Result<String, JsValue>;
+}
+
+/// A JS `Error` carrying the [`bhwi_async::ErrorKind`] of a device error as its `kind`
+/// property, so that a website can tell a cancellation from a locked device.
+fn device_error<E: Debug, F: Debug>(context: &str, error: bhwi_async::Error<E, F>) -> JsValue {
+    let js_error = js_sys::Error::new(&format!("{context}: {error:?}"));
+    let _ = js_sys::Reflect::set(
+        &js_error,
+        &"kind".into(),
+        &JsValue::from_str(&error.kind().to_string()),
+    );
+    js_error.into()
 }
 
 #[async_trait(?Send)]
-impl<T: AsyncHWI> HWI for T {
+impl<T, E, F> HWI for T
+where
+    T: AsyncHWI<Error = bhwi_async::Error<E, F>>,
+    E: Debug,
+    F: Debug,
+{
+    fn set_control(&mut self, control: CommandControl) {
+        AsyncHWI::set_control(self, control)
+    }
+
     async fn unlock(&mut self, network: &str) -> Result<(), JsValue> {
         let n = Network::from_str(network).map_err(|e| JsValue::from_str(&e.to_string()))?;
         self.unlock(n)
             .await
-            .map_err(|e| JsValue::from_str(&format!("Failed to unlock: {:?}", e)))
+            .map_err(|e| device_error("Failed to unlock", e))
     }
 
     async fn get_mfg(&mut self) -> Result<String, JsValue> {
         self.get_master_fingerprint()
             .await
             .map(|fp| fp.to_string())
-            .map_err(|e| JsValue::from_str(&format!("Failed to get fingerprint: {:?}", e)))
+            .map_err(|e| device_error("Failed to get fingerprint", e))
     }
 
     async fn get_xpub(&mut self, path: &str, display: bool) -> Result<String, JsValue> {
@@ -86,7 +214,7 @@ impl<T: AsyncHWI> HWI for T {
         self.get_extended_pubkey(p, display)
             .await
             .map(|xpub| xpub.to_string())
-            .map_err(|e| JsValue::from_str(&format!("Failed to get fingerprint: {:?}", e)))
+            .map_err(|e| device_error("Failed to get fingerprint", e))
     }
 
     async fn display_address(
@@ -96,7 +224,7 @@ impl<T: AsyncHWI> HWI for T {
     ) -> Result<String, JsValue> {
         AsyncHWI::display_address(self, address, context)
             .await
-            .map_err(|e| JsValue::from_str(&format!("Failed to display address: {:?}", e)))
+            .map_err(|e| device_error("Failed to display address", e))
     }
 
     async fn register_wallet(
@@ -106,13 +234,13 @@ impl<T: AsyncHWI> HWI for T {
     ) -> Result<WalletRegistration, JsValue> {
         AsyncHWI::register_wallet(self, name, policy)
             .await
-            .map_err(|e| JsValue::from_str(&format!("Failed to register wallet: {:?}", e)))
+            .map_err(|e| device_error("Failed to register wallet", e))
     }
 
     async fn get_info(&mut self) -> Result<JsValue, JsValue> {
         let info = AsyncHWI::get_info(self)
             .await
-            .map_err(|e| JsValue::from_str(&format!("Failed to get info: {:?}", e)))?;
+            .map_err(|e| device_error("Failed to get info", e))?;
         let obj = js_sys::Object::new();
         js_sys::Reflect::set(&obj, &"version".into(), &JsValue::from_str(&info.version)).unwrap();
         let networks = js_sys::Array::new();
@@ -134,6 +262,60 @@ impl<T: AsyncHWI> HWI for T {
         .unwrap();
         Ok(obj.into())
     }
+
+    async fn capabilities(&mut self) -> Result<JsValue, JsValue> {
+        let capabilities = AsyncHWI::capabilities(self)
+            .await
+            .map_err(|e| device_error("Failed to get capabilities", e))?;
+        capabilities_js(&capabilities)
+    }
+
+    async fn verify_attestation(&mut self) -> Result<String, JsValue> {
+        let attestation = AsyncHWI::verify_attestation(self)
+            .await
+            .map_err(|e| device_error("Failed to verify attestation", e))?;
+        Ok(match attestation {
+            Attestation::Genuine => "genuine",
+            Attestation::Counterfeit => "counterfeit",
+            Attestation::UnknownRoot => "unknown_root",
+        }
+        .to_string())
+    }
+
+    async fn sign_tx(
+        &mut self,
+        psbt: &str,
+        context: Option<DeviceContext>,
+    ) -> Result<String, JsValue> {
+        let psbt = VersionedPsbt::from_str(psbt.trim())
+            .map_err(|e| JsValue::from_str(&format!("Invalid PSBT: {e}")))?;
+        AsyncHWI::sign_versioned_tx(self, psbt, context)
+            .await
+            .map(|psbt| psbt.to_string())
+            .map_err(|e| device_error("Failed to sign transaction", e))
+    }
+
+    async fn sign_message(&mut self, message: &[u8], path: &str) -> Result<String, JsValue> {
+        let p = DerivationPath::from_str(path)
+            .map_err(|e| JsValue::from_str(&format!("Invalid derivation path: {:?}", e)))?;
+        let (header, signature) = AsyncHWI::sign_message(self, message, p)
+            .await
+            .map_err(|e| device_error("Failed to sign message", e))?;
+        Ok(message_signature_base64(header, &signature))
+    }
+
+    async fn sign_message_bip322(
+        &mut self,
+        message: &[u8],
+        path: &str,
+        address_type: AddressType,
+    ) -> Result<String, JsValue> {
+        let p = DerivationPath::from_str(path)
+            .map_err(|e| JsValue::from_str(&format!("Invalid derivation path: {:?}", e)))?;
+        AsyncHWI::sign_message_bip322(self, message, p, address_type)
+            .await
+            .map_err(|e| device_error("Failed to sign message", e))
+    }
 }
 
 #[allow(clippy::large_enum_variant)]
@@ -166,17 +348,91 @@ impl<'a> AsMut<dyn HWI + 'a> for Device {
     }
 }
 
+/// Cancels the commands of a [`Client`] it is set on, from outside the pending call.
+///
+/// A cancelled token stays cancelled, set a new one before the next command.
+#[derive(Default)]
+#[wasm_bindgen]
+pub struct Cancellation(CancellationToken);
+
+#[wasm_bindgen]
+impl Cancellation {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Cancellation {
+        Cancellation::default()
+    }
+
+    /// Abort the pending command, which fails with the `cancelled` error kind.
+    #[wasm_bindgen]
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+}
+
 #[derive(Default)]
 #[wasm_bindgen]
 pub struct Client {
     device: Option<Device>,
+    cancellation: Option<CancellationToken>,
+    exchange_timeout: Option<Duration>,
+    timeout: Option<Duration>,
+    on_event_cb: Option<js_sys::Function>,
 }
 
 #[wasm_bindgen]
 impl Client {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Client {
-        Client { device: None }
+        Client::default()
+    }
+
+    /// Bound every exchange with the device and every whole command, in milliseconds. A
+    /// command that runs out of time fails with the `transport` error kind.
+    #[wasm_bindgen]
+    pub fn set_timeouts(&mut self, exchange_timeout_ms: Option<u32>, timeout_ms: Option<u32>) {
+        self.exchange_timeout = exchange_timeout_ms.map(|ms| Duration::from_millis(ms.into()));
+        self.timeout = timeout_ms.map(|ms| Duration::from_millis(ms.into()));
+        self.apply_control();
+    }
+
+    #[wasm_bindgen]
+    pub fn set_cancellation(&mut self, cancellation: &Cancellation) {
+        self.cancellation = Some(cancellation.0.clone());
+        self.apply_control();
+    }
+
+    /// Call `on_event_cb` with the events of the running commands, as an object with a
+    /// `type` of `progress` (with `done` and `total`), `awaiting_user_confirmation`,
+    /// `pairing_code_available` (with the BitBox02 `code`) or `pin_server_round_trip`.
+    #[wasm_bindgen]
+    pub fn set_event_callback(&mut self, on_event_cb: JsValue) {
+        self.on_event_cb = on_event_cb.dyn_into::<js_sys::Function>().ok();
+        self.apply_control();
+    }
+
+    fn apply_control(&mut self) {
+        let mut control = CommandControl::new().with_timer(|duration: Duration| async move {
+            let _ = timeout_future(duration.as_millis().try_into().unwrap_or(i32::MAX)).await;
+        });
+        if let Some(token) = &self.cancellation {
+            control = control.with_cancellation(token.clone());
+        }
+        if let Some(timeout) = self.exchange_timeout {
+            control = control.with_exchange_timeout(timeout);
+        }
+        if let Some(timeout) = self.timeout {
+            control = control.with_timeout(timeout);
+        }
+        if let Some(f) = self.on_event_cb.clone() {
+            control = control.with_events(move |event| {
+                if let Ok(event) = event_js(event) {
+                    let _ = f.call1(&JsValue::NULL, &event);
+                }
+            });
+        }
+        if let Some(device) = &mut self.device {
+            device.as_mut().set_control(control);
+        }
     }
 
     #[wasm_bindgen]
@@ -190,6 +446,7 @@ impl Client {
             ColdcardTransportHID::new(device),
             &mut rng,
         )));
+        self.apply_control();
         Ok(())
     }
 
@@ -198,7 +455,6 @@ impl Client {
         &mut self,
         network: &str,
         on_close_cb: JsValue,
-        on_pairing_code_cb: JsValue,
     ) -> Result<(), JsValue> {
         let network = Network::from_str(network).map_err(|e| JsValue::from_str(&e.to_string()))?;
         let device = WebHidDevice::get_webhid_device(
@@ -209,15 +465,11 @@ impl Client {
         )
         .await
         .ok_or(JsValue::from_str("Failed to connect to bitbox"))?;
-        let mut bb = BitBox::new(BitBoxTransportHID::new(device), None).with_network(network);
-        // The pairing code hook fires synchronously inside `unlock`; forward it to JS so the
-        // website can render the code while the user confirms it on the device.
-        bb.set_pairing_code_hook(Box::new(move |code| {
-            if let Ok(f) = on_pairing_code_cb.clone().dyn_into::<js_sys::Function>() {
-                let _ = f.call1(&JsValue::NULL, &JsValue::from_str(code));
-            }
-        }));
+        // The pairing code reaches the website as a `pairing_code_available` event, see
+        // `set_event_callback`, while the user confirms it on the device.
+        let bb = BitBox::new(BitBoxTransportHID::new(device), None).with_network(network);
         self.device = Some(Device::BitBox(bb));
+        self.apply_control();
         Ok(())
     }
 
@@ -228,6 +480,7 @@ impl Client {
                 .await
                 .ok_or(JsValue::from_str("Failed to connect to ledger"))?;
         self.device = Some(Device::Ledger(Ledger::new(LedgerTransportHID::new(device))));
+        self.apply_control();
         Ok(())
     }
 
@@ -242,6 +495,7 @@ impl Client {
             .await
             .ok_or(JsValue::from_str("Failed to connect to jade"))?;
         self.device = Some(Device::Jade(Jade::new(network, device, PinServer {})));
+        self.apply_control();
         Ok(())
     }
 
@@ -269,6 +523,26 @@ impl Client {
         }
     }
 
+    /// What the device supports: `commands`, `script_types`, `sighash_types` and `backups`
+    /// as arrays of strings, and `taproot`, `miniscript` and `multisig` flags.
+    #[wasm_bindgen]
+    pub async fn capabilities(&mut self) -> Result<JsValue, JsValue> {
+        match &mut self.device {
+            Some(d) => d.as_mut().capabilities().await,
+            None => Err(JsValue::from_str("Device not connected")),
+        }
+    }
+
+    /// Check that the device is genuine: `genuine`, `counterfeit` or `unknown_root` when
+    /// its manufacturer root is not bundled.
+    #[wasm_bindgen]
+    pub async fn verify_attestation(&mut self) -> Result<String, JsValue> {
+        match &mut self.device {
+            Some(d) => d.as_mut().verify_attestation().await,
+            None => Err(JsValue::from_str("Device not connected")),
+        }
+    }
+
     #[wasm_bindgen]
     pub async fn get_extended_pubkey(
         &mut self,
@@ -338,21 +612,13 @@ impl Client {
                 let desc = wallet_descriptor.ok_or_else(|| {
                     JsValue::from_str("BitBox descriptor address requires wallet_descriptor")
                 })?;
-                let policy: WalletPolicy = desc
-                    .parse()
-                    .map_err(|e| JsValue::from_str(&format!("Invalid wallet descriptor: {e}")))?;
+                let policy = parse_wallet_policy(&desc)?;
                 Some(DeviceContext::BitBox { policy })
             }
             Some(Device::Ledger(_)) => match (wallet_hmac_hex, wallet_descriptor) {
                 (Some(hmac_hex), Some(desc)) => {
-                    let hmac_bytes = hex::decode(&hmac_hex)
-                        .map_err(|e| JsValue::from_str(&format!("Invalid hmac hex: {e}")))?;
-                    let hmac: [u8; 32] = hmac_bytes
-                        .try_into()
-                        .map_err(|_| JsValue::from_str("hmac must be 32 bytes (64 hex chars)"))?;
-                    let wallet_policy: WalletPolicy = desc.parse().map_err(|e| {
-                        JsValue::from_str(&format!("Invalid wallet descriptor: {e}"))
-                    })?;
+                    let hmac = parse_hmac(&hmac_hex)?;
+                    let wallet_policy = parse_wallet_policy(&desc)?;
                     let ledger_policy = LedgerWalletPolicy::new(
                         descriptor_name.to_string(),
                         Version::V2,
@@ -383,6 +649,89 @@ impl Client {
             None => Err(JsValue::from_str("Device not connected")),
         }
     }
+
+    /// Sign a base64 PSBT, version 0 or 2, and return the signed PSBT as base64 in the same
+    /// version.
+    #[wasm_bindgen]
+    pub async fn sign_tx(
+        &mut self,
+        psbt: &str,
+        wallet_name: Option<String>,
+        wallet_descriptor: Option<String>,
+        wallet_hmac_hex: Option<String>,
+    ) -> Result<String, JsValue> {
+        // Ledger signs under a registered policy (name, descriptor and, once registered, its
+        // hmac); BitBox only needs the policy descriptor; Coldcard/Jade infer it from the PSBT.
+        let context = match &self.device {
+            Some(Device::BitBox(_)) => wallet_descriptor
+                .map(|desc| parse_wallet_policy(&desc))
+                .transpose()?
+                .map(|policy| DeviceContext::BitBox { policy }),
+            Some(Device::Ledger(_)) => match (wallet_name, wallet_descriptor, wallet_hmac_hex) {
+                (Some(name), Some(desc), hmac_hex) => {
+                    let wallet_hmac = hmac_hex.as_deref().map(parse_hmac).transpose()?;
+                    let wallet_policy = parse_wallet_policy(&desc)?;
+                    Some(DeviceContext::Ledger {
+                        wallet_policy: LedgerWalletPolicy::new(name, Version::V2, wallet_policy),
+                        wallet_hmac,
+                    })
+                }
+                (None, None, None) => None,
+                (None, None, Some(_)) => {
+                    return Err(JsValue::from_str(
+                        "wallet_hmac_hex requires wallet_name and wallet_descriptor",
+                    ));
+                }
+                _ => {
+                    return Err(JsValue::from_str(
+                        "Both wallet_name and wallet_descriptor must be provided together",
+                    ));
+                }
+            },
+            _ => None,
+        };
+        match &mut self.device {
+            Some(d) => d.as_mut().sign_tx(psbt, context).await,
+            None => Err(JsValue::from_str("Device not connected")),
+        }
+    }
+
+    /// Sign a message with the key at `path` and return a base64 BIP-137 signature.
+    #[wasm_bindgen]
+    pub async fn sign_message(&mut self, message: &str, path: &str) -> Result<String, JsValue> {
+        match &mut self.device {
+            Some(d) => d.as_mut().sign_message(message.as_bytes(), path).await,
+            None => Err(JsValue::from_str("Device not connected")),
+        }
+    }
+
+    /// Sign a message with the key at `path` and return a base64 BIP-322 simple signature
+    /// for its `native-segwit` or `taproot` address.
+    #[wasm_bindgen]
+    pub async fn sign_message_bip322(
+        &mut self,
+        message: &str,
+        path: &str,
+        address_format: &str,
+    ) -> Result<String, JsValue> {
+        let address_type = match address_format {
+            "native-segwit" => AddressType::P2wpkh,
+            "taproot" => AddressType::P2tr,
+            f => {
+                return Err(JsValue::from_str(&format!(
+                    "Invalid address format: {f}. Expected: native-segwit, taproot"
+                )));
+            }
+        };
+        match &mut self.device {
+            Some(d) => {
+                d.as_mut()
+                    .sign_message_bip322(message.as_bytes(), path, address_type)
+                    .await
+            }
+            None => Err(JsValue::from_str("Device not connected")),
+        }
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -9,7 +9,7 @@ use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{ReadableStreamDefaultReader, SerialPort};
 
-use crate::WasmError;
+use crate::{WasmError, timeout_future};
 
 #[wasm_bindgen]
 pub struct WebSerialDevice {
@@ -77,26 +77,6 @@ impl WebSerialDevice {
         let reader = reader
             .dyn_into::<ReadableStreamDefaultReader>()
             .expect("Failed to cast to ReadableStreamDefaultReader");
-        // Function to create a timeout future
-        fn create_timeout_future(timeout_ms: i32) -> JsFuture {
-            let promise = js_sys::Promise::new(&mut |resolve, _| {
-                let closure = Closure::wrap(Box::new(move || {
-                    resolve.call0(&JsValue::UNDEFINED).unwrap();
-                }) as Box<dyn FnMut()>);
-
-                web_sys::window()
-                    .unwrap()
-                    .set_timeout_with_callback_and_timeout_and_arguments_0(
-                        closure.as_ref().unchecked_ref(),
-                        timeout_ms,
-                    )
-                    .unwrap();
-
-                closure.forget(); // Avoid dropping the closure prematurely
-            });
-            JsFuture::from(promise)
-        }
-
         let mut res = Vec::new();
 
         // Perform the first read without a timeout for the unlock;
@@ -129,7 +109,7 @@ impl WebSerialDevice {
         loop {
             match select(
                 wasm_bindgen_futures::JsFuture::from(reader.read()),
-                create_timeout_future(500),
+                timeout_future(500),
             )
             .await
             {
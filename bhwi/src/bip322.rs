@@ -0,0 +1,445 @@
+//! BIP-322 generic message signing.
+//!
+//! A BIP-322 signature is the witness of a virtual `to_sign` transaction spending a virtual
+//! `to_spend` output locked to the signing address, with the message committed in the
+//! `to_spend` input. Devices do not need to know about BIP-322: [`to_sign_psbt`] builds
+//! `to_sign` as a regular PSBT for the device's `SignTx` path, and [`encode_signature`] turns
+//! the signed PSBT into the "simple" encoding, the base64 of the consensus-encoded witness.
+//! Interpreters answer `Command::SignMessageBip322` by running a [`Session`], which chains
+//! those steps as common commands.
+//!
+//! Only single-key segwit addresses (P2WPKH and P2TR key path) have a simple encoding; legacy
+//! addresses keep using BIP-137 signatures.
+
+use std::collections::BTreeMap;
+
+use base64ct::{Base64, Encoding};
+use bitcoin::{
+    Amount, OutPoint, PublicKey, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    absolute::LockTime,
+    address::AddressType,
+    bip32::{DerivationPath, Fingerprint, Xpub},
+    consensus::encode::serialize,
+    hashes::{Hash, HashEngine, sha256},
+    opcodes::{OP_0, all::OP_RETURN},
+    psbt::Psbt,
+    script::Builder,
+    secp256k1::Secp256k1,
+    transaction::Version,
+};
+
+use crate::{
+    common::{Command, DeviceContext, Response},
+    ledger::{LedgerWalletPolicy, Version, singlesig_wallet_policy},
+};
+
+const MESSAGE_TAG: &[u8] = b"BIP0322-signed-message";
+
+#[derive(Debug, thiserror::Error)]
+pub enum Bip322Error {
+    #[error("BIP-322 simple signatures do not support {0} addresses")]
+    UnsupportedAddress(AddressType),
+    #[error("invalid input: {0}")]
+    InvalidInput(String),
+    #[error("invalid BIP-322 PSBT: {0}")]
+    Psbt(String),
+    #[error("unexpected response while signing a BIP-322 message")]
+    UnexpectedResponse,
+    #[error("the BIP-322 PSBT was not signed")]
+    NotSigned,
+}
+
+/// Tagged hash of `message` committed in the `to_spend` transaction.
+pub fn message_hash(message: &[u8]) -> sha256::Hash {
+    let tag = sha256::Hash::hash(MESSAGE_TAG);
+    let mut engine = sha256::Hash::engine();
+    engine.input(tag.as_ref());
+    engine.input(tag.as_ref());
+    engine.input(message);
+    sha256::Hash::from_engine(engine)
+}
+
+/// The virtual transaction whose single output, locked to `script_pubkey`, is spent by the
+/// signature.
+pub fn to_spend(script_pubkey: ScriptBuf, message: &[u8]) -> Transaction {
+    let script_sig = Builder::new()
+        .push_opcode(OP_0)
+        .push_slice(message_hash(message).to_byte_array())
+        .into_script();
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: Txid::all_zeros(),
+                vout: 0xFFFFFFFF,
+            },
+            script_sig,
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey,
+        }],
+    }
+}
+
+/// The unsigned virtual transaction spending the `to_spend` output.
+pub fn to_sign(to_spend: &Transaction) -> Transaction {
+    Transaction {
+        version: Version(0),
+        lock_time: LockTime::ZERO,
+        input: vec![TxIn {
+            previous_output: OutPoint {
+                txid: to_spend.compute_txid(),
+                vout: 0,
+            },
+            script_sig: ScriptBuf::new(),
+            sequence: Sequence::ZERO,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: Amount::ZERO,
+            script_pubkey: Builder::new().push_opcode(OP_RETURN).into_script(),
+        }],
+    }
+}
+
+/// Build the `to_sign` PSBT for the single key `pubkey` at `fingerprint/path`, with the
+/// address of `address_type` (`P2wpkh` or `P2tr`) as the signing address.
+pub fn to_sign_psbt(
+    message: &[u8],
+    address_type: AddressType,
+    pubkey: PublicKey,
+    fingerprint: Fingerprint,
+    path: DerivationPath,
+) -> Result<Psbt, Bip322Error> {
+    let script_pubkey = match address_type {
+        AddressType::P2wpkh => ScriptBuf::new_p2wpkh(
+            &pubkey
+                .wpubkey_hash()
+                .map_err(|_| Bip322Error::InvalidInput("uncompressed key".into()))?,
+        ),
+        AddressType::P2tr => {
+            let internal_key = pubkey.inner.x_only_public_key().0;
+            ScriptBuf::new_p2tr(&Secp256k1::verification_only(), internal_key, None)
+        }
+        other => return Err(Bip322Error::UnsupportedAddress(other)),
+    };
+    let to_spend = to_spend(script_pubkey, message);
+    let mut psbt =
+        Psbt::from_unsigned_tx(to_sign(&to_spend)).map_err(|e| Bip322Error::Psbt(e.to_string()))?;
+    let input = &mut psbt.inputs[0];
+    input.witness_utxo = Some(to_spend.output[0].clone());
+    match address_type {
+        AddressType::P2tr => {
+            let internal_key = pubkey.inner.x_only_public_key().0;
+            input.tap_internal_key = Some(internal_key);
+            input.tap_key_origins =
+                BTreeMap::from([(internal_key, (Vec::new(), (fingerprint, path)))]);
+        }
+        _ => {
+            // Segwit v0 signers also want the full previous transaction.
+            input.non_witness_utxo = Some(to_spend);
+            input.bip32_derivation = BTreeMap::from([(pubkey.inner, (fingerprint, path))]);
+        }
+    }
+    Ok(psbt)
+}
+
+/// Account of the key at `path`, whose xpub a Ledger needs to sign the `to_sign` PSBT, see
+/// [`ledger_context`].
+pub fn account_path(path: &DerivationPath) -> Result<DerivationPath, Bip322Error> {
+    let children: &[_] = path.as_ref();
+    if children.len() < 5 {
+        return Err(Bip322Error::InvalidInput(format!(
+            "{path} is not a purpose/coin/account/change/index path"
+        )));
+    }
+    Ok(DerivationPath::from(&children[..3]))
+}
+
+/// Context of the Ledger `SignTx` of the `to_sign` PSBT for the key at `fingerprint/path`:
+/// the single-sig policy of its account, whose xpub is `account_xpub`. The Ledger Bitcoin app
+/// signs only for a wallet policy, the default one needs no registration.
+pub fn ledger_context(
+    path: &DerivationPath,
+    fingerprint: Fingerprint,
+    account_xpub: Xpub,
+) -> Result<DeviceContext, Bip322Error> {
+    let policy = singlesig_wallet_policy(path, fingerprint, account_xpub)
+        .map_err(|e| Bip322Error::InvalidInput(e.to_string()))?;
+    Ok(DeviceContext::Ledger {
+        wallet_policy: LedgerWalletPolicy::new(String::new(), Version::V2, policy),
+        wallet_hmac: None,
+    })
+}
+
+/// Encode the signature of a `to_sign` PSBT signed by the device, in the BIP-322 simple
+/// format.
+pub fn encode_signature(psbt: &Psbt) -> Result<String, Bip322Error> {
+    let input = psbt
+        .inputs
+        .first()
+        .ok_or_else(|| Bip322Error::Psbt("no input".into()))?;
+    let witness = if let Some(witness) = &input.final_script_witness {
+        witness.clone()
+    } else if let Some(signature) = input.tap_key_sig {
+        Witness::from_slice(&[signature.to_vec()])
+    } else if let Some((pubkey, signature)) = input.partial_sigs.iter().next() {
+        Witness::from_slice(&[signature.to_vec(), pubkey.to_bytes()])
+    } else {
+        return Err(Bip322Error::NotSigned);
+    };
+    Ok(Base64::encode_string(&serialize(&witness)))
+}
+
+/// A BIP-322 simple signature run by an interpreter as a sequence of common commands: the
+/// master fingerprint, the xpub of the signing key, the xpub of its account when signing
+/// under the account policy, then the `SignTx` of the `to_sign` PSBT.
+pub struct Session {
+    message: Vec<u8>,
+    path: DerivationPath,
+    address_type: AddressType,
+    account_policy: bool,
+    step: Step,
+}
+
+enum Step {
+    Fingerprint,
+    Xpub {
+        fingerprint: Fingerprint,
+    },
+    AccountXpub {
+        fingerprint: Fingerprint,
+        psbt: Psbt,
+    },
+    SignTx,
+}
+
+/// What a [`Session`] asks of its interpreter after a command.
+pub enum Next {
+    /// Run the command and feed its response to [`Session::next`].
+    Run(Command),
+    /// The signature, in the BIP-322 simple format.
+    Signature(String),
+}
+
+impl Session {
+    /// Start signing `message` with the `address_type` address of the key at `path`, and
+    /// return the first command to run. With `account_policy`, the PSBT is signed under the
+    /// default policy of the key's account, as the Ledger Bitcoin app requires.
+    pub fn start(
+        message: Vec<u8>,
+        path: DerivationPath,
+        address_type: AddressType,
+        account_policy: bool,
+    ) -> Result<(Self, Command), Bip322Error> {
+        if !matches!(address_type, AddressType::P2wpkh | AddressType::P2tr) {
+            return Err(Bip322Error::UnsupportedAddress(address_type));
+        }
+        if account_policy {
+            account_path(&path)?;
+        }
+        let session = Self {
+            message,
+            path,
+            address_type,
+            account_policy,
+            step: Step::Fingerprint,
+        };
+        Ok((session, Command::GetMasterFingerprint))
+    }
+
+    /// Feed the response of the last command run.
+    pub fn next(&mut self, response: Response) -> Result<Next, Bip322Error> {
+        match (std::mem::replace(&mut self.step, Step::SignTx), response) {
+            (Step::Fingerprint, Response::MasterFingerprint(fingerprint)) => {
+                self.step = Step::Xpub { fingerprint };
+                Ok(Next::Run(Command::GetXpub {
+                    path: self.path.clone(),
+                    display: false,
+                }))
+            }
+            (Step::Xpub { fingerprint }, Response::Xpub(xpub)) => {
+                let psbt = to_sign_psbt(
+                    &self.message,
+                    self.address_type,
+                    PublicKey::new(xpub.public_key),
+                    fingerprint,
+                    self.path.clone(),
+                )?;
+                if !self.account_policy {
+                    return Ok(Next::Run(Command::SignTx(psbt, None)));
+                }
+                self.step = Step::AccountXpub { fingerprint, psbt };
+                Ok(Next::Run(Command::GetXpub {
+                    path: account_path(&self.path)?,
+                    display: false,
+                }))
+            }
+            (Step::AccountXpub { fingerprint, psbt }, Response::Xpub(account_xpub)) => {
+                let context = ledger_context(&self.path, fingerprint, account_xpub)?;
+                Ok(Next::Run(Command::SignTx(psbt, Some(context))))
+            }
+            (Step::SignTx, Response::SignedPsbt(psbt)) => {
+                Ok(Next::Signature(encode_signature(&psbt)?))
+            }
+            _ => Err(Bip322Error::UnexpectedResponse),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{
+        Address, EcdsaSighashType, ecdsa,
+        secp256k1::{Message, SecretKey},
+    };
+
+    use super::*;
+
+    fn script_pubkey() -> ScriptBuf {
+        Address::from_str("bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l")
+            .unwrap()
+            .assume_checked()
+            .script_pubkey()
+    }
+
+    #[test]
+    fn message_hash_vectors() {
+        assert_eq!(
+            message_hash(b"").to_string(),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            message_hash(b"Hello World").to_string(),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+    }
+
+    #[test]
+    fn transaction_vectors() {
+        let spend = to_spend(script_pubkey(), b"");
+        assert_eq!(
+            spend.compute_txid().to_string(),
+            "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7"
+        );
+        assert_eq!(
+            to_sign(&spend).compute_txid().to_string(),
+            "1e9654e951a5ba44c8604c4de6c67fd78a27e81dcadcfe1edf638ba3aaebaed6"
+        );
+
+        let spend = to_spend(script_pubkey(), b"Hello World");
+        assert_eq!(
+            spend.compute_txid().to_string(),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+        assert_eq!(
+            to_sign(&spend).compute_txid().to_string(),
+            "88737ae86f2077145f93cc4b153ae9a1cb8d56afa511988c149c5c8c9d93bddf"
+        );
+    }
+
+    #[test]
+    fn signs_through_psbt() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = PublicKey::new(secret_key.public_key(&secp));
+        let path = DerivationPath::from_str("m/84'/0'/0'/0/0").unwrap();
+        let fingerprint = Fingerprint::from([0xf5, 0xac, 0xc2, 0xfd]);
+
+        let mut psbt = to_sign_psbt(
+            b"Hello World",
+            AddressType::P2wpkh,
+            pubkey,
+            fingerprint,
+            path,
+        )
+        .unwrap();
+        assert_eq!(
+            psbt.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey,
+            ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash().unwrap())
+        );
+        assert_eq!(
+            psbt.inputs[0].bip32_derivation.get(&pubkey.inner),
+            Some(&(
+                fingerprint,
+                DerivationPath::from_str("m/84'/0'/0'/0/0").unwrap()
+            ))
+        );
+        assert!(matches!(
+            encode_signature(&psbt),
+            Err(Bip322Error::NotSigned)
+        ));
+
+        let signature = ecdsa::Signature {
+            signature: secp.sign_ecdsa(&Message::from_digest([2; 32]), &secret_key),
+            sighash_type: EcdsaSighashType::All,
+        };
+        psbt.inputs[0].partial_sigs.insert(pubkey, signature);
+        let encoded = Base64::decode_vec(&encode_signature(&psbt).unwrap()).unwrap();
+        let witness: Witness = bitcoin::consensus::deserialize(&encoded).unwrap();
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness.nth(0).unwrap(), signature.to_vec());
+        assert_eq!(witness.nth(1).unwrap(), pubkey.to_bytes());
+    }
+
+    #[test]
+    fn session_signs_the_to_sign_psbt() {
+        let xpub = Xpub::from_str("tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP").unwrap();
+        let path = DerivationPath::from_str("m/86'/1'/0'/0/0").unwrap();
+        let (mut session, command) =
+            Session::start(b"Hello World".to_vec(), path, AddressType::P2tr, false).unwrap();
+        assert!(matches!(command, Command::GetMasterFingerprint));
+
+        let next = session
+            .next(Response::MasterFingerprint(Fingerprint::from([1; 4])))
+            .unwrap();
+        assert!(matches!(
+            next,
+            Next::Run(Command::GetXpub { display: false, .. })
+        ));
+        let Next::Run(Command::SignTx(mut psbt, None)) =
+            session.next(Response::Xpub(xpub)).unwrap()
+        else {
+            panic!("expected the to_sign PSBT");
+        };
+        assert_eq!(
+            psbt.inputs[0].tap_internal_key,
+            Some(xpub.public_key.x_only_public_key().0)
+        );
+
+        psbt.inputs[0].final_script_witness = Some(Witness::from_slice(&[[7u8; 64]]));
+        let Next::Signature(signature) = session.next(Response::SignedPsbt(psbt)).unwrap() else {
+            panic!("expected the signature");
+        };
+        assert_eq!(
+            Base64::decode_vec(&signature).unwrap(),
+            serialize(&Witness::from_slice(&[[7u8; 64]]))
+        );
+        assert!(matches!(
+            session.next(Response::TaskDone),
+            Err(Bip322Error::UnexpectedResponse)
+        ));
+    }
+
+    #[test]
+    fn legacy_addresses_are_rejected() {
+        let secp = Secp256k1::new();
+        let pubkey = PublicKey::new(SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp));
+        assert!(matches!(
+            to_sign_psbt(
+                b"",
+                AddressType::P2pkh,
+                pubkey,
+                Fingerprint::default(),
+                DerivationPath::master()
+            ),
+            Err(Bip322Error::UnsupportedAddress(AddressType::P2pkh))
+        ));
+    }
+}
@@ -104,6 +104,42 @@ pub fn create_backup_request(timestamp: u32, timezone_offset: i32) -> pb::reques
     })
 }
 
+/// List the backups stored on the inserted microSD card.
+pub fn list_backups_request() -> pb::request::Request {
+    pb::request::Request::ListBackups(pb::ListBackupsRequest {})
+}
+
+/// Look for the microSD backup of the device's seed. `silent` skips the on-device result
+/// screen.
+pub fn check_backup_request(silent: bool) -> pb::request::Request {
+    pb::request::Request::CheckBackup(pb::CheckBackupRequest { silent })
+}
+
+/// Restore an unseeded device from the microSD backup with `id`.
+pub fn restore_backup_request(
+    id: impl Into<String>,
+    timestamp: u32,
+    timezone_offset: i32,
+) -> pb::request::Request {
+    pb::request::Request::RestoreBackup(pb::RestoreBackupRequest {
+        id: id.into(),
+        timestamp,
+        timezone_offset,
+    })
+}
+
+/// Ask whether a microSD card is inserted.
+pub fn check_sdcard_request() -> pb::request::Request {
+    pb::request::Request::CheckSdcard(pb::CheckSdCardRequest {})
+}
+
+/// Prompt the user to insert a microSD card, returning once one is inserted.
+pub fn insert_sdcard_request() -> pb::request::Request {
+    pb::request::Request::InsertRemoveSdcard(pb::InsertRemoveSdCardRequest {
+        action: pb::insert_remove_sd_card_request::SdCardAction::InsertCard as i32,
+    })
+}
+
 /// Start the on-device mnemonic restore flow.
 pub fn restore_from_mnemonic_request(timestamp: u32, timezone_offset: i32) -> pb::request::Request {
     pb::request::Request::RestoreFromMnemonic(pb::RestoreFromMnemonicRequest {
@@ -112,6 +148,13 @@ pub fn restore_from_mnemonic_request(timestamp: u32, timezone_offset: i32) -> pb
     })
 }
 
+/// Reboot the device into its bootloader, after the user confirms on the device.
+pub fn reboot_to_bootloader_request() -> pb::request::Request {
+    pb::request::Request::Reboot(pb::RebootRequest {
+        purpose: pb::reboot_request::Purpose::Upgrade as i32,
+    })
+}
+
 /// Erase wallet material and return the device to its uninitialized state.
 pub fn reset_request() -> pb::request::Request {
     pb::request::Request::Reset(pb::ResetRequest {})
@@ -191,6 +234,34 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn backup_requests_preserve_external_inputs() {
+        assert!(matches!(
+            check_backup_request(true),
+            pb::request::Request::CheckBackup(pb::CheckBackupRequest { silent: true })
+        ));
+        assert!(matches!(
+            restore_backup_request("backup-id", 1_750_000_000, 3600),
+            pb::request::Request::RestoreBackup(pb::RestoreBackupRequest {
+                id,
+                timestamp: 1_750_000_000,
+                timezone_offset: 3600,
+            }) if id == "backup-id"
+        ));
+        assert!(matches!(
+            insert_sdcard_request(),
+            pb::request::Request::InsertRemoveSdcard(pb::InsertRemoveSdCardRequest { action: 1 })
+        ));
+    }
+
+    #[test]
+    fn reboot_to_bootloader_request_asks_for_upgrade() {
+        assert!(matches!(
+            reboot_to_bootloader_request(),
+            pb::request::Request::Reboot(pb::RebootRequest { purpose: 0 })
+        ));
+    }
+
     #[test]
     fn setup_requests_preserve_external_inputs() {
         assert!(matches!(
@@ -0,0 +1,247 @@
+//! BitBox02 attestation: proof that a device was made by Shift Crypto.
+//!
+//! Every BitBox02 holds a P-256 attestation key generated during production. Shift signs the
+//! device public key, together with the hash of its bootloader, with a secp256k1 root key.
+//! Sent `a` followed by a random challenge, with or without a noise channel, the device
+//! answers with that certificate and its signature of the challenge.
+
+use bitcoin::hashes::{Hash, HashEngine, sha256};
+use bitcoin::secp256k1::{self, Message, Secp256k1, ecdsa};
+use ring::signature::{ECDSA_P256_SHA256_FIXED, UnparsedPublicKey};
+
+use crate::common::Attestation;
+
+use super::error::BitBoxError;
+use super::{OP_ATTESTATION, RESPONSE_SUCCESS};
+
+const BOOTLOADER_HASH_LEN: usize = 32;
+const DEVICE_PUBKEY_LEN: usize = 64;
+const CERTIFICATE_LEN: usize = 64;
+const ROOT_ID_LEN: usize = 32;
+const CHALLENGE_SIGNATURE_LEN: usize = 64;
+const RESPONSE_LEN: usize = BOOTLOADER_HASH_LEN
+    + DEVICE_PUBKEY_LEN
+    + CERTIFICATE_LEN
+    + ROOT_ID_LEN
+    + CHALLENGE_SIGNATURE_LEN;
+
+/// A Shift attestation root, identified on the wire by the SHA-256 of its public key.
+pub struct AttestationRoot {
+    /// Uncompressed secp256k1 public key.
+    pub pubkey: [u8; 65],
+    /// Bootloader the root certifies devices for, when it is restricted to one.
+    pub accepted_bootloader_hash: Option<[u8; 32]>,
+}
+
+/// Shift attestation roots, mirroring the list of bitbox-api-rs (`src/attestation.rs`).
+///
+/// No root is bundled yet: the keys must be copied from that published list, never
+/// retyped. Until they are, every device that signs the challenge reports
+/// [`Attestation::UnknownRoot`].
+pub const ATTESTATION_ROOTS: &[AttestationRoot] = &[];
+
+pub fn gen_challenge() -> Result<[u8; 32], BitBoxError> {
+    let mut challenge = [0u8; 32];
+    getrandom::getrandom(&mut challenge)
+        .map_err(|_| BitBoxError::Attestation("failed generating challenge"))?;
+    Ok(challenge)
+}
+
+pub fn attestation_request(challenge: &[u8; 32]) -> Vec<u8> {
+    let mut request = vec![OP_ATTESTATION];
+    request.extend_from_slice(challenge);
+    request
+}
+
+/// Check the answer of the device to [`attestation_request`] against `roots`.
+pub fn verify_attestation(
+    roots: &[AttestationRoot],
+    challenge: &[u8; 32],
+    response: &[u8],
+) -> Result<Attestation, BitBoxError> {
+    let (&status, body) = response
+        .split_first()
+        .ok_or(BitBoxError::UnexpectedResponse)?;
+    if status != RESPONSE_SUCCESS {
+        return Ok(Attestation::Counterfeit);
+    }
+    if body.len() != RESPONSE_LEN {
+        return Err(BitBoxError::Attestation("unexpected response length"));
+    }
+    let (bootloader_hash, rest) = body.split_at(BOOTLOADER_HASH_LEN);
+    let (device_pubkey, rest) = rest.split_at(DEVICE_PUBKEY_LEN);
+    let (certificate, rest) = rest.split_at(CERTIFICATE_LEN);
+    let (root_id, challenge_signature) = rest.split_at(ROOT_ID_LEN);
+
+    let mut uncompressed = vec![0x04];
+    uncompressed.extend_from_slice(device_pubkey);
+    if UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &uncompressed)
+        .verify(challenge, challenge_signature)
+        .is_err()
+    {
+        return Ok(Attestation::Counterfeit);
+    }
+
+    let Some(root) = roots
+        .iter()
+        .find(|root| sha256::Hash::hash(&root.pubkey).as_byte_array() == root_id)
+    else {
+        return Ok(Attestation::UnknownRoot);
+    };
+    if root
+        .accepted_bootloader_hash
+        .is_some_and(|accepted| accepted != bootloader_hash)
+        || !verify_certificate(root, bootloader_hash, device_pubkey, certificate)
+    {
+        return Ok(Attestation::Counterfeit);
+    }
+    Ok(Attestation::Genuine)
+}
+
+/// The certificate is the root's signature of `sha256(bootloader_hash || device_pubkey)`.
+fn verify_certificate(
+    root: &AttestationRoot,
+    bootloader_hash: &[u8],
+    device_pubkey: &[u8],
+    certificate: &[u8],
+) -> bool {
+    let (Ok(root), Ok(mut signature)) = (
+        secp256k1::PublicKey::from_slice(&root.pubkey),
+        ecdsa::Signature::from_compact(certificate),
+    ) else {
+        return false;
+    };
+    signature.normalize_s();
+    let mut engine = sha256::Hash::engine();
+    engine.input(bootloader_hash);
+    engine.input(device_pubkey);
+    let digest = sha256::Hash::from_engine(engine);
+    Secp256k1::verification_only()
+        .verify_ecdsa(
+            &Message::from_digest(digest.to_byte_array()),
+            &signature,
+            &root,
+        )
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::secp256k1::SecretKey;
+    use ring::rand::SystemRandom;
+    use ring::signature::{ECDSA_P256_SHA256_FIXED_SIGNING, EcdsaKeyPair, KeyPair};
+
+    const BOOTLOADER_HASH: [u8; 32] = [7; 32];
+
+    fn root_key() -> SecretKey {
+        SecretKey::from_slice(&[1; 32]).unwrap()
+    }
+
+    fn root(accepted_bootloader_hash: Option<[u8; 32]>) -> AttestationRoot {
+        let secp = Secp256k1::new();
+        AttestationRoot {
+            pubkey: root_key().public_key(&secp).serialize_uncompressed(),
+            accepted_bootloader_hash,
+        }
+    }
+
+    /// Answer of a device certified by `root_key()`, with its signature of `signed`.
+    fn device_response(signed: &[u8; 32]) -> Vec<u8> {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let device =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+        let device_pubkey = &device.public_key().as_ref()[1..];
+
+        let mut certified = BOOTLOADER_HASH.to_vec();
+        certified.extend_from_slice(device_pubkey);
+        let digest = sha256::Hash::hash(&certified);
+        let certificate =
+            Secp256k1::new().sign_ecdsa(&Message::from_digest(digest.to_byte_array()), &root_key());
+
+        let mut response = vec![RESPONSE_SUCCESS];
+        response.extend_from_slice(&BOOTLOADER_HASH);
+        response.extend_from_slice(device_pubkey);
+        response.extend_from_slice(&certificate.serialize_compact());
+        response.extend_from_slice(sha256::Hash::hash(&root(None).pubkey).as_byte_array());
+        response.extend_from_slice(device.sign(&rng, signed).unwrap().as_ref());
+        response
+    }
+
+    #[test]
+    fn request_is_opcode_and_challenge() {
+        let request = attestation_request(&[3; 32]);
+        assert_eq!(request[0], b'a');
+        assert_eq!(&request[1..], &[3; 32]);
+    }
+
+    #[test]
+    fn certified_device_is_genuine() {
+        let challenge = gen_challenge().unwrap();
+        let response = device_response(&challenge);
+        assert_eq!(
+            verify_attestation(&[root(None)], &challenge, &response).unwrap(),
+            Attestation::Genuine
+        );
+        assert_eq!(
+            verify_attestation(&[root(Some(BOOTLOADER_HASH))], &challenge, &response).unwrap(),
+            Attestation::Genuine
+        );
+    }
+
+    #[test]
+    fn replayed_signature_is_counterfeit() {
+        let response = device_response(&[1; 32]);
+        assert_eq!(
+            verify_attestation(&[root(None)], &[2; 32], &response).unwrap(),
+            Attestation::Counterfeit
+        );
+    }
+
+    #[test]
+    fn forged_certificate_is_counterfeit() {
+        let challenge = [1; 32];
+        let mut response = device_response(&challenge);
+        response[1 + BOOTLOADER_HASH_LEN + DEVICE_PUBKEY_LEN] ^= 1;
+        assert_eq!(
+            verify_attestation(&[root(None)], &challenge, &response).unwrap(),
+            Attestation::Counterfeit
+        );
+        let response = device_response(&challenge);
+        assert_eq!(
+            verify_attestation(&[root(Some([0; 32]))], &challenge, &response).unwrap(),
+            Attestation::Counterfeit
+        );
+    }
+
+    #[test]
+    fn unbundled_root_is_unknown() {
+        let challenge = [1; 32];
+        let response = device_response(&challenge);
+        assert_eq!(
+            verify_attestation(&[], &challenge, &response).unwrap(),
+            Attestation::UnknownRoot
+        );
+    }
+
+    #[test]
+    fn bad_challenge_signature_is_counterfeit_whatever_the_root() {
+        let response = device_response(&[1; 32]);
+        assert_eq!(
+            verify_attestation(&[], &[2; 32], &response).unwrap(),
+            Attestation::Counterfeit
+        );
+    }
+
+    #[test]
+    fn failure_status_and_malformed_responses() {
+        assert_eq!(
+            verify_attestation(&[root(None)], &[1; 32], &[0x01]).unwrap(),
+            Attestation::Counterfeit
+        );
+        assert!(verify_attestation(&[root(None)], &[1; 32], &[]).is_err());
+        assert!(verify_attestation(&[root(None)], &[1; 32], &[RESPONSE_SUCCESS; 100]).is_err());
+    }
+}
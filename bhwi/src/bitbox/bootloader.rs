@@ -0,0 +1,637 @@
+//! BitBox02 bootloader protocol.
+//!
+//! The bootloader enumerates with the firmware's VID/PID under its own product string (see
+//! [`BITBOX02_BOOTLOADER_PRODUCT_STRINGS`](super::BITBOX02_BOOTLOADER_PRODUCT_STRINGS)) and
+//! answers on U2F HID command `0xC3`, without HWW framing or noise encryption. A request is
+//! an opcode followed by its arguments; the reply echoes the opcode, then a status byte, 0 on
+//! success, then the result.
+//!
+//! A firmware upgrade erases the chunks the new firmware needs, writes it 4 kB at a time,
+//! then writes the signature data, which the bootloader checks against its signing keys
+//! before it marks the firmware bootable. The interpreter then reads back the firmware hash
+//! and compares it with the one computed over the image.
+
+use std::fmt;
+
+use bitcoin::hashes::{Hash, HashEngine, sha256d};
+
+use super::error::BitBoxError;
+use crate::Interpreter;
+use crate::common::{Recipient, Transmit};
+
+pub const FIRMWARE_CHUNK_LEN: usize = 4096;
+pub const MAX_FIRMWARE_LEN: usize = 884_736;
+const MAX_FIRMWARE_CHUNKS: usize = MAX_FIRMWARE_LEN / FIRMWARE_CHUNK_LEN;
+
+const OP_VERSIONS: u8 = b'v';
+const OP_HASHES: u8 = b'h';
+const OP_SET_SHOW_FIRMWARE_HASH: u8 = b'H';
+const OP_ERASE: u8 = b'e';
+const OP_WRITE_FIRMWARE_CHUNK: u8 = b'w';
+const OP_WRITE_SIGNATURE_DATA: u8 = b's';
+const OP_REBOOT: u8 = b'r';
+
+// Signed firmware layout: magic, signature data, firmware. The signature data holds the
+// signing keys, signed by the root keys, then the firmware version and its signatures.
+const MAGIC_LEN: usize = 4;
+const VERSION_LEN: usize = 4;
+const NUM_ROOT_KEYS: usize = 3;
+const NUM_SIGNING_KEYS: usize = 3;
+const SIGNING_PUBKEYS_DATA_LEN: usize = VERSION_LEN + NUM_SIGNING_KEYS * 64 + NUM_ROOT_KEYS * 64;
+const FIRMWARE_DATA_LEN: usize = VERSION_LEN + NUM_SIGNING_KEYS * 64;
+pub const SIGNATURE_DATA_LEN: usize = SIGNING_PUBKEYS_DATA_LEN + FIRMWARE_DATA_LEN;
+
+const MAGIC_MULTI: u32 = 0x653f_362b;
+const MAGIC_BTC_ONLY: u32 = 0x1123_3b0b;
+const MAGIC_PLUS_MULTI: u32 = 0x5b64_8ceb;
+const MAGIC_PLUS_BTC_ONLY: u32 = 0x4871_4774;
+
+/// Firmware edition, which has to match the edition of the bootloader.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FirmwareEdition {
+    Multi,
+    BtcOnly,
+    PlusMulti,
+    PlusBtcOnly,
+}
+
+impl FirmwareEdition {
+    fn from_magic(magic: u32) -> Option<Self> {
+        match magic {
+            MAGIC_MULTI => Some(Self::Multi),
+            MAGIC_BTC_ONLY => Some(Self::BtcOnly),
+            MAGIC_PLUS_MULTI => Some(Self::PlusMulti),
+            MAGIC_PLUS_BTC_ONLY => Some(Self::PlusBtcOnly),
+            _ => None,
+        }
+    }
+
+    /// Edition of a BitBox02 bootloader, from its HID product string.
+    pub fn from_bootloader_product(product: &str) -> Option<Self> {
+        match product {
+            "bb02-bootloader" => Some(Self::Multi),
+            "bb02btc-bootloader" => Some(Self::BtcOnly),
+            "bb02p-bootloader" => Some(Self::PlusMulti),
+            "bb02pbtc-bootloader" => Some(Self::PlusBtcOnly),
+            _ => None,
+        }
+    }
+}
+
+/// A signed firmware binary, as published with the BitBox02 firmware releases.
+pub struct SignedFirmware {
+    magic: u32,
+    signature_data: Vec<u8>,
+    firmware: Vec<u8>,
+}
+
+impl SignedFirmware {
+    pub fn parse(binary: &[u8]) -> Result<Self, BitBoxError> {
+        let header_len = MAGIC_LEN + SIGNATURE_DATA_LEN;
+        if binary.len() <= header_len {
+            return Err(BitBoxError::InvalidInput("signed firmware is too short"));
+        }
+        let (header, firmware) = binary.split_at(header_len);
+        if firmware.len() > MAX_FIRMWARE_LEN {
+            return Err(BitBoxError::InvalidInput("firmware is too large"));
+        }
+        let (magic, signature_data) = header.split_at(MAGIC_LEN);
+        Ok(Self {
+            magic: u32::from_be_bytes(magic.try_into().expect("4 byte magic")),
+            signature_data: signature_data.to_vec(),
+            firmware: firmware.to_vec(),
+        })
+    }
+
+    /// `None` for an unknown edition.
+    pub fn edition(&self) -> Option<FirmwareEdition> {
+        FirmwareEdition::from_magic(self.magic)
+    }
+
+    /// Monotonic firmware version, which the bootloader refuses to downgrade.
+    pub fn version(&self) -> u32 {
+        let version = &self.signature_data[SIGNING_PUBKEYS_DATA_LEN..][..VERSION_LEN];
+        u32::from_le_bytes(version.try_into().expect("4 byte version"))
+    }
+
+    pub fn firmware(&self) -> &[u8] {
+        &self.firmware
+    }
+
+    /// Hash the bootloader reports, and shows on screen, once this firmware is flashed: the
+    /// double SHA-256 of the version and of the firmware padded with `0xff` to the maximum
+    /// firmware size.
+    pub fn firmware_hash(&self) -> [u8; 32] {
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&self.version().to_le_bytes());
+        engine.input(&self.firmware);
+        engine.input(&vec![0xff; MAX_FIRMWARE_LEN - self.firmware.len()]);
+        sha256d::Hash::from_engine(engine).to_byte_array()
+    }
+
+    fn num_chunks(&self) -> usize {
+        self.firmware.len().div_ceil(FIRMWARE_CHUNK_LEN)
+    }
+
+    /// Chunk `index`, padded with `0xff` to the chunk size.
+    fn chunk(&self, index: usize) -> Vec<u8> {
+        let mut chunk = self
+            .firmware
+            .chunks(FIRMWARE_CHUNK_LEN)
+            .nth(index)
+            .unwrap_or_default()
+            .to_vec();
+        chunk.resize(FIRMWARE_CHUNK_LEN, 0xff);
+        chunk
+    }
+}
+
+impl fmt::Debug for SignedFirmware {
+    fn fmt(&self, formatter: &mut fmt::Formatter<'_>) -> fmt::Result {
+        formatter
+            .debug_struct("SignedFirmware")
+            .field("edition", &self.edition())
+            .field("version", &self.version())
+            .field("len", &self.firmware.len())
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum BootloaderCommand {
+    Versions,
+    /// Firmware and signing key hashes. The device shows the requested ones until the user
+    /// confirms.
+    Hashes {
+        display_firmware_hash: bool,
+        display_signing_keydata_hash: bool,
+    },
+    /// Show the firmware hash at every boot, until disabled.
+    SetShowFirmwareHash(bool),
+    /// Flash a signed firmware and check the hash of what was written.
+    Upgrade(SignedFirmware),
+    /// Erase the firmware. The device stays in the bootloader until a firmware is flashed.
+    Erase,
+    /// Leave the bootloader and boot the firmware.
+    Reboot,
+}
+
+/// Monotonic versions the bootloader checks upgrades against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootloaderVersions {
+    pub firmware: u32,
+    pub signing_keydata: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BootloaderHashes {
+    pub firmware: [u8; 32],
+    pub signing_keydata: [u8; 32],
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum BootloaderResponse {
+    TaskDone,
+    Versions(BootloaderVersions),
+    Hashes(BootloaderHashes),
+}
+
+/// Upgrade progress, in bytes of the firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UpgradeProgress {
+    pub written: usize,
+    pub total: usize,
+}
+
+enum Pending {
+    TaskDone,
+    Versions,
+    Hashes,
+    Upgrade {
+        firmware: SignedFirmware,
+        phase: UpgradePhase,
+    },
+}
+
+enum UpgradePhase {
+    Erasing,
+    Writing { chunk: usize },
+    WritingSignatureData,
+    Verifying,
+}
+
+#[derive(Default)]
+enum State {
+    #[default]
+    New,
+    Waiting {
+        opcode: u8,
+        pending: Pending,
+    },
+    Finished(BootloaderResponse),
+}
+
+#[derive(Default)]
+pub struct BootloaderInterpreter {
+    state: State,
+}
+
+impl BootloaderInterpreter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes of the firmware the device accepted so far, while a
+    /// [`BootloaderCommand::Upgrade`] runs.
+    pub fn upgrade_progress(&self) -> Option<UpgradeProgress> {
+        match &self.state {
+            State::Waiting {
+                pending: Pending::Upgrade { firmware, phase },
+                ..
+            } => {
+                let total = firmware.firmware.len();
+                let written = match phase {
+                    UpgradePhase::Erasing => 0,
+                    UpgradePhase::Writing { chunk } => total.min(chunk * FIRMWARE_CHUNK_LEN),
+                    UpgradePhase::WritingSignatureData | UpgradePhase::Verifying => total,
+                };
+                Some(UpgradeProgress { written, total })
+            }
+            _ => None,
+        }
+    }
+
+    fn send(&mut self, opcode: u8, args: &[u8], pending: Pending) -> Transmit {
+        let mut payload = Vec::with_capacity(1 + args.len());
+        payload.push(opcode);
+        payload.extend_from_slice(args);
+        self.state = State::Waiting { opcode, pending };
+        Transmit {
+            recipient: Recipient::Device,
+            payload,
+            encrypted: false,
+        }
+    }
+
+    fn finish(&mut self, response: BootloaderResponse) -> Option<Transmit> {
+        self.state = State::Finished(response);
+        None
+    }
+
+    fn upgrade(
+        &mut self,
+        firmware: SignedFirmware,
+        phase: UpgradePhase,
+        reply: &[u8],
+    ) -> Result<Option<Transmit>, BitBoxError> {
+        let next_chunk = match phase {
+            UpgradePhase::Erasing => 0,
+            UpgradePhase::Writing { chunk } => chunk + 1,
+            UpgradePhase::WritingSignatureData => {
+                let phase = UpgradePhase::Verifying;
+                return Ok(Some(self.send(
+                    OP_HASHES,
+                    &[0, 0],
+                    Pending::Upgrade { firmware, phase },
+                )));
+            }
+            UpgradePhase::Verifying => {
+                let hashes = parse_hashes(reply)?;
+                if hashes.firmware != firmware.firmware_hash() {
+                    return Err(BitBoxError::FirmwareHashMismatch);
+                }
+                return Ok(self.finish(BootloaderResponse::TaskDone));
+            }
+        };
+        if next_chunk < firmware.num_chunks() {
+            let mut args = vec![next_chunk as u8];
+            args.extend(firmware.chunk(next_chunk));
+            let phase = UpgradePhase::Writing { chunk: next_chunk };
+            Ok(Some(self.send(
+                OP_WRITE_FIRMWARE_CHUNK,
+                &args,
+                Pending::Upgrade { firmware, phase },
+            )))
+        } else {
+            let args = firmware.signature_data.clone();
+            let phase = UpgradePhase::WritingSignatureData;
+            Ok(Some(self.send(
+                OP_WRITE_SIGNATURE_DATA,
+                &args,
+                Pending::Upgrade { firmware, phase },
+            )))
+        }
+    }
+}
+
+impl Interpreter for BootloaderInterpreter {
+    type Command = BootloaderCommand;
+    type Transmit = Transmit;
+    type Response = BootloaderResponse;
+    type Error = BitBoxError;
+
+    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
+        Ok(match command {
+            BootloaderCommand::Versions => self.send(OP_VERSIONS, &[], Pending::Versions),
+            BootloaderCommand::Hashes {
+                display_firmware_hash,
+                display_signing_keydata_hash,
+            } => self.send(
+                OP_HASHES,
+                &[
+                    display_firmware_hash as u8,
+                    display_signing_keydata_hash as u8,
+                ],
+                Pending::Hashes,
+            ),
+            BootloaderCommand::SetShowFirmwareHash(enabled) => self.send(
+                OP_SET_SHOW_FIRMWARE_HASH,
+                &[enabled as u8],
+                Pending::TaskDone,
+            ),
+            BootloaderCommand::Upgrade(firmware) => {
+                let num_chunks = firmware.num_chunks() as u8;
+                self.send(
+                    OP_ERASE,
+                    &[num_chunks],
+                    Pending::Upgrade {
+                        firmware,
+                        phase: UpgradePhase::Erasing,
+                    },
+                )
+            }
+            BootloaderCommand::Erase => {
+                self.send(OP_ERASE, &[MAX_FIRMWARE_CHUNKS as u8], Pending::TaskDone)
+            }
+            BootloaderCommand::Reboot => self.send(OP_REBOOT, &[], Pending::TaskDone),
+        })
+    }
+
+    fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
+        let State::Waiting { opcode, pending } = std::mem::take(&mut self.state) else {
+            return Ok(None);
+        };
+        let reply = parse_reply(opcode, &data)?;
+        match pending {
+            Pending::TaskDone => Ok(self.finish(BootloaderResponse::TaskDone)),
+            Pending::Versions => {
+                let versions = parse_versions(reply)?;
+                Ok(self.finish(BootloaderResponse::Versions(versions)))
+            }
+            Pending::Hashes => {
+                let hashes = parse_hashes(reply)?;
+                Ok(self.finish(BootloaderResponse::Hashes(hashes)))
+            }
+            Pending::Upgrade { firmware, phase } => self.upgrade(firmware, phase, reply),
+        }
+    }
+
+    fn end(self) -> Result<Self::Response, Self::Error> {
+        match self.state {
+            State::Finished(response) => Ok(response),
+            _ => Err(BitBoxError::UnexpectedResponse),
+        }
+    }
+}
+
+/// Result of a reply to `opcode`, once its status is checked.
+fn parse_reply(opcode: u8, reply: &[u8]) -> Result<&[u8], BitBoxError> {
+    match reply {
+        [echo, 0, result @ ..] if *echo == opcode => Ok(result),
+        [echo, code, ..] if *echo == opcode => Err(BitBoxError::Bootloader(*code)),
+        _ => Err(BitBoxError::UnexpectedResponse),
+    }
+}
+
+fn parse_versions(reply: &[u8]) -> Result<BootloaderVersions, BitBoxError> {
+    let version = |offset: usize| {
+        reply
+            .get(offset..offset + VERSION_LEN)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().expect("4 byte version")))
+            .ok_or(BitBoxError::UnexpectedResponse)
+    };
+    Ok(BootloaderVersions {
+        firmware: version(0)?,
+        signing_keydata: version(VERSION_LEN)?,
+    })
+}
+
+fn parse_hashes(reply: &[u8]) -> Result<BootloaderHashes, BitBoxError> {
+    let hash = |offset: usize| {
+        reply
+            .get(offset..offset + 32)
+            .map(|bytes| <[u8; 32]>::try_from(bytes).expect("32 byte hash"))
+            .ok_or(BitBoxError::UnexpectedResponse)
+    };
+    Ok(BootloaderHashes {
+        firmware: hash(0)?,
+        signing_keydata: hash(32)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signed_firmware(magic: u32, version: u32, firmware: &[u8]) -> Vec<u8> {
+        let mut binary = magic.to_be_bytes().to_vec();
+        let mut signature_data = vec![0x5a; SIGNATURE_DATA_LEN];
+        signature_data[SIGNING_PUBKEYS_DATA_LEN..][..VERSION_LEN]
+            .copy_from_slice(&version.to_le_bytes());
+        binary.extend(signature_data);
+        binary.extend_from_slice(firmware);
+        binary
+    }
+
+    fn ack(opcode: u8, result: &[u8]) -> Vec<u8> {
+        let mut reply = vec![opcode, 0];
+        reply.extend_from_slice(result);
+        reply
+    }
+
+    #[test]
+    fn signed_firmware_is_split_into_signature_data_and_firmware() {
+        let binary = signed_firmware(MAGIC_BTC_ONLY, 42, &[1, 2, 3]);
+        let firmware = SignedFirmware::parse(&binary).unwrap();
+        assert_eq!(firmware.edition(), Some(FirmwareEdition::BtcOnly));
+        assert_eq!(firmware.version(), 42);
+        assert_eq!(firmware.firmware(), &[1, 2, 3]);
+        assert_eq!(firmware.signature_data.len(), SIGNATURE_DATA_LEN);
+
+        assert!(SignedFirmware::parse(&binary[..MAGIC_LEN + SIGNATURE_DATA_LEN]).is_err());
+        let oversized = signed_firmware(MAGIC_MULTI, 1, &vec![0; MAX_FIRMWARE_LEN + 1]);
+        assert!(SignedFirmware::parse(&oversized).is_err());
+        let unknown = signed_firmware(0xdead_beef, 1, &[0]);
+        assert_eq!(SignedFirmware::parse(&unknown).unwrap().edition(), None);
+    }
+
+    #[test]
+    fn every_bootloader_product_has_an_edition() {
+        for product in crate::bitbox::BITBOX02_BOOTLOADER_PRODUCT_STRINGS {
+            assert!(FirmwareEdition::from_bootloader_product(product).is_some());
+        }
+        let plus = signed_firmware(MAGIC_PLUS_BTC_ONLY, 1, &[0]);
+        assert_eq!(
+            SignedFirmware::parse(&plus).unwrap().edition(),
+            Some(FirmwareEdition::PlusBtcOnly)
+        );
+    }
+
+    #[test]
+    fn firmware_hash_covers_version_and_padding() {
+        let firmware = SignedFirmware::parse(&signed_firmware(MAGIC_MULTI, 7, &[1])).unwrap();
+        let mut preimage = 7u32.to_le_bytes().to_vec();
+        preimage.push(1);
+        preimage.resize(VERSION_LEN + MAX_FIRMWARE_LEN, 0xff);
+        assert_eq!(
+            firmware.firmware_hash(),
+            sha256d::Hash::hash(&preimage).to_byte_array()
+        );
+        let newer = SignedFirmware::parse(&signed_firmware(MAGIC_MULTI, 8, &[1])).unwrap();
+        assert_ne!(firmware.firmware_hash(), newer.firmware_hash());
+    }
+
+    #[test]
+    fn versions_and_hashes_frames() {
+        let mut interpreter = BootloaderInterpreter::new();
+        let transmit = interpreter.start(BootloaderCommand::Versions).unwrap();
+        assert_eq!(transmit.payload, b"v");
+        assert!(!transmit.encrypted);
+        let reply = ack(b'v', &[0x2a, 0, 0, 0, 3, 0, 0, 0]);
+        assert!(interpreter.exchange(reply).unwrap().is_none());
+        assert_eq!(
+            interpreter.end().unwrap(),
+            BootloaderResponse::Versions(BootloaderVersions {
+                firmware: 42,
+                signing_keydata: 3,
+            })
+        );
+
+        let mut interpreter = BootloaderInterpreter::new();
+        let transmit = interpreter
+            .start(BootloaderCommand::Hashes {
+                display_firmware_hash: true,
+                display_signing_keydata_hash: false,
+            })
+            .unwrap();
+        assert_eq!(transmit.payload, [b'h', 1, 0]);
+        let mut hashes = [0x11; 32].to_vec();
+        hashes.extend([0x22; 32]);
+        interpreter.exchange(ack(b'h', &hashes)).unwrap();
+        assert_eq!(
+            interpreter.end().unwrap(),
+            BootloaderResponse::Hashes(BootloaderHashes {
+                firmware: [0x11; 32],
+                signing_keydata: [0x22; 32],
+            })
+        );
+    }
+
+    #[test]
+    fn simple_commands_frames() {
+        for (command, payload) in [
+            (BootloaderCommand::SetShowFirmwareHash(true), vec![b'H', 1]),
+            (
+                BootloaderCommand::Erase,
+                vec![b'e', MAX_FIRMWARE_CHUNKS as u8],
+            ),
+            (BootloaderCommand::Reboot, vec![b'r']),
+        ] {
+            let mut interpreter = BootloaderInterpreter::new();
+            let transmit = interpreter.start(command).unwrap();
+            assert_eq!(transmit.payload, payload);
+            interpreter.exchange(ack(payload[0], &[])).unwrap();
+            assert_eq!(interpreter.end().unwrap(), BootloaderResponse::TaskDone);
+        }
+    }
+
+    #[test]
+    fn upgrade_erases_writes_chunks_signature_data_and_checks_hash() {
+        let image: Vec<u8> = (0..FIRMWARE_CHUNK_LEN + 10).map(|i| i as u8).collect();
+        let binary = signed_firmware(MAGIC_MULTI, 9, &image);
+        let firmware = SignedFirmware::parse(&binary).unwrap();
+        let expected_hash = firmware.firmware_hash();
+
+        let mut interpreter = BootloaderInterpreter::new();
+        let transmit = interpreter
+            .start(BootloaderCommand::Upgrade(firmware))
+            .unwrap();
+        assert_eq!(transmit.payload, [b'e', 2]);
+        assert_eq!(
+            interpreter.upgrade_progress(),
+            Some(UpgradeProgress {
+                written: 0,
+                total: image.len(),
+            })
+        );
+
+        let transmit = interpreter.exchange(ack(b'e', &[])).unwrap().unwrap();
+        assert_eq!(transmit.payload[..2], [b'w', 0]);
+        assert_eq!(transmit.payload[2..], image[..FIRMWARE_CHUNK_LEN]);
+
+        let transmit = interpreter.exchange(ack(b'w', &[])).unwrap().unwrap();
+        assert_eq!(transmit.payload.len(), 2 + FIRMWARE_CHUNK_LEN);
+        assert_eq!(transmit.payload[..2], [b'w', 1]);
+        assert_eq!(transmit.payload[2..12], image[FIRMWARE_CHUNK_LEN..]);
+        assert!(transmit.payload[12..].iter().all(|byte| *byte == 0xff));
+        assert_eq!(
+            interpreter.upgrade_progress().unwrap().written,
+            FIRMWARE_CHUNK_LEN
+        );
+
+        let transmit = interpreter.exchange(ack(b'w', &[])).unwrap().unwrap();
+        assert_eq!(transmit.payload[0], b's');
+        assert_eq!(
+            transmit.payload[1..],
+            binary[MAGIC_LEN..MAGIC_LEN + SIGNATURE_DATA_LEN]
+        );
+        assert_eq!(interpreter.upgrade_progress().unwrap().written, image.len());
+
+        let transmit = interpreter.exchange(ack(b's', &[])).unwrap().unwrap();
+        assert_eq!(transmit.payload, [b'h', 0, 0]);
+
+        let mut hashes = expected_hash.to_vec();
+        hashes.extend([0; 32]);
+        assert!(interpreter.exchange(ack(b'h', &hashes)).unwrap().is_none());
+        assert_eq!(interpreter.end().unwrap(), BootloaderResponse::TaskDone);
+    }
+
+    #[test]
+    fn upgrade_rejects_unexpected_firmware_hash() {
+        let binary = signed_firmware(MAGIC_MULTI, 9, &[1, 2, 3]);
+        let mut interpreter = BootloaderInterpreter::new();
+        interpreter
+            .start(BootloaderCommand::Upgrade(
+                SignedFirmware::parse(&binary).unwrap(),
+            ))
+            .unwrap();
+        for opcode in [b'e', b'w', b's'] {
+            interpreter.exchange(ack(opcode, &[])).unwrap();
+        }
+        assert!(matches!(
+            interpreter.exchange(ack(b'h', &[0; 64])),
+            Err(BitBoxError::FirmwareHashMismatch)
+        ));
+    }
+
+    #[test]
+    fn failed_status_and_mismatched_opcode_are_errors() {
+        let mut interpreter = BootloaderInterpreter::new();
+        interpreter
+            .start(BootloaderCommand::Upgrade(
+                SignedFirmware::parse(&signed_firmware(MAGIC_MULTI, 1, &[0])).unwrap(),
+            ))
+            .unwrap();
+        assert!(matches!(
+            interpreter.exchange(vec![b'e', 4]),
+            Err(BitBoxError::Bootloader(4))
+        ));
+
+        let mut interpreter = BootloaderInterpreter::new();
+        interpreter.start(BootloaderCommand::Versions).unwrap();
+        assert!(matches!(
+            interpreter.exchange(ack(b'h', &[0; 8])),
+            Err(BitBoxError::UnexpectedResponse)
+        ));
+    }
+}
@@ -5,6 +5,8 @@
 
 use thiserror::Error;
 
+use crate::common::ErrorKind;
+
 /// Errors returned by the BitBox02 device itself (protobuf `error.code`).
 #[derive(Error, Debug, Clone, PartialEq, Eq)]
 pub enum BitBoxDeviceError {
@@ -47,6 +49,25 @@ impl BitBoxDeviceError {
     }
 }
 
+impl From<&BitBoxDeviceError> for ErrorKind {
+    fn from(error: &BitBoxDeviceError) -> Self {
+        match error {
+            BitBoxDeviceError::UserAbort => ErrorKind::UserCancelled,
+            // Endpoints needing a seed answer `InvalidState` on an uninitialized device.
+            BitBoxDeviceError::InvalidState => ErrorKind::NotInitialized,
+            BitBoxDeviceError::Disabled => ErrorKind::Unsupported,
+            BitBoxDeviceError::InvalidInput | BitBoxDeviceError::Duplicate => {
+                ErrorKind::InvalidInput
+            }
+            BitBoxDeviceError::Unknown
+            | BitBoxDeviceError::Memory
+            | BitBoxDeviceError::Generic
+            | BitBoxDeviceError::NoiseEncrypt
+            | BitBoxDeviceError::NoiseDecrypt => ErrorKind::Protocol,
+        }
+    }
+}
+
 /// Top-level BitBox integration error.
 #[derive(Error, Debug)]
 pub enum BitBoxError {
@@ -80,6 +101,20 @@ pub enum BitBoxError {
     Framing(&'static str),
     #[error("transport error: {0}")]
     Transport(String),
+    #[error("command cancelled")]
+    Cancelled,
+    #[error("command timed out")]
+    Timeout,
+    #[error("device still has to reply to an interrupted command, reconnect it")]
+    PendingReply,
+    #[error("{0} is not supported by the BitBox02")]
+    Unsupported(&'static str),
+    #[error("bootloader error code {0}")]
+    Bootloader(u8),
+    #[error("flashed firmware hash does not match the firmware image")]
+    FirmwareHashMismatch,
+    #[error("attestation error: {0}")]
+    Attestation(&'static str),
 }
 
 impl From<prost::DecodeError> for BitBoxError {
@@ -1,3 +1,4 @@
+use std::collections::VecDeque;
 use std::marker::PhantomData;
 
 use bitcoin::address::AddressType;
@@ -6,18 +7,21 @@ use bitcoin::psbt::Psbt;
 use prost::Message;
 
 use crate::Interpreter;
+use crate::RawCommand;
 use crate::common::{
-    Command, DeviceBackup, DeviceContext, DisplayAddress, Error, Info, Recipient, Response,
-    Transmit,
+    Attestation, Command, DeviceBackup, DeviceContext, DeviceKind, DisplayAddress, Error,
+    ErrorKind, Event, Info, Recipient, Response, Transmit,
 };
 
 use super::api;
+use super::attestation::{self, ATTESTATION_ROOTS};
 use super::error::{BitBoxDeviceError, BitBoxError};
 use super::noise::{HandshakeState, NoiseState};
 use super::proto as pb;
 use super::sign::{OurKey, Transaction, TxOutput, apply_signatures, is_schnorr};
+use super::silentpayment::{self, SilentPaymentInputs};
 use super::{
-    ManagementContext, OP_HER_COMEZ_TEH_HANDSHAEK, OP_I_CAN_HAS_HANDSHAEK,
+    BackupInfo, ManagementContext, OP_HER_COMEZ_TEH_HANDSHAEK, OP_I_CAN_HAS_HANDSHAEK,
     OP_I_CAN_HAS_PAIRIN_VERIFICASHUN, OP_NOISE_MSG, OP_UNLOCK, RESPONSE_SUCCESS, SetupMode,
 };
 use super::{antiklepto, policy};
@@ -100,6 +104,57 @@ pub enum BitBoxCommand {
     },
     /// Start the BitBox02 mnemonic backup display flow.
     Backup,
+    /// List the backups on the microSD card.
+    ListBackups,
+    /// Look for the microSD backup matching the device's seed.
+    CheckBackup {
+        silent: bool,
+    },
+    /// Restore an unseeded device from the microSD backup with `id`.
+    RestoreBackup {
+        id: String,
+        timestamp: u32,
+        timezone_offset: i32,
+    },
+    CheckSdCard,
+    /// Prompt the user to insert a microSD card.
+    InsertSdCard,
+    /// Reboot into the bootloader to upgrade the firmware, once the user confirms.
+    RebootToBootloader,
+    /// Challenge the device attestation key. Works with or without a noise channel.
+    VerifyAttestation,
+}
+
+impl BitBoxCommand {
+    /// Whether the device asks the user to review the command before answering.
+    fn awaits_user(&self) -> bool {
+        match self {
+            BitBoxCommand::GetXpub { display, .. }
+            | BitBoxCommand::ShowSimpleAddress { display, .. }
+            | BitBoxCommand::ShowPolicyAddress { display, .. }
+            | BitBoxCommand::ShowDescriptorAddress { display, .. } => *display,
+            BitBoxCommand::CheckBackup { silent } => !silent,
+            BitBoxCommand::RegisterScriptConfig { .. }
+            | BitBoxCommand::SignPsbt { .. }
+            | BitBoxCommand::SignMessage { .. }
+            | BitBoxCommand::Setup { .. }
+            | BitBoxCommand::Wipe
+            | BitBoxCommand::Restore { .. }
+            | BitBoxCommand::TogglePassphrase
+            | BitBoxCommand::RestoreFromMnemonic { .. }
+            | BitBoxCommand::Backup
+            | BitBoxCommand::RestoreBackup { .. }
+            | BitBoxCommand::InsertSdCard
+            | BitBoxCommand::RebootToBootloader => true,
+            BitBoxCommand::UnlockAndPair
+            | BitBoxCommand::GetVersion
+            | BitBoxCommand::GetMasterFingerprint
+            | BitBoxCommand::IsScriptConfigRegistered { .. }
+            | BitBoxCommand::ListBackups
+            | BitBoxCommand::CheckSdCard
+            | BitBoxCommand::VerifyAttestation => false,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -117,6 +172,11 @@ pub enum BitBoxResponse {
     SignedPsbt(Box<Psbt>),
     Signature(u8, bitcoin::secp256k1::ecdsa::Signature),
     Backup,
+    Backups(Vec<BackupInfo>),
+    /// Id of the microSD backup matching the seed, `None` if there is none.
+    BackupCheck(Option<String>),
+    SdCardInserted(bool),
+    Attestation(Attestation),
 }
 
 /// Internal state machine.
@@ -159,6 +219,9 @@ enum State {
     RestoreWaitMnemonic,
     TogglePassphraseWaitInfo,
     TogglePassphraseWaitSet,
+    AttestationWaitResponse {
+        challenge: [u8; 32],
+    },
     Finished(BitBoxResponse),
 }
 
@@ -196,6 +259,9 @@ struct SignCtx {
     sigs: Vec<Vec<u8>>,
     is_inputs_pass2: bool,
     phase: SignPhase,
+    /// Input key sum and smallest outpoint, present when the transaction pays silent payment
+    /// addresses.
+    silent_payment_inputs: Option<SilentPaymentInputs>,
 }
 
 /// What the previous round of the sign loop just did — determines how the next response
@@ -216,6 +282,9 @@ enum SignPhase {
         host_nonce: [u8; 32],
         signer_commitment: Vec<u8>,
     },
+    /// Sent a silent payment `BtcSignOutput`; the next response carries the generated output
+    /// script and its DLEQ proof, which must be verified before the script is used.
+    ExpectSilentPaymentOutput { index: usize },
 }
 
 enum SignStep {
@@ -234,12 +303,19 @@ enum EncryptedContext {
     RegisterScriptConfig,
     RestoreFromMnemonic,
     Backup,
+    ListBackups,
+    CheckBackup,
+    RestoreBackup,
+    CheckSdCard,
+    InsertSdCard,
+    Reboot,
 }
 
 pub struct BitBoxInterpreter<'a, C, T, R, E> {
     state: State,
     noise: &'a mut NoiseState,
     network: bitcoin::Network,
+    events: VecDeque<Event>,
     _marker: PhantomData<(C, T, R, E)>,
 }
 
@@ -249,6 +325,7 @@ impl<'a, C, T, R, E> BitBoxInterpreter<'a, C, T, R, E> {
             state: State::New,
             noise,
             network: bitcoin::Network::Bitcoin,
+            events: VecDeque::new(),
             _marker: PhantomData,
         }
     }
@@ -300,7 +377,7 @@ fn build_sign_init_request(coin: pb::BtcCoin, tx: &Transaction) -> pb::request::
         num_outputs: tx.outputs.len() as _,
         locktime: tx.locktime,
         format_unit: pb::btc_sign_init_request::FormatUnit::Default as _,
-        contains_silent_payment_outputs: false,
+        contains_silent_payment_outputs: tx.has_silent_payment_outputs(),
     })
 }
 
@@ -462,6 +539,35 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
                 ctx.sigs.push(next.signature.clone());
                 next
             }
+            SignPhase::ExpectSilentPaymentOutput { index } => {
+                let next = decode_sign_next(response)?;
+                let TxOutput::SilentPayment(output) = &ctx.transaction.outputs[index] else {
+                    return Err(BitBoxError::UnexpectedResponse);
+                };
+                let inputs = ctx
+                    .silent_payment_inputs
+                    .as_ref()
+                    .ok_or(BitBoxError::UnexpectedResponse)?;
+                // BIP-352 numbers the outputs paying the same scan key in transaction order.
+                let k = ctx.transaction.outputs[..index]
+                    .iter()
+                    .filter(|o| match o {
+                        TxOutput::SilentPayment(o) => {
+                            o.address.scan_pubkey == output.address.scan_pubkey
+                        }
+                        _ => false,
+                    })
+                    .count();
+                let script_pubkey = silentpayment::verify_output(
+                    inputs,
+                    &output.address,
+                    k as u32,
+                    &next.silent_payment_dleq_proof,
+                    &next.generated_output_pkscript,
+                )?;
+                ctx.psbt.unsigned_tx.output[index].script_pubkey = script_pubkey;
+                next
+            }
         };
 
         let ty = pb::btc_sign_next_response::Type::try_from(next_response.r#type)
@@ -558,7 +664,12 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
                 Ok(SignStep::Continue { ctx, bytes })
             }
             pb::btc_sign_next_response::Type::Output => {
-                let output = &ctx.transaction.outputs[next_response.index as usize];
+                let output_index = next_response.index as usize;
+                let output = ctx
+                    .transaction
+                    .outputs
+                    .get(output_index)
+                    .ok_or(BitBoxError::UnexpectedResponse)?;
                 let request = pb::request::Request::BtcSignOutput(match output {
                     TxOutput::Internal(o) => pb::BtcSignOutputRequest {
                         ours: true,
@@ -572,9 +683,23 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
                         value: o.value,
                         r#type: o.payload.output_type as _,
                         payload: o.payload.data.clone(),
+                        payment_request_index: o.payment_request_index,
+                        ..Default::default()
+                    },
+                    TxOutput::SilentPayment(o) => pb::BtcSignOutputRequest {
+                        ours: false,
+                        value: o.value,
+                        silent_payment: Some(pb::btc_sign_output_request::SilentPayment {
+                            address: o.address.encode(self.network),
+                        }),
                         ..Default::default()
                     },
                 });
+                if matches!(output, TxOutput::SilentPayment(_)) {
+                    ctx.phase = SignPhase::ExpectSilentPaymentOutput {
+                        index: output_index,
+                    };
+                }
                 let bytes = self.build_encrypted(request)?;
                 Ok(SignStep::Continue { ctx, bytes })
             }
@@ -588,10 +713,21 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
                 apply_signatures(&mut psbt, &sigs, &our_keys)?;
                 Ok(SignStep::Done { psbt })
             }
-            pb::btc_sign_next_response::Type::HostNonce
-            | pb::btc_sign_next_response::Type::PaymentRequest => {
-                Err(BitBoxError::UnexpectedResponse)
+            pb::btc_sign_next_response::Type::PaymentRequest => {
+                let payment_request = ctx
+                    .transaction
+                    .payment_requests
+                    .get(next_response.index as usize)
+                    .ok_or(BitBoxError::UnexpectedResponse)?;
+                let request = pb::request::Request::Btc(pb::BtcRequest {
+                    request: Some(pb::btc_request::Request::PaymentRequest(
+                        payment_request.into(),
+                    )),
+                });
+                let bytes = self.build_encrypted(request)?;
+                Ok(SignStep::Continue { ctx, bytes })
             }
+            pb::btc_sign_next_response::Type::HostNonce => Err(BitBoxError::UnexpectedResponse),
         }
     }
 
@@ -614,6 +750,19 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
         Ok(bytes)
     }
 
+    /// Start an encrypted query that needs no state besides the noise channel.
+    fn start_paired_query(
+        &mut self,
+        request: pb::request::Request,
+        ctx: EncryptedContext,
+    ) -> Result<Transmit, BitBoxError> {
+        if !self.noise.is_paired() {
+            return Err(BitBoxError::Noise("not paired"));
+        }
+        let bytes = self.start_encrypted_query(request, ctx)?;
+        Ok(encrypted_transmit(bytes))
+    }
+
     fn decode_encrypted_response(
         &mut self,
         data: Vec<u8>,
@@ -669,10 +818,19 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
         ctx: EncryptedContext,
         data: Vec<u8>,
     ) -> Result<BitBoxResponse, BitBoxError> {
-        let response = self.decode_encrypted_response(data)?;
+        let response = match self.decode_encrypted_response(data) {
+            // The device answers a check without matching backup with a generic error.
+            Err(BitBoxError::Device(BitBoxDeviceError::Generic))
+                if matches!(ctx, EncryptedContext::CheckBackup) =>
+            {
+                return Ok(BitBoxResponse::BackupCheck(None));
+            }
+            response => response?,
+        };
         use pb::response::Response as R;
         match (ctx, response) {
             (EncryptedContext::Version, R::DeviceInfo(info)) => Ok(BitBoxResponse::Info(Info {
+                device: Some(DeviceKind::BitBox02),
                 version: info.version,
                 networks: vec![],
                 firmware: Some(info.name),
@@ -707,6 +865,21 @@ impl<C, T, R, E> BitBoxInterpreter<'_, C, T, R, E> {
             ) => Ok(BitBoxResponse::Registered),
             (EncryptedContext::RestoreFromMnemonic, R::Success(_)) => Ok(BitBoxResponse::TaskDone),
             (EncryptedContext::Backup, R::Success(_)) => Ok(BitBoxResponse::Backup),
+            (EncryptedContext::ListBackups, R::ListBackups(list)) => Ok(BitBoxResponse::Backups(
+                list.info.into_iter().map(BackupInfo::from).collect(),
+            )),
+            (EncryptedContext::CheckBackup, R::CheckBackup(check)) => {
+                Ok(BitBoxResponse::BackupCheck(Some(check.id)))
+            }
+            (
+                EncryptedContext::RestoreBackup
+                | EncryptedContext::InsertSdCard
+                | EncryptedContext::Reboot,
+                R::Success(_),
+            ) => Ok(BitBoxResponse::TaskDone),
+            (EncryptedContext::CheckSdCard, R::CheckSdcard(check)) => {
+                Ok(BitBoxResponse::SdCardInserted(check.inserted))
+            }
             _ => Err(BitBoxError::UnexpectedResponse),
         }
     }
@@ -726,6 +899,9 @@ where
 
     fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
         let command: BitBoxCommand = command.try_into()?;
+        if command.awaits_user() {
+            self.events.push_back(Event::AwaitingUserConfirmation);
+        }
         match command {
             BitBoxCommand::UnlockAndPair => {
                 self.state = State::WaitUnlockAck;
@@ -965,6 +1141,42 @@ where
                 )?;
                 Ok(encrypted_transmit(bytes).into())
             }
+            BitBoxCommand::ListBackups => Ok(self
+                .start_paired_query(api::list_backups_request(), EncryptedContext::ListBackups)?
+                .into()),
+            BitBoxCommand::CheckBackup { silent } => Ok(self
+                .start_paired_query(
+                    api::check_backup_request(silent),
+                    EncryptedContext::CheckBackup,
+                )?
+                .into()),
+            BitBoxCommand::RestoreBackup {
+                id,
+                timestamp,
+                timezone_offset,
+            } => Ok(self
+                .start_paired_query(
+                    api::restore_backup_request(id, timestamp, timezone_offset),
+                    EncryptedContext::RestoreBackup,
+                )?
+                .into()),
+            BitBoxCommand::CheckSdCard => Ok(self
+                .start_paired_query(api::check_sdcard_request(), EncryptedContext::CheckSdCard)?
+                .into()),
+            BitBoxCommand::InsertSdCard => Ok(self
+                .start_paired_query(api::insert_sdcard_request(), EncryptedContext::InsertSdCard)?
+                .into()),
+            BitBoxCommand::RebootToBootloader => Ok(self
+                .start_paired_query(
+                    api::reboot_to_bootloader_request(),
+                    EncryptedContext::Reboot,
+                )?
+                .into()),
+            BitBoxCommand::VerifyAttestation => {
+                let challenge = attestation::gen_challenge()?;
+                self.state = State::AttestationWaitResponse { challenge };
+                Ok(plain_transmit(attestation::attestation_request(&challenge)).into())
+            }
         }
     }
 
@@ -1011,9 +1223,10 @@ where
                         .try_into()
                         .map_err(|_| BitBoxError::Noise("bad handshake hash"))?;
                     let pairing_code = NoiseState::pairing_code_from_hash(&hash);
-                    // Fire the hook synchronously so the caller (CLI/UI) can display the
-                    // code before we block on the device's verification response.
-                    self.noise.on_pairing_code(&pairing_code);
+                    // Raised before the verification request, so the caller (CLI/UI) can
+                    // display the code while the device waits for the user to compare it.
+                    self.events
+                        .push_back(Event::PairingCodeAvailable(pairing_code.clone()));
                     self.state = State::WaitPairingConfirm(host, Some(pairing_code));
                     Ok(Some(
                         plain_transmit(vec![OP_I_CAN_HAS_PAIRIN_VERIFICASHUN]).into(),
@@ -1037,6 +1250,11 @@ where
                 self.state = State::Finished(BitBoxResponse::TaskDone);
                 Ok(None)
             }
+            State::AttestationWaitResponse { challenge } => {
+                let result = attestation::verify_attestation(ATTESTATION_ROOTS, &challenge, &data)?;
+                self.state = State::Finished(BitBoxResponse::Attestation(result));
+                Ok(None)
+            }
             State::WaitEncryptedResponse(ctx) => {
                 let result = self.handle_encrypted_response(ctx, data)?;
                 self.state = State::Finished(result);
@@ -1058,13 +1276,22 @@ where
                 // A registered policy needs the device fingerprint (only known now) to resolve
                 // the account keypath, so build the forced script config here rather than at
                 // command-conversion time.
-                let force_script_config = match (force_script_config, policy) {
+                let force_script_config = match (force_script_config, &policy) {
                     (Some(config), _) => Some(config),
-                    (None, Some(policy)) => Some(policy_script_config(&policy, &fingerprint)?),
+                    (None, Some(policy)) => Some(policy_script_config(policy, &fingerprint)?),
                     (None, None) => None,
                 };
-                let (transaction, our_keys) =
-                    Transaction::from_psbt(&fingerprint, &psbt, force_script_config)?;
+                let (transaction, our_keys) = Transaction::from_psbt(
+                    &fingerprint,
+                    &psbt,
+                    force_script_config,
+                    policy.as_ref().map(|policy| &policy.wallet),
+                )?;
+                let silent_payment_inputs = if transaction.has_silent_payment_outputs() {
+                    Some(SilentPaymentInputs::from_psbt(&psbt, &our_keys)?)
+                } else {
+                    None
+                };
                 let coin = api::coin_from_network(self.network);
 
                 let init_request = build_sign_init_request(coin, &transaction);
@@ -1076,19 +1303,30 @@ where
                     sigs: Vec::new(),
                     is_inputs_pass2: false,
                     phase: SignPhase::ExpectNext,
+                    silent_payment_inputs,
                 }));
                 Ok(Some(encrypted_transmit(bytes).into()))
             }
-            State::SignPsbtWaitNext(ctx) => match self.drive_sign(ctx, data)? {
-                SignStep::Continue { ctx, bytes } => {
-                    self.state = State::SignPsbtWaitNext(ctx);
-                    Ok(Some(encrypted_transmit(bytes).into()))
-                }
-                SignStep::Done { psbt } => {
-                    self.state = State::Finished(BitBoxResponse::SignedPsbt(psbt));
-                    Ok(None)
+            State::SignPsbtWaitNext(ctx) => {
+                let signed = ctx.sigs.len();
+                match self.drive_sign(ctx, data)? {
+                    SignStep::Continue { ctx, bytes } => {
+                        // The second pass over the inputs returns their signatures one by one.
+                        if ctx.sigs.len() > signed {
+                            self.events.push_back(Event::Progress {
+                                done: ctx.sigs.len(),
+                                total: ctx.transaction.inputs.len(),
+                            });
+                        }
+                        self.state = State::SignPsbtWaitNext(ctx);
+                        Ok(Some(encrypted_transmit(bytes).into()))
+                    }
+                    SignStep::Done { psbt } => {
+                        self.state = State::Finished(BitBoxResponse::SignedPsbt(psbt));
+                        Ok(None)
+                    }
                 }
-            },
+            }
             State::SignMessageWaitCommitment { host_nonce } => {
                 let body = expect_success(&data)?;
                 let decrypted = self.noise.decrypt(body)?;
@@ -1325,6 +1563,17 @@ where
             _ => Err(BitBoxError::UnexpectedResponse.into()),
         }
     }
+
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+impl TryFrom<RawCommand<BitBoxCommand>> for BitBoxCommand {
+    type Error = BitBoxError;
+    fn try_from(cmd: RawCommand<BitBoxCommand>) -> Result<Self, Self::Error> {
+        Ok(cmd.0)
+    }
 }
 
 impl TryFrom<Command> for BitBoxCommand {
@@ -1372,6 +1621,8 @@ impl TryFrom<Command> for BitBoxCommand {
                 })
             }
             Command::TogglePassphrase => Ok(BitBoxCommand::TogglePassphrase),
+            // The passphrase is only ever typed on the device.
+            Command::SetPassphrase(_) => Err(BitBoxError::Unsupported("set passphrase")),
             Command::Unlock { .. } => Ok(BitBoxCommand::UnlockAndPair),
             Command::GetVersion => Ok(BitBoxCommand::GetVersion),
             Command::GetMasterFingerprint => Ok(BitBoxCommand::GetMasterFingerprint),
@@ -1452,7 +1703,13 @@ impl TryFrom<Command> for BitBoxCommand {
                 keypath: path,
                 message,
             }),
+            // The firmware refuses the `to_sign` transaction: its version is 0 and its only
+            // output a bare OP_RETURN.
+            Command::SignMessageBip322 { .. } => {
+                Err(BitBoxError::Unsupported("BIP-322 message signing"))
+            }
             Command::Backup => Ok(BitBoxCommand::Backup),
+            Command::VerifyAttestation => Ok(BitBoxCommand::VerifyAttestation),
         }
     }
 }
@@ -1475,6 +1732,10 @@ impl From<BitBoxResponse> for Response {
             BitBoxResponse::SignedPsbt(psbt) => Response::SignedPsbt(*psbt),
             BitBoxResponse::Signature(header, sig) => Response::Signature(header, sig),
             BitBoxResponse::Backup => Response::Backup(DeviceBackup::Complete),
+            BitBoxResponse::Backups(_)
+            | BitBoxResponse::BackupCheck(_)
+            | BitBoxResponse::SdCardInserted(_) => Response::TaskDone,
+            BitBoxResponse::Attestation(attestation) => Response::Attestation(attestation),
         }
     }
 }
@@ -1486,9 +1747,21 @@ impl From<BitBoxError> for Error {
                 Error::AuthenticationRefused
             }
             BitBoxError::NoisePairingRejected => Error::AuthenticationRefused,
+            BitBoxError::Unsupported(operation) => Error::Unsupported {
+                device: "BitBox02",
+                operation,
+            },
             BitBoxError::ProtobufDecode(s) | BitBoxError::ProtobufEncode(s) => {
                 Error::Serialization(s)
             }
+            BitBoxError::Device(ref code) => Error::Device(code.into(), e.to_string()),
+            BitBoxError::Version(_) => Error::Device(ErrorKind::Unsupported, e.to_string()),
+            BitBoxError::InvalidInput(_) => Error::Device(ErrorKind::InvalidInput, e.to_string()),
+            BitBoxError::Transport(_) | BitBoxError::Timeout => {
+                Error::Device(ErrorKind::Transport, e.to_string())
+            }
+            BitBoxError::Cancelled => Error::Device(ErrorKind::UserCancelled, e.to_string()),
+            BitBoxError::PendingReply => Error::Device(ErrorKind::Busy, e.to_string()),
             other => Error::Serialization(other.to_string()),
         }
     }
@@ -1595,6 +1868,14 @@ mod tests {
         assert!(matches!(command, BitBoxCommand::Backup));
     }
 
+    #[test]
+    fn only_prompting_commands_await_the_user() {
+        assert!(BitBoxCommand::Backup.awaits_user());
+        assert!(BitBoxCommand::CheckBackup { silent: false }.awaits_user());
+        assert!(!BitBoxCommand::CheckBackup { silent: true }.awaits_user());
+        assert!(!BitBoxCommand::GetMasterFingerprint.awaits_user());
+    }
+
     #[test]
     fn common_setup_maps_to_bitbox_setup() {
         let command = BitBoxCommand::try_from(Command::Setup(
@@ -1702,9 +1983,101 @@ mod tests {
         ));
     }
 
+    #[test]
+    fn common_set_passphrase_is_unsupported() {
+        let error = BitBoxCommand::try_from(Command::SetPassphrase(None))
+            .err()
+            .expect("unsupported");
+        assert!(matches!(
+            Error::from(error),
+            Error::Unsupported {
+                device: "BitBox02",
+                operation: "set passphrase"
+            }
+        ));
+    }
+
+    #[test]
+    fn bip322_messages_are_unsupported() {
+        use bitcoin::PublicKey;
+        use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+        // The firmware only takes OP_RETURN outputs with a data push.
+        let secp = Secp256k1::new();
+        let pubkey = PublicKey::new(SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp));
+        let fingerprint = Fingerprint::from_str("f5acc2fd").unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let psbt = crate::bip322::to_sign_psbt(
+            b"Hello World",
+            AddressType::P2wpkh,
+            pubkey,
+            fingerprint,
+            path.clone(),
+        )
+        .unwrap();
+        assert!(matches!(
+            Transaction::from_psbt(fingerprint.as_bytes(), &psbt, None, None),
+            Err(BitBoxError::BtcSign(message)) if message.contains("OP_RETURN")
+        ));
+
+        let error = BitBoxCommand::try_from(Command::SignMessageBip322 {
+            message: b"Hello World".to_vec(),
+            path,
+            address_type: AddressType::P2wpkh,
+        })
+        .err()
+        .expect("unsupported");
+        assert!(matches!(
+            Error::from(error),
+            Error::Unsupported {
+                device: "BitBox02",
+                operation: "BIP-322 message signing"
+            }
+        ));
+    }
+
     #[test]
     fn bitbox_backup_response_maps_to_completed_backup() {
         let response = Response::from(BitBoxResponse::Backup);
         assert!(matches!(response, Response::Backup(DeviceBackup::Complete)));
     }
+
+    #[test]
+    fn sdcard_backup_responses_are_bitbox_only() {
+        let backups = BitBoxResponse::Backups(vec![BackupInfo::from(pb::BackupInfo {
+            id: "backup-id".to_string(),
+            timestamp: 1_750_000_000,
+            name: "My BitBox".to_string(),
+        })]);
+        assert!(matches!(Response::from(backups), Response::TaskDone));
+        assert!(matches!(
+            Response::from(BitBoxResponse::BackupCheck(None)),
+            Response::TaskDone
+        ));
+        assert!(matches!(
+            Response::from(BitBoxResponse::SdCardInserted(true)),
+            Response::TaskDone
+        ));
+    }
+    #[test]
+    fn attestation_challenges_the_device_without_pairing() {
+        let mut noise = NoiseState::new(None);
+        let mut interpreter: BitBoxInterpreter<'_, Command, Transmit, Response, Error> =
+            BitBoxInterpreter::new(&mut noise);
+        let transmit = interpreter.start(Command::VerifyAttestation).unwrap();
+        assert!(!transmit.encrypted);
+        assert_eq!(transmit.payload.len(), 33);
+        assert_eq!(transmit.payload[0], b'a');
+
+        // A root identifier no bundled key hashes to.
+        let mut reply = vec![RESPONSE_SUCCESS];
+        reply.extend_from_slice(&[0; 160]);
+        reply.extend_from_slice(&[0xff; 32]);
+        reply.extend_from_slice(&[0; 64]);
+        assert!(interpreter.exchange(reply).unwrap().is_none());
+        assert!(matches!(
+            interpreter.end().unwrap(),
+            Response::Attestation(Attestation::UnknownRoot)
+        ));
+    }
 }
@@ -1,19 +1,27 @@
 pub mod antiklepto;
 pub mod api;
+pub mod attestation;
+pub mod bootloader;
 pub mod error;
 pub mod interpreter;
 pub mod noise;
+pub mod paymentrequest;
 pub mod policy;
 pub mod proto;
 pub mod sign;
+pub mod silentpayment;
 pub mod u2f;
 
 pub use interpreter::{BitBoxCommand, BitBoxInterpreter, BitBoxResponse};
 
 use std::fmt;
 
+use bitcoin::address::AddressType;
+use bitcoin::sighash::EcdsaSighashType;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+use crate::common::{BackupMode, Capabilities, CommandKind, Info, version_at_least};
+
 /// Host entropy used when initializing a new BitBox02 wallet.
 ///
 /// The custom `Debug` implementation prevents seed material from being exposed by callers
@@ -60,6 +68,55 @@ pub enum ManagementContext {
     },
 }
 
+/// A wallet backup stored on the microSD card.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackupInfo {
+    pub id: String,
+    /// Creation time, in seconds since the Unix epoch.
+    pub timestamp: u32,
+    /// Device name at the time of the backup.
+    pub name: String,
+}
+
+impl From<proto::BackupInfo> for BackupInfo {
+    fn from(info: proto::BackupInfo) -> Self {
+        Self {
+            id: info.id,
+            timestamp: info.timestamp,
+            name: info.name,
+        }
+    }
+}
+
+/// Capabilities of the BitBox02 firmware reported in `info`. Taproot single-sig came with
+/// v9.10.0 and wallet policies with v9.15.0.
+pub fn capabilities(info: &Info) -> Capabilities {
+    let taproot = version_at_least(&info.version, (9, 10, 0));
+    let mut commands = CommandKind::BASE.to_vec();
+    commands.extend([
+        CommandKind::Backup,
+        CommandKind::Setup,
+        CommandKind::Wipe,
+        CommandKind::Restore,
+        CommandKind::TogglePassphrase,
+        CommandKind::RegisterWallet,
+        CommandKind::VerifyAttestation,
+    ]);
+    let mut script_types = vec![AddressType::P2sh, AddressType::P2wpkh];
+    if taproot {
+        script_types.push(AddressType::P2tr);
+    }
+    Capabilities {
+        commands,
+        script_types,
+        taproot,
+        miniscript: version_at_least(&info.version, (9, 15, 0)),
+        multisig: true,
+        sighash_types: vec![EcdsaSighashType::All],
+        backups: vec![BackupMode::MicroSd],
+    }
+}
+
 /// USB VID/PID of the BitBox02.
 pub const BITBOX02_VID: u16 = 0x03eb;
 pub const BITBOX02_PID: u16 = 0x2403;
@@ -77,11 +134,20 @@ pub const BITBOX02_PRODUCT_STRINGS: &[&str] = &[
     "BitBox02 Nova BTC-only",
 ];
 
+/// HID product strings of the BitBox02 bootloader, which shares the firmware's VID/PID.
+pub const BITBOX02_BOOTLOADER_PRODUCT_STRINGS: &[&str] = &[
+    "bb02-bootloader",
+    "bb02btc-bootloader",
+    "bb02p-bootloader",
+    "bb02pbtc-bootloader",
+];
+
 pub const OP_UNLOCK: u8 = b'u';
 pub const OP_I_CAN_HAS_HANDSHAEK: u8 = b'h';
 pub const OP_HER_COMEZ_TEH_HANDSHAEK: u8 = b'H';
 pub const OP_I_CAN_HAS_PAIRIN_VERIFICASHUN: u8 = b'v';
 pub const OP_NOISE_MSG: u8 = b'n';
+pub const OP_ATTESTATION: u8 = b'a';
 pub const RESPONSE_SUCCESS: u8 = 0x00;
 
 #[cfg(test)]
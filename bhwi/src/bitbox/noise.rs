@@ -39,15 +39,9 @@ impl NoiseConfigData {
     }
 }
 
-/// Called synchronously by the interpreter the moment the pairing code becomes available
-/// (right before emitting `OP_I_CAN_HAS_PAIRIN_VERIFICASHUN`). The caller is expected to
-/// display the code so the user can confirm it on the device screen.
-pub type PairingCodeHook = Box<dyn FnMut(&str)>;
-
 /// Persistent noise state held by the async wrapper across calls.
 pub struct NoiseState {
     inner: NoiseInner,
-    pairing_code_hook: Option<PairingCodeHook>,
 }
 
 /// - `Idle`: pre-handshake or ready to re-handshake with cached data.
@@ -70,21 +64,6 @@ impl NoiseState {
             inner: NoiseInner::Idle {
                 data: data.unwrap_or_default(),
             },
-            pairing_code_hook: None,
-        }
-    }
-
-    /// Install a hook that fires the moment the pairing code becomes available during a
-    /// first-time pair. The hook runs synchronously inside `Interpreter::exchange`, so it
-    /// must be non-blocking (e.g. `eprintln!` / `log::info!` / a channel send).
-    pub fn set_pairing_code_hook(&mut self, hook: PairingCodeHook) {
-        self.pairing_code_hook = Some(hook);
-    }
-
-    /// Fire the pairing-code hook if one is installed.
-    pub(crate) fn on_pairing_code(&mut self, code: &str) {
-        if let Some(hook) = self.pairing_code_hook.as_mut() {
-            hook(code);
         }
     }
 
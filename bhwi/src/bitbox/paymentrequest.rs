@@ -0,0 +1,216 @@
+//! SLIP-24 payment requests for BitBox02 signing.
+//!
+//! A payment request is signed by a payment provider the firmware trusts, so the device can
+//! show the recipient name and memos of a payment instead of a bare address. Requests are
+//! attached to the outputs they pay in a `bhwi` proprietary PSBT output field holding the
+//! protobuf encoded `BTCPaymentRequestRequest`; outputs paid by one request carry the same
+//! value. When the device reaches one of these outputs it asks for the request by index.
+
+use bitcoin::psbt::{Output, raw};
+use prost::Message;
+
+use super::error::BitBoxError;
+use super::proto as pb;
+use pb::btc_payment_request_request::{Memo as PbMemo, memo};
+
+/// Prefix of the proprietary PSBT fields defined by bhwi.
+pub const PSBT_PROPRIETARY_PREFIX: &[u8] = b"bhwi";
+/// Subtype of the proprietary output field carrying a SLIP-24 payment request.
+pub const PSBT_OUT_PAYMENT_REQUEST: u8 = 0x00;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Memo {
+    /// Free text shown to the user, such as an invoice reference.
+    Text(String),
+}
+
+/// A SLIP-24 payment request, as issued and signed by the payment provider.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PaymentRequest {
+    pub recipient_name: String,
+    pub memos: Vec<Memo>,
+    pub nonce: Vec<u8>,
+    /// Sum of the outputs paying the request, in satoshis.
+    pub total_amount: u64,
+    pub signature: Vec<u8>,
+}
+
+fn proprietary_key() -> raw::ProprietaryKey {
+    raw::ProprietaryKey {
+        prefix: PSBT_PROPRIETARY_PREFIX.to_vec(),
+        subtype: PSBT_OUT_PAYMENT_REQUEST,
+        key: vec![],
+    }
+}
+
+impl PaymentRequest {
+    /// Read the payment request an output pays, if any.
+    pub fn from_psbt_output(output: &Output) -> Result<Option<Self>, BitBoxError> {
+        let Some(value) = output.proprietary.get(&proprietary_key()) else {
+            return Ok(None);
+        };
+        let request = pb::BtcPaymentRequestRequest::decode(value.as_slice())
+            .map_err(|_| BitBoxError::Psbt("invalid payment request".into()))?;
+        Ok(Some(request.into()))
+    }
+
+    /// Mark `output` as paying this request.
+    pub fn add_to_psbt_output(&self, output: &mut Output) {
+        output.proprietary.insert(
+            proprietary_key(),
+            pb::BtcPaymentRequestRequest::from(self).encode_to_vec(),
+        );
+    }
+}
+
+impl From<pb::BtcPaymentRequestRequest> for PaymentRequest {
+    fn from(request: pb::BtcPaymentRequestRequest) -> Self {
+        PaymentRequest {
+            recipient_name: request.recipient_name,
+            memos: request
+                .memos
+                .into_iter()
+                .filter_map(|m| match m.memo {
+                    Some(memo::Memo::TextMemo(text)) => Some(Memo::Text(text.note)),
+                    None => None,
+                })
+                .collect(),
+            nonce: request.nonce,
+            total_amount: request.total_amount,
+            signature: request.signature,
+        }
+    }
+}
+
+impl From<&PaymentRequest> for pb::BtcPaymentRequestRequest {
+    fn from(request: &PaymentRequest) -> Self {
+        pb::BtcPaymentRequestRequest {
+            recipient_name: request.recipient_name.clone(),
+            memos: request
+                .memos
+                .iter()
+                .map(|m| match m {
+                    Memo::Text(note) => PbMemo {
+                        memo: Some(memo::Memo::TextMemo(memo::TextMemo { note: note.clone() })),
+                    },
+                })
+                .collect(),
+            nonce: request.nonce.clone(),
+            total_amount: request.total_amount,
+            signature: request.signature.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::bip32::{DerivationPath, Fingerprint, Xpriv};
+    use bitcoin::psbt::Psbt;
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{
+        Amount, Network, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Witness,
+        absolute, transaction,
+    };
+
+    use super::*;
+    use crate::bitbox::sign::{self, TxOutput};
+
+    fn request(total_amount: u64) -> PaymentRequest {
+        PaymentRequest {
+            recipient_name: "Merchant".into(),
+            memos: vec![Memo::Text("Order #42".into())],
+            nonce: vec![],
+            total_amount,
+            signature: vec![7; 64],
+        }
+    }
+
+    fn external(value: u64) -> TxOut {
+        TxOut {
+            value: Amount::from_sat(value),
+            script_pubkey: ScriptBuf::new_p2wsh(&ScriptBuf::new().wscript_hash()),
+        }
+    }
+
+    /// A PSBT spending one of our P2WPKH coins to `outputs`.
+    fn psbt(outputs: Vec<TxOut>) -> (Fingerprint, Psbt) {
+        let secp = Secp256k1::new();
+        let master = Xpriv::new_master(Network::Testnet, &[1; 32]).unwrap();
+        let path = DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap();
+        let pubkey = master
+            .derive_priv(&secp, &path)
+            .unwrap()
+            .private_key
+            .public_key(&secp);
+        let fingerprint = master.fingerprint(&secp);
+        let utxo = TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(
+                &bitcoin::CompressedPublicKey(pubkey).wpubkey_hash(),
+            ),
+        };
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::MAX,
+                witness: Witness::new(),
+            }],
+            output: outputs,
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(utxo);
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(pubkey, (fingerprint, path));
+        (fingerprint, psbt)
+    }
+
+    #[test]
+    fn round_trips_through_psbt_output() {
+        let mut output = Output::default();
+        assert_eq!(PaymentRequest::from_psbt_output(&output).unwrap(), None);
+        request(1000).add_to_psbt_output(&mut output);
+        assert_eq!(
+            PaymentRequest::from_psbt_output(&output).unwrap(),
+            Some(request(1000))
+        );
+
+        output
+            .proprietary
+            .insert(proprietary_key(), vec![0xff, 0xff]);
+        assert!(PaymentRequest::from_psbt_output(&output).is_err());
+    }
+
+    #[test]
+    fn outputs_of_one_request_share_its_index() {
+        let (fingerprint, mut psbt) = psbt(vec![external(600), external(400), external(5000)]);
+        request(1000).add_to_psbt_output(&mut psbt.outputs[0]);
+        request(1000).add_to_psbt_output(&mut psbt.outputs[1]);
+        let (transaction, _) =
+            sign::Transaction::from_psbt(fingerprint.as_bytes(), &psbt, None, None).unwrap();
+
+        assert_eq!(transaction.payment_requests, vec![request(1000)]);
+        let indexes: Vec<_> = transaction
+            .outputs
+            .iter()
+            .map(|output| match output {
+                TxOutput::External(o) => o.payment_request_index,
+                _ => panic!("external outputs only"),
+            })
+            .collect();
+        assert_eq!(indexes, vec![Some(0), Some(0), None]);
+    }
+
+    #[test]
+    fn total_amount_must_match_the_outputs() {
+        let (fingerprint, mut psbt) = psbt(vec![external(600), external(400)]);
+        request(600).add_to_psbt_output(&mut psbt.outputs[0]);
+        request(600).add_to_psbt_output(&mut psbt.outputs[1]);
+        assert!(sign::Transaction::from_psbt(fingerprint.as_bytes(), &psbt, None, None).is_err());
+    }
+}
@@ -9,6 +9,8 @@ use super::proto as pb;
 pub struct Policy {
     pub template: String,
     pub pubkeys: Vec<KeyInfo>,
+    /// The policy this was built from, to tell change outputs apart when signing.
+    pub wallet: WalletPolicy,
 }
 
 #[derive(Clone, Debug)]
@@ -36,7 +38,11 @@ impl Policy {
                 })
             })
             .collect::<Result<Vec<_>, BitBoxError>>()?;
-        Ok(Policy { template, pubkeys })
+        Ok(Policy {
+            template,
+            pubkeys,
+            wallet: policy.clone(),
+        })
     }
 }
 
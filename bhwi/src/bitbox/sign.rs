@@ -16,9 +16,14 @@ use bitcoin::{
     blockdata::{opcodes, script::Instruction},
 };
 
+use miniscript::descriptor::WalletPolicy;
+
 use super::api::make_script_config_simple;
 use super::error::BitBoxError;
+use super::paymentrequest::PaymentRequest;
 use super::proto as pb;
+use super::silentpayment::SilentPaymentAddress;
+use crate::preview::TxPreview;
 
 /// The leading run of hardened elements of a derivation path (the account-level prefix).
 fn hardened_prefix(path: &DerivationPath) -> DerivationPath {
@@ -192,6 +197,8 @@ impl Payload {
 pub struct TxExternalOutput {
     pub payload: Payload,
     pub value: u64,
+    /// References a payment request of the transaction.
+    pub payment_request_index: Option<u32>,
 }
 
 impl TryFrom<&bitcoin::TxOut> for TxExternalOutput {
@@ -200,14 +207,23 @@ impl TryFrom<&bitcoin::TxOut> for TxExternalOutput {
         Ok(TxExternalOutput {
             payload: Payload::from_pkscript(value.script_pubkey.as_bytes())?,
             value: value.value.to_sat(),
+            payment_request_index: None,
         })
     }
 }
 
+/// Output paying a BIP-352 silent payment address. The device generates the output script.
+#[derive(Debug, PartialEq)]
+pub struct TxSilentPaymentOutput {
+    pub address: SilentPaymentAddress,
+    pub value: u64,
+}
+
 #[derive(Debug, PartialEq)]
 pub enum TxOutput {
     Internal(TxInternalOutput),
     External(TxExternalOutput),
+    SilentPayment(TxSilentPaymentOutput),
 }
 
 #[derive(Debug, PartialEq)]
@@ -217,6 +233,7 @@ pub struct Transaction {
     pub inputs: Vec<TxInput>,
     pub outputs: Vec<TxOutput>,
     pub locktime: u32,
+    pub payment_requests: Vec<PaymentRequest>,
 }
 
 /// Per-input key info recorded during PSBT lowering. Used at the end of the sign flow
@@ -379,10 +396,19 @@ fn script_config_from_utxo(
 }
 
 impl Transaction {
+    pub fn has_silent_payment_outputs(&self) -> bool {
+        self.outputs
+            .iter()
+            .any(|output| matches!(output, TxOutput::SilentPayment(_)))
+    }
+
+    /// Lowers `psbt`. Outputs are internal when [`TxPreview`] counts them as change under
+    /// `policy`, or as single-sig change without one, and carry a key of ours.
     pub fn from_psbt(
         our_root_fingerprint: &[u8],
         psbt: &bitcoin::psbt::Psbt,
         force_script_config: Option<pb::BtcScriptConfigWithKeypath>,
+        policy: Option<&WalletPolicy>,
     ) -> Result<(Self, Vec<OurKey>), BitBoxError> {
         let mut script_configs: Vec<pb::BtcScriptConfigWithKeypath> = Vec::new();
         let mut is_script_config_forced = false;
@@ -433,11 +459,54 @@ impl Transaction {
             our_keys.push(our_key);
         }
 
+        let change: Vec<usize> = TxPreview::new(psbt, policy)
+            .map_err(|e| BitBoxError::Psbt(e.to_string()))?
+            .change
+            .iter()
+            .map(|output| output.index)
+            .collect();
         let mut outputs: Vec<TxOutput> = Vec::new();
-        for (tx_output, psbt_output) in psbt.unsigned_tx.output.iter().zip(&psbt.outputs) {
+        let mut payment_requests: Vec<PaymentRequest> = Vec::new();
+        let mut payment_request_amounts: Vec<u64> = Vec::new();
+        for (index, (tx_output, psbt_output)) in psbt
+            .unsigned_tx
+            .output
+            .iter()
+            .zip(&psbt.outputs)
+            .enumerate()
+        {
+            if let Some(request) = PaymentRequest::from_psbt_output(psbt_output)? {
+                if find_our_key(our_root_fingerprint, psbt_output).is_ok()
+                    || SilentPaymentAddress::from_psbt_output(psbt_output)?.is_some()
+                {
+                    return Err(BitBoxError::BtcSign(
+                        "payment requests only apply to external outputs".into(),
+                    ));
+                }
+                let index = match payment_requests.iter().position(|el| el == &request) {
+                    Some(pos) => pos,
+                    None => {
+                        payment_requests.push(request);
+                        payment_request_amounts.push(0);
+                        payment_requests.len() - 1
+                    }
+                };
+                payment_request_amounts[index] += tx_output.value.to_sat();
+                let mut output = TxExternalOutput::try_from(tx_output)?;
+                output.payment_request_index = Some(index as _);
+                outputs.push(TxOutput::External(output));
+                continue;
+            }
+            if let Some(address) = SilentPaymentAddress::from_psbt_output(psbt_output)? {
+                outputs.push(TxOutput::SilentPayment(TxSilentPaymentOutput {
+                    address,
+                    value: tx_output.value.to_sat(),
+                }));
+                continue;
+            }
             let our_key = find_our_key(our_root_fingerprint, psbt_output);
             match our_key {
-                Ok(our_key) => {
+                Ok(our_key) if change.contains(&index) => {
                     let script_config_index = if is_script_config_forced {
                         0
                     } else {
@@ -453,11 +522,20 @@ impl Transaction {
                         script_config_index: script_config_index as _,
                     }));
                 }
-                Err(_) => {
+                _ => {
                     outputs.push(TxOutput::External(tx_output.try_into()?));
                 }
             }
         }
+        if payment_requests
+            .iter()
+            .zip(&payment_request_amounts)
+            .any(|(request, amount)| request.total_amount != *amount)
+        {
+            return Err(BitBoxError::BtcSign(
+                "payment request total does not match its outputs".into(),
+            ));
+        }
 
         Ok((
             Transaction {
@@ -466,6 +544,7 @@ impl Transaction {
                 inputs,
                 outputs,
                 locktime: psbt.unsigned_tx.lock_time.to_consensus_u32(),
+                payment_requests,
             },
             our_keys,
         ))
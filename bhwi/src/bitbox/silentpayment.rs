@@ -0,0 +1,335 @@
+//! BIP-352 silent payment outputs for BitBox02 signing.
+//!
+//! Recipients are read from the BIP-375 `PSBT_OUT_SP_V0_INFO` output field and sent to the
+//! device as `sp1...` addresses. The device generates the output script itself, so the host
+//! recomputes it from the ECDH share and checks the BIP-374 DLEQ proof that the share was
+//! computed with the input keys being spent.
+
+use bitcoin::consensus::encode::serialize;
+use bitcoin::psbt::{Output, Psbt, raw};
+use bitcoin::secp256k1::{
+    ONE_KEY, Parity, PublicKey, Scalar, Secp256k1, SecretKey, XOnlyPublicKey,
+};
+use bitcoin::{Network, ScriptBuf};
+
+use super::antiklepto::tagged_sha256;
+use super::error::BitBoxError;
+use super::sign::OurKey;
+
+/// Type: Silent Payment v0 Info PSBT_OUT_SP_V0_INFO = 0x09
+pub const PSBT_OUT_SP_V0_INFO: u8 = 0x09;
+
+/// Length of the device's proof: the 33-byte ECDH share followed by the 64-byte DLEQ proof.
+const DLEQ_PROOF_LEN: usize = 33 + 64;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SilentPaymentAddress {
+    pub scan_pubkey: PublicKey,
+    pub spend_pubkey: PublicKey,
+}
+
+impl SilentPaymentAddress {
+    /// Read the recipient of a silent payment output from its `PSBT_OUT_SP_V0_INFO` field.
+    pub fn from_psbt_output(output: &Output) -> Result<Option<Self>, BitBoxError> {
+        let key = raw::Key {
+            type_value: PSBT_OUT_SP_V0_INFO,
+            key: vec![],
+        };
+        let Some(value) = output.unknown.get(&key) else {
+            return Ok(None);
+        };
+        if value.len() != 66 {
+            return Err(BitBoxError::Psbt("invalid silent payment info".into()));
+        }
+        let parse = |bytes: &[u8]| {
+            PublicKey::from_slice(bytes)
+                .map_err(|_| BitBoxError::Psbt("invalid silent payment key".into()))
+        };
+        Ok(Some(Self {
+            scan_pubkey: parse(&value[..33])?,
+            spend_pubkey: parse(&value[33..])?,
+        }))
+    }
+
+    /// Version 0 bech32m encoding of the address.
+    pub fn encode(&self, network: Network) -> String {
+        let hrp = if network == Network::Bitcoin {
+            "sp"
+        } else {
+            "tsp"
+        };
+        let mut payload = self.scan_pubkey.serialize().to_vec();
+        payload.extend_from_slice(&self.spend_pubkey.serialize());
+        bech32m_encode(hrp, 0, &payload)
+    }
+}
+
+/// Sum of the input public keys and the smallest outpoint of the transaction, the two values
+/// BIP-352 derives the shared secret from.
+pub struct SilentPaymentInputs {
+    pub pubkey_sum: PublicKey,
+    pub smallest_outpoint: Vec<u8>,
+}
+
+impl SilentPaymentInputs {
+    /// Taproot inputs contribute their even-y output key, segwit v0 inputs the device key.
+    pub fn from_psbt(psbt: &Psbt, our_keys: &[OurKey]) -> Result<Self, BitBoxError> {
+        let mut pubkeys = Vec::with_capacity(our_keys.len());
+        for (input_index, our_key) in our_keys.iter().enumerate() {
+            let utxo = psbt
+                .spend_utxo(input_index)
+                .map_err(|e| BitBoxError::Psbt(e.to_string()))?;
+            let pubkey = match our_key {
+                OurKey::Segwit(pubkey, _) => *pubkey,
+                OurKey::TaprootInternal(_) if utxo.script_pubkey.is_p2tr() => {
+                    XOnlyPublicKey::from_slice(&utxo.script_pubkey.as_bytes()[2..34])
+                        .map_err(|_| BitBoxError::Psbt("invalid taproot output key".into()))?
+                        .public_key(Parity::Even)
+                }
+                _ => {
+                    return Err(BitBoxError::BtcSign(
+                        "silent payments require key path inputs".into(),
+                    ));
+                }
+            };
+            pubkeys.push(pubkey);
+        }
+        let pubkey_sum = PublicKey::combine_keys(&pubkeys.iter().collect::<Vec<_>>())
+            .map_err(|_| BitBoxError::BtcSign("invalid silent payment input keys".into()))?;
+        let smallest_outpoint = psbt
+            .unsigned_tx
+            .input
+            .iter()
+            .map(|input| serialize(&input.previous_output))
+            .min()
+            .ok_or(BitBoxError::BtcSign("transaction has no inputs".into()))?;
+        Ok(Self {
+            pubkey_sum,
+            smallest_outpoint,
+        })
+    }
+}
+
+/// Check the output script generated by the device for the `k`-th output paying `address`'s
+/// scan key. Returns the verified script.
+pub fn verify_output(
+    inputs: &SilentPaymentInputs,
+    address: &SilentPaymentAddress,
+    k: u32,
+    dleq_proof: &[u8],
+    pkscript: &[u8],
+) -> Result<ScriptBuf, BitBoxError> {
+    let invalid = || BitBoxError::BtcSign("silent payment output verification failed".into());
+    if dleq_proof.len() != DLEQ_PROOF_LEN {
+        return Err(invalid());
+    }
+    let ecdh_share = PublicKey::from_slice(&dleq_proof[..33]).map_err(|_| invalid())?;
+    let proof: &[u8; 64] = dleq_proof[33..].try_into().expect("length checked");
+    if !verify_dleq(&inputs.pubkey_sum, &address.scan_pubkey, &ecdh_share, proof) {
+        return Err(invalid());
+    }
+
+    let secp = Secp256k1::verification_only();
+    let mut input_hash_data = inputs.smallest_outpoint.clone();
+    input_hash_data.extend_from_slice(&inputs.pubkey_sum.serialize());
+    let input_hash =
+        scalar(tagged_sha256(b"BIP0352/Inputs", &input_hash_data)).ok_or_else(invalid)?;
+    let shared_secret = ecdh_share
+        .mul_tweak(&secp, &input_hash)
+        .map_err(|_| invalid())?;
+    let mut tweak_data = shared_secret.serialize().to_vec();
+    tweak_data.extend_from_slice(&k.to_be_bytes());
+    let tweak = scalar(tagged_sha256(b"BIP0352/SharedSecret", &tweak_data)).ok_or_else(invalid)?;
+    let output_key = address
+        .spend_pubkey
+        .add_exp_tweak(&secp, &tweak)
+        .map_err(|_| invalid())?;
+
+    let expected = ScriptBuf::new_p2tr_tweaked(
+        bitcoin::key::TweakedPublicKey::dangerous_assume_tweaked(output_key.x_only_public_key().0),
+    );
+    if expected.as_bytes() != pkscript {
+        return Err(invalid());
+    }
+    Ok(expected)
+}
+
+fn scalar(bytes: [u8; 32]) -> Option<Scalar> {
+    Scalar::from_be_bytes(bytes).ok()
+}
+
+/// BIP-374 verification that `log_G(a) == log_b(c)`, without message.
+fn verify_dleq(a: &PublicKey, b: &PublicKey, c: &PublicKey, proof: &[u8; 64]) -> bool {
+    let secp = Secp256k1::new();
+    let Ok(e) = Scalar::from_be_bytes(proof[..32].try_into().expect("32 bytes")) else {
+        return false;
+    };
+    let Ok(s) = SecretKey::from_slice(&proof[32..]) else {
+        return false;
+    };
+    let generator = PublicKey::from_secret_key(&secp, &ONE_KEY);
+    // R1 = s*G - e*A, R2 = s*B - e*C
+    let r1 = a
+        .mul_tweak(&secp, &e)
+        .and_then(|ea| PublicKey::from_secret_key(&secp, &s).combine(&ea.negate(&secp)));
+    let r2 = b.mul_tweak(&secp, &Scalar::from(s)).and_then(|sb| {
+        c.mul_tweak(&secp, &e)
+            .and_then(|ec| sb.combine(&ec.negate(&secp)))
+    });
+    let (Ok(r1), Ok(r2)) = (r1, r2) else {
+        return false;
+    };
+    let mut challenge = Vec::with_capacity(6 * 33);
+    for point in [a, b, c, &generator, &r1, &r2] {
+        challenge.extend_from_slice(&point.serialize());
+    }
+    tagged_sha256(b"BIP0374/challenge", &challenge) == proof[..32]
+}
+
+/// Minimal bech32m encoder (BIP-350). Silent payment addresses exceed the 90 characters the
+/// segwit encoders accept, so the checksum is computed inline.
+fn bech32m_encode(hrp: &str, version: u8, payload: &[u8]) -> String {
+    const CHARSET: &[u8; 32] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+    const BECH32M_CONST: u32 = 0x2bc830a3;
+
+    let mut data = vec![version];
+    let mut buffer: u32 = 0;
+    let mut bits: u32 = 0;
+    for &byte in payload {
+        buffer = (buffer << 8) | byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            data.push(((buffer >> bits) & 0x1f) as u8);
+        }
+    }
+    if bits > 0 {
+        data.push(((buffer << (5 - bits)) & 0x1f) as u8);
+    }
+
+    let mut values: Vec<u8> = hrp.bytes().map(|c| c >> 5).collect();
+    values.push(0);
+    values.extend(hrp.bytes().map(|c| c & 0x1f));
+    values.extend_from_slice(&data);
+    values.extend_from_slice(&[0; 6]);
+    let polymod = bech32_polymod(&values) ^ BECH32M_CONST;
+    data.extend((0..6).map(|i| ((polymod >> (5 * (5 - i))) & 0x1f) as u8));
+
+    let mut out = String::with_capacity(hrp.len() + 1 + data.len());
+    out.push_str(hrp);
+    out.push('1');
+    out.extend(data.iter().map(|&d| CHARSET[d as usize] as char));
+    out
+}
+
+fn bech32_polymod(values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+    let mut chk: u32 = 1;
+    for &value in values {
+        let top = chk >> 25;
+        chk = ((chk & 0x1ffffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                chk ^= generator;
+            }
+        }
+    }
+    chk
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn pubkey(hex: &str) -> PublicKey {
+        PublicKey::from_str(hex).unwrap()
+    }
+
+    fn unhex(hex: &str) -> Vec<u8> {
+        <Vec<u8> as bitcoin::hashes::hex::FromHex>::from_hex(hex).unwrap()
+    }
+
+    fn address() -> SilentPaymentAddress {
+        SilentPaymentAddress {
+            scan_pubkey: pubkey(
+                "0220bcfac5b99e04ad1a06ddfb016ee13582609d60b6291e98d01a9bc9a16c96d4",
+            ),
+            spend_pubkey: pubkey(
+                "025cc9856d6f8375350e123978daac200c260cb5b5ae83106cab90484dcd8fcf36",
+            ),
+        }
+    }
+
+    // Generated with a reference implementation of BIP-352 and BIP-374 for a single input.
+    const ECDH_SHARE: &str = "03ea78b611bee37c872299f45e8337996713e85a215c08047bd640d59dd82ab8f4";
+    const PROOF: &str = "7ddf0c74a26614c88682e8c75f130cdd612aae5e9d87ae37d69df889e4360bac128b45ca4f491f4ce761d72212e0166ba2d25113d1e978a1a6a8c2cc50e1250c";
+    const PKSCRIPT: &str = "5120df675c9d98690d8042d8bff52786105d14fb52d3aab086401043376d5dac6cff";
+
+    fn inputs() -> SilentPaymentInputs {
+        // txid bytes in serialization order, vout 1
+        let mut smallest_outpoint =
+            unhex("f4184fc596403b9d638783cf57adfe4c75c605f6356fbc91338530e9831e9e16");
+        smallest_outpoint.extend_from_slice(&1u32.to_le_bytes());
+        SilentPaymentInputs {
+            pubkey_sum: pubkey(
+                "02ae1b954044a20ca73f5fdc3c0dd07ca22af3063444ced1bf209f888afc37ae3d",
+            ),
+            smallest_outpoint,
+        }
+    }
+
+    #[test]
+    fn encodes_bip352_address() {
+        assert_eq!(
+            address().encode(Network::Bitcoin),
+            "sp1qqgste7k9hx0qftg6qmwlkqtwuy6cycyavzmzj85c6qdfhjdpdjtdgqjuexzk6murw56suy3e0rd2cgqvycxttddwsvgxe2usfpxumr70xc9pkqwv"
+        );
+        assert!(address().encode(Network::Testnet).starts_with("tsp1q"));
+    }
+
+    #[test]
+    fn reads_address_from_psbt_output() {
+        let mut output = Output::default();
+        assert_eq!(
+            SilentPaymentAddress::from_psbt_output(&output).unwrap(),
+            None
+        );
+
+        let mut value = address().scan_pubkey.serialize().to_vec();
+        value.extend_from_slice(&address().spend_pubkey.serialize());
+        output.unknown.insert(
+            raw::Key {
+                type_value: PSBT_OUT_SP_V0_INFO,
+                key: vec![],
+            },
+            value,
+        );
+        assert_eq!(
+            SilentPaymentAddress::from_psbt_output(&output).unwrap(),
+            Some(address())
+        );
+    }
+
+    #[test]
+    fn verifies_generated_output() {
+        let mut dleq_proof = unhex(ECDH_SHARE);
+        dleq_proof.extend(unhex(PROOF));
+        let script =
+            verify_output(&inputs(), &address(), 0, &dleq_proof, &unhex(PKSCRIPT)).unwrap();
+        assert_eq!(script.as_bytes(), unhex(PKSCRIPT));
+
+        // Wrong output index.
+        assert!(verify_output(&inputs(), &address(), 1, &dleq_proof, &unhex(PKSCRIPT)).is_err());
+
+        // Tampered proof.
+        let mut tampered = dleq_proof.clone();
+        tampered[40] ^= 0x01;
+        assert!(verify_output(&inputs(), &address(), 0, &tampered, &unhex(PKSCRIPT)).is_err());
+
+        // Output that does not pay the recipient.
+        let mut other = unhex(PKSCRIPT);
+        other[10] ^= 0x01;
+        assert!(verify_output(&inputs(), &address(), 0, &dleq_proof, &other).is_err());
+    }
+}
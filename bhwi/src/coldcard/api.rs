@@ -163,6 +163,69 @@ pub mod request {
     pub fn get_version() -> Vec<u8> {
         b"vers".to_vec()
     }
+
+    /// Longest BIP39 passphrase the firmware accepts, in bytes.
+    pub const MAX_PASSPHRASE_LEN: usize = 99;
+
+    pub fn bip39_passphrase(passphrase: &str) -> Vec<u8> {
+        let mut rv = b"pass".to_vec();
+        rv.extend(passphrase.as_bytes());
+        rv
+    }
+
+    pub fn get_passphrase_done() -> Vec<u8> {
+        b"pwok".to_vec()
+    }
+
+    // https://github.com/Coldcard/ckcc-protocol/blob/master/ckcc/constants.py
+    pub mod user_auth {
+        pub const USER_AUTH_TOTP: u8 = 1;
+        pub const USER_AUTH_HOTP: u8 = 2;
+        pub const USER_AUTH_HMAC: u8 = 3;
+        pub const USER_AUTH_SHOW_QR: u8 = 0x80;
+    }
+
+    /// Start HSM mode with the uploaded policy file, or with the policy already saved on
+    /// the device when `policy` is `None`.
+    pub fn hsm_start(policy: Option<(u32, &[u8; 32])>) -> Vec<u8> {
+        let mut rv = b"hsms".to_vec();
+        if let Some((length, file_sha)) = policy {
+            rv.extend(length.to_le_bytes());
+            rv.extend(file_sha);
+        }
+        rv
+    }
+
+    pub fn hsm_status() -> Vec<u8> {
+        b"hsts".to_vec()
+    }
+
+    pub fn create_user(username: &[u8], auth_mode: u8, secret: &[u8]) -> Vec<u8> {
+        let mut rv = b"nwur".to_vec();
+        rv.push(auth_mode);
+        rv.push(username.len() as u8);
+        rv.push(secret.len() as u8);
+        rv.extend(username);
+        rv.extend(secret);
+        rv
+    }
+
+    pub fn delete_user(username: &[u8]) -> Vec<u8> {
+        let mut rv = b"rmur".to_vec();
+        rv.push(username.len() as u8);
+        rv.extend(username);
+        rv
+    }
+
+    pub fn user_auth(username: &[u8], token: &[u8], totp_time: u32) -> Vec<u8> {
+        let mut rv = b"user".to_vec();
+        rv.extend(totp_time.to_le_bytes());
+        rv.push(username.len() as u8);
+        rv.push(token.len() as u8);
+        rv.extend(username);
+        rv.extend(token);
+        rv
+    }
 }
 
 #[cfg(test)]
@@ -219,6 +282,47 @@ mod tests {
         assert_eq!(super::request::get_backup_file(), b"bkok");
     }
 
+    #[test]
+    fn hsm_requests_encode_policy_and_users() {
+        let hash = [5u8; 32];
+        assert_eq!(super::request::hsm_start(None), b"hsms");
+        let req = super::request::hsm_start(Some((77, &hash)));
+        assert_eq!(&req[..4], b"hsms");
+        assert_eq!(u32::from_le_bytes(req[4..8].try_into().unwrap()), 77);
+        assert_eq!(&req[8..], &hash);
+
+        let req = super::request::create_user(b"alice", 3, &[6u8; 32]);
+        assert_eq!(&req[..7], b"nwur\x03\x05\x20");
+        assert_eq!(&req[7..12], b"alice");
+        assert_eq!(&req[12..], &[6u8; 32]);
+
+        assert_eq!(super::request::delete_user(b"bob"), b"rmur\x03bob");
+
+        let req = super::request::user_auth(b"alice", b"123456", 42);
+        assert_eq!(&req[..4], b"user");
+        assert_eq!(u32::from_le_bytes(req[4..8].try_into().unwrap()), 42);
+        assert_eq!(&req[8..10], &[5, 6]);
+        assert_eq!(&req[10..], b"alice123456");
+    }
+
+    #[test]
+    fn hsm_responses_decode_status_and_device_errors() {
+        match super::response::hsm_status(b"asci{\"active\": false}").unwrap() {
+            crate::coldcard::ColdcardResponse::HsmStatus(status) => {
+                assert_eq!(status["active"], false)
+            }
+            _ => panic!("expected hsm status"),
+        }
+        match super::response::create_user(b"asciJBSWY3DPEHPK3PXP").unwrap() {
+            crate::coldcard::ColdcardResponse::UserSecret(secret) => {
+                assert_eq!(secret, "JBSWY3DPEHPK3PXP")
+            }
+            _ => panic!("expected user secret"),
+        }
+        let error = super::response::hsm_okay(b"err_Auth failed").unwrap_err();
+        assert!(error.to_string().contains("Auth failed"));
+    }
+
     #[test]
     fn upload_response_decodes_acknowledged_offset() {
         let mut res = b"int1".to_vec();
@@ -478,6 +582,68 @@ pub mod response {
         }
     }
 
+    /// HSM requests answer `okay`, or `err_` with the reason the device rejected them.
+    pub fn hsm_okay(res: &[u8]) -> Result<ColdcardResponse, ColdcardError> {
+        match ResponseHandler::parse_response(res)? {
+            (ResponseMessage::Okay, _) => Ok(ColdcardResponse::Ok),
+            (ResponseMessage::Err_, data) => Err(ColdcardError::Device(
+                String::from_utf8_lossy(data).into_owned(),
+            )),
+            (msg, _) => Err(ColdcardError::unexpected_response_message(
+                msg,
+                &[ResponseMessage::Okay],
+            )),
+        }
+    }
+
+    pub fn hsm_status(res: &[u8]) -> Result<ColdcardResponse, ColdcardError> {
+        let data = ResponseHandler::expect_response(res, ResponseMessage::Asci)?;
+        let status = serde_json::from_slice(data)
+            .map_err(|e| ColdcardError::Serialization(e.to_string()))?;
+        Ok(ColdcardResponse::HsmStatus(status))
+    }
+
+    /// The new user's secret: the base32 OTP key, or the password the device picked.
+    pub fn create_user(res: &[u8]) -> Result<ColdcardResponse, ColdcardError> {
+        match ResponseHandler::parse_response(res)? {
+            (ResponseMessage::Asci, data) => Ok(ColdcardResponse::UserSecret(String::from_utf8(
+                data.to_owned(),
+            )?)),
+            (ResponseMessage::Err_, data) => Err(ColdcardError::Device(
+                String::from_utf8_lossy(data).into_owned(),
+            )),
+            (msg, _) => Err(ColdcardError::unexpected_response_message(
+                msg,
+                &[ResponseMessage::Asci],
+            )),
+        }
+    }
+
+    /// `okay` or `busy` until the operator confirms the passphrase, then the master xpub
+    /// of the wallet now in use.
+    pub fn passphrase_done(res: &[u8]) -> Result<Option<ColdcardResponse>, ColdcardError> {
+        match ResponseHandler::parse_response(res)? {
+            (ResponseMessage::Okay, _) | (ResponseMessage::Busy, _) => Ok(None),
+            (ResponseMessage::Asci, _) => Ok(Some(ColdcardResponse::MasterFingerprint(
+                xpub(res)?.fingerprint(),
+            ))),
+            (ResponseMessage::Refu, _) => Err(ColdcardError::Device(
+                "passphrase refused on the device".to_string(),
+            )),
+            (ResponseMessage::Err_, data) => Err(ColdcardError::Device(
+                String::from_utf8_lossy(data).into_owned(),
+            )),
+            (msg, _) => Err(ColdcardError::unexpected_response_message(
+                msg,
+                &[
+                    ResponseMessage::Okay,
+                    ResponseMessage::Busy,
+                    ResponseMessage::Asci,
+                ],
+            )),
+        }
+    }
+
     pub fn download(res: &[u8]) -> Result<Vec<u8>, ColdcardError> {
         Ok(ResponseHandler::expect_response(res, ResponseMessage::Biny)?.to_vec())
     }
@@ -0,0 +1,181 @@
+//! Coldcard HSM mode: policy upload, status and the users that authorise spends.
+//!
+//! Mirrors ckcc-protocol. A policy JSON is uploaded like any other file and started with
+//! `hsms`, which the operator confirms on the device. Users are created with `nwur` and
+//! removed with `rmur`; before a PSBT is signed, `user` tells the device which users are
+//! present, either with a TOTP code or with a password derived token bound to the PSBT.
+
+use bitcoin::hashes::{Hash, HashEngine, hmac, sha256, sha512};
+
+use super::ColdcardError;
+use super::api::request::user_auth as api_mode;
+
+pub const MAX_USERNAME_LEN: usize = 16;
+
+/// Iterations used by ckcc-protocol to stretch user passwords.
+const PBKDF2_ITER_COUNT: u32 = 2500;
+
+/// Status report of `hsts`: whether HSM mode is active, the policy summary, users,
+/// approval and refusal counters, and the reason of the last refusal.
+pub type HsmStatus = serde_json::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthMode {
+    /// Time based one-time password, checked against the code's 30 second time step.
+    Totp,
+    /// Counter based one-time password.
+    Hotp,
+    /// Password, presented as an HMAC over the PSBT being signed.
+    Password,
+}
+
+impl AuthMode {
+    pub(crate) fn code(self) -> u8 {
+        match self {
+            AuthMode::Totp => api_mode::USER_AUTH_TOTP,
+            AuthMode::Hotp => api_mode::USER_AUTH_HOTP,
+            AuthMode::Password => api_mode::USER_AUTH_HMAC,
+        }
+    }
+
+    fn valid_secret(self, secret: &[u8]) -> bool {
+        match self {
+            AuthMode::Totp | AuthMode::Hotp => matches!(secret.len(), 10 | 20),
+            AuthMode::Password => secret.len() == 32,
+        }
+    }
+}
+
+/// Credentials of a user present for a signature.
+pub enum UserAuth {
+    /// Current code of a TOTP user and the 30 second time step it was generated for.
+    Totp { code: String, time_step: u32 },
+    /// Password of a password user. The device serial number salts the password hash.
+    Password { password: String, serial: String },
+}
+
+impl UserAuth {
+    /// TOTP credentials for a code read at `unix_time`, in seconds.
+    pub fn totp(code: impl Into<String>, unix_time: u64) -> Self {
+        UserAuth::Totp {
+            code: code.into(),
+            time_step: (unix_time / 30) as u32,
+        }
+    }
+
+    /// Token and TOTP time step sent with `user` before signing `psbt`.
+    pub(crate) fn token(&self, psbt: &[u8]) -> Result<(Vec<u8>, u32), ColdcardError> {
+        match self {
+            UserAuth::Totp { code, time_step } => {
+                if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(ColdcardError::InvalidInput(
+                        "TOTP codes are 6 digits".to_string(),
+                    ));
+                }
+                Ok((code.as_bytes().to_vec(), *time_step))
+            }
+            UserAuth::Password { password, serial } => {
+                let key = hash_password(password, serial);
+                let mut engine = hmac::HmacEngine::<sha256::Hash>::new(&key);
+                engine.input(sha256::Hash::hash(psbt).as_byte_array());
+                let token = hmac::Hmac::<sha256::Hash>::from_engine(engine);
+                Ok((token.to_byte_array().to_vec(), 0))
+            }
+        }
+    }
+}
+
+/// Stretch a user password into the 32 byte secret the device stores for password users:
+/// PBKDF2-HMAC-SHA512 salted with `sha256("pepper" || serial)`, truncated to 32 bytes.
+pub fn hash_password(password: &str, serial: &str) -> [u8; 32] {
+    let mut salt = b"pepper".to_vec();
+    salt.extend(serial.as_bytes());
+    let salt = sha256::Hash::hash(&salt);
+
+    let prf = |data: &[u8]| {
+        let mut engine = hmac::HmacEngine::<sha512::Hash>::new(password.as_bytes());
+        engine.input(data);
+        hmac::Hmac::<sha512::Hash>::from_engine(engine).to_byte_array()
+    };
+    // 32 bytes fit in the first 64 byte PBKDF2 block.
+    let mut block = salt.to_byte_array().to_vec();
+    block.extend(1u32.to_be_bytes());
+    let mut u = prf(&block);
+    let mut output = u;
+    for _ in 1..PBKDF2_ITER_COUNT {
+        u = prf(&u);
+        output.iter_mut().zip(u).for_each(|(out, u)| *out ^= u);
+    }
+    output[..32].try_into().expect("64 byte block")
+}
+
+pub(crate) fn validate_username(username: &str) -> Result<(), ColdcardError> {
+    if username.is_empty() || username.len() > MAX_USERNAME_LEN || !username.is_ascii() {
+        return Err(ColdcardError::InvalidInput(format!(
+            "Coldcard usernames must be 1 to {MAX_USERNAME_LEN} ASCII characters"
+        )));
+    }
+    Ok(())
+}
+
+/// An empty secret asks the device to pick one and return it.
+pub(crate) fn validate_secret(mode: AuthMode, secret: &[u8]) -> Result<(), ColdcardError> {
+    if !secret.is_empty() && !mode.valid_secret(secret) {
+        return Err(ColdcardError::InvalidInput(
+            "Coldcard user secrets are 10 or 20 byte OTP keys or 32 byte password hashes"
+                .to_string(),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn totp_token_is_the_code_and_time_step() {
+        let auth = UserAuth::totp("123456", 1_700_000_010);
+        let (token, time_step) = auth.token(b"psbt").unwrap();
+        assert_eq!(token, b"123456");
+        assert_eq!(time_step, 56_666_667);
+
+        let auth = UserAuth::totp("12345a", 0);
+        assert!(auth.token(b"psbt").is_err());
+    }
+
+    #[test]
+    fn password_token_is_bound_to_the_psbt() {
+        let auth = UserAuth::Password {
+            password: "correct horse".to_string(),
+            serial: "F1F1F1F1F1F1".to_string(),
+        };
+        let (first, time_step) = auth.token(b"first psbt").unwrap();
+        let (second, _) = auth.token(b"second psbt").unwrap();
+        assert_eq!(time_step, 0);
+        assert_eq!(first.len(), 32);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn password_hash_matches_ckcc() {
+        use bitcoin::hashes::hex::FromHex;
+
+        // hashlib.pbkdf2_hmac('sha512', b'correct horse', sha256(b'pepperF1F1F1F1F1F1'), 2500)[:32]
+        let expected: [u8; 32] =
+            FromHex::from_hex("456d8bb2eab9c5c3b332a6bf7d07bae81df36e55f4cf54a3c8165c85faa72271")
+                .unwrap();
+        assert_eq!(hash_password("correct horse", "F1F1F1F1F1F1"), expected);
+        assert_ne!(hash_password("correct horse", "A2A2A2A2A2A2"), expected);
+    }
+
+    #[test]
+    fn usernames_and_secrets_are_validated() {
+        assert!(validate_username("alice").is_ok());
+        assert!(validate_username("").is_err());
+        assert!(validate_username("a-very-long-username").is_err());
+        assert!(validate_secret(AuthMode::Totp, &[]).is_ok());
+        assert!(validate_secret(AuthMode::Totp, &[0; 10]).is_ok());
+        assert!(validate_secret(AuthMode::Password, &[0; 10]).is_err());
+    }
+}
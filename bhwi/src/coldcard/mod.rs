@@ -1,6 +1,8 @@
 pub mod api;
 pub mod encrypt;
+pub mod hsm;
 
+use std::collections::VecDeque;
 use std::string::FromUtf8Error;
 
 use bitcoin::PublicKey;
@@ -11,10 +13,13 @@ use bitcoin::psbt::Psbt;
 use bitcoin::secp256k1::{Secp256k1, ecdsa::Signature};
 
 use crate::Interpreter;
+use crate::RawCommand;
+use crate::bip322::{self, Bip322Error};
 use crate::coldcard::api::response::ResponseMessage;
 use crate::common::{
-    Command, DeviceBackup, DisplayAddress, Error, Info, MultisigAddressType,
-    MultisigDisplayAddress, Recipient, Response, Transmit,
+    BackupMode, Capabilities, Command, CommandKind, DeviceBackup, DeviceKind, DisplayAddress,
+    Error, ErrorKind, Event, Info, MultisigAddressType, MultisigDisplayAddress, Recipient,
+    Response, Transmit, Zeroizing,
 };
 use crate::device::DeviceId;
 use crate::miniscript::{
@@ -49,6 +54,12 @@ pub enum ColdcardError {
     #[error("invalid input: {0}")]
     InvalidInput(String),
 
+    #[error("{0} is not supported by Coldcard")]
+    Unsupported(&'static str),
+
+    #[error(transparent)]
+    Bip322(#[from] Bip322Error),
+
     /// Unexpected response message from device
     #[error("unexpected response message: got {got:?}, expected {expected:?}")]
     UnexpectedResponseMessage {
@@ -58,6 +69,39 @@ pub enum ColdcardError {
 }
 
 impl ColdcardError {
+    /// Class of an `err_` reply. Coldcard only sends free text, so it is matched by keyword
+    /// and anything unrecognised is taken as a rejected request.
+    pub fn device_error_kind(message: &str) -> ErrorKind {
+        let message = message.to_ascii_lowercase();
+        let words: Vec<&str> = message
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .collect();
+        let has = |keys: &[&str]| {
+            keys.iter().any(|key| {
+                if key.contains(' ') {
+                    message.contains(key)
+                } else {
+                    words.contains(key)
+                }
+            })
+        };
+        if has(&["busy"]) {
+            ErrorKind::Busy
+        } else if has(&["refused", "rejected", "cancelled", "canceled"]) {
+            ErrorKind::UserCancelled
+        } else if has(&["pin", "locked", "login"]) {
+            ErrorKind::Locked
+        } else if has(&["no secrets", "no seed", "blank"]) {
+            ErrorKind::NotInitialized
+        } else if has(&["testnet", "mainnet", "wrong chain"]) {
+            ErrorKind::NetworkMismatch
+        } else if has(&["unknown cmd", "unsupported", "not supported"]) {
+            ErrorKind::Unsupported
+        } else {
+            ErrorKind::InvalidInput
+        }
+    }
+
     pub fn unexpected_response_message(
         got: ResponseMessage,
         expected: &[ResponseMessage],
@@ -75,10 +119,19 @@ pub enum ColdcardCommand {
     GetVersion,
     GetMasterFingerprint,
     GetXpub(DerivationPath),
+    /// Apply a BIP39 passphrase once the operator confirms it; an empty one goes back to
+    /// the wallet without passphrase.
+    SetPassphrase(Zeroizing<String>),
     SignMessage {
         message: Vec<u8>,
         path: DerivationPath,
     },
+    /// Run as a [`bip322::Session`] of the other commands.
+    SignMessageBip322 {
+        message: Vec<u8>,
+        path: DerivationPath,
+        address_type: bitcoin::address::AddressType,
+    },
     ShowAddress {
         path: DerivationPath,
         addr_fmt: u32,
@@ -97,6 +150,28 @@ pub enum ColdcardCommand {
     RegisterWallet {
         payload: Vec<u8>,
     },
+    /// Upload an HSM policy JSON and ask the operator to start HSM mode with it, or
+    /// restart with the policy saved on the device when `policy` is `None`.
+    StartHsm {
+        policy: Option<Vec<u8>>,
+    },
+    HsmStatus,
+    /// Create an HSM user. An empty `secret` lets the device pick one.
+    CreateUser {
+        username: String,
+        mode: hsm::AuthMode,
+        secret: Vec<u8>,
+        show_qr: bool,
+    },
+    DeleteUser {
+        username: String,
+    },
+    /// Authenticate `username` for this PSBT, then sign it under the HSM policy.
+    SignPsbtAsUser {
+        psbt: Psbt,
+        username: String,
+        auth: hsm::UserAuth,
+    },
 }
 
 pub struct ColdcardMultisigDisplayAddress {
@@ -126,10 +201,13 @@ pub enum ColdcardResponse {
         xpub: Option<Xpub>,
     },
     Signature(u8, Signature),
+    Bip322Signature(String),
     Address(String),
     Backup(Vec<u8>),
     SignedPsbt(Psbt),
     WalletRegistrationPending,
+    HsmStatus(hsm::HsmStatus),
+    UserSecret(String),
 }
 
 pub struct ColdcardTransmit {
@@ -152,8 +230,13 @@ enum State {
     },
     SigningPsbt,
     EnrollingWallet,
+    StartingHsm,
+    AuthorizingUser {
+        psbt: Vec<u8>,
+    },
     PollingSignedPsbt,
     PollingBackupFile,
+    PollingPassphrase,
     DownloadingFile {
         response: FileDownloadResponse,
         file_number: u32,
@@ -169,6 +252,7 @@ enum State {
 enum UploadAction {
     SignPsbt,
     RegisterWallet,
+    StartHsm,
 }
 
 enum FileDownloadResponse {
@@ -179,6 +263,8 @@ enum FileDownloadResponse {
 pub struct ColdcardInterpreter<'a, C, T, R, E> {
     state: State,
     encryption: &'a mut encrypt::Engine,
+    bip322: Option<bip322::Session>,
+    events: VecDeque<Event>,
     _marker: std::marker::PhantomData<(C, T, R, E)>,
 }
 
@@ -187,9 +273,30 @@ impl<'a, C, T, R, E> ColdcardInterpreter<'a, C, T, R, E> {
         Self {
             state: State::New,
             encryption,
+            bip322: None,
+            events: VecDeque::new(),
             _marker: std::marker::PhantomData,
         }
     }
+
+    /// Start uploading `bytes`, the file a command acts on, from its first chunk.
+    fn upload(
+        &mut self,
+        bytes: Vec<u8>,
+        action: UploadAction,
+    ) -> Result<ColdcardTransmit, ColdcardError> {
+        let req = request(file_upload_request(&bytes, 0)?, self.encryption)?;
+        self.events.push_back(Event::Progress {
+            done: 0,
+            total: bytes.len(),
+        });
+        self.state = State::UploadingFile {
+            bytes,
+            offset: 0,
+            action,
+        };
+        Ok(req)
+    }
 }
 
 fn request(
@@ -228,20 +335,12 @@ fn download_request(
     Ok(api::request::download(offset, length, file_number))
 }
 
-impl<'a, C, T, R, E> Interpreter for ColdcardInterpreter<'a, C, T, R, E>
+impl<C, T, R, E> ColdcardInterpreter<'_, C, T, R, E>
 where
-    C: TryInto<ColdcardCommand, Error = ColdcardError>,
     T: From<ColdcardTransmit>,
-    R: From<ColdcardResponse>,
     E: From<ColdcardError>,
 {
-    type Command = C;
-    type Transmit = T;
-    type Response = R;
-    type Error = E;
-
-    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
-        let command: ColdcardCommand = command.try_into()?;
+    fn start_command(&mut self, command: ColdcardCommand) -> Result<T, E> {
         let req = match &command {
             ColdcardCommand::StartEncryption => ColdcardTransmit {
                 payload: api::request::start_encryption(None, &self.encryption.pub_key()?),
@@ -256,9 +355,30 @@ where
             ColdcardCommand::GetXpub(path) => {
                 request(api::request::get_xpub(path), self.encryption)?
             }
+            ColdcardCommand::SetPassphrase(passphrase) => {
+                if passphrase.len() > api::request::MAX_PASSPHRASE_LEN {
+                    return Err(ColdcardError::InvalidInput(format!(
+                        "Coldcard passphrases are at most {} bytes",
+                        api::request::MAX_PASSPHRASE_LEN
+                    ))
+                    .into());
+                }
+                request(api::request::bip39_passphrase(passphrase), self.encryption)?
+            }
             ColdcardCommand::SignMessage { message, path } => {
                 request(api::request::sign_message(message, path), self.encryption)?
             }
+            ColdcardCommand::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => {
+                let (session, command) =
+                    bip322::Session::start(message.clone(), path.clone(), *address_type, false)
+                        .map_err(ColdcardError::from)?;
+                self.bip322 = Some(session);
+                return self.start_command(command.try_into()?);
+            }
             ColdcardCommand::ShowAddress { path, addr_fmt } => {
                 request(api::request::show_address(path, *addr_fmt), self.encryption)?
             }
@@ -284,31 +404,79 @@ where
                 self.encryption,
             )?,
             ColdcardCommand::SignPsbt { psbt } => {
-                let bytes = psbt.serialize();
-                let req = request(file_upload_request(&bytes, 0)?, self.encryption)?;
-                self.state = State::UploadingFile {
-                    bytes,
-                    offset: 0,
-                    action: UploadAction::SignPsbt,
-                };
-                return Ok(req.into());
+                return Ok(self
+                    .upload(psbt.serialize(), UploadAction::SignPsbt)?
+                    .into());
             }
             ColdcardCommand::RegisterWallet { payload } => {
-                let bytes = payload.clone();
-                let req = request(file_upload_request(&bytes, 0)?, self.encryption)?;
-                self.state = State::UploadingFile {
-                    bytes,
-                    offset: 0,
-                    action: UploadAction::RegisterWallet,
-                };
+                return Ok(self
+                    .upload(payload.clone(), UploadAction::RegisterWallet)?
+                    .into());
+            }
+            ColdcardCommand::StartHsm {
+                policy: Some(policy),
+            } => {
+                validate_hsm_policy(policy)?;
+                return Ok(self.upload(policy.clone(), UploadAction::StartHsm)?.into());
+            }
+            ColdcardCommand::StartHsm { policy: None } => {
+                request(api::request::hsm_start(None), self.encryption)?
+            }
+            ColdcardCommand::HsmStatus => request(api::request::hsm_status(), self.encryption)?,
+            ColdcardCommand::CreateUser {
+                username,
+                mode,
+                secret,
+                show_qr,
+            } => {
+                hsm::validate_username(username)?;
+                hsm::validate_secret(*mode, secret)?;
+                let mut auth_mode = mode.code();
+                if *show_qr {
+                    auth_mode |= api::request::user_auth::USER_AUTH_SHOW_QR;
+                }
+                request(
+                    api::request::create_user(username.as_bytes(), auth_mode, secret),
+                    self.encryption,
+                )?
+            }
+            ColdcardCommand::DeleteUser { username } => {
+                hsm::validate_username(username)?;
+                request(
+                    api::request::delete_user(username.as_bytes()),
+                    self.encryption,
+                )?
+            }
+            ColdcardCommand::SignPsbtAsUser {
+                psbt,
+                username,
+                auth,
+            } => {
+                hsm::validate_username(username)?;
+                let psbt = psbt.serialize();
+                let (token, totp_time) = auth.token(&psbt)?;
+                let req = request(
+                    api::request::user_auth(username.as_bytes(), &token, totp_time),
+                    self.encryption,
+                )?;
+                self.state = State::AuthorizingUser { psbt };
                 return Ok(req.into());
             }
         };
 
+        if matches!(
+            command,
+            ColdcardCommand::Backup
+                | ColdcardCommand::SetPassphrase(_)
+                | ColdcardCommand::SignMessage { .. }
+        ) {
+            self.events.push_back(Event::AwaitingUserConfirmation);
+        }
         self.state = State::Running(command);
         Ok(req.into())
     }
-    fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
+
+    fn exchange_command(&mut self, data: Vec<u8>) -> Result<Option<T>, E> {
         match &mut self.state {
             State::New => Ok(None),
             State::Running(ColdcardCommand::GetVersion) => {
@@ -326,6 +494,17 @@ where
                 self.state = State::Finished(api::response::get_xpub(&data)?);
                 Ok(None)
             }
+            State::Running(ColdcardCommand::SetPassphrase(_)) | State::PollingPassphrase => {
+                let data = self.encryption.decrypt(data)?;
+                if let Some(res) = api::response::passphrase_done(&data)? {
+                    self.state = State::Finished(res);
+                    return Ok(None);
+                }
+                self.state = State::PollingPassphrase;
+                Ok(Some(
+                    request(api::request::get_passphrase_done(), self.encryption)?.into(),
+                ))
+            }
             State::Running(ColdcardCommand::SignMessage { .. }) => {
                 let data = self.encryption.decrypt(data)?;
                 let res = api::response::sign_message(&data)?;
@@ -368,10 +547,35 @@ where
                 self.state = State::Finished(api::response::miniscript_address(&data)?);
                 Ok(None)
             }
-            State::Running(ColdcardCommand::SignPsbt { .. }) => unreachable!("handled in start"),
-            State::Running(ColdcardCommand::RegisterWallet { .. }) => {
+            State::Running(ColdcardCommand::StartHsm { .. })
+            | State::Running(ColdcardCommand::DeleteUser { .. }) => {
+                let data = self.encryption.decrypt(data)?;
+                self.state = State::Finished(api::response::hsm_okay(&data)?);
+                Ok(None)
+            }
+            State::Running(ColdcardCommand::HsmStatus) => {
+                let data = self.encryption.decrypt(data)?;
+                self.state = State::Finished(api::response::hsm_status(&data)?);
+                Ok(None)
+            }
+            State::Running(ColdcardCommand::CreateUser { .. }) => {
+                let data = self.encryption.decrypt(data)?;
+                self.state = State::Finished(api::response::create_user(&data)?);
+                Ok(None)
+            }
+            State::Running(
+                ColdcardCommand::SignPsbt { .. } | ColdcardCommand::SignMessageBip322 { .. },
+            ) => unreachable!("handled in start"),
+            State::Running(ColdcardCommand::RegisterWallet { .. })
+            | State::Running(ColdcardCommand::SignPsbtAsUser { .. }) => {
                 unreachable!("handled in start")
             }
+            State::AuthorizingUser { psbt } => {
+                let data = self.encryption.decrypt(data)?;
+                api::response::hsm_okay(&data)?;
+                let bytes = std::mem::take(psbt);
+                Ok(Some(self.upload(bytes, UploadAction::SignPsbt)?.into()))
+            }
             State::UploadingFile {
                 bytes,
                 offset,
@@ -387,6 +591,10 @@ where
                 }
 
                 let next_offset = (*offset + api::request::MAX_UPLOAD_CHUNK_LEN).min(bytes.len());
+                self.events.push_back(Event::Progress {
+                    done: next_offset,
+                    total: bytes.len(),
+                });
                 if next_offset < bytes.len() {
                     let req = request(file_upload_request(bytes, next_offset)?, self.encryption)?;
                     *offset = next_offset;
@@ -425,12 +633,17 @@ where
                 let request_payload = match action {
                     UploadAction::SignPsbt => {
                         self.state = State::SigningPsbt;
+                        self.events.push_back(Event::AwaitingUserConfirmation);
                         api::request::sign_transaction(length, &expected_sha)
                     }
                     UploadAction::RegisterWallet => {
                         self.state = State::EnrollingWallet;
                         api::request::multisig_enroll(length, &expected_sha)
                     }
+                    UploadAction::StartHsm => {
+                        self.state = State::StartingHsm;
+                        api::request::hsm_start(Some((length, &expected_sha)))
+                    }
                 };
                 Ok(Some(request(request_payload, self.encryption)?.into()))
             }
@@ -445,6 +658,11 @@ where
                 }
                 Ok(None)
             }
+            State::StartingHsm => {
+                let data = self.encryption.decrypt(data)?;
+                self.state = State::Finished(api::response::hsm_okay(&data)?);
+                Ok(None)
+            }
             State::EnrollingWallet => {
                 let data = self.encryption.decrypt(data)?;
                 api::response::okay(&data)?;
@@ -528,6 +746,10 @@ where
                 }
                 bytes.extend_from_slice(&chunk);
                 *offset += chunk.len();
+                self.events.push_back(Event::Progress {
+                    done: (*offset).min(*total_len),
+                    total: *total_len,
+                });
                 if *offset < *total_len {
                     let req = request(
                         download_request(*offset, *total_len, *file_number)?,
@@ -562,6 +784,53 @@ where
             State::Finished(..) => Ok(None),
         }
     }
+
+    /// Feed the response of the command that just finished to the running BIP-322 session,
+    /// starting its next command or finishing with its signature.
+    fn next_bip322_step(&mut self) -> Result<Option<T>, E> {
+        let Some(session) = self.bip322.as_mut() else {
+            return Ok(None);
+        };
+        let State::Finished(response) = std::mem::replace(&mut self.state, State::New) else {
+            return Err(ColdcardError::NoErrorOrResult.into());
+        };
+        match session
+            .next(Response::from(response))
+            .map_err(ColdcardError::from)?
+        {
+            bip322::Next::Run(command) => self.start_command(command.try_into()?).map(Some),
+            bip322::Next::Signature(signature) => {
+                self.bip322 = None;
+                self.state = State::Finished(ColdcardResponse::Bip322Signature(signature));
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<'a, C, T, R, E> Interpreter for ColdcardInterpreter<'a, C, T, R, E>
+where
+    C: TryInto<ColdcardCommand, Error = ColdcardError>,
+    T: From<ColdcardTransmit>,
+    R: From<ColdcardResponse>,
+    E: From<ColdcardError>,
+{
+    type Command = C;
+    type Transmit = T;
+    type Response = R;
+    type Error = E;
+
+    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
+        self.start_command(command.try_into()?)
+    }
+
+    fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
+        let transmit = self.exchange_command(data)?;
+        if transmit.is_some() {
+            return Ok(transmit);
+        }
+        self.next_bip322_step()
+    }
     fn end(self) -> Result<Self::Response, Self::Error> {
         if let State::Finished(res) = self.state {
             Ok(Self::Response::from(res))
@@ -569,6 +838,45 @@ where
             Err(ColdcardError::NoErrorOrResult.into())
         }
     }
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+/// Capabilities of the Coldcard reported in `info`. Only the EDGE firmware, whose version
+/// ends with `X`, signs taproot inputs, and registration takes `sortedmulti` policies only.
+pub fn capabilities(info: &Info) -> Capabilities {
+    use bitcoin::address::AddressType;
+    use bitcoin::sighash::EcdsaSighashType;
+
+    let edge = info.version.ends_with('X');
+    let mut commands = CommandKind::BASE.to_vec();
+    commands.extend([
+        CommandKind::SignMessageBip322,
+        CommandKind::Backup,
+        CommandKind::SetPassphrase,
+        CommandKind::RegisterWallet,
+    ]);
+    let mut script_types = vec![AddressType::P2pkh, AddressType::P2sh, AddressType::P2wpkh];
+    if edge {
+        script_types.push(AddressType::P2tr);
+    }
+    Capabilities {
+        commands,
+        script_types,
+        taproot: edge,
+        miniscript: false,
+        multisig: true,
+        sighash_types: vec![EcdsaSighashType::All],
+        backups: vec![BackupMode::File],
+    }
+}
+
+impl TryFrom<RawCommand<ColdcardCommand>> for ColdcardCommand {
+    type Error = ColdcardError;
+    fn try_from(cmd: RawCommand<ColdcardCommand>) -> Result<Self, Self::Error> {
+        Ok(cmd.0)
+    }
 }
 
 impl TryFrom<Command> for ColdcardCommand {
@@ -587,11 +895,23 @@ impl TryFrom<Command> for ColdcardCommand {
             Command::TogglePassphrase => Err(ColdcardError::MissingCommandInfo(
                 "Toggle passphrase not supported by Coldcard",
             )),
+            Command::SetPassphrase(passphrase) => Ok(Self::SetPassphrase(
+                passphrase.unwrap_or_else(|| Zeroizing::new(String::new())),
+            )),
             Command::Backup => Ok(Self::Backup),
             Command::Unlock { .. } => Ok(Self::StartEncryption),
             Command::GetMasterFingerprint => Ok(Self::GetMasterFingerprint),
             Command::GetXpub { path, .. } => Ok(Self::GetXpub(path)),
             Command::SignMessage { message, path } => Ok(Self::SignMessage { message, path }),
+            Command::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => Ok(Self::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            }),
             Command::GetVersion => Ok(Self::GetVersion),
             Command::DisplayAddress(
                 DisplayAddress::ByPath {
@@ -635,6 +955,9 @@ impl TryFrom<Command> for ColdcardCommand {
                 }
                 Ok(Self::SignPsbt { psbt })
             }
+            // Coldcard has no attestation key: its genuineness is checked with the bag
+            // number and the anti-phishing words shown at login.
+            Command::VerifyAttestation => Err(ColdcardError::Unsupported("attestation")),
         }
     }
 }
@@ -686,6 +1009,18 @@ fn coldcard_registration_payload(
     Ok(payload)
 }
 
+fn validate_hsm_policy(policy: &[u8]) -> Result<(), ColdcardError> {
+    match serde_json::from_slice::<serde_json::Value>(policy) {
+        Ok(serde_json::Value::Object(_)) => Ok(()),
+        Ok(_) => Err(ColdcardError::InvalidInput(
+            "Coldcard HSM policy must be a JSON object".to_string(),
+        )),
+        Err(e) => Err(ColdcardError::InvalidInput(format!(
+            "invalid Coldcard HSM policy: {e}"
+        ))),
+    }
+}
+
 fn coldcard_sortedmulti_size(
     descriptor: &Descriptor<DescriptorPublicKey>,
 ) -> Option<(usize, usize)> {
@@ -845,6 +1180,7 @@ impl From<ColdcardResponse> for Response {
                 version,
                 device_model,
             } => Response::Info(Info {
+                device: Some(DeviceKind::Coldcard),
                 version: version.as_str().into(),
                 networks: vec![],
                 firmware: Some(device_model),
@@ -856,6 +1192,7 @@ impl From<ColdcardResponse> for Response {
             ColdcardResponse::Signature(header, signature) => {
                 Response::Signature(header, signature)
             }
+            ColdcardResponse::Bip322Signature(signature) => Response::Bip322Signature(signature),
             ColdcardResponse::Ok => Response::TaskDone,
             ColdcardResponse::Busy => Response::TaskBusy,
             ColdcardResponse::Address(address) => Response::Address(address),
@@ -864,6 +1201,7 @@ impl From<ColdcardResponse> for Response {
             ColdcardResponse::WalletRegistrationPending => Response::WalletRegistration(
                 crate::common::WalletRegistration::PendingUserConfirmation,
             ),
+            ColdcardResponse::HsmStatus(_) | ColdcardResponse::UserSecret(_) => Response::TaskDone,
         }
     }
 }
@@ -883,10 +1221,18 @@ impl From<ColdcardError> for Error {
         match error {
             ColdcardError::Encryption(e) => Error::Encryption(e),
             ColdcardError::MissingCommandInfo(e) => Error::MissingCommandInfo(e),
-            ColdcardError::Device(s) => Error::Device(format!("Coldcard Error: {s}")),
+            ColdcardError::Device(s) => Error::Device(
+                ColdcardError::device_error_kind(&s),
+                format!("Coldcard Error: {s}"),
+            ),
             ColdcardError::NoErrorOrResult => Error::NoErrorOrResult,
             ColdcardError::Serialization(s) => Error::Serialization(s),
             ColdcardError::InvalidInput(s) => Error::InvalidInput(s),
+            ColdcardError::Unsupported(operation) => Error::Unsupported {
+                device: "Coldcard",
+                operation,
+            },
+            ColdcardError::Bip322(e) => Error::from(e),
             ColdcardError::UnexpectedResponseMessage { got, expected } => Error::unexpected_result(
                 format!("{got:?}").into_bytes(),
                 format!("coldcard unexpected response: expected {expected:?}, got {got:?}"),
@@ -930,6 +1276,202 @@ mod tests {
         device.decrypt(request.payload).unwrap()
     }
 
+    fn empty_psbt() -> Psbt {
+        Psbt::from_unsigned_tx(bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![],
+            output: vec![],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn start_hsm_uploads_policy_then_starts() {
+        let (mut host, mut device) = paired_engines();
+        let policy = br#"{"rules": [{"max_amount": 100000}]}"#.to_vec();
+        let mut interpreter: ColdcardInterpreter<
+            '_,
+            RawCommand<ColdcardCommand>,
+            Transmit,
+            Response,
+            Error,
+        > = ColdcardInterpreter::new(&mut host);
+
+        let request = interpreter
+            .start(RawCommand(ColdcardCommand::StartHsm {
+                policy: Some(policy.clone()),
+            }))
+            .unwrap();
+        let upload = decrypt_request(&mut device, request);
+        assert_eq!(&upload[..4], b"upld");
+        assert_eq!(&upload[12..], &policy[..]);
+
+        let mut acknowledged = b"int1".to_vec();
+        acknowledged.extend(0u32.to_le_bytes());
+        let request = interpreter
+            .exchange(encrypt_response(&mut device, &acknowledged))
+            .unwrap()
+            .expect("sha request");
+        assert_eq!(decrypt_request(&mut device, request), b"sha2");
+
+        let policy_sha = sha256::Hash::hash(&policy).to_byte_array();
+        let mut sha_response = b"biny".to_vec();
+        sha_response.extend(policy_sha);
+        let request = interpreter
+            .exchange(encrypt_response(&mut device, &sha_response))
+            .unwrap()
+            .expect("hsm start request");
+        let start = decrypt_request(&mut device, request);
+        assert_eq!(&start[..4], b"hsms");
+        assert_eq!(
+            u32::from_le_bytes(start[4..8].try_into().unwrap()),
+            policy.len() as u32
+        );
+        assert_eq!(&start[8..], &policy_sha);
+
+        assert!(
+            interpreter
+                .exchange(encrypt_response(&mut device, b"okay"))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(interpreter.end().unwrap(), Response::TaskDone));
+    }
+
+    #[test]
+    fn sign_psbt_reports_upload_progress_then_awaits_the_user() {
+        let (mut host, mut device) = paired_engines();
+        let bytes = empty_psbt().serialize();
+        let mut interpreter: ColdcardInterpreter<
+            '_,
+            RawCommand<ColdcardCommand>,
+            Transmit,
+            Response,
+            Error,
+        > = ColdcardInterpreter::new(&mut host);
+
+        interpreter
+            .start(RawCommand(ColdcardCommand::SignPsbt { psbt: empty_psbt() }))
+            .unwrap();
+        let progress = |done| Event::Progress {
+            done,
+            total: bytes.len(),
+        };
+        assert_eq!(interpreter.poll_event(), Some(progress(0)));
+        assert_eq!(interpreter.poll_event(), None);
+
+        let mut acknowledged = b"int1".to_vec();
+        acknowledged.extend(0u32.to_le_bytes());
+        interpreter
+            .exchange(encrypt_response(&mut device, &acknowledged))
+            .unwrap();
+        assert_eq!(interpreter.poll_event(), Some(progress(bytes.len())));
+
+        let mut sha_response = b"biny".to_vec();
+        sha_response.extend(sha256::Hash::hash(&bytes).to_byte_array());
+        interpreter
+            .exchange(encrypt_response(&mut device, &sha_response))
+            .unwrap();
+        assert_eq!(
+            interpreter.poll_event(),
+            Some(Event::AwaitingUserConfirmation)
+        );
+    }
+
+    #[test]
+    fn start_hsm_rejects_invalid_policy() {
+        let (mut host, _) = paired_engines();
+        let mut interpreter: ColdcardInterpreter<
+            '_,
+            RawCommand<ColdcardCommand>,
+            Transmit,
+            Response,
+            Error,
+        > = ColdcardInterpreter::new(&mut host);
+        let error = interpreter
+            .start(RawCommand(ColdcardCommand::StartHsm {
+                policy: Some(b"[]".to_vec()),
+            }))
+            .err()
+            .expect("policy must be an object");
+        assert!(matches!(error, Error::InvalidInput(_)));
+    }
+
+    #[test]
+    fn sign_psbt_as_user_authenticates_before_upload() {
+        let (mut host, mut device) = paired_engines();
+        let psbt = empty_psbt();
+        let bytes = psbt.serialize();
+        let mut interpreter: ColdcardInterpreter<
+            '_,
+            RawCommand<ColdcardCommand>,
+            Transmit,
+            Response,
+            Error,
+        > = ColdcardInterpreter::new(&mut host);
+
+        let request = interpreter
+            .start(RawCommand(ColdcardCommand::SignPsbtAsUser {
+                psbt,
+                username: "alice".to_string(),
+                auth: hsm::UserAuth::totp("123456", 60),
+            }))
+            .unwrap();
+        let auth = decrypt_request(&mut device, request);
+        assert_eq!(&auth[..4], b"user");
+        assert_eq!(u32::from_le_bytes(auth[4..8].try_into().unwrap()), 2);
+        assert_eq!(&auth[10..], b"alice123456");
+
+        let request = interpreter
+            .exchange(encrypt_response(&mut device, b"okay"))
+            .unwrap()
+            .expect("upload request");
+        let upload = decrypt_request(&mut device, request);
+        assert_eq!(&upload[..4], b"upld");
+        assert_eq!(&upload[12..], &bytes[..]);
+    }
+
+    #[test]
+    fn sign_psbt_as_user_surfaces_auth_failure() {
+        let (mut host, mut device) = paired_engines();
+        let mut interpreter: ColdcardInterpreter<
+            '_,
+            RawCommand<ColdcardCommand>,
+            Transmit,
+            Response,
+            Error,
+        > = ColdcardInterpreter::new(&mut host);
+
+        interpreter
+            .start(RawCommand(ColdcardCommand::SignPsbtAsUser {
+                psbt: empty_psbt(),
+                username: "alice".to_string(),
+                auth: hsm::UserAuth::totp("123456", 60),
+            }))
+            .unwrap();
+        let error = interpreter
+            .exchange(encrypt_response(&mut device, b"err_Auth failed"))
+            .err()
+            .expect("auth failure");
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(matches!(error, Error::Device(_, msg) if msg.contains("Auth failed")));
+    }
+
+    #[test]
+    fn device_errors_are_classified_by_keyword() {
+        for (message, kind) in [
+            ("Busy with another request", ErrorKind::Busy),
+            ("Rejected by user", ErrorKind::UserCancelled),
+            ("Need PIN first", ErrorKind::Locked),
+            ("Coldcard is blank", ErrorKind::NotInitialized),
+            ("Unknown cmd", ErrorKind::Unsupported),
+            ("Mapping of output 1 is wrong", ErrorKind::InvalidInput),
+        ] {
+            assert_eq!(ColdcardError::device_error_kind(message), kind, "{message}");
+        }
+    }
+
     #[test]
     fn backup_state_machine_polls_and_downloads_file_zero() {
         let (mut host, mut device) = paired_engines();
@@ -984,6 +1526,57 @@ mod tests {
         }
     }
 
+    #[test]
+    fn set_passphrase_polls_until_the_new_xpub() {
+        let (mut host, mut device) = paired_engines();
+        let xpub = "tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP";
+        let mut interpreter: ColdcardInterpreter<'_, Command, Transmit, Response, Error> =
+            ColdcardInterpreter::new(&mut host);
+
+        let request = interpreter
+            .start(Command::SetPassphrase(Some(Zeroizing::new(
+                "correct horse".to_string(),
+            ))))
+            .unwrap();
+        assert_eq!(decrypt_request(&mut device, request), b"passcorrect horse");
+
+        let request = interpreter
+            .exchange(encrypt_response(&mut device, b"okay"))
+            .unwrap()
+            .expect("poll request");
+        assert_eq!(decrypt_request(&mut device, request), b"pwok");
+
+        let request = interpreter
+            .exchange(encrypt_response(&mut device, b"busy"))
+            .unwrap()
+            .expect("second poll request");
+        assert_eq!(decrypt_request(&mut device, request), b"pwok");
+
+        let mut done = b"asci".to_vec();
+        done.extend(xpub.as_bytes());
+        assert!(
+            interpreter
+                .exchange(encrypt_response(&mut device, &done))
+                .unwrap()
+                .is_none()
+        );
+        match interpreter.end().unwrap() {
+            Response::MasterFingerprint(fingerprint) => {
+                assert_eq!(fingerprint, Xpub::from_str(xpub).unwrap().fingerprint())
+            }
+            _ => panic!("expected master fingerprint"),
+        }
+    }
+
+    #[test]
+    fn clearing_passphrase_sends_empty_passphrase() {
+        let (mut host, mut device) = paired_engines();
+        let mut interpreter: ColdcardInterpreter<'_, Command, Transmit, Response, Error> =
+            ColdcardInterpreter::new(&mut host);
+        let request = interpreter.start(Command::SetPassphrase(None)).unwrap();
+        assert_eq!(decrypt_request(&mut device, request), b"pass");
+    }
+
     #[test]
     fn registration_payload_contains_name_and_full_descriptor() {
         let policy = WalletPolicy::from_str(REGISTRATION_POLICY).unwrap();
@@ -1,3 +1,4 @@
+use crate::bip322::Bip322Error;
 #[cfg(feature = "bitbox")]
 use crate::bitbox;
 use crate::miniscript::descriptor::{DescriptorPublicKey, WalletPolicy};
@@ -7,6 +8,8 @@ use bitcoin::address::AddressType;
 use bitcoin::bip32::{DerivationPath, Fingerprint, Xpub};
 use bitcoin::psbt::Psbt;
 use bitcoin::secp256k1::ecdsa::Signature;
+use bitcoin::sighash::EcdsaSighashType;
+pub use zeroize::Zeroizing;
 
 #[derive(Default)]
 pub struct UnlockOptions {
@@ -86,6 +89,9 @@ pub enum Command {
     Wipe,
     Restore(RestoreOptions, Option<DeviceContext>),
     TogglePassphrase,
+    /// Apply a BIP39 passphrase supplied by the host, or go back to the wallet without
+    /// one. Answered with the master fingerprint of the wallet now in use.
+    SetPassphrase(Option<Zeroizing<String>>),
     GetMasterFingerprint,
     GetVersion,
     GetXpub {
@@ -97,14 +103,109 @@ pub enum Command {
         name: String,
         policy: WalletPolicy,
     },
+    /// Sign a PSBT in the version 0 data model. Interpreters never see a version 2 PSBT:
+    /// hosts lower it, and restore it after signing, with [`crate::psbt::VersionedPsbt`],
+    /// as `sign_versioned_tx` of `bhwi-async` and `bhwi-blocking` does.
     SignTx(Psbt, Option<DeviceContext>),
+    /// Sign a message in the legacy format.
     SignMessage {
         message: Vec<u8>,
         path: DerivationPath,
     },
+    /// Sign a message in the BIP-322 simple format with the `address_type` (`P2wpkh` or
+    /// `P2tr`) address of the key at `path`. The interpreter runs it as a
+    /// [`crate::bip322::Session`], answering with [`Response::Bip322Signature`].
+    SignMessageBip322 {
+        message: Vec<u8>,
+        path: DerivationPath,
+        address_type: AddressType,
+    },
     Unlock {
         options: UnlockOptions,
     },
+    /// Challenge the device to prove it was made by its manufacturer, checking its answer
+    /// against the bundled manufacturer root keys.
+    VerifyAttestation,
+}
+
+impl Command {
+    pub fn kind(&self) -> CommandKind {
+        match self {
+            Command::Backup => CommandKind::Backup,
+            Command::Setup(..) => CommandKind::Setup,
+            Command::Wipe => CommandKind::Wipe,
+            Command::Restore(..) => CommandKind::Restore,
+            Command::TogglePassphrase => CommandKind::TogglePassphrase,
+            Command::SetPassphrase(_) => CommandKind::SetPassphrase,
+            Command::GetMasterFingerprint => CommandKind::GetMasterFingerprint,
+            Command::GetVersion => CommandKind::GetVersion,
+            Command::GetXpub { .. } => CommandKind::GetXpub,
+            Command::DisplayAddress(..) => CommandKind::DisplayAddress,
+            Command::RegisterWallet { .. } => CommandKind::RegisterWallet,
+            Command::SignTx(..) => CommandKind::SignTx,
+            Command::SignMessage { .. } => CommandKind::SignMessage,
+            Command::SignMessageBip322 { .. } => CommandKind::SignMessageBip322,
+            Command::Unlock { .. } => CommandKind::Unlock,
+            Command::VerifyAttestation => CommandKind::VerifyAttestation,
+        }
+    }
+}
+
+/// A [`Command`] without its arguments.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CommandKind {
+    Backup,
+    Setup,
+    Wipe,
+    Restore,
+    TogglePassphrase,
+    SetPassphrase,
+    GetMasterFingerprint,
+    GetVersion,
+    GetXpub,
+    DisplayAddress,
+    RegisterWallet,
+    SignTx,
+    SignMessage,
+    SignMessageBip322,
+    Unlock,
+    VerifyAttestation,
+}
+
+impl CommandKind {
+    /// Commands every device answers.
+    pub(crate) const BASE: &[CommandKind] = &[
+        CommandKind::Unlock,
+        CommandKind::GetMasterFingerprint,
+        CommandKind::GetVersion,
+        CommandKind::GetXpub,
+        CommandKind::DisplayAddress,
+        CommandKind::SignTx,
+        CommandKind::SignMessage,
+    ];
+}
+
+impl std::fmt::Display for CommandKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CommandKind::Backup => "backup",
+            CommandKind::Setup => "setup",
+            CommandKind::Wipe => "wipe",
+            CommandKind::Restore => "restore",
+            CommandKind::TogglePassphrase => "toggle_passphrase",
+            CommandKind::SetPassphrase => "set_passphrase",
+            CommandKind::GetMasterFingerprint => "get_master_fingerprint",
+            CommandKind::GetVersion => "get_version",
+            CommandKind::GetXpub => "get_xpub",
+            CommandKind::DisplayAddress => "display_address",
+            CommandKind::RegisterWallet => "register_wallet",
+            CommandKind::SignTx => "sign_tx",
+            CommandKind::SignMessage => "sign_message",
+            CommandKind::SignMessageBip322 => "sign_message_bip322",
+            CommandKind::Unlock => "unlock",
+            CommandKind::VerifyAttestation => "verify_attestation",
+        })
+    }
 }
 
 /// Device-specific context data required by certain commands.
@@ -135,9 +236,35 @@ pub enum Response {
     Xpub(Xpub),
     EncryptionKey([u8; 64]),
     Signature(u8, Signature),
+    /// Base64 BIP-322 simple signature.
+    Bip322Signature(String),
     SignedPsbt(Psbt),
     Address(String),
     WalletRegistration(WalletRegistration),
+    Attestation(Attestation),
+}
+
+/// Outcome of a device genuineness check.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Attestation {
+    /// The device signed the challenge with a key certified by a bundled manufacturer root.
+    Genuine,
+    /// The certificate or the challenge signature does not verify. The device should not
+    /// be trusted.
+    Counterfeit,
+    /// The device is certified by a root that is not bundled, so its genuineness could not
+    /// be established.
+    UnknownRoot,
+}
+
+impl std::fmt::Display for Attestation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Attestation::Genuine => "genuine",
+            Attestation::Counterfeit => "counterfeit",
+            Attestation::UnknownRoot => "unknown root",
+        })
+    }
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -161,9 +288,21 @@ pub enum DeviceBackup {
     File(Vec<u8>),
 }
 
+/// Hardware wallet family.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DeviceKind {
+    #[cfg(feature = "bitbox")]
+    BitBox02,
+    Coldcard,
+    Jade,
+    Ledger,
+}
+
 /// Device Information
 #[derive(Debug, Clone, Default)]
 pub struct Info {
+    /// Device that answered, set by its interpreter.
+    pub device: Option<DeviceKind>,
     pub version: String,
     pub networks: Vec<Network>,
     pub firmware: Option<String>,
@@ -171,6 +310,84 @@ pub struct Info {
     pub initialized: Option<bool>,
 }
 
+impl Info {
+    /// What the device supports at this firmware version, `None` when `device` is unset.
+    pub fn capabilities(&self) -> Option<Capabilities> {
+        Some(match self.device? {
+            #[cfg(feature = "bitbox")]
+            DeviceKind::BitBox02 => bitbox::capabilities(self),
+            DeviceKind::Coldcard => coldcard::capabilities(self),
+            DeviceKind::Jade => jade::capabilities(self),
+            DeviceKind::Ledger => ledger::capabilities(self),
+        })
+    }
+}
+
+/// What a device supports, so callers can hide a feature instead of discovering it is
+/// unsupported by error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Capabilities {
+    pub commands: Vec<CommandKind>,
+    /// Single-sig address types of `DisplayAddress::ByPath`.
+    pub script_types: Vec<AddressType>,
+    /// Whether taproot inputs can be signed.
+    pub taproot: bool,
+    /// Whether miniscript wallet policies can be registered and signed for.
+    pub miniscript: bool,
+    /// Whether multisig wallet policies can be registered and signed for.
+    pub multisig: bool,
+    /// Sighash types inputs can be signed with.
+    pub sighash_types: Vec<EcdsaSighashType>,
+    /// How `Command::Backup` backs up the seed, empty when it is not supported.
+    pub backups: Vec<BackupMode>,
+}
+
+impl Capabilities {
+    pub fn supports(&self, command: CommandKind) -> bool {
+        self.commands.contains(&command)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum BackupMode {
+    /// Backups written by the device to its microSD card.
+    MicroSd,
+    /// Encrypted backup file returned to the host.
+    File,
+}
+
+impl std::fmt::Display for BackupMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            BackupMode::MicroSd => "microsd",
+            BackupMode::File => "file",
+        })
+    }
+}
+
+/// Whether a firmware `version` such as `v9.21.0` or `6.3.3X` is at least `min`. Unparsable
+/// versions are assumed recent.
+pub(crate) fn version_at_least(version: &str, min: (u32, u32, u32)) -> bool {
+    let mut numbers = version
+        .trim_start_matches('v')
+        .split(['.', '-'])
+        .map(|part| {
+            part.chars()
+                .take_while(char::is_ascii_digit)
+                .collect::<String>()
+                .parse::<u32>()
+                .ok()
+        });
+    match (
+        numbers.next().flatten(),
+        numbers.next().flatten(),
+        numbers.next().flatten().or(Some(0)),
+    ) {
+        (Some(major), Some(minor), Some(patch)) => (major, minor, patch) >= min,
+        _ => true,
+    }
+}
+
 pub enum Recipient {
     Device,
     PinServer { url: String },
@@ -182,6 +399,20 @@ pub struct Transmit {
     pub encrypted: bool,
 }
 
+/// What a running command is waiting for, so callers can show progress and prompts. See
+/// [`crate::Interpreter::poll_event`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Event {
+    /// `done` of `total` steps of a long transfer or signing pass are complete.
+    Progress { done: usize, total: usize },
+    /// The command now waits for the user to confirm or reject on the device.
+    AwaitingUserConfirmation,
+    /// The code to compare with the one shown on the device while pairing.
+    PairingCodeAvailable(String),
+    /// The next transmit goes to the pin server rather than the device.
+    PinServerRoundTrip,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("encryption error: {0}")]
@@ -193,8 +424,9 @@ pub enum Error {
     #[error("missing command info: {0}")]
     MissingCommandInfo(&'static str),
 
-    #[error("{0}")]
-    Device(String),
+    /// A failure reported by the device, classified by the interpreter that received it.
+    #[error("{1}")]
+    Device(ErrorKind, String),
 
     #[error("unexpected result for {1}: {0:x?}")]
     UnexpectedResult(Vec<u8>, String),
@@ -216,12 +448,91 @@ pub enum Error {
 
     #[error("unsupported display address: {0}")]
     UnsupportedDisplayAddress(String),
+
+    #[error("{operation} is not supported by {device}")]
+    Unsupported {
+        device: &'static str,
+        operation: &'static str,
+    },
+}
+
+impl From<Bip322Error> for Error {
+    fn from(error: Bip322Error) -> Error {
+        match error {
+            Bip322Error::UnsupportedAddress(_) | Bip322Error::InvalidInput(_) => {
+                Error::InvalidInput(error.to_string())
+            }
+            Bip322Error::Psbt(e) => Error::Serialization(e),
+            Bip322Error::UnexpectedResponse | Bip322Error::NotSigned => {
+                Error::unexpected_result(Vec::new(), error.to_string())
+            }
+        }
+    }
 }
 
 impl Error {
     pub fn unexpected_result(data: Vec<u8>, context: impl Into<String>) -> Self {
         Error::UnexpectedResult(data, context.into())
     }
+
+    /// The device-independent class of the error, for applications that react to it.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Error::Device(kind, _) => *kind,
+            Error::Rpc(code, _) => jade::api::ErrorCode::try_from(*code)
+                .map(ErrorKind::from)
+                .unwrap_or(ErrorKind::Protocol),
+            Error::AuthenticationRefused => ErrorKind::UserCancelled,
+            Error::InvalidInput(_) | Error::MissingCommandInfo(_) => ErrorKind::InvalidInput,
+            Error::Unsupported { .. } | Error::UnsupportedDisplayAddress(_) => {
+                ErrorKind::Unsupported
+            }
+            Error::Encryption(_)
+            | Error::NoErrorOrResult
+            | Error::UnexpectedResult(..)
+            | Error::Serialization(_)
+            | Error::Request(_) => ErrorKind::Protocol,
+        }
+    }
+}
+
+/// Device-independent class of an [`Error`], see [`Error::kind`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ErrorKind {
+    /// The user refused or cancelled the operation on the device.
+    UserCancelled,
+    /// The device must be unlocked with its PIN first.
+    Locked,
+    /// The device is set up for another network than the one requested.
+    NetworkMismatch,
+    /// The device has no seed yet.
+    NotInitialized,
+    /// The device is handling another request.
+    Busy,
+    /// The device or its firmware does not support the operation.
+    Unsupported,
+    /// The device rejected the request content.
+    InvalidInput,
+    /// The device replied something the interpreter did not expect.
+    Protocol,
+    /// The host failed to exchange with the device or to persist its state.
+    Transport,
+}
+
+impl std::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ErrorKind::UserCancelled => "user_cancelled",
+            ErrorKind::Locked => "locked",
+            ErrorKind::NetworkMismatch => "network_mismatch",
+            ErrorKind::NotInitialized => "not_initialized",
+            ErrorKind::Busy => "busy",
+            ErrorKind::Unsupported => "unsupported",
+            ErrorKind::InvalidInput => "invalid_input",
+            ErrorKind::Protocol => "protocol",
+            ErrorKind::Transport => "transport",
+        })
+    }
 }
 
 #[cfg(feature = "bitbox")]
@@ -263,4 +574,77 @@ mod tests {
         ];
         assert_eq!(interpreters.len(), 2);
     }
+
+    #[test]
+    fn firmware_versions_compare_numerically() {
+        assert!(version_at_least("v9.21.0", (9, 10, 0)));
+        assert!(version_at_least("6.3.3X", (6, 3, 3)));
+        assert!(version_at_least("2.1", (2, 1, 0)));
+        assert!(!version_at_least("v9.9.1", (9, 10, 0)));
+        assert!(!version_at_least("1.0.9", (1, 0, 26)));
+        assert!(version_at_least("unknown", (9, 10, 0)));
+    }
+
+    #[test]
+    fn errors_map_to_device_independent_kinds() {
+        assert_eq!(Error::Rpc(-32000, None).kind(), ErrorKind::UserCancelled);
+        assert_eq!(Error::Rpc(-32002, None).kind(), ErrorKind::Locked);
+        assert_eq!(Error::Rpc(-32003, None).kind(), ErrorKind::NetworkMismatch);
+        assert_eq!(Error::Rpc(42, None).kind(), ErrorKind::Protocol);
+        assert_eq!(
+            Error::AuthenticationRefused.kind(),
+            ErrorKind::UserCancelled
+        );
+
+        let locked: Error =
+            ledger::LedgerError::status(ledger::apdu::StatusWord::LockedDevice, "get_version")
+                .into();
+        assert_eq!(locked.kind(), ErrorKind::Locked);
+        assert_eq!(
+            locked.to_string(),
+            "get_version failed with status LockedDevice"
+        );
+
+        #[cfg(feature = "bitbox")]
+        {
+            let error: Error =
+                bitbox::error::BitBoxError::Device(bitbox::error::BitBoxDeviceError::InvalidState)
+                    .into();
+            assert_eq!(error.kind(), ErrorKind::NotInitialized);
+        }
+        assert_eq!(ErrorKind::NetworkMismatch.to_string(), "network_mismatch");
+    }
+
+    #[test]
+    fn capabilities_follow_the_reported_firmware() {
+        let info = |device, version: &str, firmware: Option<&str>| Info {
+            device: Some(device),
+            version: version.into(),
+            firmware: firmware.map(Into::into),
+            ..Default::default()
+        };
+
+        let app = info(DeviceKind::Ledger, "2.2.1", Some("Bitcoin Test"))
+            .capabilities()
+            .unwrap();
+        assert!(app.taproot && app.miniscript);
+        assert!(app.supports(CommandKind::RegisterWallet));
+        let legacy = info(DeviceKind::Ledger, "2.0.6", Some("Bitcoin Test"))
+            .capabilities()
+            .unwrap();
+        assert!(!legacy.taproot && !legacy.multisig);
+        assert!(!legacy.supports(CommandKind::RegisterWallet));
+
+        let edge = info(DeviceKind::Coldcard, "6.3.3X", Some("mk4"))
+            .capabilities()
+            .unwrap();
+        assert!(edge.taproot && edge.supports(CommandKind::Backup));
+        let mainline = info(DeviceKind::Coldcard, "5.4.0", Some("mk4"))
+            .capabilities()
+            .unwrap();
+        assert!(!mainline.taproot);
+        assert!(!mainline.supports(CommandKind::Setup));
+
+        assert_eq!(Info::default().capabilities(), None);
+    }
 }
@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::{collections::BTreeMap, fmt::Display};
 
 use super::JadeError;
+use crate::common::ErrorKind;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Request<'a, T: Serialize> {
@@ -47,6 +48,37 @@ pub enum ErrorCode {
     NetworkMismatch = -32003,
 }
 
+impl TryFrom<i32> for ErrorCode {
+    type Error = i32;
+
+    fn try_from(code: i32) -> Result<Self, Self::Error> {
+        match code {
+            -32600 => Ok(ErrorCode::InvalidRequest),
+            -32601 => Ok(ErrorCode::UnknownMethod),
+            -32602 => Ok(ErrorCode::BadParameters),
+            -32603 => Ok(ErrorCode::InternalError),
+            -32000 => Ok(ErrorCode::UserCancelled),
+            -32001 => Ok(ErrorCode::ProtocolError),
+            -32002 => Ok(ErrorCode::HwLocked),
+            -32003 => Ok(ErrorCode::NetworkMismatch),
+            code => Err(code),
+        }
+    }
+}
+
+impl From<ErrorCode> for ErrorKind {
+    fn from(code: ErrorCode) -> Self {
+        match code {
+            ErrorCode::UserCancelled => ErrorKind::UserCancelled,
+            ErrorCode::HwLocked => ErrorKind::Locked,
+            ErrorCode::NetworkMismatch => ErrorKind::NetworkMismatch,
+            ErrorCode::UnknownMethod => ErrorKind::Unsupported,
+            ErrorCode::InvalidRequest | ErrorCode::BadParameters => ErrorKind::InvalidInput,
+            ErrorCode::InternalError | ErrorCode::ProtocolError => ErrorKind::Protocol,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Error {
     pub code: i32,
@@ -64,6 +96,8 @@ pub struct GetXpubParams<'a> {
 pub struct AuthUserParams<'a> {
     pub network: &'a str,
     pub epoch: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub passphrase: Option<&'a str>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -108,6 +142,8 @@ pub struct GetInfoResponse {
     pub jade_state: JadeState,
     #[serde(alias = "JADE_NETWORKS")]
     pub jade_networks: JadeNetworks,
+    #[serde(alias = "JADE_OTA_MAX_CHUNK", default)]
+    pub jade_ota_max_chunk: Option<u32>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -249,6 +285,57 @@ pub struct SignPsbtParams<'a> {
 pub struct SignMessageParams<'a> {
     pub path: Vec<u32>,
     pub message: &'a str,
+    #[serde(with = "serde_bytes")]
+    pub ae_host_commitment: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GetSignatureParams {
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub ae_host_entropy: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignTxParams<'a> {
+    pub network: &'a str,
+    #[serde(with = "serde_bytes")]
+    pub txn: Vec<u8>,
+    pub num_inputs: u32,
+    pub use_ae_signatures: bool,
+    pub change: Vec<Option<ChangeOutput>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeOutput {
+    pub path: Vec<u32>,
+    pub variant: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct TxInputParams {
+    pub is_witness: bool,
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub input_tx: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub satoshi: Option<u64>,
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub script: Option<Vec<u8>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<Vec<u32>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sighash: Option<u32>,
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub ae_host_commitment: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct OtaParams {
+    pub fwsize: u32,
+    pub cmpsize: u32,
+    #[serde(with = "serde_bytes")]
+    pub cmphash: Vec<u8>,
+    #[serde(with = "serde_bytes", default, skip_serializing_if = "Option::is_none")]
+    pub fwhash: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -259,6 +346,23 @@ pub struct GetExtendedDataParams<'a> {
     pub seqlen: u32,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignAttestationParams {
+    #[serde(with = "serde_bytes")]
+    pub challenge: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SignAttestationResponse {
+    /// RSA-PSS signature of the challenge by the device attestation key.
+    #[serde(with = "serde_bytes")]
+    pub signature: Vec<u8>,
+    pub pubkey_pem: String,
+    /// Signature of `pubkey_pem` by the Blockstream attestation authority.
+    #[serde(with = "serde_bytes")]
+    pub ext_signature: Vec<u8>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResponseBytes {
     pub id: String,
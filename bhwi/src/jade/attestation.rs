@@ -0,0 +1,201 @@
+//! Jade attestation: proof that a device was made by Blockstream.
+//!
+//! Jade holds an RSA attestation key whose PEM encoded public key is signed by the
+//! Blockstream attestation authority. `sign_attestation` returns that certificate with the
+//! device signature of a random challenge, both RSA-PSS over SHA-256.
+
+use base64ct::{Base64, Encoding};
+use ring::signature::{RSA_PSS_2048_8192_SHA256, UnparsedPublicKey};
+
+use crate::common::Attestation;
+
+use super::JadeError;
+use super::api::SignAttestationResponse;
+
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_BIT_STRING: u8 = 0x03;
+const RSA_ENCRYPTION_OID: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01,
+];
+
+/// DER encoded `SubjectPublicKeyInfo` of the Blockstream attestation authorities.
+///
+/// No authority is bundled yet: the key must be copied from the one Blockstream publishes
+/// with the Jade firmware, never retyped. Until it is, every device that signs the
+/// challenge reports [`Attestation::UnknownRoot`].
+pub const ATTESTATION_AUTHORITIES: &[&[u8]] = &[];
+
+pub fn gen_challenge() -> Result<[u8; 32], JadeError> {
+    let mut challenge = [0u8; 32];
+    getrandom::getrandom(&mut challenge)
+        .map_err(|_| JadeError::Attestation("failed generating challenge".to_string()))?;
+    Ok(challenge)
+}
+
+/// Check the answer of the device to `sign_attestation` against `authorities`.
+pub fn verify_attestation(
+    authorities: &[&[u8]],
+    challenge: &[u8],
+    response: &SignAttestationResponse,
+) -> Result<Attestation, JadeError> {
+    let device_key = pem_to_der(&response.pubkey_pem)?;
+    if !verify_pss(&device_key, challenge, &response.signature)? {
+        return Ok(Attestation::Counterfeit);
+    }
+    if authorities.is_empty() {
+        return Ok(Attestation::UnknownRoot);
+    }
+    for authority in authorities {
+        if verify_pss(
+            authority,
+            response.pubkey_pem.as_bytes(),
+            &response.ext_signature,
+        )? {
+            return Ok(Attestation::Genuine);
+        }
+    }
+    Ok(Attestation::Counterfeit)
+}
+
+fn verify_pss(spki: &[u8], message: &[u8], signature: &[u8]) -> Result<bool, JadeError> {
+    let key = rsa_public_key(spki)?;
+    Ok(UnparsedPublicKey::new(&RSA_PSS_2048_8192_SHA256, key)
+        .verify(message, signature)
+        .is_ok())
+}
+
+fn pem_to_der(pem: &str) -> Result<Vec<u8>, JadeError> {
+    let body: String = pem
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with("-----"))
+        .collect();
+    Base64::decode_vec(&body).map_err(|e| JadeError::Attestation(e.to_string()))
+}
+
+/// The PKCS#1 `RSAPublicKey` wrapped in a `SubjectPublicKeyInfo`.
+fn rsa_public_key(spki: &[u8]) -> Result<&[u8], JadeError> {
+    let malformed = || JadeError::Attestation("malformed RSA public key".to_string());
+    let (TAG_SEQUENCE, spki, _) = der_element(spki).ok_or_else(malformed)? else {
+        return Err(malformed());
+    };
+    let (TAG_SEQUENCE, algorithm, rest) = der_element(spki).ok_or_else(malformed)? else {
+        return Err(malformed());
+    };
+    if !algorithm.starts_with(RSA_ENCRYPTION_OID) {
+        return Err(malformed());
+    }
+    let (TAG_BIT_STRING, bits, _) = der_element(rest).ok_or_else(malformed)? else {
+        return Err(malformed());
+    };
+    match bits.split_first() {
+        Some((0, key)) => Ok(key),
+        _ => Err(malformed()),
+    }
+}
+
+/// Split a DER element into its tag, its content and the bytes following it.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, input) = input.split_first()?;
+    let (len, input) = if first < 0x80 {
+        (first as usize, input)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > 4 || input.len() < count {
+            return None;
+        }
+        let (len, input) = input.split_at(count);
+        let len = len.iter().fold(0usize, |acc, &b| (acc << 8) | b as usize);
+        (len, input)
+    };
+    if input.len() < len {
+        return None;
+    }
+    let (content, rest) = input.split_at(len);
+    Some((tag, content, rest))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ring::rand::SystemRandom;
+    use ring::signature::{RSA_PSS_SHA256, RsaKeyPair};
+
+    const KEY_PKCS8: &[u8] = include_bytes!("testdata/attestation_key.pk8");
+    const KEY_PEM: &str = include_str!("testdata/attestation_key.pem");
+
+    fn sign(message: &[u8]) -> Vec<u8> {
+        let key = RsaKeyPair::from_pkcs8(KEY_PKCS8).unwrap();
+        let mut signature = vec![0; key.public().modulus_len()];
+        key.sign(
+            &RSA_PSS_SHA256,
+            &SystemRandom::new(),
+            message,
+            &mut signature,
+        )
+        .unwrap();
+        signature
+    }
+
+    /// A device whose key certifies itself, signing `signed` as the challenge.
+    fn device_response(signed: &[u8]) -> SignAttestationResponse {
+        SignAttestationResponse {
+            signature: sign(signed),
+            pubkey_pem: KEY_PEM.to_string(),
+            ext_signature: sign(KEY_PEM.as_bytes()),
+        }
+    }
+
+    #[test]
+    fn certified_device_is_genuine() {
+        let authority = pem_to_der(KEY_PEM).unwrap();
+        let challenge = gen_challenge().unwrap();
+        assert_eq!(
+            verify_attestation(
+                &[authority.as_slice()],
+                &challenge,
+                &device_response(&challenge)
+            )
+            .unwrap(),
+            Attestation::Genuine
+        );
+    }
+
+    #[test]
+    fn wrong_signatures_are_counterfeit() {
+        let authority = pem_to_der(KEY_PEM).unwrap();
+        assert_eq!(
+            verify_attestation(
+                &[authority.as_slice()],
+                &[2; 32],
+                &device_response(&[1; 32])
+            )
+            .unwrap(),
+            Attestation::Counterfeit
+        );
+
+        let mut response = device_response(&[1; 32]);
+        response.ext_signature = sign(b"another key");
+        assert_eq!(
+            verify_attestation(&[authority.as_slice()], &[1; 32], &response).unwrap(),
+            Attestation::Counterfeit
+        );
+    }
+
+    #[test]
+    fn without_authority_the_root_is_unknown() {
+        assert_eq!(
+            verify_attestation(&[], &[1; 32], &device_response(&[1; 32])).unwrap(),
+            Attestation::UnknownRoot
+        );
+    }
+
+    #[test]
+    fn malformed_keys_are_rejected() {
+        let mut response = device_response(&[1; 32]);
+        response.pubkey_pem = "-----BEGIN PUBLIC KEY-----\nMAA=\n-----END PUBLIC KEY-----".into();
+        assert!(verify_attestation(&[], &[1; 32], &response).is_err());
+        assert!(rsa_public_key(&[0x30, 0x82, 0x01]).is_err());
+    }
+}
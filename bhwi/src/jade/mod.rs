@@ -1,6 +1,11 @@
 pub mod api;
+pub mod attestation;
+mod ota;
+mod sign_tx;
 
-use std::collections::BTreeMap;
+pub use ota::{DEFAULT_OTA_CHUNK_SIZE, OtaProgress, OtaUpdate};
+
+use std::collections::{BTreeMap, VecDeque};
 use std::str::FromStr;
 use std::sync::atomic::{AtomicUsize, Ordering};
 
@@ -15,9 +20,13 @@ use serde::Serialize;
 use serde::de::DeserializeOwned;
 
 use crate::Interpreter;
+use crate::RawCommand;
+use crate::antiexfil::{self, AntiExfilError};
+use crate::bip322::{self, Bip322Error};
 use crate::common::{
-    Command, DisplayAddress, Error, Info, MultisigAddressType, MultisigDisplayAddress, Recipient,
-    Response, Transmit,
+    Attestation, Capabilities, Command, CommandKind, DeviceKind, DisplayAddress, Error, ErrorKind,
+    Event, Info, MultisigAddressType, MultisigDisplayAddress, Recipient, Response, Transmit,
+    Zeroizing,
 };
 use crate::device::DeviceId;
 use crate::jade::api::GetInfoResponse;
@@ -45,10 +54,28 @@ pub enum JadeError {
     UnexpectedResult(String),
     HandshakeRefused,
     UnsupportedDisplayAddress,
+    AntiExfil(String),
+    Attestation(String),
+    Bip322(Bip322Error),
+}
+
+impl From<AntiExfilError> for JadeError {
+    fn from(e: AntiExfilError) -> Self {
+        JadeError::AntiExfil(e.to_string())
+    }
+}
+
+impl From<Bip322Error> for JadeError {
+    fn from(e: Bip322Error) -> Self {
+        JadeError::Bip322(e)
+    }
 }
 
 pub enum JadeCommand {
     Auth,
+    /// Log out, then authenticate again with the given BIP39 passphrase, or without one.
+    /// Answered with the master fingerprint of the wallet now in use.
+    SetPassphrase(Option<Zeroizing<String>>),
     GetMasterFingerprint,
     GetInfo,
     GetXpub(DerivationPath),
@@ -67,9 +94,18 @@ pub enum JadeCommand {
         message: Vec<u8>,
         path: DerivationPath,
     },
+    /// Run as a [`bip322::Session`] of the other commands.
+    SignMessageBip322 {
+        message: Vec<u8>,
+        path: DerivationPath,
+        address_type: AddressType,
+    },
     SignPsbt {
         psbt: Psbt,
     },
+    OtaUpdate(OtaUpdate),
+    /// Have the device sign a random challenge with its attestation key.
+    VerifyAttestation,
 }
 
 pub enum ReceiveAddress {
@@ -92,11 +128,13 @@ pub enum JadeResponse {
     GetInfo(GetInfoResponse),
     MasterFingerprint(Fingerprint),
     Signature(u8, Signature),
+    Bip322Signature(String),
     TaskDone,
     Xpub(Xpub),
     Address(String),
     RegisteredDescriptor,
     SignedPsbt(Psbt),
+    Attestation(Attestation),
 }
 
 pub enum JadeRecipient {
@@ -112,6 +150,15 @@ pub struct JadeTransmit {
 enum State {
     New,
     Running(JadeCommand),
+    SigningMessage {
+        host_nonce: [u8; 32],
+        signer_commitment: Option<Vec<u8>>,
+    },
+    SigningTx(sign_tx::Session),
+    Updating(ota::Session),
+    Attesting {
+        challenge: [u8; 32],
+    },
     WaitingPinServer,
     WaitingFinalHandshake,
     GettingExtendedData {
@@ -126,7 +173,11 @@ enum State {
 pub struct JadeInterpreter<C, T, R, E> {
     network: &'static str,
     state: State,
+    /// Answer authentication with the master fingerprint, for `SetPassphrase`.
+    fingerprint_after_auth: bool,
+    bip322: Option<bip322::Session>,
     response: Option<JadeResponse>,
+    events: VecDeque<Event>,
     _marker: std::marker::PhantomData<(C, T, R, E)>,
 }
 
@@ -135,7 +186,10 @@ impl<C, T, R, E> Default for JadeInterpreter<C, T, R, E> {
         Self {
             network: JADE_NETWORK_MAINNET,
             state: State::New,
+            fingerprint_after_auth: false,
+            bip322: None,
             response: None,
+            events: VecDeque::new(),
             _marker: std::marker::PhantomData,
         }
     }
@@ -150,6 +204,15 @@ impl<C, T, R, E> JadeInterpreter<C, T, R, E> {
         };
         self
     }
+
+    /// Bytes of the firmware image accepted by the device so far, while an
+    /// [`JadeCommand::OtaUpdate`] runs.
+    pub fn ota_progress(&self) -> Option<OtaProgress> {
+        match &self.state {
+            State::Updating(session) => Some(session.progress()),
+            _ => None,
+        }
+    }
 }
 
 // Initialize a static atomic counter
@@ -180,6 +243,20 @@ where
     .into())
 }
 
+fn get_master_xpub<T, E>(network: &str) -> Result<T, E>
+where
+    T: From<JadeTransmit>,
+    E: From<JadeError>,
+{
+    request(
+        "get_xpub",
+        Some(api::GetXpubParams {
+            network,
+            path: DerivationPath::master().to_u32_vec(),
+        }),
+    )
+}
+
 fn from_response<D: DeserializeOwned>(buffer: &[u8]) -> Result<api::Response<D>, JadeError> {
     serde_cbor::from_slice(buffer).map_err(|_| JadeError::Cbor)
 }
@@ -188,41 +265,76 @@ fn from_response_bytes(buffer: &[u8]) -> Result<api::ResponseBytes, JadeError> {
     serde_cbor::from_slice(buffer).map_err(|_| JadeError::Cbor)
 }
 
+fn ota_event(session: &ota::Session) -> Event {
+    let progress = session.progress();
+    Event::Progress {
+        done: progress.written,
+        total: progress.total,
+    }
+}
+
+/// The Jade command of a step of a [`bip322::Session`]. `TryFrom<Command>` is not used as its
+/// error is not a `JadeError`.
+fn bip322_command(command: Command) -> Result<JadeCommand, JadeError> {
+    match command {
+        Command::GetMasterFingerprint => Ok(JadeCommand::GetMasterFingerprint),
+        Command::GetXpub { path, .. } => Ok(JadeCommand::GetXpub(path)),
+        Command::SignTx(psbt, _) => Ok(JadeCommand::SignPsbt { psbt }),
+        _ => Err(JadeError::Bip322(Bip322Error::UnexpectedResponse)),
+    }
+}
+
 fn parse_signed_psbt(bytes: &[u8]) -> Result<JadeResponse, JadeError> {
     Psbt::deserialize(bytes)
         .map(JadeResponse::SignedPsbt)
         .map_err(|e| JadeError::Serialization(e.to_string()))
 }
 
-impl<C, T, R, E> Interpreter for JadeInterpreter<C, T, R, E>
+impl<C, T, R, E> JadeInterpreter<C, T, R, E>
 where
-    C: TryInto<JadeCommand, Error = E>,
     T: From<JadeTransmit>,
-    R: From<JadeResponse>,
     E: From<JadeError>,
 {
-    type Command = C;
-    type Transmit = T;
-    type Response = R;
-    type Error = E;
-
-    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
-        let command: JadeCommand = command.try_into()?;
+    fn start_command(&mut self, command: JadeCommand) -> Result<T, E> {
+        let command = match command {
+            JadeCommand::SignPsbt { psbt } if sign_tx::is_supported(&psbt) => {
+                let (session, transmit) = sign_tx::Session::start(psbt, self.network)?;
+                self.state = State::SigningTx(session);
+                return Ok(transmit.into());
+            }
+            JadeCommand::OtaUpdate(update) => {
+                let (session, transmit) = ota::Session::start(update)?;
+                self.events.push_back(ota_event(&session));
+                self.state = State::Updating(session);
+                return Ok(transmit.into());
+            }
+            JadeCommand::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => {
+                let (session, command) = bip322::Session::start(message, path, address_type, false)
+                    .map_err(JadeError::from)?;
+                self.bip322 = Some(session);
+                return self.start_command(bip322_command(command)?);
+            }
+            command => command,
+        };
         let req = match &command {
             JadeCommand::Auth => request(
                 "auth_user",
                 Some(api::AuthUserParams {
                     network: self.network,
                     epoch: None,
+                    passphrase: None,
                 }),
             ),
-            JadeCommand::GetMasterFingerprint => request(
-                "get_xpub",
-                Some(api::GetXpubParams {
-                    network: self.network,
-                    path: DerivationPath::master().to_u32_vec(),
-                }),
-            ),
+            JadeCommand::SetPassphrase(_) => {
+                // A passphrase is only taken when the wallet is unlocked.
+                self.fingerprint_after_auth = true;
+                request("logout", None::<api::EmptyRequest>)
+            }
+            JadeCommand::GetMasterFingerprint => get_master_xpub(self.network),
             JadeCommand::GetXpub(path) => request(
                 "get_xpub",
                 Some(api::GetXpubParams {
@@ -230,14 +342,24 @@ where
                     path: path.to_u32_vec(),
                 }),
             ),
-            JadeCommand::SignMessage { message, path } => request(
-                "sign_message",
-                Some(api::SignMessageParams {
-                    path: path.to_u32_vec(),
-                    message: &String::from_utf8(message.to_vec())
-                        .map_err(|e| JadeError::Serialization(e.to_string()))?,
-                }),
-            ),
+            JadeCommand::SignMessage { message, path } => {
+                let host_nonce = antiexfil::gen_host_nonce().map_err(JadeError::from)?;
+                let req = request(
+                    "sign_message",
+                    Some(api::SignMessageParams {
+                        path: path.to_u32_vec(),
+                        message: &String::from_utf8(message.to_vec())
+                            .map_err(|e| JadeError::Serialization(e.to_string()))?,
+                        ae_host_commitment: antiexfil::host_commit(&host_nonce).to_vec(),
+                    }),
+                );
+                self.state = State::SigningMessage {
+                    host_nonce,
+                    signer_commitment: None,
+                };
+                self.events.push_back(Event::AwaitingUserConfirmation);
+                return req;
+            }
             JadeCommand::SignPsbt { psbt } => request(
                 "sign_psbt",
                 Some(api::SignPsbtParams {
@@ -305,12 +427,105 @@ where
                 }),
             ),
             JadeCommand::GetInfo => request("get_version_info", None::<api::EmptyRequest>),
+            JadeCommand::VerifyAttestation => {
+                let challenge = attestation::gen_challenge()?;
+                let req = request(
+                    "sign_attestation",
+                    Some(api::SignAttestationParams {
+                        challenge: challenge.to_vec(),
+                    }),
+                );
+                self.state = State::Attesting { challenge };
+                return req;
+            }
+            JadeCommand::OtaUpdate(_) | JadeCommand::SignMessageBip322 { .. } => {
+                unreachable!("handled before the request match")
+            }
         };
 
+        if matches!(
+            command,
+            JadeCommand::SignPsbt { .. }
+                | JadeCommand::GetReceiveAddress(_)
+                | JadeCommand::RegisterDescriptor { .. }
+                | JadeCommand::RegisterMultisig { .. }
+        ) {
+            self.events.push_back(Event::AwaitingUserConfirmation);
+        }
         self.state = State::Running(command);
         req
     }
-    fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
+
+    fn exchange_command(&mut self, data: Vec<u8>) -> Result<Option<T>, E> {
+        if let State::SigningTx(session) = &mut self.state {
+            return match session.exchange(&data)? {
+                sign_tx::Step::Send(transmit) => {
+                    self.events.extend(session.event());
+                    Ok(Some(transmit.into()))
+                }
+                sign_tx::Step::Done(psbt) => {
+                    self.response = Some(JadeResponse::SignedPsbt(psbt));
+                    Ok(None)
+                }
+            };
+        }
+        if let State::Updating(session) = &mut self.state {
+            return match session.exchange(&data)? {
+                ota::Step::Send(transmit) => {
+                    self.events.push_back(ota_event(session));
+                    Ok(Some(transmit.into()))
+                }
+                ota::Step::Done => {
+                    self.response = Some(JadeResponse::TaskDone);
+                    Ok(None)
+                }
+            };
+        }
+        if let State::Attesting { challenge } = &self.state {
+            let res: api::SignAttestationResponse = from_response(&data)?.into_result()?;
+            let attestation = attestation::verify_attestation(
+                attestation::ATTESTATION_AUTHORITIES,
+                challenge,
+                &res,
+            )?;
+            self.response = Some(JadeResponse::Attestation(attestation));
+            return Ok(None);
+        }
+        if let State::SigningMessage {
+            host_nonce,
+            signer_commitment,
+        } = &mut self.state
+        {
+            let Some(commitment) = signer_commitment.as_ref() else {
+                let res = from_response_bytes(&data)?;
+                if let Some(e) = res.error {
+                    return Err(JadeError::Rpc(e).into());
+                }
+                *signer_commitment = Some(res.result.ok_or(JadeError::NoErrorOrResult)?);
+                return Ok(Some(request(
+                    "get_signature",
+                    Some(api::GetSignatureParams {
+                        ae_host_entropy: Some(host_nonce.to_vec()),
+                    }),
+                )?));
+            };
+            let s: String = from_response(&data)?.into_result()?;
+            let sig_bytes =
+                Base64::decode_vec(&s).map_err(|e| JadeError::Serialization(e.to_string()))?;
+            if sig_bytes.len() != 65 {
+                return Err(JadeError::Serialization(format!(
+                    "unexpected signature length {}",
+                    sig_bytes.len()
+                ))
+                .into());
+            }
+            antiexfil::verify_ecdsa(&host_nonce[..], commitment, &sig_bytes[1..])
+                .map_err(JadeError::from)?;
+            let sig = Signature::from_compact(&sig_bytes[1..])
+                .map_err(|e| JadeError::Serialization(e.to_string()))?;
+            self.response = Some(JadeResponse::Signature(sig_bytes[0], sig));
+            return Ok(None);
+        }
         if let State::GettingExtendedData {
             origid,
             orig,
@@ -332,6 +547,10 @@ where
                 .into());
             }
             chunks.extend_from_slice(&chunk);
+            self.events.push_back(Event::Progress {
+                done: seqnum as usize,
+                total: *seqlen as usize,
+            });
             if seqnum >= *seqlen {
                 self.response = Some(parse_signed_psbt(chunks)?);
                 return Ok(None);
@@ -354,11 +573,30 @@ where
 
         let transmit = match &self.state {
             State::New => None,
+            State::Running(JadeCommand::SetPassphrase(passphrase)) => {
+                let logged_out: bool = from_response(&data)?.into_result()?;
+                if !logged_out {
+                    return Err(
+                        JadeError::UnexpectedResult("logout returned false".to_string()).into(),
+                    );
+                }
+                let req = request(
+                    "auth_user",
+                    Some(api::AuthUserParams {
+                        network: self.network,
+                        epoch: None,
+                        passphrase: passphrase.as_ref().map(|p| p.as_str()),
+                    }),
+                )?;
+                next_state = Some(State::Running(JadeCommand::Auth));
+                Some(req)
+            }
             State::Running(JadeCommand::Auth) => {
                 let res: api::AuthUserResponse = from_response(&data)?.into_result()?;
                 match res {
                     api::AuthUserResponse::PinServerRequired { http_request } => {
                         next_state = Some(State::WaitingPinServer);
+                        self.events.push_back(Event::PinServerRoundTrip);
                         let url = match &http_request.params.urls {
                             api::PinServerUrls::Array(urls) => urls.first().ok_or(
                                 JadeError::UnexpectedResult("No url provided".to_string()),
@@ -376,6 +614,10 @@ where
                             .into(),
                         )
                     }
+                    api::AuthUserResponse::Authenticated(_) if self.fingerprint_after_auth => {
+                        next_state = Some(State::Running(JadeCommand::GetMasterFingerprint));
+                        Some(get_master_xpub(self.network)?)
+                    }
                     api::AuthUserResponse::Authenticated(_) => {
                         response = Some(JadeResponse::TaskDone);
                         None
@@ -394,8 +636,13 @@ where
                 if !handshake_completed {
                     return Err(JadeError::HandshakeRefused.into());
                 }
-                response = Some(JadeResponse::TaskDone);
-                None
+                if self.fingerprint_after_auth {
+                    next_state = Some(State::Running(JadeCommand::GetMasterFingerprint));
+                    Some(get_master_xpub(self.network)?)
+                } else {
+                    response = Some(JadeResponse::TaskDone);
+                    None
+                }
             }
             State::Running(JadeCommand::GetMasterFingerprint) => {
                 let s: String = from_response(&data)?.into_result()?;
@@ -411,15 +658,6 @@ where
                 response = Some(JadeResponse::Xpub(xpub));
                 None
             }
-            State::Running(JadeCommand::SignMessage { .. }) => {
-                let s: String = from_response(&data)?.into_result()?;
-                let sig_bytes =
-                    Base64::decode_vec(&s).map_err(|e| JadeError::Serialization(e.to_string()))?;
-                let sig = Signature::from_compact(&sig_bytes[1..])
-                    .map_err(|e| JadeError::Serialization(e.to_string()))?;
-                response = Some(JadeResponse::Signature(sig_bytes[0], sig));
-                None
-            }
             State::Running(JadeCommand::SignPsbt { .. }) => {
                 let res = from_response_bytes(&data)?;
                 if let Some(e) = res.error {
@@ -438,6 +676,10 @@ where
                     response = Some(parse_signed_psbt(&chunk)?);
                     None
                 } else {
+                    self.events.push_back(Event::Progress {
+                        done: seqnum as usize,
+                        total: seqlen as usize,
+                    });
                     let next_seqnum = seqnum + 1;
                     next_state = Some(State::GettingExtendedData {
                         origid: res.id.clone(),
@@ -457,7 +699,19 @@ where
                     )?)
                 }
             }
-            State::GettingExtendedData { .. } => unreachable!("handled before immutable match"),
+            State::GettingExtendedData { .. }
+            | State::SigningMessage { .. }
+            | State::SigningTx(_)
+            | State::Updating(_)
+            | State::Attesting { .. }
+            | State::Running(
+                JadeCommand::SignMessage { .. }
+                | JadeCommand::SignMessageBip322 { .. }
+                | JadeCommand::OtaUpdate(_)
+                | JadeCommand::VerifyAttestation,
+            ) => {
+                unreachable!("handled before immutable match")
+            }
             State::Running(JadeCommand::GetReceiveAddress(_)) => {
                 let address: String = from_response(&data)?.into_result()?;
                 response = Some(JadeResponse::Address(address));
@@ -516,11 +770,87 @@ where
         }
         Ok(transmit)
     }
+
+    /// Feed the response of the command that just finished to the running BIP-322 session,
+    /// starting its next command or finishing with its signature.
+    fn next_bip322_step(&mut self) -> Result<Option<T>, E> {
+        let Some(session) = self.bip322.as_mut() else {
+            return Ok(None);
+        };
+        let response = self.response.take().ok_or(JadeError::NoErrorOrResult)?;
+        match session
+            .next(Response::from(response))
+            .map_err(JadeError::from)?
+        {
+            bip322::Next::Run(command) => self.start_command(bip322_command(command)?).map(Some),
+            bip322::Next::Signature(signature) => {
+                self.bip322 = None;
+                self.response = Some(JadeResponse::Bip322Signature(signature));
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<C, T, R, E> Interpreter for JadeInterpreter<C, T, R, E>
+where
+    C: TryInto<JadeCommand, Error = E>,
+    T: From<JadeTransmit>,
+    R: From<JadeResponse>,
+    E: From<JadeError>,
+{
+    type Command = C;
+    type Transmit = T;
+    type Response = R;
+    type Error = E;
+
+    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
+        self.start_command(command.try_into()?)
+    }
+
+    fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
+        let transmit = self.exchange_command(data)?;
+        if transmit.is_some() {
+            return Ok(transmit);
+        }
+        self.next_bip322_step()
+    }
     fn end(self) -> Result<Self::Response, Self::Error> {
         self.response
             .map(Self::Response::from)
             .ok_or_else(|| JadeError::NoErrorOrResult.into())
     }
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+/// Capabilities of Jade. Addresses are displayed by path for the script types of
+/// `jade_path_variant` only, although taproot inputs can be signed.
+pub fn capabilities(_info: &Info) -> Capabilities {
+    let mut commands = CommandKind::BASE.to_vec();
+    commands.extend([
+        CommandKind::SignMessageBip322,
+        CommandKind::SetPassphrase,
+        CommandKind::RegisterWallet,
+        CommandKind::VerifyAttestation,
+    ]);
+    Capabilities {
+        commands,
+        script_types: vec![AddressType::P2pkh, AddressType::P2sh, AddressType::P2wpkh],
+        taproot: true,
+        miniscript: true,
+        multisig: true,
+        sighash_types: vec![bitcoin::sighash::EcdsaSighashType::All],
+        backups: vec![],
+    }
+}
+
+impl TryFrom<RawCommand<JadeCommand>> for JadeCommand {
+    type Error = JadeError;
+    fn try_from(cmd: RawCommand<JadeCommand>) -> Result<Self, Self::Error> {
+        Ok(cmd.0)
+    }
 }
 
 impl TryFrom<Command> for JadeCommand {
@@ -536,6 +866,7 @@ impl TryFrom<Command> for JadeCommand {
             )),
             Command::Backup => Err(Error::MissingCommandInfo("Backup not supported by Jade")),
             Command::Unlock { .. } => Ok(Self::Auth),
+            Command::SetPassphrase(passphrase) => Ok(Self::SetPassphrase(passphrase)),
             Command::GetMasterFingerprint => Ok(Self::GetMasterFingerprint),
             Command::GetXpub { path, .. } => Ok(Self::GetXpub(path)),
             Command::DisplayAddress(
@@ -566,6 +897,15 @@ impl TryFrom<Command> for JadeCommand {
                 jade_multisig_command(address)
             }
             Command::SignMessage { message, path } => Ok(Self::SignMessage { message, path }),
+            Command::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => Ok(Self::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            }),
             Command::GetVersion => Ok(Self::GetInfo),
             Command::RegisterWallet { name, policy } => {
                 let (descriptor, keys) = crate::policy::extract_parts(&policy)
@@ -585,6 +925,7 @@ impl TryFrom<Command> for JadeCommand {
                 })
             }
             Command::SignTx(psbt, _) => Ok(Self::SignPsbt { psbt }),
+            Command::VerifyAttestation => Ok(Self::VerifyAttestation),
         }
     }
 }
@@ -669,11 +1010,13 @@ impl From<JadeResponse> for Response {
             JadeResponse::MasterFingerprint(fg) => Response::MasterFingerprint(fg),
             JadeResponse::Xpub(xpub) => Response::Xpub(xpub),
             JadeResponse::Signature(header, signature) => Response::Signature(header, signature),
+            JadeResponse::Bip322Signature(signature) => Response::Bip322Signature(signature),
             JadeResponse::GetInfo(info) => Response::Info(Info {
+                device: Some(DeviceKind::Jade),
                 version: info.jade_version.as_str().into(),
                 networks: info.jade_networks.into(),
                 firmware: None,
-                initialized: None,
+                initialized: Some(info.jade_state != api::JadeState::Uninit),
             }),
             JadeResponse::Address(address) => Response::Address(address),
             JadeResponse::RegisteredDescriptor => {
@@ -682,6 +1025,7 @@ impl From<JadeResponse> for Response {
                 })
             }
             JadeResponse::SignedPsbt(psbt) => Response::SignedPsbt(psbt),
+            JadeResponse::Attestation(attestation) => Response::Attestation(attestation),
         }
     }
 }
@@ -720,6 +1064,14 @@ impl From<JadeError> for Error {
             JadeError::UnsupportedDisplayAddress => {
                 Error::UnsupportedDisplayAddress("unsupported display address on Jade".into())
             }
+            JadeError::AntiExfil(msg) => Error::Device(
+                ErrorKind::Protocol,
+                format!("anti-exfil verification failed: {msg}"),
+            ),
+            JadeError::Attestation(msg) => {
+                Error::Device(ErrorKind::Protocol, format!("attestation error: {msg}"))
+            }
+            JadeError::Bip322(e) => Error::from(e),
         }
     }
 }
@@ -929,4 +1281,449 @@ mod tests {
         );
         assert_eq!(paths, vec![vec![0, 7], vec![0, 7]]);
     }
+
+    #[derive(Debug, Deserialize)]
+    struct Rpc<P> {
+        method: String,
+        params: Option<P>,
+    }
+
+    fn decode<'de, P: Deserialize<'de>>(payload: &'de [u8]) -> (String, P) {
+        let rpc: Rpc<P> = serde_cbor::from_slice(payload).unwrap();
+        (rpc.method, rpc.params.unwrap())
+    }
+
+    fn reply<T: Serialize>(result: T) -> Vec<u8> {
+        serde_cbor::to_vec(&api::Response {
+            id: "1".to_string(),
+            seqlen: None,
+            seqnum: None,
+            result: Some(result),
+            error: None,
+        })
+        .unwrap()
+    }
+
+    fn reply_bytes(result: Vec<u8>) -> Vec<u8> {
+        serde_cbor::to_vec(&api::ResponseBytes {
+            id: "1".to_string(),
+            seqlen: None,
+            seqnum: None,
+            result: Some(result),
+            error: None,
+        })
+        .unwrap()
+    }
+
+    /// Plays the device side of the protocol: commits to `signer_nonce` and returns the
+    /// compact signature whose `r` is the nonce tweaked with `host_nonce`.
+    fn anti_exfil_signature(signer_nonce: [u8; 32], host_nonce: &[u8]) -> (Vec<u8>, [u8; 64]) {
+        use bitcoin::secp256k1::{PublicKey, Scalar, Secp256k1, SecretKey};
+
+        let secp = Secp256k1::new();
+        let commitment =
+            PublicKey::from_secret_key(&secp, &SecretKey::from_slice(&signer_nonce).unwrap());
+        let mut data = commitment.serialize().to_vec();
+        data.extend_from_slice(host_nonce);
+        let tweak = antiexfil::tagged_sha256(b"s2c/ecdsa/point", &data);
+        let nonce = commitment
+            .add_exp_tweak(&secp, &Scalar::from_be_bytes(tweak).unwrap())
+            .unwrap();
+        let mut compact = [0u8; 64];
+        compact[..32].copy_from_slice(&nonce.serialize()[1..33]);
+        compact[63] = 1;
+        (commitment.serialize().to_vec(), compact)
+    }
+
+    #[test]
+    fn sign_message_verifies_anti_exfil_signature() {
+        let mut interpreter = JadeInterpreter::default();
+        let transmit = interpreter
+            .start(Command::SignMessage {
+                message: b"hello".to_vec(),
+                path: "m/84'/0'/0'/0/0".parse().unwrap(),
+            })
+            .unwrap();
+        let (method, params): (_, api::SignMessageParams) = decode(&transmit.payload);
+        assert_eq!(method, "sign_message");
+        assert_eq!(params.message, "hello");
+        let State::SigningMessage { host_nonce, .. } = &interpreter.state else {
+            panic!("expected anti-exfil message signing");
+        };
+        let host_nonce = *host_nonce;
+        assert_eq!(
+            params.ae_host_commitment,
+            antiexfil::host_commit(&host_nonce).to_vec()
+        );
+
+        let (commitment, compact) = anti_exfil_signature([7; 32], &host_nonce);
+        let transmit = interpreter
+            .exchange(reply_bytes(commitment))
+            .unwrap()
+            .unwrap();
+        let (method, params): (_, api::GetSignatureParams) = decode(&transmit.payload);
+        assert_eq!(method, "get_signature");
+        assert_eq!(params.ae_host_entropy, Some(host_nonce.to_vec()));
+
+        let mut signature = vec![31];
+        signature.extend_from_slice(&compact);
+        assert!(
+            interpreter
+                .exchange(reply(Base64::encode_string(&signature)))
+                .unwrap()
+                .is_none()
+        );
+        assert!(matches!(
+            interpreter.end().unwrap(),
+            Response::Signature(31, sig) if sig.serialize_compact() == compact
+        ));
+    }
+
+    #[test]
+    fn sign_message_rejects_signature_without_host_nonce() {
+        let mut interpreter = JadeInterpreter::default();
+        interpreter
+            .start(Command::SignMessage {
+                message: b"hello".to_vec(),
+                path: "m/84'/0'/0'/0/0".parse().unwrap(),
+            })
+            .unwrap();
+        let (commitment, compact) = anti_exfil_signature([7; 32], &[0; 32]);
+        interpreter.exchange(reply_bytes(commitment)).unwrap();
+
+        let mut signature = vec![31];
+        signature.extend_from_slice(&compact);
+        let error = match interpreter.exchange(reply(Base64::encode_string(&signature))) {
+            Err(error) => error,
+            Ok(_) => panic!("signature ignoring the host nonce must fail"),
+        };
+        assert!(error.to_string().contains("anti-exfil"));
+    }
+
+    #[test]
+    fn sign_psbt_uses_anti_exfil_sign_tx_flow() {
+        use bitcoin::bip32::Xpriv;
+        use bitcoin::secp256k1::Secp256k1;
+        use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, transaction};
+
+        let secp = Secp256k1::new();
+        let master = Xpriv::new_master(Network::Testnet, &[1; 32]).unwrap();
+        let master_xpub = Xpub::from_priv(&secp, &master);
+        let path: DerivationPath = "m/84'/1'/0'/0/0".parse().unwrap();
+        let key = master.derive_priv(&secp, &path).unwrap();
+        let pubkey = bitcoin::CompressedPublicKey(key.private_key.public_key(&secp));
+        let script_pubkey = ScriptBuf::new_p2wpkh(&pubkey.wpubkey_hash());
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey,
+        });
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(pubkey.0, (master_xpub.fingerprint(), path.clone()));
+        psbt.outputs[0]
+            .bip32_derivation
+            .insert(pubkey.0, (master_xpub.fingerprint(), path.clone()));
+
+        let mut interpreter = JadeInterpreter::default().with_network(Network::Testnet);
+        let transmit = interpreter.start(Command::SignTx(psbt, None)).unwrap();
+        let (method, _): (_, api::GetXpubParams) = decode(&transmit.payload);
+        assert_eq!(method, "get_xpub");
+
+        let transmit = interpreter
+            .exchange(reply(master_xpub.to_string()))
+            .unwrap()
+            .unwrap();
+        let (method, params): (_, api::SignTxParams) = decode(&transmit.payload);
+        assert_eq!(method, "sign_tx");
+        assert!(params.use_ae_signatures);
+        assert_eq!(params.num_inputs, 1);
+        let change = params.change[0].as_ref().unwrap();
+        assert_eq!(change.variant, "wpkh(k)");
+        assert_eq!(change.path, path.to_u32_vec());
+
+        let transmit = interpreter.exchange(reply(true)).unwrap().unwrap();
+        let (method, params): (_, api::TxInputParams) = decode(&transmit.payload);
+        assert_eq!(method, "tx_input");
+        assert!(params.is_witness);
+        assert_eq!(params.satoshi, Some(10_000));
+        assert_eq!(params.path, Some(path.to_u32_vec()));
+        assert_eq!(
+            params.script,
+            Some(ScriptBuf::new_p2pkh(&pubkey.pubkey_hash()).to_bytes())
+        );
+        let host_commitment = params.ae_host_commitment.unwrap();
+
+        let (commitment, _) = anti_exfil_signature([9; 32], &[0; 32]);
+        let transmit = interpreter
+            .exchange(reply_bytes(commitment))
+            .unwrap()
+            .unwrap();
+        let (method, params): (_, api::GetSignatureParams) = decode(&transmit.payload);
+        assert_eq!(method, "get_signature");
+        let host_nonce = params.ae_host_entropy.unwrap();
+        assert_eq!(
+            antiexfil::host_commit(&host_nonce).to_vec(),
+            host_commitment
+        );
+
+        let (_, compact) = anti_exfil_signature([9; 32], &host_nonce);
+        let signature =
+            bitcoin::ecdsa::Signature::sighash_all(Signature::from_compact(&compact).unwrap());
+        assert!(
+            interpreter
+                .exchange(reply_bytes(signature.to_vec()))
+                .unwrap()
+                .is_none()
+        );
+        let Response::SignedPsbt(signed) = interpreter.end().unwrap() else {
+            panic!("expected signed psbt");
+        };
+        assert_eq!(
+            signed.inputs[0]
+                .partial_sigs
+                .get(&bitcoin::PublicKey::new(pubkey.0)),
+            Some(&signature)
+        );
+    }
+
+    #[test]
+    fn multisig_psbt_is_left_to_sign_psbt() {
+        use bitcoin::secp256k1::Secp256k1;
+        use bitcoin::{Amount, OutPoint, ScriptBuf, Transaction, TxIn, TxOut, transaction};
+
+        let secp = Secp256k1::new();
+        let fingerprint = Fingerprint::from_str("f5acc2fd").unwrap();
+        let path: DerivationPath = "m/48'/1'/0'/2'/0/0".parse().unwrap();
+        let keys: Vec<_> = [1u8, 2]
+            .iter()
+            .map(|seed| {
+                bitcoin::secp256k1::SecretKey::from_slice(&[*seed; 32])
+                    .unwrap()
+                    .public_key(&secp)
+            })
+            .collect();
+        let witness_script = bitcoin::script::Builder::new()
+            .push_int(2)
+            .push_slice(keys[0].serialize())
+            .push_slice(keys[1].serialize())
+            .push_int(2)
+            .push_opcode(bitcoin::opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        let script_pubkey = ScriptBuf::new_p2wsh(&witness_script.wscript_hash());
+
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                ..Default::default()
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: script_pubkey.clone(),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey,
+        });
+        psbt.inputs[0].witness_script = Some(witness_script.clone());
+        psbt.outputs[0].witness_script = Some(witness_script);
+        for key in &keys {
+            psbt.inputs[0]
+                .bip32_derivation
+                .insert(*key, (fingerprint, path.clone()));
+            psbt.outputs[0]
+                .bip32_derivation
+                .insert(*key, (fingerprint, path.clone()));
+        }
+        assert!(!sign_tx::is_supported(&psbt));
+
+        let mut interpreter = JadeInterpreter::default().with_network(Network::Testnet);
+        let transmit = interpreter.start(Command::SignTx(psbt, None)).unwrap();
+        let (method, _): (_, serde_cbor::Value) = decode(&transmit.payload);
+        assert_eq!(method, "sign_psbt");
+    }
+
+    #[test]
+    fn ota_update_uploads_image_in_device_sized_chunks() {
+        let image = (0u8..10).collect::<Vec<_>>();
+        let mut interpreter = super::JadeInterpreter::<
+            RawCommand<JadeCommand>,
+            JadeTransmit,
+            JadeResponse,
+            JadeError,
+        >::default();
+        let transmit = interpreter
+            .start(RawCommand(JadeCommand::OtaUpdate(OtaUpdate {
+                image: image.clone(),
+                firmware_size: 20,
+                firmware_hash: Some([3; 32]),
+            })))
+            .unwrap();
+        let rpc: Rpc<api::EmptyRequest> = serde_cbor::from_slice(&transmit.payload).unwrap();
+        assert_eq!(rpc.method, "get_version_info");
+
+        let transmit = interpreter
+            .exchange(reply(api::GetInfoResponse {
+                jade_version: "1.0.39".to_string(),
+                jade_state: api::JadeState::Ready,
+                jade_networks: api::JadeNetworks::All,
+                jade_ota_max_chunk: Some(4),
+            }))
+            .unwrap()
+            .unwrap();
+        let (method, params): (_, api::OtaParams) = decode(&transmit.payload);
+        assert_eq!(method, "ota");
+        assert_eq!(params.fwsize, 20);
+        assert_eq!(params.cmpsize, 10);
+        assert_eq!(
+            params.cmphash,
+            sha256::Hash::hash(&image).to_byte_array().to_vec()
+        );
+        assert_eq!(params.fwhash, Some(vec![3; 32]));
+
+        let mut transmit = interpreter.exchange(reply(true)).unwrap().unwrap();
+        let mut uploaded = Vec::new();
+        for written in [4, 8, 10] {
+            let (method, chunk): (_, serde_bytes::ByteBuf) = decode(&transmit.payload);
+            assert_eq!(method, "ota_data");
+            uploaded.extend_from_slice(&chunk);
+            transmit = interpreter.exchange(reply(true)).unwrap().unwrap();
+            assert_eq!(
+                interpreter.ota_progress(),
+                Some(OtaProgress { written, total: 10 })
+            );
+            assert_eq!(
+                std::iter::from_fn(|| interpreter.poll_event()).last(),
+                Some(Event::Progress {
+                    done: written,
+                    total: 10
+                })
+            );
+        }
+        assert_eq!(uploaded, image);
+        let rpc: Rpc<api::EmptyRequest> = serde_cbor::from_slice(&transmit.payload).unwrap();
+        assert_eq!(rpc.method, "ota_complete");
+
+        assert!(interpreter.exchange(reply(true)).unwrap().is_none());
+        assert!(matches!(interpreter.end().unwrap(), JadeResponse::TaskDone));
+    }
+
+    #[test]
+    fn ota_update_rejects_refused_chunk() {
+        let mut interpreter = super::JadeInterpreter::<
+            RawCommand<JadeCommand>,
+            JadeTransmit,
+            JadeResponse,
+            JadeError,
+        >::default();
+        interpreter
+            .start(RawCommand(JadeCommand::OtaUpdate(OtaUpdate {
+                image: vec![1; 8],
+                firmware_size: 16,
+                firmware_hash: None,
+            })))
+            .unwrap();
+        interpreter
+            .exchange(reply(api::GetInfoResponse {
+                jade_version: "1.0.39".to_string(),
+                jade_state: api::JadeState::Ready,
+                jade_networks: api::JadeNetworks::All,
+                jade_ota_max_chunk: None,
+            }))
+            .unwrap();
+        interpreter.exchange(reply(true)).unwrap();
+
+        assert!(matches!(
+            interpreter.exchange(reply(false)),
+            Err(JadeError::UnexpectedResult(msg)) if msg == "ota_data returned false"
+        ));
+    }
+
+    #[derive(Deserialize)]
+    struct AuthParams {
+        network: String,
+        passphrase: Option<String>,
+    }
+
+    #[test]
+    fn set_passphrase_logs_out_and_reauthenticates_with_it() {
+        let xpub = "tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP";
+        let mut interpreter = JadeInterpreter::default().with_network(Network::Testnet);
+
+        let transmit = interpreter
+            .start(Command::SetPassphrase(Some(Zeroizing::new(
+                "correct horse".to_string(),
+            ))))
+            .unwrap();
+        let logout: Rpc<()> = serde_cbor::from_slice(&transmit.payload).unwrap();
+        assert_eq!(logout.method, "logout");
+
+        let transmit = interpreter.exchange(reply(true)).unwrap().unwrap();
+        let (method, params): (String, AuthParams) = decode(&transmit.payload);
+        assert_eq!(method, "auth_user");
+        assert_eq!(params.network, JADE_NETWORK_TESTNET);
+        assert_eq!(params.passphrase.as_deref(), Some("correct horse"));
+
+        let transmit = interpreter.exchange(reply(true)).unwrap().unwrap();
+        let (method, _): (String, api::GetXpubParams) = decode(&transmit.payload);
+        assert_eq!(method, "get_xpub");
+
+        assert!(interpreter.exchange(reply(xpub)).unwrap().is_none());
+        match interpreter.end().unwrap() {
+            Response::MasterFingerprint(fingerprint) => {
+                assert_eq!(fingerprint, Xpub::from_str(xpub).unwrap().fingerprint())
+            }
+            _ => panic!("expected master fingerprint"),
+        }
+    }
+
+    #[test]
+    fn unlock_does_not_send_a_passphrase() {
+        let mut interpreter = JadeInterpreter::default().with_network(Network::Testnet);
+        let transmit = interpreter
+            .start(Command::Unlock {
+                options: Default::default(),
+            })
+            .unwrap();
+        let (method, params): (String, AuthParams) = decode(&transmit.payload);
+        assert_eq!(method, "auth_user");
+        assert!(params.passphrase.is_none());
+    }
+
+    #[test]
+    fn attestation_checks_the_challenge_signature() {
+        let mut interpreter = JadeInterpreter::default();
+        let transmit = interpreter.start(Command::VerifyAttestation).unwrap();
+        let (method, params): (_, api::SignAttestationParams) = decode(&transmit.payload);
+        assert_eq!(method, "sign_attestation");
+        assert_eq!(params.challenge.len(), 32);
+
+        let response = interpreter.exchange(reply(api::SignAttestationResponse {
+            signature: vec![0; 256],
+            pubkey_pem: include_str!("testdata/attestation_key.pem").to_string(),
+            ext_signature: vec![0; 256],
+        }));
+        assert!(response.unwrap().is_none());
+        assert!(matches!(
+            interpreter.end().unwrap(),
+            Response::Attestation(Attestation::Counterfeit)
+        ));
+    }
 }
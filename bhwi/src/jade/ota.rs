@@ -0,0 +1,141 @@
+//! Firmware update through Jade's `ota` and `ota_data` methods.
+//!
+//! The compressed image is announced with its compressed and uncompressed sizes and hashes,
+//! then streamed in chunks no larger than the device's `JADE_OTA_MAX_CHUNK`, then committed
+//! with `ota_complete`. The device checks the image against the announced hashes before it
+//! marks it bootable.
+
+use bitcoin::hashes::{Hash, sha256};
+
+use super::{JadeError, JadeTransmit, api, from_response, request};
+
+/// Chunk size used by jadepy when the device does not advertise one.
+pub const DEFAULT_OTA_CHUNK_SIZE: usize = 4096;
+
+/// Upload progress, in bytes of the compressed image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtaProgress {
+    pub written: usize,
+    pub total: usize,
+}
+
+pub struct OtaUpdate {
+    /// Compressed firmware image, as distributed by Blockstream.
+    pub image: Vec<u8>,
+    /// Size of the uncompressed firmware.
+    pub firmware_size: u32,
+    /// Expected SHA-256 of the uncompressed firmware.
+    pub firmware_hash: Option<[u8; 32]>,
+}
+
+enum Phase {
+    GettingInfo,
+    Starting,
+    Uploading,
+    Completing,
+}
+
+pub(super) enum Step {
+    Send(JadeTransmit),
+    Done,
+}
+
+pub(super) struct Session {
+    update: OtaUpdate,
+    chunk_size: usize,
+    written: usize,
+    phase: Phase,
+}
+
+impl Session {
+    pub(super) fn start(update: OtaUpdate) -> Result<(Self, JadeTransmit), JadeError> {
+        if update.image.is_empty() {
+            return Err(JadeError::Serialization(
+                "firmware image is empty".to_string(),
+            ));
+        }
+        let transmit: JadeTransmit =
+            request::<_, _, JadeError>("get_version_info", None::<api::EmptyRequest>)?;
+        Ok((
+            Self {
+                update,
+                chunk_size: DEFAULT_OTA_CHUNK_SIZE,
+                written: 0,
+                phase: Phase::GettingInfo,
+            },
+            transmit,
+        ))
+    }
+
+    pub(super) fn progress(&self) -> OtaProgress {
+        OtaProgress {
+            written: self.written,
+            total: self.update.image.len(),
+        }
+    }
+
+    pub(super) fn exchange(&mut self, data: &[u8]) -> Result<Step, JadeError> {
+        match self.phase {
+            Phase::GettingInfo => {
+                let info: api::GetInfoResponse = from_response(data)?.into_result()?;
+                if let Some(chunk_size) = info.jade_ota_max_chunk.filter(|size| *size > 0) {
+                    self.chunk_size = chunk_size as usize;
+                }
+                self.phase = Phase::Starting;
+                let cmpsize = u32::try_from(self.update.image.len()).map_err(|_| {
+                    JadeError::Serialization("firmware image is too large".to_string())
+                })?;
+                request(
+                    "ota",
+                    Some(api::OtaParams {
+                        fwsize: self.update.firmware_size,
+                        cmpsize,
+                        cmphash: sha256::Hash::hash(&self.update.image)
+                            .to_byte_array()
+                            .to_vec(),
+                        fwhash: self.update.firmware_hash.map(|hash| hash.to_vec()),
+                    }),
+                )
+                .map(Step::Send)
+            }
+            Phase::Starting => {
+                expect_true(data, "ota")?;
+                self.phase = Phase::Uploading;
+                self.next_chunk().map(Step::Send)
+            }
+            Phase::Uploading => {
+                expect_true(data, "ota_data")?;
+                self.written = (self.written + self.chunk_size).min(self.update.image.len());
+                if self.written < self.update.image.len() {
+                    return self.next_chunk().map(Step::Send);
+                }
+                self.phase = Phase::Completing;
+                request("ota_complete", None::<api::EmptyRequest>).map(Step::Send)
+            }
+            Phase::Completing => {
+                expect_true(data, "ota_complete")?;
+                Ok(Step::Done)
+            }
+        }
+    }
+
+    fn next_chunk(&self) -> Result<JadeTransmit, JadeError> {
+        let end = (self.written + self.chunk_size).min(self.update.image.len());
+        request(
+            "ota_data",
+            Some(serde_bytes::Bytes::new(
+                &self.update.image[self.written..end],
+            )),
+        )
+    }
+}
+
+fn expect_true(data: &[u8], method: &str) -> Result<(), JadeError> {
+    let accepted: bool = from_response(data)?.into_result()?;
+    if !accepted {
+        return Err(JadeError::UnexpectedResult(format!(
+            "{method} returned false"
+        )));
+    }
+    Ok(())
+}
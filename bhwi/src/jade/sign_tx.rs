@@ -0,0 +1,367 @@
+//! PSBT signing through Jade's `sign_tx` flow with anti-exfil signatures.
+//!
+//! `sign_psbt` lets the device pick its own nonces, so ECDSA inputs are signed
+//! input by input instead: `sign_tx` with `use_ae_signatures`, one `tx_input`
+//! per input carrying the host nonce commitment, then one `get_signature` per
+//! input revealing the host nonce. Each returned signature is checked against
+//! the signer commitment before it is added to the PSBT.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use bitcoin::bip32::{DerivationPath, Fingerprint, KeySource, Xpub};
+use bitcoin::consensus::encode::serialize;
+use bitcoin::psbt::{Input, Output, Psbt};
+use bitcoin::{PublicKey, ScriptBuf, ecdsa, secp256k1};
+use serde::Serialize;
+
+use super::{JadeError, JadeTransmit, api, from_response, from_response_bytes, request};
+use crate::antiexfil;
+use crate::common::Event;
+
+/// Whether the PSBT can go through the anti-exfil flow. Jade only supports
+/// anti-exfil for ECDSA, taproot spends are left to `sign_psbt`. So are multisig
+/// and miniscript wallets: this flow signs one key per input and declares only
+/// single-sig change, while `sign_psbt` matches them against the registered wallet.
+pub(super) fn is_supported(psbt: &Psbt) -> bool {
+    !psbt.inputs.is_empty()
+        && psbt.inputs.iter().all(|input| {
+            input.tap_key_sig.is_none()
+                && input.tap_script_sigs.is_empty()
+                && input.tap_scripts.is_empty()
+                && input.tap_key_origins.is_empty()
+                && input.tap_internal_key.is_none()
+                && input
+                    .witness_utxo
+                    .as_ref()
+                    .is_none_or(|utxo| !utxo.script_pubkey.is_p2tr())
+                && is_single_sig(
+                    &input.bip32_derivation,
+                    input.redeem_script.as_ref(),
+                    input.witness_script.as_ref(),
+                )
+        })
+        && psbt.outputs.iter().all(|output| {
+            output.tap_key_origins.is_empty()
+                && is_single_sig(
+                    &output.bip32_derivation,
+                    output.redeem_script.as_ref(),
+                    output.witness_script.as_ref(),
+                )
+        })
+}
+
+fn is_single_sig(
+    origins: &BTreeMap<secp256k1::PublicKey, KeySource>,
+    redeem_script: Option<&ScriptBuf>,
+    witness_script: Option<&ScriptBuf>,
+) -> bool {
+    origins.len() <= 1
+        && witness_script.is_none()
+        && redeem_script.is_none_or(|script| script.is_p2wpkh())
+}
+
+pub(super) enum Step {
+    Send(JadeTransmit),
+    Done(Psbt),
+}
+
+struct Signer {
+    pubkey: PublicKey,
+    host_nonce: [u8; 32],
+    signer_commitment: Vec<u8>,
+}
+
+struct TxInput {
+    params: api::TxInputParams,
+    signer: Option<Signer>,
+}
+
+enum Phase {
+    Fingerprint,
+    Started(Vec<TxInput>),
+    Inputs {
+        inputs: Vec<TxInput>,
+        current: usize,
+    },
+    Signatures {
+        inputs: Vec<TxInput>,
+        current: usize,
+    },
+}
+
+pub(super) struct Session {
+    network: &'static str,
+    psbt: Psbt,
+    phase: Phase,
+}
+
+impl Session {
+    pub(super) fn start(
+        psbt: Psbt,
+        network: &'static str,
+    ) -> Result<(Self, JadeTransmit), JadeError> {
+        let transmit = send(
+            "get_xpub",
+            api::GetXpubParams {
+                network,
+                path: DerivationPath::master().to_u32_vec(),
+            },
+        )?;
+        Ok((
+            Self {
+                network,
+                psbt,
+                phase: Phase::Fingerprint,
+            },
+            transmit,
+        ))
+    }
+
+    /// Where the signing passes stand once a request was sent: the device shows the
+    /// transaction on `sign_tx`, then takes every input twice, for its commitment and for
+    /// its signature.
+    pub(super) fn event(&self) -> Option<Event> {
+        match &self.phase {
+            Phase::Fingerprint => None,
+            Phase::Started(_) => Some(Event::AwaitingUserConfirmation),
+            Phase::Inputs { inputs, current } => Some(Event::Progress {
+                done: *current,
+                total: 2 * inputs.len(),
+            }),
+            Phase::Signatures { inputs, current } => Some(Event::Progress {
+                done: inputs.len() + current,
+                total: 2 * inputs.len(),
+            }),
+        }
+    }
+
+    pub(super) fn exchange(&mut self, data: &[u8]) -> Result<Step, JadeError> {
+        match std::mem::replace(&mut self.phase, Phase::Fingerprint) {
+            Phase::Fingerprint => {
+                let s: String = from_response(data)?.into_result()?;
+                let xpub =
+                    Xpub::from_str(&s).map_err(|e| JadeError::Serialization(e.to_string()))?;
+                let fingerprint = xpub.fingerprint();
+                let inputs = self
+                    .psbt
+                    .inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(index, input)| tx_input(&self.psbt, index, input, fingerprint))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let change = self
+                    .psbt
+                    .outputs
+                    .iter()
+                    .zip(&self.psbt.unsigned_tx.output)
+                    .map(|(output, txout)| change_output(output, &txout.script_pubkey, fingerprint))
+                    .collect();
+                self.phase = Phase::Started(inputs);
+                send(
+                    "sign_tx",
+                    api::SignTxParams {
+                        network: self.network,
+                        txn: serialize(&self.psbt.unsigned_tx),
+                        num_inputs: self.psbt.inputs.len() as u32,
+                        use_ae_signatures: true,
+                        change,
+                    },
+                )
+                .map(Step::Send)
+            }
+            Phase::Started(inputs) => {
+                let started: bool = from_response(data)?.into_result()?;
+                if !started {
+                    return Err(JadeError::UnexpectedResult(
+                        "sign_tx returned false".to_string(),
+                    ));
+                }
+                let transmit = send("tx_input", &inputs[0].params)?;
+                self.phase = Phase::Inputs { inputs, current: 0 };
+                Ok(Step::Send(transmit))
+            }
+            Phase::Inputs {
+                mut inputs,
+                current,
+            } => {
+                let signer_commitment = bytes_result(data)?;
+                if let Some(signer) = &mut inputs[current].signer {
+                    if signer_commitment.is_empty() {
+                        return Err(JadeError::AntiExfil(format!(
+                            "no signer commitment for input {current}"
+                        )));
+                    }
+                    signer.signer_commitment = signer_commitment;
+                }
+                let next = current + 1;
+                let transmit = if next < inputs.len() {
+                    let transmit = send("tx_input", &inputs[next].params)?;
+                    self.phase = Phase::Inputs {
+                        inputs,
+                        current: next,
+                    };
+                    transmit
+                } else {
+                    let transmit = get_signature(&inputs[0])?;
+                    self.phase = Phase::Signatures { inputs, current: 0 };
+                    transmit
+                };
+                Ok(Step::Send(transmit))
+            }
+            Phase::Signatures { inputs, current } => {
+                let signature = bytes_result(data)?;
+                if let Some(signer) = &inputs[current].signer {
+                    let signature = ecdsa::Signature::from_slice(&signature)
+                        .map_err(|e| JadeError::Serialization(e.to_string()))?;
+                    antiexfil::verify_ecdsa(
+                        &signer.host_nonce,
+                        &signer.signer_commitment,
+                        &signature.signature.serialize_compact(),
+                    )?;
+                    self.psbt.inputs[current]
+                        .partial_sigs
+                        .insert(signer.pubkey, signature);
+                }
+                let next = current + 1;
+                if next < inputs.len() {
+                    let transmit = get_signature(&inputs[next])?;
+                    self.phase = Phase::Signatures {
+                        inputs,
+                        current: next,
+                    };
+                    Ok(Step::Send(transmit))
+                } else {
+                    Ok(Step::Done(self.psbt.clone()))
+                }
+            }
+        }
+    }
+}
+
+/// `tx_input` and `get_signature` answer with bytes, empty for inputs the
+/// device does not sign.
+fn bytes_result(data: &[u8]) -> Result<Vec<u8>, JadeError> {
+    let res = from_response_bytes(data)?;
+    if let Some(e) = res.error {
+        return Err(JadeError::Rpc(e));
+    }
+    Ok(res.result.unwrap_or_default())
+}
+
+fn send<S: Serialize + Unpin>(method: &str, params: S) -> Result<JadeTransmit, JadeError> {
+    request(method, Some(params))
+}
+
+fn get_signature(input: &TxInput) -> Result<JadeTransmit, JadeError> {
+    send(
+        "get_signature",
+        api::GetSignatureParams {
+            ae_host_entropy: input
+                .signer
+                .as_ref()
+                .map(|signer| signer.host_nonce.to_vec()),
+        },
+    )
+}
+
+fn tx_input(
+    psbt: &Psbt,
+    index: usize,
+    input: &Input,
+    fingerprint: Fingerprint,
+) -> Result<TxInput, JadeError> {
+    let outpoint = psbt.unsigned_tx.input[index].previous_output;
+    let utxo = match (&input.witness_utxo, &input.non_witness_utxo) {
+        (Some(utxo), _) => Some(utxo.clone()),
+        (None, Some(tx)) => tx.output.get(outpoint.vout as usize).cloned(),
+        (None, None) => None,
+    };
+    let origin = input
+        .bip32_derivation
+        .iter()
+        .find(|(_, (fg, _))| *fg == fingerprint);
+
+    let Some((pubkey, (_, path))) = origin else {
+        return Ok(TxInput {
+            params: api::TxInputParams {
+                is_witness: input.witness_utxo.is_some(),
+                input_tx: input.non_witness_utxo.as_ref().map(serialize),
+                satoshi: utxo.map(|utxo| utxo.value.to_sat()),
+                ..Default::default()
+            },
+            signer: None,
+        });
+    };
+    let utxo = utxo.ok_or_else(|| {
+        JadeError::Serialization(format!("missing previous output for input {index}"))
+    })?;
+
+    let mut script = utxo.script_pubkey.clone();
+    if script.is_p2sh() {
+        script = input.redeem_script.clone().ok_or_else(|| {
+            JadeError::Serialization(format!("missing redeem script for input {index}"))
+        })?;
+    }
+    let is_witness = script.is_witness_program();
+    let script: ScriptBuf = if script.is_p2wsh() {
+        input.witness_script.clone().ok_or_else(|| {
+            JadeError::Serialization(format!("missing witness script for input {index}"))
+        })?
+    } else if let Some(script_code) = script.p2wpkh_script_code() {
+        script_code
+    } else {
+        script
+    };
+
+    let host_nonce = antiexfil::gen_host_nonce()?;
+    Ok(TxInput {
+        params: api::TxInputParams {
+            is_witness,
+            input_tx: input.non_witness_utxo.as_ref().map(serialize),
+            satoshi: Some(utxo.value.to_sat()),
+            script: Some(script.to_bytes()),
+            path: Some(path.to_u32_vec()),
+            sighash: input.sighash_type.map(|sighash| sighash.to_u32()),
+            ae_host_commitment: Some(antiexfil::host_commit(&host_nonce).to_vec()),
+        },
+        signer: Some(Signer {
+            pubkey: PublicKey::new(*pubkey),
+            host_nonce,
+            signer_commitment: Vec::new(),
+        }),
+    })
+}
+
+/// Single-sig change is declared so the device can verify it instead of
+/// showing it as a recipient.
+fn change_output(
+    output: &Output,
+    script_pubkey: &ScriptBuf,
+    fingerprint: Fingerprint,
+) -> Option<api::ChangeOutput> {
+    let [(_, (fg, path))] = output.bip32_derivation.iter().collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    if *fg != fingerprint {
+        return None;
+    }
+    let variant = if script_pubkey.is_p2wpkh() {
+        "wpkh(k)"
+    } else if script_pubkey.is_p2pkh() {
+        "pkh(k)"
+    } else if script_pubkey.is_p2sh()
+        && output
+            .redeem_script
+            .as_ref()
+            .is_some_and(|script| script.is_p2wpkh())
+    {
+        "sh(wpkh(k))"
+    } else {
+        return None;
+    };
+    Some(api::ChangeOutput {
+        path: path.to_u32_vec(),
+        variant: variant.to_string(),
+    })
+}
@@ -10,6 +10,7 @@ pub const CURRENT_PROTOCOL_VERSION: u8 = 1;
 #[repr(u8)]
 pub enum Cla {
     Default = 0xB0,
+    Legacy = 0xE0,
     Bitcoin = 0xE1,
     Framework = 0xF8,
 }
@@ -26,6 +27,17 @@ pub enum BitcoinCommandCode {
     SignMessage = 0x10,
 }
 
+/// Commands of the legacy Bitcoin app (1.x and "Bitcoin Legacy"), see [`super::legacy`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LegacyCommandCode {
+    GetWalletPublicKey = 0x40,
+    GetTrustedInput = 0x42,
+    HashInputStart = 0x44,
+    HashSign = 0x48,
+    HashInputFinalizeFull = 0x4A,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum FrameworkCommandCode {
@@ -80,6 +92,10 @@ pub enum StatusWord {
     SignatureFail = 0xB008,
     /// Command not allowed
     CommandNotAllowed = 0x6901,
+    /// Security status not satisfied (device locked)
+    SecurityStatusNotSatisfied = 0x6982,
+    /// Device locked by the OS, before any app handles the command
+    LockedDevice = 0x5515,
     /// Success
     OK = 0x9000,
     /// The command is interrupted, and requires the client's response
@@ -93,6 +109,8 @@ impl TryFrom<u16> for StatusWord {
         match value {
             0x6985 => Ok(StatusWord::Deny),
             0x6901 => Ok(StatusWord::CommandNotAllowed),
+            0x6982 => Ok(StatusWord::SecurityStatusNotSatisfied),
+            0x5515 => Ok(StatusWord::LockedDevice),
             0x6A80 => Ok(StatusWord::IncorrectData),
             0x6A82 => Ok(StatusWord::NotSupported),
             0x6A86 => Ok(StatusWord::WrongP1P2),
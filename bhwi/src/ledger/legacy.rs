@@ -0,0 +1,1030 @@
+//! Legacy Bitcoin app protocol.
+//!
+//! Bitcoin app 1.x and the "Bitcoin Legacy" app do not implement the merkleized commands of
+//! app-bitcoin-new; they speak the original btchip APDU set under [`Cla::Legacy`]. Only
+//! single-sig is covered: public keys, addresses by path and signing of P2PKH, P2SH-P2WPKH
+//! and P2WPKH inputs.
+//!
+//! Signing streams the transaction through `HASH_INPUT_START` and `HASH_INPUT_FINALIZE_FULL`
+//! before each `HASH_SIGN`. Segwit transactions announce every input by outpoint and amount;
+//! legacy ones first obtain a trusted input per previous transaction with `GET_TRUSTED_INPUT`.
+
+use std::collections::VecDeque;
+
+use bitcoin::{
+    Amount, CompressedPublicKey, Network, NetworkKind, OutPoint, PublicKey, ScriptBuf, Sequence,
+    Transaction, TxOut,
+    absolute::LockTime,
+    address::AddressType,
+    bip32::{ChainCode, ChildNumber, DerivationPath, Fingerprint, Xpub},
+    consensus::encode::{VarInt, serialize},
+    ecdsa,
+    hashes::{Hash, hash160},
+    psbt::Psbt,
+    secp256k1,
+};
+
+use super::{
+    LedgerCommand, LedgerError, LedgerResponse,
+    apdu::{ApduCommand, ApduResponse, Cla, LegacyCommandCode, StatusWord},
+};
+use crate::common::DisplayAddress;
+
+/// Scripts are streamed in chunks leaving room for a trailing sequence.
+const SCRIPT_CHUNK_SIZE: usize = 251;
+const OUTPUTS_CHUNK_SIZE: usize = 255;
+
+const SIGHASH_ALL: u8 = 0x01;
+
+/// Address encoding returned by `GET_WALLET_PUBLIC_KEY`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum AddressFormat {
+    Legacy = 0x00,
+    P2shP2wpkh = 0x01,
+    Bech32 = 0x02,
+}
+
+impl TryFrom<AddressType> for AddressFormat {
+    type Error = LedgerError;
+
+    fn try_from(address_type: AddressType) -> Result<Self, Self::Error> {
+        match address_type {
+            AddressType::P2pkh => Ok(AddressFormat::Legacy),
+            AddressType::P2sh => Ok(AddressFormat::P2shP2wpkh),
+            AddressType::P2wpkh => Ok(AddressFormat::Bech32),
+            other => Err(LedgerError::UnsupportedAppVersion(format!(
+                "{other} addresses"
+            ))),
+        }
+    }
+}
+
+/// An input as announced to `HASH_INPUT_START`.
+#[derive(Clone, Debug)]
+pub enum HashInput {
+    /// Trusted input returned by `GET_TRUSTED_INPUT`.
+    Trusted {
+        trusted_input: Vec<u8>,
+        sequence: Sequence,
+    },
+    /// Segwit input, whose amount is committed to by the signature.
+    Segwit {
+        outpoint: OutPoint,
+        amount: Amount,
+        sequence: Sequence,
+    },
+}
+
+fn legacy_apdu(ins: LegacyCommandCode, p1: u8, p2: u8, data: Vec<u8>) -> ApduCommand {
+    ApduCommand {
+        cla: Cla::Legacy as u8,
+        ins: ins as u8,
+        p1,
+        p2,
+        data,
+    }
+}
+
+fn serialize_path(path: &DerivationPath) -> Vec<u8> {
+    let child_numbers: &[ChildNumber] = path.as_ref();
+    child_numbers
+        .iter()
+        .fold(vec![child_numbers.len() as u8], |mut acc, &x| {
+            acc.extend_from_slice(&u32::from(x).to_be_bytes());
+            acc
+        })
+}
+
+/// Split `script` in APDU-sized chunks, the last one followed by `sequence` if any.
+fn script_chunks(script: &[u8], sequence: Option<Sequence>) -> Vec<Vec<u8>> {
+    let mut chunks: Vec<Vec<u8>> = script
+        .chunks(SCRIPT_CHUNK_SIZE)
+        .map(<[u8]>::to_vec)
+        .collect();
+    if let Some(sequence) = sequence {
+        let sequence = sequence.0.to_le_bytes();
+        match chunks.last_mut() {
+            Some(last) => last.extend_from_slice(&sequence),
+            None => chunks.push(sequence.to_vec()),
+        }
+    }
+    chunks
+}
+
+/// Creates the APDU command to get the public key, address and chain code at `path`.
+pub fn get_wallet_public_key(
+    path: &DerivationPath,
+    display: bool,
+    format: AddressFormat,
+) -> ApduCommand {
+    legacy_apdu(
+        LegacyCommandCode::GetWalletPublicKey,
+        display as u8,
+        format as u8,
+        serialize_path(path),
+    )
+}
+
+/// Creates the APDU commands streaming `tx` to get a trusted input for its output `vout`.
+pub fn get_trusted_input(tx: &Transaction, vout: u32) -> Vec<ApduCommand> {
+    let mut header = vout.to_be_bytes().to_vec();
+    header.extend_from_slice(&tx.version.0.to_le_bytes());
+    header.extend(serialize(&VarInt(tx.input.len() as u64)));
+    let mut chunks = vec![header];
+    for input in &tx.input {
+        let mut data = serialize(&input.previous_output);
+        data.extend(serialize(&VarInt(input.script_sig.len() as u64)));
+        chunks.push(data);
+        chunks.extend(script_chunks(
+            input.script_sig.as_bytes(),
+            Some(input.sequence),
+        ));
+    }
+    chunks.push(serialize(&VarInt(tx.output.len() as u64)));
+    for output in &tx.output {
+        let mut data = output.value.to_sat().to_le_bytes().to_vec();
+        data.extend(serialize(&VarInt(output.script_pubkey.len() as u64)));
+        chunks.push(data);
+        chunks.extend(script_chunks(output.script_pubkey.as_bytes(), None));
+    }
+    chunks.push(tx.lock_time.to_consensus_u32().to_le_bytes().to_vec());
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, data)| {
+            legacy_apdu(
+                LegacyCommandCode::GetTrustedInput,
+                if i == 0 { 0x00 } else { 0x80 },
+                0x00,
+                data,
+            )
+        })
+        .collect()
+}
+
+/// Creates the APDU commands hashing `inputs`, with `script` as the script of input `index`
+/// and empty scripts for the others.
+pub fn hash_input_start(
+    new_transaction: bool,
+    segwit: bool,
+    version: i32,
+    inputs: &[HashInput],
+    index: usize,
+    script: &[u8],
+) -> Vec<ApduCommand> {
+    let p2 = match (new_transaction, segwit) {
+        (true, false) => 0x00,
+        (true, true) => 0x02,
+        (false, _) => 0x80,
+    };
+    let mut header = version.to_le_bytes().to_vec();
+    header.extend(serialize(&VarInt(inputs.len() as u64)));
+    let mut apdus = vec![legacy_apdu(
+        LegacyCommandCode::HashInputStart,
+        0x00,
+        p2,
+        header,
+    )];
+    for (i, input) in inputs.iter().enumerate() {
+        let (mut data, sequence) = match input {
+            HashInput::Trusted {
+                trusted_input,
+                sequence,
+            } => {
+                let mut data = vec![0x01, trusted_input.len() as u8];
+                data.extend_from_slice(trusted_input);
+                (data, *sequence)
+            }
+            HashInput::Segwit {
+                outpoint,
+                amount,
+                sequence,
+            } => {
+                let mut data = vec![0x02];
+                data.extend(serialize(outpoint));
+                data.extend_from_slice(&amount.to_sat().to_le_bytes());
+                (data, *sequence)
+            }
+        };
+        let script = if i == index { script } else { &[] };
+        data.extend(serialize(&VarInt(script.len() as u64)));
+        apdus.push(legacy_apdu(
+            LegacyCommandCode::HashInputStart,
+            0x80,
+            0x00,
+            data,
+        ));
+        apdus.extend(
+            script_chunks(script, Some(sequence))
+                .into_iter()
+                .map(|data| legacy_apdu(LegacyCommandCode::HashInputStart, 0x80, 0x00, data)),
+        );
+    }
+    apdus
+}
+
+/// Creates the APDU commands hashing `outputs`, preceded by the change path if any so the
+/// device does not ask to confirm the change output.
+pub fn hash_input_finalize_full(
+    outputs: &[TxOut],
+    change_path: Option<&DerivationPath>,
+) -> Vec<ApduCommand> {
+    let mut apdus = Vec::new();
+    if let Some(path) = change_path {
+        apdus.push(legacy_apdu(
+            LegacyCommandCode::HashInputFinalizeFull,
+            0xFF,
+            0x00,
+            serialize_path(path),
+        ));
+    }
+    let mut data = serialize(&VarInt(outputs.len() as u64));
+    for output in outputs {
+        data.extend(serialize(output));
+    }
+    let chunks: Vec<&[u8]> = data.chunks(OUTPUTS_CHUNK_SIZE).collect();
+    let last = chunks.len() - 1;
+    apdus.extend(chunks.into_iter().enumerate().map(|(i, chunk)| {
+        legacy_apdu(
+            LegacyCommandCode::HashInputFinalizeFull,
+            if i == last { 0x80 } else { 0x00 },
+            0x00,
+            chunk.to_vec(),
+        )
+    }));
+    apdus
+}
+
+/// Creates the APDU command signing the hashed transaction with the key at `path`.
+pub fn hash_sign(path: &DerivationPath, lock_time: LockTime, sighash_type: u8) -> ApduCommand {
+    let mut data = serialize_path(path);
+    // No second factor validation code.
+    data.push(0x00);
+    data.extend_from_slice(&lock_time.to_consensus_u32().to_be_bytes());
+    data.push(sighash_type);
+    legacy_apdu(LegacyCommandCode::HashSign, 0x00, 0x00, data)
+}
+
+/// Parsed response of `GET_WALLET_PUBLIC_KEY`.
+///
+/// - length-prefixed uncompressed public key
+/// - length-prefixed address
+/// - 32 bytes: chain code
+#[derive(Debug, Clone)]
+pub struct WalletPublicKey {
+    pub public_key: secp256k1::PublicKey,
+    pub address: String,
+    pub chain_code: ChainCode,
+}
+
+impl WalletPublicKey {
+    /// Fingerprint of the key, as the parent fingerprint of its children.
+    pub fn fingerprint(&self) -> Fingerprint {
+        let hash = hash160::Hash::hash(&self.public_key.serialize());
+        let mut fg = [0x00; 4];
+        fg.copy_from_slice(&hash.to_byte_array()[0..4]);
+        Fingerprint::from(fg)
+    }
+}
+
+impl TryFrom<&[u8]> for WalletPublicKey {
+    type Error = LedgerError;
+
+    fn try_from(data: &[u8]) -> Result<Self, Self::Error> {
+        let invalid = || LedgerError::unexpected_result(data.to_vec(), "wallet public key");
+        let (&key_length, rest) = data.split_first().ok_or_else(invalid)?;
+        let (key, rest) = rest
+            .split_at_checked(key_length as usize)
+            .ok_or_else(invalid)?;
+        let (&address_length, rest) = rest.split_first().ok_or_else(invalid)?;
+        let (address, rest) = rest
+            .split_at_checked(address_length as usize)
+            .ok_or_else(invalid)?;
+        let chain_code: [u8; 32] = rest
+            .get(..32)
+            .and_then(|chain_code| chain_code.try_into().ok())
+            .ok_or_else(invalid)?;
+        Ok(WalletPublicKey {
+            public_key: secp256k1::PublicKey::from_slice(key).map_err(|_| invalid())?,
+            address: String::from_utf8(address.to_vec()).map_err(|_| invalid())?,
+            chain_code: ChainCode::from(chain_code),
+        })
+    }
+}
+
+/// Address type of a BIP-44/49/84 path.
+fn purpose_address_type(path: &DerivationPath) -> Result<AddressType, LedgerError> {
+    match path.as_ref().first() {
+        Some(ChildNumber::Hardened { index: 44 }) => Ok(AddressType::P2pkh),
+        Some(ChildNumber::Hardened { index: 49 }) => Ok(AddressType::P2sh),
+        Some(ChildNumber::Hardened { index: 84 }) => Ok(AddressType::P2wpkh),
+        _ => Err(LedgerError::UnsupportedDisplayAddress(format!(
+            "cannot infer the address type of {path}, set the address format"
+        ))),
+    }
+}
+
+/// What the response of a sent APDU is used for.
+#[derive(Clone, Copy)]
+enum Reply {
+    /// Only the status word matters.
+    Status,
+    MasterFingerprint,
+    ParentPublicKey,
+    PublicKey,
+    Address,
+    TrustedInput,
+    Signature {
+        input: usize,
+        pubkey: PublicKey,
+    },
+}
+
+enum Task {
+    MasterFingerprint,
+    Xpub {
+        path: DerivationPath,
+        parent_fingerprint: Fingerprint,
+    },
+    Address,
+    SignTx(SignTx),
+}
+
+/// A key of the device signing one input.
+struct Signer {
+    input: usize,
+    pubkey: PublicKey,
+    path: DerivationPath,
+    script_code: ScriptBuf,
+}
+
+struct SignTx {
+    psbt: Psbt,
+    signers: Vec<Signer>,
+    change_path: Option<DerivationPath>,
+    trusted_inputs: Vec<Vec<u8>>,
+}
+
+/// Previous output spent by input `index`.
+fn spent_output(psbt: &Psbt, index: usize) -> Result<TxOut, LedgerError> {
+    let input = &psbt.inputs[index];
+    if let Some(utxo) = &input.witness_utxo {
+        return Ok(utxo.clone());
+    }
+    let vout = psbt.unsigned_tx.input[index].previous_output.vout as usize;
+    input
+        .non_witness_utxo
+        .as_ref()
+        .and_then(|tx| tx.output.get(vout).cloned())
+        .ok_or_else(|| LedgerError::InvalidPsbt(format!("input {index} has no previous output")))
+}
+
+fn is_segwit(psbt: &Psbt, index: usize) -> Result<bool, LedgerError> {
+    let script_pubkey = spent_output(psbt, index)?.script_pubkey;
+    Ok(script_pubkey.is_witness_program()
+        || (script_pubkey.is_p2sh()
+            && psbt.inputs[index]
+                .redeem_script
+                .as_ref()
+                .is_some_and(|script| script.is_witness_program())))
+}
+
+/// Path of an output paying back to a change key of `fingerprint`.
+fn change_path(psbt: &Psbt, fingerprint: Fingerprint) -> Option<DerivationPath> {
+    psbt.outputs
+        .iter()
+        .zip(&psbt.unsigned_tx.output)
+        .find_map(|(output, txout)| {
+            output
+                .bip32_derivation
+                .iter()
+                .find_map(|(key, (fg, path))| {
+                    let children: &[ChildNumber] = path.as_ref();
+                    let is_change = children.len() >= 2
+                        && children[children.len() - 2] == ChildNumber::Normal { index: 1 };
+                    let key = CompressedPublicKey(*key);
+                    let p2wpkh = ScriptBuf::new_p2wpkh(&key.wpubkey_hash());
+                    let pays_key = txout.script_pubkey == ScriptBuf::new_p2pkh(&key.pubkey_hash())
+                        || txout.script_pubkey == ScriptBuf::new_p2sh(&p2wpkh.script_hash())
+                        || txout.script_pubkey == p2wpkh;
+                    (*fg == fingerprint && is_change && pays_key).then(|| path.clone())
+                })
+        })
+}
+
+impl SignTx {
+    fn new(psbt: Psbt) -> Result<Self, LedgerError> {
+        if psbt.inputs.len() != psbt.unsigned_tx.input.len()
+            || psbt.outputs.len() != psbt.unsigned_tx.output.len()
+        {
+            return Err(LedgerError::InvalidPsbt(
+                "psbt maps do not match unsigned transaction".to_string(),
+            ));
+        }
+        Ok(SignTx {
+            psbt,
+            signers: Vec::new(),
+            change_path: None,
+            trusted_inputs: Vec::new(),
+        })
+    }
+
+    fn sighash_type(&self, index: usize) -> u8 {
+        self.psbt.inputs[index]
+            .sighash_type
+            .map_or(SIGHASH_ALL, |sighash| sighash.to_u32() as u8)
+    }
+
+    /// Find the inputs to sign with the keys of `fingerprint`, and the APDU commands to send
+    /// next.
+    fn plan(&mut self, fingerprint: Fingerprint) -> Result<Vec<(ApduCommand, Reply)>, LedgerError> {
+        for (index, input) in self.psbt.inputs.iter().enumerate() {
+            let Some((key, path)) = input
+                .bip32_derivation
+                .iter()
+                .find_map(|(key, (fg, path))| (*fg == fingerprint).then_some((key, path)))
+            else {
+                continue;
+            };
+            let key = CompressedPublicKey(*key);
+            let script_pubkey = spent_output(&self.psbt, index)?.script_pubkey;
+            let script_code = if script_pubkey.is_p2pkh() {
+                script_pubkey
+            } else if script_pubkey.is_p2wpkh()
+                || input
+                    .redeem_script
+                    .as_ref()
+                    .is_some_and(|script| script.is_p2wpkh())
+            {
+                ScriptBuf::new_p2pkh(&key.pubkey_hash())
+            } else {
+                return Err(LedgerError::UnsupportedAppVersion(format!(
+                    "signing input {index}, only single-sig inputs are supported"
+                )));
+            };
+            self.signers.push(Signer {
+                input: index,
+                pubkey: PublicKey::from(key),
+                path: path.clone(),
+                script_code,
+            });
+        }
+        if self.signers.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.change_path = change_path(&self.psbt, fingerprint);
+
+        let segwit = (0..self.psbt.inputs.len())
+            .map(|index| is_segwit(&self.psbt, index))
+            .collect::<Result<Vec<_>, _>>()?;
+        if segwit.iter().all(|segwit| *segwit) {
+            return self.segwit_signing();
+        }
+        if segwit.iter().any(|segwit| *segwit) {
+            return Err(LedgerError::UnsupportedAppVersion(
+                "signing transactions mixing legacy and segwit inputs".to_string(),
+            ));
+        }
+        let mut apdus = Vec::new();
+        for (index, (input, txin)) in self
+            .psbt
+            .inputs
+            .iter()
+            .zip(&self.psbt.unsigned_tx.input)
+            .enumerate()
+        {
+            let tx = input.non_witness_utxo.as_ref().ok_or_else(|| {
+                LedgerError::InvalidPsbt(format!("input {index} has no previous transaction"))
+            })?;
+            let mut chunks = get_trusted_input(tx, txin.previous_output.vout);
+            let last = chunks.pop().expect("trusted input ends with the lock time");
+            apdus.extend(chunks.into_iter().map(|apdu| (apdu, Reply::Status)));
+            apdus.push((last, Reply::TrustedInput));
+        }
+        Ok(apdus)
+    }
+
+    /// Hash the whole transaction once, then each signed input alone with its script code.
+    fn segwit_signing(&self) -> Result<Vec<(ApduCommand, Reply)>, LedgerError> {
+        let tx = &self.psbt.unsigned_tx;
+        let inputs = tx
+            .input
+            .iter()
+            .enumerate()
+            .map(|(index, txin)| {
+                Ok(HashInput::Segwit {
+                    outpoint: txin.previous_output,
+                    amount: spent_output(&self.psbt, index)?.value,
+                    sequence: txin.sequence,
+                })
+            })
+            .collect::<Result<Vec<_>, LedgerError>>()?;
+        let mut apdus: Vec<_> = hash_input_start(true, true, tx.version.0, &inputs, 0, &[])
+            .into_iter()
+            .chain(hash_input_finalize_full(
+                &tx.output,
+                self.change_path.as_ref(),
+            ))
+            .map(|apdu| (apdu, Reply::Status))
+            .collect();
+        for signer in &self.signers {
+            apdus.extend(
+                hash_input_start(
+                    false,
+                    true,
+                    tx.version.0,
+                    &inputs[signer.input..=signer.input],
+                    0,
+                    signer.script_code.as_bytes(),
+                )
+                .into_iter()
+                .map(|apdu| (apdu, Reply::Status)),
+            );
+            apdus.push(self.hash_sign(signer));
+        }
+        Ok(apdus)
+    }
+
+    /// Hash the whole transaction for each signed input, with its script code.
+    fn legacy_signing(&self) -> Vec<(ApduCommand, Reply)> {
+        let tx = &self.psbt.unsigned_tx;
+        let inputs: Vec<_> = self
+            .trusted_inputs
+            .iter()
+            .zip(&tx.input)
+            .map(|(trusted_input, txin)| HashInput::Trusted {
+                trusted_input: trusted_input.clone(),
+                sequence: txin.sequence,
+            })
+            .collect();
+        let mut apdus = Vec::new();
+        for (i, signer) in self.signers.iter().enumerate() {
+            apdus.extend(
+                hash_input_start(
+                    i == 0,
+                    false,
+                    tx.version.0,
+                    &inputs,
+                    signer.input,
+                    signer.script_code.as_bytes(),
+                )
+                .into_iter()
+                .chain(hash_input_finalize_full(
+                    &tx.output,
+                    self.change_path.as_ref(),
+                ))
+                .map(|apdu| (apdu, Reply::Status)),
+            );
+            apdus.push(self.hash_sign(signer));
+        }
+        apdus
+    }
+
+    fn hash_sign(&self, signer: &Signer) -> (ApduCommand, Reply) {
+        (
+            hash_sign(
+                &signer.path,
+                self.psbt.unsigned_tx.lock_time,
+                self.sighash_type(signer.input),
+            ),
+            Reply::Signature {
+                input: signer.input,
+                pubkey: signer.pubkey,
+            },
+        )
+    }
+}
+
+pub(super) enum Step {
+    Continue(Session, ApduCommand),
+    Done(LedgerResponse),
+}
+
+/// A command in progress with a legacy app.
+pub(super) struct Session {
+    network: Network,
+    task: Task,
+    awaiting: Reply,
+    queue: VecDeque<(ApduCommand, Reply)>,
+}
+
+impl Session {
+    pub(super) fn start(
+        command: LedgerCommand,
+        network: Network,
+    ) -> Result<(Self, ApduCommand), LedgerError> {
+        let master = DerivationPath::master();
+        let (task, queue) = match command {
+            LedgerCommand::GetMasterFingerprint => (
+                Task::MasterFingerprint,
+                vec![(
+                    get_wallet_public_key(&master, false, AddressFormat::Legacy),
+                    Reply::MasterFingerprint,
+                )],
+            ),
+            LedgerCommand::GetXpub { path, display } => {
+                let mut queue = Vec::new();
+                let children: &[ChildNumber] = path.as_ref();
+                if let Some((_, parent)) = children.split_last() {
+                    queue.push((
+                        get_wallet_public_key(
+                            &DerivationPath::from(parent.to_vec()),
+                            false,
+                            AddressFormat::Legacy,
+                        ),
+                        Reply::ParentPublicKey,
+                    ));
+                }
+                queue.push((
+                    get_wallet_public_key(&path, display, AddressFormat::Legacy),
+                    Reply::PublicKey,
+                ));
+                (
+                    Task::Xpub {
+                        path,
+                        parent_fingerprint: Fingerprint::default(),
+                    },
+                    queue,
+                )
+            }
+            LedgerCommand::GetWalletAddress {
+                address:
+                    DisplayAddress::ByPath {
+                        path,
+                        display,
+                        address_format,
+                    },
+                ..
+            } => {
+                let address_type = match address_format {
+                    Some(address_type) => address_type,
+                    None => purpose_address_type(&path)?,
+                };
+                (
+                    Task::Address,
+                    vec![(
+                        get_wallet_public_key(
+                            &path,
+                            display,
+                            AddressFormat::try_from(address_type)?,
+                        ),
+                        Reply::Address,
+                    )],
+                )
+            }
+            LedgerCommand::GetWalletAddress { .. } => {
+                return Err(LedgerError::UnsupportedAppVersion(
+                    "wallet policy addresses".to_string(),
+                ));
+            }
+            LedgerCommand::SignTx { psbt } | LedgerCommand::SignPsbt { psbt, .. } => (
+                Task::SignTx(SignTx::new(psbt)?),
+                vec![(
+                    get_wallet_public_key(&master, false, AddressFormat::Legacy),
+                    Reply::MasterFingerprint,
+                )],
+            ),
+            LedgerCommand::SignMessage { .. } => {
+                return Err(LedgerError::UnsupportedAppVersion(
+                    "message signing".to_string(),
+                ));
+            }
+            LedgerCommand::RegisterWallet { .. } => {
+                return Err(LedgerError::UnsupportedAppVersion(
+                    "wallet registration".to_string(),
+                ));
+            }
+            LedgerCommand::OpenApp(_) | LedgerCommand::GetAppInfo => {
+                return Err(LedgerError::MissingCommandInfo(
+                    "app management commands do not depend on the app protocol",
+                ));
+            }
+            LedgerCommand::SignMessageBip322 { .. } => {
+                return Err(LedgerError::MissingCommandInfo(
+                    "BIP-322 signatures run as a session of other commands",
+                ));
+            }
+        };
+        let mut queue = VecDeque::from(queue);
+        let (apdu, awaiting) = queue.pop_front().expect("every task sends a command");
+        Ok((
+            Session {
+                network,
+                task,
+                awaiting,
+                queue,
+            },
+            apdu,
+        ))
+    }
+
+    pub(super) fn exchange(mut self, res: ApduResponse) -> Result<Step, LedgerError> {
+        match res.status_word {
+            StatusWord::OK => {}
+            status_word => return Err(LedgerError::status(status_word, "legacy app")),
+        }
+        match (self.awaiting, &mut self.task) {
+            (Reply::Status, _) => {}
+            (Reply::MasterFingerprint, Task::SignTx(sign)) => {
+                let fingerprint = WalletPublicKey::try_from(res.data.as_slice())?.fingerprint();
+                self.queue.extend(sign.plan(fingerprint)?);
+            }
+            (Reply::MasterFingerprint, _) => {
+                let fingerprint = WalletPublicKey::try_from(res.data.as_slice())?.fingerprint();
+                return Ok(Step::Done(LedgerResponse::MasterFingerprint(fingerprint)));
+            }
+            (
+                Reply::ParentPublicKey,
+                Task::Xpub {
+                    parent_fingerprint, ..
+                },
+            ) => {
+                *parent_fingerprint = WalletPublicKey::try_from(res.data.as_slice())?.fingerprint();
+            }
+            (
+                Reply::PublicKey,
+                Task::Xpub {
+                    path,
+                    parent_fingerprint,
+                },
+            ) => {
+                let key = WalletPublicKey::try_from(res.data.as_slice())?;
+                let children: &[ChildNumber] = path.as_ref();
+                return Ok(Step::Done(LedgerResponse::Xpub(Xpub {
+                    network: NetworkKind::from(self.network),
+                    depth: children.len() as u8,
+                    parent_fingerprint: *parent_fingerprint,
+                    child_number: children
+                        .last()
+                        .copied()
+                        .unwrap_or(ChildNumber::Normal { index: 0 }),
+                    public_key: key.public_key,
+                    chain_code: key.chain_code,
+                })));
+            }
+            (Reply::Address, _) => {
+                let key = WalletPublicKey::try_from(res.data.as_slice())?;
+                return Ok(Step::Done(LedgerResponse::Address(key.address)));
+            }
+            (Reply::TrustedInput, Task::SignTx(sign)) => {
+                sign.trusted_inputs.push(res.data);
+                if sign.trusted_inputs.len() == sign.psbt.inputs.len() {
+                    self.queue.extend(sign.legacy_signing());
+                }
+            }
+            (Reply::Signature { input, pubkey }, Task::SignTx(sign)) => {
+                let mut data = res.data;
+                // The first byte carries the parity of the nonce point on top of the DER tag.
+                if let Some(tag) = data.first_mut() {
+                    *tag = 0x30;
+                }
+                let signature = ecdsa::Signature::from_slice(&data).map_err(|_| {
+                    LedgerError::unexpected_result(data.clone(), "legacy signature")
+                })?;
+                sign.psbt.inputs[input]
+                    .partial_sigs
+                    .insert(pubkey, signature);
+            }
+            _ => return Err(LedgerError::NoErrorOrResult),
+        }
+        if let Some((apdu, awaiting)) = self.queue.pop_front() {
+            self.awaiting = awaiting;
+            return Ok(Step::Continue(self, apdu));
+        }
+        match self.task {
+            Task::SignTx(sign) => Ok(Step::Done(LedgerResponse::SignedPsbt(sign.psbt))),
+            _ => Err(LedgerError::NoErrorOrResult),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::{TxIn, Txid, Witness, transaction::Version};
+
+    use super::*;
+
+    // BIP-32 test vector 1 master key, as returned by the app at `m`.
+    const MASTER_SECRET_KEY: &str =
+        "e8f32e723decf4051aefac8e2c93c9c5b214313817cdb01a1494b917c8436b35";
+    const MASTER_CHAIN_CODE: &str =
+        "873dff81c02f525623fd1fe5167eac3a55a049de3d314bb42ee227ffed37d508";
+
+    fn wallet_public_key_reply(address: &str) -> Vec<u8> {
+        let secret_key = secp256k1::SecretKey::from_slice(&hex_decode(MASTER_SECRET_KEY)).unwrap();
+        let key = secret_key
+            .public_key(&secp256k1::Secp256k1::new())
+            .serialize_uncompressed();
+        let mut reply = vec![key.len() as u8];
+        reply.extend_from_slice(&key);
+        reply.push(address.len() as u8);
+        reply.extend_from_slice(address.as_bytes());
+        reply.extend(hex_decode(MASTER_CHAIN_CODE));
+        reply.extend_from_slice(&[0x90, 0x00]);
+        reply
+    }
+
+    fn hex_decode(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn ok() -> ApduResponse {
+        ApduResponse::try_from(vec![0x90, 0x00]).unwrap()
+    }
+
+    #[test]
+    fn master_fingerprint_from_wallet_public_key() {
+        let (session, apdu) =
+            Session::start(LedgerCommand::GetMasterFingerprint, Network::Bitcoin).unwrap();
+        assert_eq!(apdu.encode(), vec![0xe0, 0x40, 0x00, 0x00, 0x01, 0x00]);
+        let reply = ApduResponse::try_from(wallet_public_key_reply("")).unwrap();
+        match session.exchange(reply).unwrap() {
+            Step::Done(LedgerResponse::MasterFingerprint(fingerprint)) => {
+                assert_eq!(fingerprint, Fingerprint::from_str("3442193e").unwrap())
+            }
+            _ => panic!("expected the master fingerprint"),
+        }
+    }
+
+    #[test]
+    fn xpub_is_built_from_parent_and_child_keys() {
+        let path = DerivationPath::from_str("m/0h").unwrap();
+        let (session, apdu) = Session::start(
+            LedgerCommand::GetXpub {
+                path: path.clone(),
+                display: false,
+            },
+            Network::Bitcoin,
+        )
+        .unwrap();
+        // Parent key first.
+        assert_eq!(apdu.encode(), vec![0xe0, 0x40, 0x00, 0x00, 0x01, 0x00]);
+        let reply = ApduResponse::try_from(wallet_public_key_reply("")).unwrap();
+        let Step::Continue(session, apdu) = session.exchange(reply).unwrap() else {
+            panic!("expected the child key request");
+        };
+        assert_eq!(
+            apdu.encode(),
+            vec![0xe0, 0x40, 0x00, 0x00, 0x05, 0x01, 0x80, 0x00, 0x00, 0x00]
+        );
+        let reply = ApduResponse::try_from(wallet_public_key_reply("")).unwrap();
+        match session.exchange(reply).unwrap() {
+            Step::Done(LedgerResponse::Xpub(xpub)) => {
+                assert_eq!(xpub.depth, 1);
+                assert_eq!(
+                    xpub.parent_fingerprint,
+                    Fingerprint::from_str("3442193e").unwrap()
+                );
+                assert_eq!(xpub.child_number, ChildNumber::Hardened { index: 0 });
+            }
+            _ => panic!("expected an xpub"),
+        }
+    }
+
+    #[test]
+    fn address_format_follows_purpose() {
+        let path = DerivationPath::from_str("m/84h/0h/0h/0/1").unwrap();
+        let (session, apdu) = Session::start(
+            LedgerCommand::GetWalletAddress {
+                address: DisplayAddress::ByPath {
+                    path,
+                    display: true,
+                    address_format: None,
+                },
+                context: None,
+            },
+            Network::Bitcoin,
+        )
+        .unwrap();
+        assert_eq!(apdu.encode()[..4], [0xe0, 0x40, 0x01, 0x02]);
+        let address = "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu";
+        let reply = ApduResponse::try_from(wallet_public_key_reply(address)).unwrap();
+        assert!(matches!(
+            session.exchange(reply).unwrap(),
+            Step::Done(LedgerResponse::Address(a)) if a == address
+        ));
+    }
+
+    #[test]
+    fn denied_prompt_is_an_error() {
+        let path = DerivationPath::from_str("m/84h/0h/0h/0/1").unwrap();
+        let (session, _) = Session::start(
+            LedgerCommand::GetWalletAddress {
+                address: DisplayAddress::ByPath {
+                    path,
+                    display: true,
+                    address_format: None,
+                },
+                context: None,
+            },
+            Network::Bitcoin,
+        )
+        .unwrap();
+        let reply = ApduResponse::try_from(vec![0x69, 0x85]).unwrap();
+        assert!(matches!(
+            session.exchange(reply),
+            Err(LedgerError::Status(StatusWord::Deny, _))
+        ));
+    }
+
+    #[test]
+    fn unsupported_commands_are_rejected() {
+        assert!(matches!(
+            Session::start(
+                LedgerCommand::SignMessage {
+                    message: b"hello".to_vec(),
+                    path: DerivationPath::master(),
+                },
+                Network::Bitcoin,
+            ),
+            Err(LedgerError::UnsupportedAppVersion(_))
+        ));
+    }
+
+    #[test]
+    fn segwit_transaction_is_hashed_then_signed_per_input() {
+        let secp = secp256k1::Secp256k1::new();
+        let key = CompressedPublicKey(
+            secp256k1::SecretKey::from_slice(&[1; 32])
+                .unwrap()
+                .public_key(&secp),
+        );
+        let fingerprint = Fingerprint::from_str("3442193e").unwrap();
+        let path = DerivationPath::from_str("m/84h/0h/0h/0/0").unwrap();
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::all_zeros(), 1),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: Amount::from_sat(9_000),
+                script_pubkey: ScriptBuf::new_p2wpkh(&key.wpubkey_hash()),
+            }],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(10_000),
+            script_pubkey: ScriptBuf::new_p2wpkh(&key.wpubkey_hash()),
+        });
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(key.0, (fingerprint, path.clone()));
+
+        let (mut session, _) =
+            Session::start(LedgerCommand::SignTx { psbt }, Network::Bitcoin).unwrap();
+        let reply = ApduResponse::try_from(wallet_public_key_reply("")).unwrap();
+        let mut apdus = Vec::new();
+        let mut step = session.exchange(reply).unwrap();
+        while let Step::Continue(next, apdu) = step {
+            session = next;
+            apdus.push(apdu.encode());
+            step = if apdu.ins == LegacyCommandCode::HashSign as u8 {
+                // DER signature with the parity bit set, then the sighash type.
+                let mut sig = hex_decode(
+                    "3144022011111111111111111111111111111111111111111111111111111111111111110220222222222222222222222222222222222222222222222222222222222222222201",
+                );
+                sig.extend_from_slice(&[0x90, 0x00]);
+                session
+                    .exchange(ApduResponse::try_from(sig).unwrap())
+                    .unwrap()
+            } else {
+                session.exchange(ok()).unwrap()
+            };
+        }
+        // New segwit transaction, then the input alone with its script code.
+        assert_eq!(apdus[0][..4], [0xe0, 0x44, 0x00, 0x02]);
+        assert!(
+            apdus
+                .iter()
+                .any(|apdu| apdu[..4] == [0xe0, 0x4a, 0x80, 0x00])
+        );
+        assert!(
+            apdus
+                .iter()
+                .any(|apdu| apdu[..4] == [0xe0, 0x44, 0x00, 0x80])
+        );
+        let last = apdus.last().unwrap();
+        assert_eq!(last[..2], [0xe0, 0x48]);
+        assert_eq!(last[last.len() - 1], SIGHASH_ALL);
+
+        let Step::Done(LedgerResponse::SignedPsbt(psbt)) = step else {
+            panic!("expected a signed psbt");
+        };
+        assert_eq!(psbt.inputs[0].partial_sigs.len(), 1);
+        assert!(
+            psbt.inputs[0]
+                .partial_sigs
+                .contains_key(&PublicKey::from(key))
+        );
+    }
+}
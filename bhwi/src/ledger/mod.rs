@@ -4,9 +4,11 @@ pub mod store;
 
 pub mod apdu;
 pub mod error;
+pub mod legacy;
 pub mod psbt;
 pub mod wallet;
 
+use std::collections::{BTreeSet, VecDeque};
 use std::str::FromStr;
 
 use apdu::{ApduCommand, ApduError, ApduResponse, StatusWord};
@@ -16,10 +18,16 @@ use bitcoin::consensus::encode::deserialize_partial;
 use bitcoin::psbt::Psbt;
 use bitcoin::secp256k1::ecdsa::Signature;
 use store::{DelegatedStore, StoreError};
-pub use wallet::{AddressType, LedgerWalletPolicy, Version, WalletError, singlesig_wallet_policy};
+pub use wallet::{
+    AddressType, LedgerPolicy, LedgerWalletPolicy, Version, WalletError, singlesig_wallet_policy,
+};
 
 use crate::Interpreter;
-use crate::common::{Command, DeviceContext, DisplayAddress, Error, Info, Response};
+use crate::bip322::{self, Bip322Error};
+use crate::common::{
+    Capabilities, Command, CommandKind, DeviceContext, DeviceKind, DisplayAddress, Error,
+    ErrorKind, Event, Info, Response,
+};
 use crate::device::DeviceId;
 
 pub const LEDGER_DEVICE_ID: DeviceId = DeviceId::new(0x2c97)
@@ -49,6 +57,9 @@ pub enum LedgerError {
     #[error("unexpected result for {1}: {0:x?}")]
     UnexpectedResult(Vec<u8>, String),
 
+    #[error("{1} failed with status {0:?}")]
+    Status(StatusWord, String),
+
     #[error("unsupported display address: {0}")]
     UnsupportedDisplayAddress(String),
 
@@ -57,12 +68,25 @@ pub enum LedgerError {
 
     #[error("invalid psbt: {0}")]
     InvalidPsbt(String),
+
+    #[error("not supported by the legacy Bitcoin app: {0}")]
+    UnsupportedAppVersion(String),
+
+    #[error("{0} is not supported by Ledger")]
+    Unsupported(&'static str),
+
+    #[error(transparent)]
+    Bip322(#[from] Bip322Error),
 }
 
 impl LedgerError {
     pub fn unexpected_result(data: Vec<u8>, context: impl Into<String>) -> Self {
         LedgerError::UnexpectedResult(data, context.into())
     }
+
+    pub fn status(status_word: StatusWord, context: impl Into<String>) -> Self {
+        LedgerError::Status(status_word, context.into())
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -83,6 +107,12 @@ pub enum LedgerCommand {
         message: Vec<u8>,
         path: DerivationPath,
     },
+    /// Run as a [`bip322::Session`] of the other commands.
+    SignMessageBip322 {
+        message: Vec<u8>,
+        path: DerivationPath,
+        address_type: bitcoin::address::AddressType,
+    },
     RegisterWallet {
         policy: LedgerWalletPolicy,
     },
@@ -91,6 +121,10 @@ pub enum LedgerCommand {
         policy: LedgerWalletPolicy,
         hmac: Option<[u8; 32]>,
     },
+    /// Sign single-sig inputs without a wallet policy, only supported by legacy apps.
+    SignTx {
+        psbt: Psbt,
+    },
 }
 
 /// Parsed response from the `GetAppInfo` APDU command.
@@ -109,10 +143,46 @@ pub struct GetAppInfoResponse {
 
 impl GetAppInfoResponse {
     pub fn network(&self) -> Network {
-        if self.app_name == "Bitcoin" {
-            Network::Bitcoin
+        app_network(&self.app_name)
+    }
+
+    pub fn protocol(&self) -> AppProtocol {
+        AppProtocol::from_app(&self.app_name, &self.version)
+    }
+}
+
+fn app_network(app_name: &str) -> Network {
+    if matches!(app_name, "Bitcoin" | "Bitcoin Legacy") {
+        Network::Bitcoin
+    } else {
+        Network::Testnet
+    }
+}
+
+/// Protocol spoken by the Bitcoin app.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AppProtocol {
+    /// Merkleized commands of app-bitcoin-new, from version 2.1.0.
+    #[default]
+    Current,
+    /// btchip commands of Bitcoin app 1.x and of the "Bitcoin Legacy" app, see [`legacy`].
+    Legacy { network: Network },
+}
+
+impl AppProtocol {
+    /// Protocol of the app named `app_name` at `version`, as reported by `GetAppInfo`.
+    pub fn from_app(app_name: &str, version: &str) -> Self {
+        let mut numbers = version.split(['.', '-']).map(str::parse::<u32>);
+        let pre_2_1 = match (numbers.next(), numbers.next()) {
+            (Some(Ok(major)), Some(Ok(minor))) => (major, minor) < (2, 1),
+            _ => false,
+        };
+        if app_name.ends_with("Legacy") || (app_name.starts_with("Bitcoin") && pre_2_1) {
+            AppProtocol::Legacy {
+                network: app_network(app_name),
+            }
         } else {
-            Network::Testnet
+            AppProtocol::Current
         }
     }
 }
@@ -145,6 +215,7 @@ pub enum LedgerResponse {
     AppInfo(GetAppInfoResponse),
     MasterFingerprint(Fingerprint),
     Signature(u8, Signature),
+    Bip322Signature(String),
     TaskDone,
     Xpub(Xpub),
     Address(String),
@@ -162,6 +233,7 @@ enum State {
         store: Option<DelegatedStore>,
     },
     GetWalletAddress(GetWalletAddressStep),
+    Legacy(legacy::Session),
     Finished(LedgerResponse),
 }
 
@@ -181,8 +253,34 @@ enum GetWalletAddressStep {
     },
 }
 
+impl LedgerCommand {
+    /// Whether the app asks the user to review the command before answering.
+    fn awaits_user(&self) -> bool {
+        match self {
+            LedgerCommand::GetXpub { display, .. } => *display,
+            LedgerCommand::GetWalletAddress { address, .. } => match address {
+                DisplayAddress::ByPath { display, .. }
+                | DisplayAddress::ByDescriptor { display, .. } => *display,
+                DisplayAddress::ByMultisig(_) => false,
+            },
+            LedgerCommand::SignMessage { .. }
+            | LedgerCommand::RegisterWallet { .. }
+            | LedgerCommand::SignPsbt { .. }
+            | LedgerCommand::SignTx { .. } => true,
+            // Each command of the session announces its own confirmation.
+            LedgerCommand::OpenApp(_)
+            | LedgerCommand::GetAppInfo
+            | LedgerCommand::GetMasterFingerprint
+            | LedgerCommand::SignMessageBip322 { .. } => false,
+        }
+    }
+}
+
 pub struct LedgerInterpreter<C, T, R, E> {
     state: State,
+    protocol: AppProtocol,
+    bip322: Option<bip322::Session>,
+    events: VecDeque<Event>,
     _marker: std::marker::PhantomData<(C, T, R, E)>,
 }
 
@@ -190,11 +288,22 @@ impl<C, T, R, E> Default for LedgerInterpreter<C, T, R, E> {
     fn default() -> Self {
         Self {
             state: State::default(),
+            protocol: AppProtocol::default(),
+            bip322: None,
+            events: VecDeque::new(),
             _marker: std::marker::PhantomData,
         }
     }
 }
 
+impl<C, T, R, E> LedgerInterpreter<C, T, R, E> {
+    /// Speak `protocol`, as detected from the app info returned by `Unlock` or `GetVersion`.
+    pub fn with_protocol(mut self, protocol: AppProtocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+}
+
 fn apply_psbt_signature(psbt: &mut Psbt, yielded: &[u8]) -> Result<(), LedgerError> {
     let (input_index, yielded_object) = parse_sign_psbt_yielded(yielded)?;
     let input = psbt
@@ -202,8 +311,14 @@ fn apply_psbt_signature(psbt: &mut Psbt, yielded: &[u8]) -> Result<(), LedgerErr
         .get_mut(input_index)
         .ok_or_else(|| LedgerError::InvalidPsbt(format!("invalid input index {input_index}")))?;
 
-    let SignPsbtYieldedObject::Partial(partial_signature) = yielded_object else {
-        return Ok(());
+    let partial_signature = match yielded_object {
+        SignPsbtYieldedObject::Partial(partial_signature) => partial_signature,
+        SignPsbtYieldedObject::Musig2(musig2) => {
+            let pair = musig2.to_pair();
+            input.unknown.insert(pair.key, pair.value);
+            return Ok(());
+        }
+        SignPsbtYieldedObject::Ignored => return Ok(()),
     };
 
     match partial_signature {
@@ -222,14 +337,33 @@ fn apply_psbt_signature(psbt: &mut Psbt, yielded: &[u8]) -> Result<(), LedgerErr
 
 enum SignPsbtYieldedObject {
     Partial(psbt::PartialSignature),
+    Musig2(psbt::Musig2Yield),
     Ignored,
 }
 
+/// Reserved yield tag of a MuSig2 public nonce.
+const MUSIG2_PUB_NONCE_TAG: u64 = 0xFFFFFFFF;
+/// Reserved yield tag of a MuSig2 partial signature.
+const MUSIG2_PARTIAL_SIG_TAG: u64 = 0xFFFFFFFE;
+
 fn parse_sign_psbt_yielded(data: &[u8]) -> Result<(usize, SignPsbtYieldedObject), LedgerError> {
     let (tag, read) = deserialize_unchecked_varint(data)?;
     match tag {
-        // TODO: Parse and store MuSig2 yields once rust-bitcoin exposes the
-        // corresponding PSBT fields.
+        MUSIG2_PUB_NONCE_TAG | MUSIG2_PARTIAL_SIG_TAG => {
+            let (input_index, index_read) = deserialize_unchecked_varint(&data[read..])?;
+            let payload = &data[read + index_read..];
+            let musig2 = if tag == MUSIG2_PUB_NONCE_TAG {
+                psbt::Musig2Yield::pub_nonce_from_slice(payload)
+            } else {
+                psbt::Musig2Yield::partial_sig_from_slice(payload)
+            }
+            .map_err(|_| LedgerError::InvalidPsbt("invalid musig2 yield".to_string()))?;
+            Ok((
+                input_index_to_usize(input_index)?,
+                SignPsbtYieldedObject::Musig2(musig2),
+            ))
+        }
+        // Other reserved tags are not defined by the app yet.
         tag if tag >= 0x80000000 => {
             let (input_index, _) = deserialize_unchecked_varint(&data[read..])?;
             Ok((
@@ -250,6 +384,27 @@ fn parse_sign_psbt_yielded(data: &[u8]) -> Result<(usize, SignPsbtYieldedObject)
     }
 }
 
+/// Number of the `total` PSBT inputs the app yielded a signature for. MuSig2 public nonces
+/// only open the signing round and do not count.
+fn signed_inputs(yielded: &[Vec<u8>], total: usize) -> usize {
+    yielded
+        .iter()
+        .filter_map(|data| match parse_sign_psbt_yielded(data).ok()? {
+            (index, SignPsbtYieldedObject::Partial(_))
+            | (
+                index,
+                SignPsbtYieldedObject::Musig2(psbt::Musig2Yield {
+                    value: psbt::Musig2Value::PartialSig(_),
+                    ..
+                }),
+            ) => Some(index),
+            _ => None,
+        })
+        .filter(|index| *index < total)
+        .collect::<BTreeSet<_>>()
+        .len()
+}
+
 fn input_index_to_usize(input_index: u64) -> Result<usize, LedgerError> {
     usize::try_from(input_index).map_err(|_| {
         LedgerError::InvalidPsbt(format!("input index does not fit usize: {input_index}"))
@@ -301,33 +456,36 @@ fn deserialize_unchecked_varint(data: &[u8]) -> Result<(u64, usize), LedgerError
     }
 }
 
-impl<C, T, R, E> Interpreter for LedgerInterpreter<C, T, R, E>
+impl<C, T, R, E> LedgerInterpreter<C, T, R, E>
 where
-    C: TryInto<LedgerCommand, Error = LedgerError>,
     T: From<ApduCommand>,
-    R: From<LedgerResponse>,
     E: From<LedgerError>,
 {
-    type Command = C;
-    type Transmit = T;
-    type Response = R;
-    type Error = E;
-
-    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
-        let command: LedgerCommand = command.try_into()?;
+    fn start_command(&mut self, command: LedgerCommand) -> Result<T, E> {
+        if command.awaits_user() {
+            self.events.push_back(Event::AwaitingUserConfirmation);
+        }
+        if let AppProtocol::Legacy { network } = self.protocol
+            && !matches!(
+                command,
+                LedgerCommand::OpenApp(_)
+                    | LedgerCommand::GetAppInfo
+                    | LedgerCommand::SignMessageBip322 { .. }
+            )
+        {
+            let (session, apdu) = legacy::Session::start(command, network)?;
+            self.state = State::Legacy(session);
+            return Ok(T::from(apdu));
+        }
         let (transmit, store) = match command {
-            LedgerCommand::GetAppInfo => (Self::Transmit::from(command::get_version()), None),
-            LedgerCommand::GetMasterFingerprint => (
-                Self::Transmit::from(command::get_master_fingerprint()),
-                None,
-            ),
-            LedgerCommand::GetXpub { ref path, display } => (
-                Self::Transmit::from(command::get_extended_pubkey(path, display)),
-                None,
-            ),
-            LedgerCommand::OpenApp(network) => {
-                (Self::Transmit::from(command::open_app(network)), None)
+            LedgerCommand::GetAppInfo => (T::from(command::get_version()), None),
+            LedgerCommand::GetMasterFingerprint => {
+                (T::from(command::get_master_fingerprint()), None)
+            }
+            LedgerCommand::GetXpub { ref path, display } => {
+                (T::from(command::get_extended_pubkey(path, display)), None)
             }
+            LedgerCommand::OpenApp(network) => (T::from(command::open_app(network)), None),
             LedgerCommand::SignMessage {
                 ref message,
                 ref path,
@@ -337,7 +495,7 @@ where
                 let mut store = DelegatedStore::new();
                 let message_commitment_root = store.add_known_list(&chunks);
                 (
-                    Self::Transmit::from(command::sign_message(
+                    T::from(command::sign_message(
                         message_length,
                         &message_commitment_root,
                         path,
@@ -348,10 +506,26 @@ where
             LedgerCommand::GetWalletAddress { address, context } => {
                 self.state =
                     State::GetWalletAddress(GetWalletAddressStep::Fingerprint { address, context });
-                return Ok(Self::Transmit::from(command::get_master_fingerprint()));
+                return Ok(T::from(command::get_master_fingerprint()));
+            }
+            LedgerCommand::SignTx { .. } => {
+                return Err(LedgerError::MissingCommandInfo("ledger sign tx context").into());
+            }
+            LedgerCommand::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => {
+                // The current app signs only under a wallet policy, legacy apps without.
+                let account_policy = self.protocol == AppProtocol::Current;
+                let (session, command) =
+                    bip322::Session::start(message, path, address_type, account_policy)
+                        .map_err(LedgerError::from)?;
+                self.bip322 = Some(session);
+                return self.start_command(command.try_into()?);
             }
             LedgerCommand::RegisterWallet { ref policy } => (
-                Self::Transmit::from(command::register_wallet(policy).map_err(LedgerError::from)?),
+                T::from(command::register_wallet(policy).map_err(LedgerError::from)?),
                 Some(policy.to_store().map_err(LedgerError::from)?),
             ),
             LedgerCommand::SignPsbt {
@@ -421,7 +595,7 @@ where
                 let output_commitments_root = store.add_known_list(&output_commitments);
 
                 (
-                    Self::Transmit::from(
+                    T::from(
                         command::sign_psbt(
                             &global_commitment,
                             psbt.inputs.len(),
@@ -441,6 +615,45 @@ where
         Ok(transmit)
     }
 
+    /// Feed the response of the command that just finished to the running BIP-322 session,
+    /// starting its next command or finishing with its signature.
+    fn next_bip322_step(&mut self) -> Result<Option<T>, E> {
+        let Some(session) = self.bip322.as_mut() else {
+            return Ok(None);
+        };
+        let State::Finished(response) = std::mem::take(&mut self.state) else {
+            return Err(LedgerError::NoErrorOrResult.into());
+        };
+        match session
+            .next(Response::from(response))
+            .map_err(LedgerError::from)?
+        {
+            bip322::Next::Run(command) => self.start_command(command.try_into()?).map(Some),
+            bip322::Next::Signature(signature) => {
+                self.bip322 = None;
+                self.state = State::Finished(LedgerResponse::Bip322Signature(signature));
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl<C, T, R, E> Interpreter for LedgerInterpreter<C, T, R, E>
+where
+    C: TryInto<LedgerCommand, Error = LedgerError>,
+    T: From<ApduCommand>,
+    R: From<LedgerResponse>,
+    E: From<LedgerError>,
+{
+    type Command = C;
+    type Transmit = T;
+    type Response = R;
+    type Error = E;
+
+    fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error> {
+        self.start_command(command.try_into()?)
+    }
+
     fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error> {
         let res = ApduResponse::try_from(data).map_err(LedgerError::from)?;
         let state = std::mem::take(&mut self.state);
@@ -602,19 +815,33 @@ where
                         return Err(LedgerError::Interrupted.into());
                     }
                 } else if res.status_word != StatusWord::OK {
-                    return Err(
-                        LedgerError::unexpected_result(res.data, "display address status").into(),
-                    );
+                    return Err(LedgerError::status(res.status_word, "display address").into());
                 } else {
                     let address = String::from_utf8(res.data)
                         .map_err(|e| LedgerError::unexpected_result(vec![], e.to_string()))?;
                     (State::Finished(LedgerResponse::Address(address)), None)
                 }
             }
+            State::Legacy(session) => match session.exchange(res)? {
+                legacy::Step::Continue(session, apdu) => {
+                    (State::Legacy(session), Some(Self::Transmit::from(apdu)))
+                }
+                legacy::Step::Done(response) => (State::Finished(response), None),
+            },
             State::Running { mut store, command } => {
                 if res.status_word == StatusWord::InterruptedExecution {
                     if let Some(ref mut s) = store {
+                        let total = match &command {
+                            LedgerCommand::SignPsbt { psbt, .. } => psbt.inputs.len(),
+                            _ => 0,
+                        };
+                        let signed = signed_inputs(s.peek_yielded(), total);
                         let transmit = s.execute(res.data).map_err(LedgerError::from)?;
+                        // The app yields the signatures of a PSBT input by input.
+                        let done = signed_inputs(s.peek_yielded(), total);
+                        if done > signed {
+                            self.events.push_back(Event::Progress { done, total });
+                        }
                         let cmd = Self::Transmit::from(command::continue_interrupted(transmit));
                         self.state = State::Running { store, command };
                         return Ok(Some(cmd));
@@ -625,11 +852,7 @@ where
                 match command {
                     LedgerCommand::GetAppInfo => {
                         if res.status_word != StatusWord::OK {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "get_version response",
-                            )
-                            .into());
+                            return Err(LedgerError::status(res.status_word, "get_version").into());
                         }
                         let response = GetAppInfoResponse::try_from(res.data.clone())
                             .map_err(|e| LedgerError::unexpected_result(res.data, e))?;
@@ -666,6 +889,7 @@ where
                         };
                         let transmit =
                             Self::Transmit::from(command::get_extended_pubkey(path, true));
+                        self.events.push_back(Event::AwaitingUserConfirmation);
                         self.state = State::Running {
                             store: None,
                             command: retry,
@@ -674,9 +898,9 @@ where
                     }
                     LedgerCommand::GetXpub { .. } => {
                         if res.status_word != StatusWord::OK {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "get extended pubkey status",
+                            return Err(LedgerError::status(
+                                res.status_word,
+                                "get extended pubkey",
                             )
                             .into());
                         }
@@ -685,17 +909,22 @@ where
                         (State::Finished(LedgerResponse::Xpub(xpub)), None)
                     }
                     LedgerCommand::OpenApp(..) => {
-                        if matches!(
+                        if res.status_word == StatusWord::OK {
+                            (State::Finished(LedgerResponse::TaskDone), None)
+                        } else if matches!(
                             res.status_word,
-                            StatusWord::OK | StatusWord::ClaNotSupported
+                            StatusWord::ClaNotSupported | StatusWord::InsNotSupported
                         ) {
-                            (State::Finished(LedgerResponse::TaskDone), None)
+                            // An app is already open (legacy apps know the class but not the
+                            // instruction): report its name and version so that the caller
+                            // can pick the protocol it speaks.
+                            self.state = State::Running {
+                                store: None,
+                                command: LedgerCommand::GetAppInfo,
+                            };
+                            return Ok(Some(Self::Transmit::from(command::get_version())));
                         } else {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "open app response",
-                            )
-                            .into());
+                            return Err(LedgerError::status(res.status_word, "open app").into());
                         }
                     }
                     LedgerCommand::SignMessage { .. } => match res.status_word {
@@ -714,12 +943,8 @@ where
                                 None,
                             )
                         }
-                        _ => {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "sign message status",
-                            )
-                            .into());
+                        status_word => {
+                            return Err(LedgerError::status(status_word, "sign message").into());
                         }
                     },
                     LedgerCommand::GetWalletAddress { .. } => {
@@ -731,20 +956,16 @@ where
                             })?;
                             (State::Finished(LedgerResponse::Address(address)), None)
                         } else {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "display address status",
-                            )
-                            .into());
+                            return Err(
+                                LedgerError::status(res.status_word, "display address").into()
+                            );
                         }
                     }
                     LedgerCommand::RegisterWallet { .. } => {
                         if res.status_word != StatusWord::OK {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "register wallet status",
-                            )
-                            .into());
+                            return Err(
+                                LedgerError::status(res.status_word, "register wallet").into()
+                            );
                         }
                         if res.data.len() < 64 {
                             return Err(LedgerError::unexpected_result(
@@ -757,6 +978,17 @@ where
                         hmac.copy_from_slice(&res.data[32..64]);
                         (State::Finished(LedgerResponse::WalletHmac(hmac)), None)
                     }
+                    LedgerCommand::SignTx { .. } => {
+                        return Err(
+                            LedgerError::MissingCommandInfo("ledger sign tx context").into()
+                        );
+                    }
+                    LedgerCommand::SignMessageBip322 { .. } => {
+                        return Err(LedgerError::MissingCommandInfo(
+                            "BIP-322 signatures run as a session of other commands",
+                        )
+                        .into());
+                    }
                     LedgerCommand::SignPsbt { mut psbt, .. } => match res.status_word {
                         StatusWord::Deny
                         | StatusWord::ClaNotSupported
@@ -770,12 +1002,8 @@ where
                             }
                             (State::Finished(LedgerResponse::SignedPsbt(psbt)), None)
                         }
-                        _ => {
-                            return Err(LedgerError::unexpected_result(
-                                res.data,
-                                "sign psbt status",
-                            )
-                            .into());
+                        status_word => {
+                            return Err(LedgerError::status(status_word, "sign psbt").into());
                         }
                     },
                 }
@@ -784,7 +1012,10 @@ where
             State::Finished(_) => (state, None),
         };
         self.state = next_state;
-        Ok(result)
+        if result.is_some() {
+            return Ok(result);
+        }
+        self.next_bip322_step()
     }
 
     fn end(self) -> Result<Self::Response, Self::Error> {
@@ -794,6 +1025,43 @@ where
             Err(LedgerError::NoErrorOrResult.into())
         }
     }
+
+    fn poll_event(&mut self) -> Option<Event> {
+        self.events.pop_front()
+    }
+}
+
+/// Capabilities of the Bitcoin app reported in `info`. Apps speaking the legacy protocol
+/// only sign single-sig inputs and cannot register wallet policies.
+pub fn capabilities(info: &Info) -> Capabilities {
+    use bitcoin::address::AddressType;
+    use bitcoin::sighash::EcdsaSighashType;
+
+    let app_name = info.firmware.as_deref().unwrap_or_default();
+    let current = AppProtocol::from_app(app_name, &info.version) == AppProtocol::Current;
+    let mut commands = CommandKind::BASE.to_vec();
+    let mut script_types = vec![AddressType::P2pkh, AddressType::P2sh, AddressType::P2wpkh];
+    if current {
+        commands.push(CommandKind::SignMessageBip322);
+        commands.push(CommandKind::RegisterWallet);
+        script_types.push(AddressType::P2tr);
+    }
+    Capabilities {
+        commands,
+        script_types,
+        taproot: current,
+        miniscript: current,
+        multisig: current,
+        sighash_types: vec![
+            EcdsaSighashType::All,
+            EcdsaSighashType::None,
+            EcdsaSighashType::Single,
+            EcdsaSighashType::AllPlusAnyoneCanPay,
+            EcdsaSighashType::NonePlusAnyoneCanPay,
+            EcdsaSighashType::SinglePlusAnyoneCanPay,
+        ],
+        backups: vec![],
+    }
 }
 
 impl TryFrom<Command> for LedgerCommand {
@@ -812,6 +1080,11 @@ impl TryFrom<Command> for LedgerCommand {
             Command::TogglePassphrase => Err(LedgerError::MissingCommandInfo(
                 "Toggle passphrase not supported by Ledger",
             )),
+            // Ledger passphrases are set on the device and bound to a PIN.
+            Command::SetPassphrase(_) => Err(LedgerError::Unsupported("set passphrase")),
+            // Ledger genuineness is checked by its HSM through the dashboard, not by the
+            // Bitcoin app.
+            Command::VerifyAttestation => Err(LedgerError::Unsupported("attestation")),
             Command::Backup => Err(LedgerError::MissingCommandInfo(
                 "Backup not supported by Ledger",
             )),
@@ -833,27 +1106,32 @@ impl TryFrom<Command> for LedgerCommand {
                 context: ctx,
             }),
             Command::SignMessage { message, path } => Ok(Self::SignMessage { message, path }),
+            Command::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            } => Ok(Self::SignMessageBip322 {
+                message,
+                path,
+                address_type,
+            }),
             Command::GetVersion => Ok(Self::GetAppInfo),
             Command::RegisterWallet { name, policy } => Ok(Self::RegisterWallet {
                 policy: LedgerWalletPolicy::new(name, Version::V2, policy),
             }),
-            Command::SignTx(psbt, context) => {
-                let (ledger_policy, wallet_hmac) = context
-                    .and_then(|ctx| match ctx {
-                        DeviceContext::Ledger {
-                            wallet_policy,
-                            wallet_hmac,
-                        } => Some((wallet_policy, wallet_hmac)),
-                        #[cfg(feature = "bitbox")]
-                        _ => None,
-                    })
-                    .ok_or(LedgerError::MissingCommandInfo("ledger sign tx context"))?;
-                Ok(Self::SignPsbt {
+            Command::SignTx(psbt, context) => match context {
+                Some(DeviceContext::Ledger {
+                    wallet_policy,
+                    wallet_hmac,
+                }) => Ok(Self::SignPsbt {
                     psbt,
-                    policy: ledger_policy,
+                    policy: wallet_policy,
                     hmac: wallet_hmac,
-                })
-            }
+                }),
+                None => Ok(Self::SignTx { psbt }),
+                #[cfg(feature = "bitbox")]
+                Some(_) => Err(LedgerError::MissingCommandInfo("ledger sign tx context")),
+            },
         }
     }
 }
@@ -862,12 +1140,14 @@ impl From<LedgerResponse> for Response {
     fn from(res: LedgerResponse) -> Response {
         match res {
             LedgerResponse::AppInfo(res) => Response::Info(Info {
+                device: Some(DeviceKind::Ledger),
                 version: res.version.to_string(),
                 networks: vec![res.network()],
                 firmware: Some(res.app_name),
                 initialized: None,
             }),
             LedgerResponse::Signature(header, signature) => Response::Signature(header, signature),
+            LedgerResponse::Bip322Signature(signature) => Response::Bip322Signature(signature),
             LedgerResponse::TaskDone => Response::TaskDone,
             LedgerResponse::Xpub(xpub) => Response::Xpub(xpub),
             LedgerResponse::MasterFingerprint(fg) => Response::MasterFingerprint(fg),
@@ -882,6 +1162,26 @@ impl From<LedgerResponse> for Response {
     }
 }
 
+impl From<StatusWord> for ErrorKind {
+    fn from(status_word: StatusWord) -> Self {
+        match status_word {
+            StatusWord::Deny => ErrorKind::UserCancelled,
+            StatusWord::SecurityStatusNotSatisfied | StatusWord::LockedDevice => ErrorKind::Locked,
+            StatusWord::NotSupported
+            | StatusWord::InsNotSupported
+            | StatusWord::ClaNotSupported
+            | StatusWord::CommandNotAllowed => ErrorKind::Unsupported,
+            StatusWord::IncorrectData | StatusWord::WrongP1P2 | StatusWord::WrongDataLength => {
+                ErrorKind::InvalidInput
+            }
+            StatusWord::BadState
+            | StatusWord::SignatureFail
+            | StatusWord::OK
+            | StatusWord::InterruptedExecution => ErrorKind::Protocol,
+        }
+    }
+}
+
 impl From<LedgerError> for Error {
     fn from(error: LedgerError) -> Error {
         match error {
@@ -892,9 +1192,22 @@ impl From<LedgerError> for Error {
             LedgerError::Wallet(_) => Error::Request("Wallet operation failed"),
             LedgerError::Interrupted => Error::Request("Operation interrupted"),
             LedgerError::UnexpectedResult(data, ctx) => Error::unexpected_result(data, ctx),
+            LedgerError::Status(status_word, ctx) => Error::Device(
+                status_word.into(),
+                format!("{ctx} failed with status {status_word:?}"),
+            ),
             LedgerError::UnsupportedDisplayAddress(ctx) => Error::UnsupportedDisplayAddress(ctx),
             LedgerError::FailedToOpenApp(_) => Error::AuthenticationRefused,
             LedgerError::InvalidPsbt(e) => Error::Serialization(e),
+            LedgerError::UnsupportedAppVersion(e) => Error::Device(
+                ErrorKind::Unsupported,
+                format!("not supported by the legacy Bitcoin app: {e}"),
+            ),
+            LedgerError::Bip322(e) => Error::from(e),
+            LedgerError::Unsupported(operation) => Error::Unsupported {
+                device: "Ledger",
+                operation,
+            },
         }
     }
 }
@@ -954,22 +1267,117 @@ mod tests {
         ));
     }
 
+    fn compressed_pubkey() -> Vec<u8> {
+        let mut key = vec![0x02];
+        key.extend_from_slice(&XONLY);
+        key
+    }
+
+    fn musig2_payload(tag: u64, input_index: u64, value: &[u8], tapleaf: bool) -> Vec<u8> {
+        let mut payload = encode_unchecked_varint(tag);
+        payload.extend(encode_unchecked_varint(input_index));
+        payload.extend_from_slice(value);
+        payload.extend(compressed_pubkey());
+        payload.extend(compressed_pubkey());
+        if tapleaf {
+            payload.extend_from_slice(&[0xcc; 32]);
+        }
+        payload
+    }
+
+    #[test]
+    fn parse_musig_pubnonce_yield() {
+        let payload = musig2_payload(0xFFFF_FFFF, 7, &[0xaa; 66], true);
+        assert_eq!(payload.len() - 6, 164);
+
+        let (input_index, object) =
+            parse_sign_psbt_yielded(&payload).expect("payload should parse");
+        assert_eq!(input_index, 7);
+        assert!(matches!(
+            object,
+            SignPsbtYieldedObject::Musig2(psbt::Musig2Yield {
+                tapleaf_hash: Some(_),
+                value: psbt::Musig2Value::PubNonce(nonce),
+                ..
+            }) if nonce == [0xaa; 66]
+        ));
+    }
+
     #[test]
-    fn parse_reserved_musig_pubnonce_tag_is_ignored() {
-        let mut payload = encode_unchecked_varint(0xFFFF_FFFF);
-        payload.extend(encode_unchecked_varint(7));
-        payload.extend_from_slice(&[0xaa; 164]);
+    fn parse_musig_partial_signature_yield() {
+        let payload = musig2_payload(0xFFFF_FFFE, 2, &[0xbb; 32], false);
+        assert_eq!(payload.len() - 6, 98);
+
+        let (input_index, object) =
+            parse_sign_psbt_yielded(&payload).expect("payload should parse");
+        assert_eq!(input_index, 2);
+        assert!(matches!(
+            object,
+            SignPsbtYieldedObject::Musig2(psbt::Musig2Yield {
+                tapleaf_hash: None,
+                value: psbt::Musig2Value::PartialSig(sig),
+                ..
+            }) if sig == [0xbb; 32]
+        ));
+    }
 
-        assert_ignored(&payload, 7);
+    #[test]
+    fn progress_counts_each_signed_input_once() {
+        let yielded = vec![
+            // A public nonce is not a signature yet.
+            musig2_payload(0xFFFF_FFFF, 0, &[0xaa; 66], false),
+            musig2_payload(0xFFFF_FFFE, 1, &[0xbb; 32], false),
+            musig2_payload(0xFFFF_FFFE, 1, &[0xbb; 32], true),
+            // Out of the PSBT.
+            musig2_payload(0xFFFF_FFFE, 5, &[0xbb; 32], false),
+        ];
+        assert_eq!(signed_inputs(&yielded, 2), 1);
     }
 
     #[test]
-    fn parse_reserved_musig_partial_signature_tag_is_ignored() {
+    fn parse_truncated_musig_yield_fails() {
         let mut payload = encode_unchecked_varint(0xFFFF_FFFE);
         payload.extend(encode_unchecked_varint(2));
-        payload.extend_from_slice(&[0xbb; 98]);
+        payload.extend_from_slice(&[0xbb; 40]);
 
-        assert_ignored(&payload, 2);
+        assert!(parse_sign_psbt_yielded(&payload).is_err());
+    }
+
+    #[test]
+    fn musig_yields_are_stored_as_bip373_fields() {
+        let tx = bitcoin::Transaction {
+            version: bitcoin::transaction::Version::TWO,
+            lock_time: bitcoin::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+
+        apply_psbt_signature(
+            &mut psbt,
+            &musig2_payload(0xFFFF_FFFF, 0, &[0xaa; 66], false),
+        )
+        .unwrap();
+        apply_psbt_signature(
+            &mut psbt,
+            &musig2_payload(0xFFFF_FFFE, 0, &[0xbb; 32], true),
+        )
+        .unwrap();
+
+        let mut key = compressed_pubkey();
+        key.extend(compressed_pubkey());
+        let nonce_key = bitcoin::psbt::raw::Key {
+            type_value: psbt::PSBT_IN_MUSIG2_PUB_NONCE,
+            key: key.clone(),
+        };
+        key.extend_from_slice(&[0xcc; 32]);
+        let sig_key = bitcoin::psbt::raw::Key {
+            type_value: psbt::PSBT_IN_MUSIG2_PARTIAL_SIG,
+            key,
+        };
+        let unknown = &psbt.inputs[0].unknown;
+        assert_eq!(unknown.get(&nonce_key), Some(&vec![0xaa; 66]));
+        assert_eq!(unknown.get(&sig_key), Some(&vec![0xbb; 32]));
     }
 
     #[test]
@@ -992,11 +1400,151 @@ mod tests {
 
         let initial = interpreter.start(command).unwrap();
         assert_eq!(initial.payload[5], 0);
+        assert_eq!(interpreter.poll_event(), None);
 
         let retry = interpreter
             .exchange(vec![0x6a, 0x82])
             .unwrap()
             .expect("non-standard path should be retried");
         assert_eq!(retry.payload[5], 1);
+        // The user now has to approve the path on the device.
+        assert_eq!(
+            interpreter.poll_event(),
+            Some(crate::common::Event::AwaitingUserConfirmation)
+        );
+    }
+
+    #[test]
+    fn bip322_signature_runs_under_the_account_policy() {
+        use bitcoin::{address::AddressType, secp256k1::Secp256k1};
+
+        let reply = |data: &[u8]| [data, &[0x90, 0x00]].concat();
+        let account_xpub = Xpub::from_str("tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP").unwrap();
+        let key = account_xpub
+            .derive_pub(&Secp256k1::verification_only(), &[0.into(), 0.into()])
+            .unwrap();
+
+        let mut interpreter = crate::common::LedgerInterpreter::default();
+        let transmit = interpreter
+            .start(crate::common::Command::SignMessageBip322 {
+                message: b"Hello World".to_vec(),
+                path: DerivationPath::from_str("m/84'/1'/0'/0/0").unwrap(),
+                address_type: AddressType::P2wpkh,
+            })
+            .unwrap();
+        assert_eq!(
+            transmit.payload[1],
+            apdu::BitcoinCommandCode::GetMasterFingerprint as u8
+        );
+        let steps = [
+            (
+                reply(&[0xf5, 0xac, 0xc2, 0xfd]),
+                apdu::BitcoinCommandCode::GetExtendedPubkey,
+            ),
+            (
+                reply(key.to_string().as_bytes()),
+                apdu::BitcoinCommandCode::GetExtendedPubkey,
+            ),
+            (
+                reply(account_xpub.to_string().as_bytes()),
+                apdu::BitcoinCommandCode::SignPSBT,
+            ),
+        ];
+        for (data, next) in steps {
+            let transmit = interpreter.exchange(data).unwrap().unwrap();
+            assert_eq!(transmit.payload[1], next as u8);
+        }
+    }
+
+    #[test]
+    fn bip322_signature_rejects_legacy_addresses() {
+        let mut interpreter = crate::common::LedgerInterpreter::default();
+        assert!(
+            interpreter
+                .start(crate::common::Command::SignMessageBip322 {
+                    message: Vec::new(),
+                    path: DerivationPath::from_str("m/44'/1'/0'/0/0").unwrap(),
+                    address_type: bitcoin::address::AddressType::P2pkh,
+                })
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn app_protocol_follows_app_version() {
+        assert_eq!(
+            AppProtocol::from_app("Bitcoin", "2.1.3"),
+            AppProtocol::Current
+        );
+        assert_eq!(
+            AppProtocol::from_app("Bitcoin", "1.6.5"),
+            AppProtocol::Legacy {
+                network: Network::Bitcoin
+            }
+        );
+        assert_eq!(
+            AppProtocol::from_app("Bitcoin Test", "2.0.6"),
+            AppProtocol::Legacy {
+                network: Network::Testnet
+            }
+        );
+        assert_eq!(
+            AppProtocol::from_app("Bitcoin Legacy", "2.1.3"),
+            AppProtocol::Legacy {
+                network: Network::Bitcoin
+            }
+        );
+        assert_eq!(
+            AppProtocol::from_app("Ethereum", "1.10.4"),
+            AppProtocol::Current
+        );
+    }
+
+    #[test]
+    fn unlock_in_open_app_reports_app_info() {
+        let command = crate::common::Command::Unlock {
+            options: crate::common::UnlockOptions {
+                network: Some(Network::Bitcoin),
+            },
+        };
+        let mut interpreter = crate::common::LedgerInterpreter::default();
+        interpreter.start(command).unwrap();
+
+        // The legacy app knows the class but not the open app instruction.
+        let get_version = interpreter
+            .exchange(vec![0x6d, 0x00])
+            .unwrap()
+            .expect("app info should be requested");
+        assert_eq!(get_version.payload, vec![0xb0, 0x01, 0x00, 0x00, 0x00]);
+
+        let mut reply = vec![0x01, 0x07];
+        reply.extend_from_slice(b"Bitcoin");
+        reply.push(0x05);
+        reply.extend_from_slice(b"1.6.5");
+        reply.extend_from_slice(&[0x01, 0x00, 0x90, 0x00]);
+        assert!(interpreter.exchange(reply).unwrap().is_none());
+        match interpreter.end().unwrap() {
+            Response::Info(info) => {
+                assert_eq!(
+                    AppProtocol::from_app(&info.firmware.unwrap(), &info.version),
+                    AppProtocol::Legacy {
+                        network: Network::Bitcoin
+                    }
+                );
+            }
+            _ => panic!("expected app info"),
+        }
+    }
+
+    #[test]
+    fn legacy_protocol_uses_legacy_commands() {
+        let mut interpreter =
+            crate::common::LedgerInterpreter::default().with_protocol(AppProtocol::Legacy {
+                network: Network::Bitcoin,
+            });
+        let transmit = interpreter
+            .start(crate::common::Command::GetMasterFingerprint)
+            .unwrap();
+        assert_eq!(transmit.payload[..2], [0xe0, 0x40]);
     }
 }
@@ -439,6 +439,92 @@ pub enum PartialSignatureError {
     TapLeaf(bitcoin::hashes::FromSliceError),
 }
 
+/// Type: MuSig2 Public Nonce PSBT_IN_MUSIG2_PUB_NONCE = 0x1b
+pub const PSBT_IN_MUSIG2_PUB_NONCE: u8 = 0x1b;
+/// Type: MuSig2 Participant Partial Signature PSBT_IN_MUSIG2_PARTIAL_SIG = 0x1c
+pub const PSBT_IN_MUSIG2_PARTIAL_SIG: u8 = 0x1c;
+
+/// MuSig2 data yielded by the device for an input spending a `musig()` key.
+///
+/// rust-bitcoin does not expose the BIP-373 fields yet, so they are stored in
+/// `Input::unknown`, keyed by participant pubkey, aggregate pubkey and optional leaf hash.
+pub struct Musig2Yield {
+    pub participant_pubkey: secp256k1::PublicKey,
+    pub aggregate_pubkey: secp256k1::PublicKey,
+    pub tapleaf_hash: Option<TapLeafHash>,
+    pub value: Musig2Value,
+}
+
+pub enum Musig2Value {
+    /// stored under PSBT_IN_MUSIG2_PUB_NONCE
+    PubNonce([u8; 66]),
+    /// stored under PSBT_IN_MUSIG2_PARTIAL_SIG
+    PartialSig([u8; 32]),
+}
+
+impl Musig2Yield {
+    /// Parse a pubnonce yield: `pubnonce || participant || aggregate || [tapleaf_hash]`.
+    pub fn pub_nonce_from_slice(slice: &[u8]) -> Result<Self, Musig2YieldError> {
+        let (nonce, rest) = slice
+            .split_first_chunk::<66>()
+            .ok_or(Musig2YieldError::BadLength)?;
+        Self::with_keys(Musig2Value::PubNonce(*nonce), rest)
+    }
+
+    /// Parse a partial signature yield: `partial_sig || participant || aggregate || [tapleaf_hash]`.
+    pub fn partial_sig_from_slice(slice: &[u8]) -> Result<Self, Musig2YieldError> {
+        let (sig, rest) = slice
+            .split_first_chunk::<32>()
+            .ok_or(Musig2YieldError::BadLength)?;
+        Self::with_keys(Musig2Value::PartialSig(*sig), rest)
+    }
+
+    fn with_keys(value: Musig2Value, slice: &[u8]) -> Result<Self, Musig2YieldError> {
+        if slice.len() != 66 && slice.len() != 98 {
+            return Err(Musig2YieldError::BadLength);
+        }
+        let participant_pubkey =
+            secp256k1::PublicKey::from_slice(&slice[..33]).map_err(Musig2YieldError::PubKey)?;
+        let aggregate_pubkey =
+            secp256k1::PublicKey::from_slice(&slice[33..66]).map_err(Musig2YieldError::PubKey)?;
+        let tapleaf_hash = if slice.len() == 98 {
+            Some(TapLeafHash::from_slice(&slice[66..]).map_err(Musig2YieldError::TapLeaf)?)
+        } else {
+            None
+        };
+        Ok(Self {
+            participant_pubkey,
+            aggregate_pubkey,
+            tapleaf_hash,
+            value,
+        })
+    }
+
+    /// The BIP-373 key-value pair for this yield.
+    pub fn to_pair(&self) -> raw::Pair {
+        let mut key = self.participant_pubkey.serialize().to_vec();
+        key.extend_from_slice(&self.aggregate_pubkey.serialize());
+        if let Some(tapleaf_hash) = self.tapleaf_hash {
+            key.extend_from_slice(tapleaf_hash.as_byte_array());
+        }
+        let (type_value, value) = match self.value {
+            Musig2Value::PubNonce(nonce) => (PSBT_IN_MUSIG2_PUB_NONCE, nonce.to_vec()),
+            Musig2Value::PartialSig(sig) => (PSBT_IN_MUSIG2_PARTIAL_SIG, sig.to_vec()),
+        };
+        raw::Pair {
+            key: raw::Key { type_value, key },
+            value,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Musig2YieldError {
+    BadLength,
+    PubKey(secp256k1::Error),
+    TapLeaf(bitcoin::hashes::FromSliceError),
+}
+
 mod serialize {
     use core::convert::{TryFrom, TryInto};
 
@@ -112,6 +112,11 @@ impl DelegatedStore {
         }
     }
 
+    /// Results yielded so far.
+    pub fn peek_yielded(&self) -> &[Vec<u8>] {
+        &self.yielded
+    }
+
     /// Consumes the interpreter and returns the yielded results.
     pub fn yielded(self) -> Vec<Vec<u8>> {
         self.yielded
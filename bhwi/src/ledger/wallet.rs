@@ -36,7 +36,20 @@ pub enum AddressType {
 pub struct LedgerWalletPolicy {
     pub name: String,
     pub version: Version,
-    pub policy: WalletPolicy,
+    pub policy: LedgerPolicy,
+}
+
+/// Descriptor template and keys of a Ledger wallet policy.
+#[derive(Clone, Debug)]
+pub enum LedgerPolicy {
+    /// A BIP-388 policy parsed by miniscript.
+    Miniscript(WalletPolicy),
+    /// A taproot policy with `musig(...)` key expressions, which miniscript does not parse
+    /// yet. The template is kept verbatim and `keys` are its KEY_INFO entries in `@i` order.
+    Musig {
+        template: String,
+        keys: Vec<DescriptorPublicKey>,
+    },
 }
 
 /// Extracted wallet policy data needed for Ledger wire format serialization.
@@ -46,9 +59,13 @@ struct WalletPolicyParts {
 }
 
 impl WalletPolicyParts {
-    fn from_policy(policy: &WalletPolicy) -> Result<Self, WalletError> {
-        let (descriptor_template, keys) =
-            crate::policy::extract_parts(policy).map_err(WalletError::WalletPolicy)?;
+    fn from_policy(policy: &LedgerPolicy) -> Result<Self, WalletError> {
+        let (descriptor_template, keys) = match policy {
+            LedgerPolicy::Miniscript(policy) => {
+                crate::policy::extract_parts(policy).map_err(WalletError::WalletPolicy)?
+            }
+            LedgerPolicy::Musig { template, keys } => (template.clone(), keys.clone()),
+        };
         let key_strings: Vec<String> = keys.iter().map(crate::policy::format_key_info).collect();
         Ok(Self {
             descriptor_template,
@@ -62,7 +79,50 @@ impl LedgerWalletPolicy {
         Self {
             name,
             version,
-            policy,
+            policy: LedgerPolicy::Miniscript(policy),
+        }
+    }
+
+    /// Build a policy whose template uses `musig(...)` key expressions, e.g.
+    /// `tr(musig(@0,@1)/**)`. `keys` are the KEY_INFO entries in `@i` order.
+    ///
+    /// Only `tr()` templates may contain `musig()`, and every key must be referenced.
+    pub fn with_musig(
+        name: String,
+        version: Version,
+        template: &str,
+        keys: Vec<DescriptorPublicKey>,
+    ) -> Result<Self, WalletError> {
+        if version != Version::V2 || !template.starts_with("tr(") || !template.contains("musig(") {
+            return Err(WalletError::InvalidPolicy);
+        }
+        let placeholders = template_placeholders(template)?;
+        if (0..keys.len()).any(|i| !placeholders.contains(&i))
+            || placeholders.iter().any(|i| *i >= keys.len())
+        {
+            return Err(WalletError::InvalidPolicy);
+        }
+        if keys
+            .iter()
+            .any(|key| matches!(key, DescriptorPublicKey::Single(_)))
+        {
+            return Err(WalletError::InvalidPolicy);
+        }
+        Ok(Self {
+            name,
+            version,
+            policy: LedgerPolicy::Musig {
+                template: template.to_string(),
+                keys,
+            },
+        })
+    }
+
+    /// The miniscript policy, `None` for a `musig(...)` policy.
+    pub fn wallet_policy(&self) -> Option<&WalletPolicy> {
+        match &self.policy {
+            LedgerPolicy::Miniscript(policy) => Some(policy),
+            LedgerPolicy::Musig { .. } => None,
         }
     }
 
@@ -126,6 +186,18 @@ impl LedgerWalletPolicy {
     }
 }
 
+/// Indices of the `@i` key placeholders of a descriptor template.
+fn template_placeholders(template: &str) -> Result<Vec<usize>, WalletError> {
+    template
+        .split('@')
+        .skip(1)
+        .map(|rest| {
+            let digits: String = rest.chars().take_while(char::is_ascii_digit).collect();
+            digits.parse().map_err(|_| WalletError::InvalidPolicy)
+        })
+        .collect()
+}
+
 /// Construct a `WalletPolicy` from a single-sig derivation path, fingerprint, and xpub.
 ///
 /// This creates a standard BIP-44/49/84/86 wallet policy based on the purpose
@@ -217,6 +289,45 @@ mod tests {
         assert!(wallet.serialize().is_err());
     }
 
+    const MUSIG_KEYS: [&str; 2] = [
+        "[f5acc2fd/48'/1'/0'/2']tpubDCbK3Ysvk8HjcF6mPyrgMu3KgLiaaP19RjKpNezd8GrbAbNg6v5BtWLaCt8FNm6QkLseopKLf5MNYQFtochDTKHdfgG6iqJ8cqnLNAwtXuP",
+        "[00000000/48'/1'/0'/2']tpubDDtb2WPYwEWw2WWDV7reLV348iJHw2HmhzvPysKKrJw3hYmvrd4jasyoioVPdKGQqjyaBMEvTn1HvHWDSVqQ6amyyxRZ5YjpPBBGjJ8yu8S",
+    ];
+
+    fn musig_keys() -> Vec<DescriptorPublicKey> {
+        MUSIG_KEYS
+            .iter()
+            .map(|k| DescriptorPublicKey::from_str(k).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_musig_wallet_policy_serializes_keys_in_order() {
+        let wallet = LedgerWalletPolicy::with_musig(
+            "MuSig".to_string(),
+            Version::V2,
+            "tr(musig(@0,@1)/**)",
+            musig_keys(),
+        )
+        .unwrap();
+        let parts = WalletPolicyParts::from_policy(&wallet.policy).unwrap();
+        assert_eq!(parts.descriptor_template, "tr(musig(@0,@1)/**)");
+        assert_eq!(parts.key_strings, MUSIG_KEYS);
+        assert!(wallet.serialize().is_ok());
+        assert!(wallet.to_store().is_ok());
+    }
+
+    #[test]
+    fn test_musig_wallet_policy_rejects_invalid_templates() {
+        let musig = |template: &str, keys| {
+            LedgerWalletPolicy::with_musig("MuSig".to_string(), Version::V2, template, keys)
+        };
+        assert!(musig("wsh(musig(@0,@1)/**)", musig_keys()).is_err());
+        assert!(musig("tr(@0/**)", musig_keys()).is_err());
+        assert!(musig("tr(musig(@0,@2)/**)", musig_keys()).is_err());
+        assert!(musig("tr(musig(@0,@1)/**)", musig_keys()[..1].to_vec()).is_err());
+    }
+
     #[test]
     fn test_singlesig_wallet_policy_p2tr() {
         use core::str::FromStr;
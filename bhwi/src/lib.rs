@@ -1,6 +1,9 @@
 pub use bitcoin;
 pub use miniscript;
 
+#[cfg(any(feature = "bitbox", feature = "jade"))]
+pub mod antiexfil;
+pub mod bip322;
 #[cfg(feature = "bitbox")]
 pub mod bitbox;
 pub mod coldcard;
@@ -9,6 +12,10 @@ pub mod device;
 pub mod jade;
 pub mod ledger;
 pub mod policy;
+pub mod preview;
+pub mod psbt;
+
+use std::rc::Rc;
 
 pub trait Interpreter {
     type Command;
@@ -18,4 +25,27 @@ pub trait Interpreter {
     fn start(&mut self, command: Self::Command) -> Result<Self::Transmit, Self::Error>;
     fn exchange(&mut self, data: Vec<u8>) -> Result<Option<Self::Transmit>, Self::Error>;
     fn end(self) -> Result<Self::Response, Self::Error>;
+    /// Next event raised by `start` or `exchange`. Callers drain it after each call, the
+    /// events of a command are lost with its interpreter.
+    fn poll_event(&mut self) -> Option<common::Event> {
+        None
+    }
 }
+
+/// Receives the events of the running command: progress, prompts on the device, the
+/// BitBox02 pairing code. It runs between two exchanges, so it must not block.
+pub type EventListener = Rc<dyn Fn(&common::Event)>;
+
+/// Hand the events raised by the last call on `intpr` to `listener`, or drop them without
+/// one.
+pub fn forward_events<I: Interpreter>(listener: Option<&EventListener>, intpr: &mut I) {
+    while let Some(event) = intpr.poll_event() {
+        if let Some(listener) = listener {
+            listener(&event);
+        }
+    }
+}
+
+/// A device's own command, fed straight into its interpreter for the operations that have
+/// no place in the common command set.
+pub struct RawCommand<C>(pub C);
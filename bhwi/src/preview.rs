@@ -0,0 +1,415 @@
+//! Summary of what signing a PSBT spends and pays, to cross-check what a device displays.
+//!
+//! Outputs paying back to the wallet are change, everything else is a recipient. With a
+//! wallet policy an output is change when the policy derives its script at the index of its
+//! key origins; without one, when it pays to the single-sig script of a key whose origin is
+//! under an account that also signs the inputs.
+
+use std::collections::BTreeMap;
+
+use bitcoin::{
+    Amount, CompressedPublicKey, OutPoint, ScriptBuf, Sequence, TapLeafHash, TxOut, Weight,
+    absolute::LockTime,
+    bip32::{ChildNumber, DerivationPath, Fingerprint, KeySource},
+    psbt::{self, Psbt},
+    secp256k1::{PublicKey, Secp256k1, XOnlyPublicKey},
+};
+use miniscript::descriptor::{Descriptor, DescriptorPublicKey, WalletPolicy};
+
+use crate::common::Error;
+
+/// Signature and public key pushes of a P2WPKH witness.
+const P2WPKH_SATISFACTION_WEIGHT: u64 = (1 + 72) + (1 + 33);
+/// Single Schnorr signature of a taproot key path spend.
+const P2TR_KEY_SATISFACTION_WEIGHT: u64 = 1 + 64;
+/// P2WPKH redeem script push in the script sig, four weight units per byte.
+const P2SH_P2WPKH_SCRIPT_SIG_WEIGHT: u64 = (1 + 22) * 4;
+/// Signature and public key pushes of a P2PKH script sig.
+const P2PKH_SATISFACTION_WEIGHT: u64 = ((1 + 72) + (1 + 33)) * 4;
+/// Segwit marker and flag.
+const SEGWIT_HEADER_WEIGHT: u64 = 2;
+/// Item count of the witness of every input, once the transaction has a witness.
+const WITNESS_COUNT_WEIGHT: u64 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TxPreview {
+    pub inputs: Vec<SpentInput>,
+    pub recipients: Vec<PreviewOutput>,
+    pub change: Vec<PreviewOutput>,
+    /// `None` when the amount of an input is missing from the PSBT.
+    pub fee: Option<Amount>,
+    /// Virtual size of the signed transaction, `None` when the satisfaction of an input
+    /// cannot be estimated.
+    pub estimated_vsize: Option<u64>,
+    /// Lock time, when set and enabled by at least one input sequence.
+    pub locktime: Option<LockTime>,
+    /// Whether an input signals BIP-125 replaceability.
+    pub rbf: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpentInput {
+    pub outpoint: OutPoint,
+    pub amount: Option<Amount>,
+    pub sequence: Sequence,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PreviewOutput {
+    /// Position of the output in the transaction.
+    pub index: usize,
+    pub script_pubkey: ScriptBuf,
+    pub amount: Amount,
+}
+
+impl TxPreview {
+    pub fn new(psbt: &Psbt, policy: Option<&WalletPolicy>) -> Result<Self, Error> {
+        let descriptor = policy
+            .map(|policy| policy.clone().into_descriptor())
+            .transpose()
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        let branches = descriptor
+            .clone()
+            .map(Descriptor::into_single_descriptors)
+            .transpose()
+            .map_err(|e| Error::InvalidInput(e.to_string()))?;
+        let accounts: Vec<(Fingerprint, DerivationPath)> = psbt
+            .inputs
+            .iter()
+            .flat_map(|input| key_origins(&input.bip32_derivation, &input.tap_key_origins))
+            .map(|(fingerprint, path)| (fingerprint, account(&path)))
+            .collect();
+
+        let tx = &psbt.unsigned_tx;
+        let mut inputs = Vec::new();
+        let mut satisfaction = Some(Weight::ZERO);
+        let mut segwit = false;
+        for (index, (txin, input)) in tx.input.iter().zip(&psbt.inputs).enumerate() {
+            let utxo = psbt.spend_utxo(index).ok();
+            inputs.push(SpentInput {
+                outpoint: txin.previous_output,
+                amount: utxo.map(|utxo| utxo.value),
+                sequence: txin.sequence,
+            });
+            let weight = utxo.and_then(|utxo| {
+                let (weight, witness) = satisfaction_weight(input, utxo, descriptor.as_ref())?;
+                segwit |= witness;
+                Some(weight)
+            });
+            satisfaction = satisfaction
+                .zip(weight)
+                .map(|(total, weight)| total + weight);
+        }
+
+        let mut recipients = Vec::new();
+        let mut change = Vec::new();
+        for (index, (txout, output)) in tx.output.iter().zip(&psbt.outputs).enumerate() {
+            let preview = PreviewOutput {
+                index,
+                script_pubkey: txout.script_pubkey.clone(),
+                amount: txout.value,
+            };
+            let ours = match &branches {
+                Some(branches) => derived_by(branches, txout, output),
+                None => single_sig_origins(txout, output)
+                    .any(|(fingerprint, path)| accounts.contains(&(*fingerprint, account(path)))),
+            };
+            if ours {
+                change.push(preview);
+            } else {
+                recipients.push(preview);
+            }
+        }
+
+        let spent = inputs
+            .iter()
+            .map(|input| input.amount)
+            .sum::<Option<Amount>>();
+        let paid: Amount = tx.output.iter().map(|txout| txout.value).sum();
+        let fee = spent.and_then(|spent| spent.checked_sub(paid));
+        let estimated_vsize = satisfaction.map(|satisfaction| {
+            let header = if segwit {
+                Weight::from_wu(SEGWIT_HEADER_WEIGHT + WITNESS_COUNT_WEIGHT * tx.input.len() as u64)
+            } else {
+                Weight::ZERO
+            };
+            (tx.weight() + satisfaction + header).to_vbytes_ceil()
+        });
+
+        Ok(Self {
+            inputs,
+            recipients,
+            change,
+            fee,
+            estimated_vsize,
+            locktime: (tx.is_lock_time_enabled() && tx.lock_time != LockTime::ZERO)
+                .then_some(tx.lock_time),
+            rbf: tx.is_explicitly_rbf(),
+        })
+    }
+
+    /// Fee rate in sat/vB, estimated for the signed transaction.
+    pub fn fee_rate(&self) -> Option<f64> {
+        let fee = self.fee?;
+        let vsize = self.estimated_vsize?;
+        Some(fee.to_sat() as f64 / vsize as f64)
+    }
+}
+
+/// Key origins of the BIP-32 and taproot keys of an input or output.
+fn key_origins<'a>(
+    bip32: &'a BTreeMap<PublicKey, KeySource>,
+    tap: &'a BTreeMap<XOnlyPublicKey, (Vec<TapLeafHash>, KeySource)>,
+) -> impl Iterator<Item = KeySource> + 'a {
+    bip32
+        .values()
+        .chain(tap.values().map(|(_, origin)| origin))
+        .cloned()
+}
+
+/// Key origins of the output keys whose single-sig script is the output script, so that an
+/// origin cannot claim an output paying elsewhere.
+fn single_sig_origins<'a>(
+    txout: &'a TxOut,
+    output: &'a psbt::Output,
+) -> impl Iterator<Item = &'a KeySource> + 'a {
+    let secp = Secp256k1::verification_only();
+    let script = &txout.script_pubkey;
+    let bip32 = output
+        .bip32_derivation
+        .iter()
+        .filter(move |(key, _)| {
+            let key = CompressedPublicKey(**key);
+            let wpkh = ScriptBuf::new_p2wpkh(&key.wpubkey_hash());
+            *script == ScriptBuf::new_p2pkh(&key.pubkey_hash())
+                || *script == ScriptBuf::new_p2sh(&wpkh.script_hash())
+                || *script == wpkh
+        })
+        .map(|(_, origin)| origin);
+    let tap = output
+        .tap_key_origins
+        .iter()
+        .filter(move |(key, _)| *script == ScriptBuf::new_p2tr(&secp, **key, None))
+        .map(|(_, (_, origin))| origin);
+    bip32.chain(tap)
+}
+
+/// The leading hardened steps of a key path, identifying the account it belongs to.
+fn account(path: &DerivationPath) -> DerivationPath {
+    path.into_iter()
+        .take_while(|child| child.is_hardened())
+        .cloned()
+        .collect()
+}
+
+/// Whether a branch of the wallet derives the output script at the index of one of the
+/// output key origins.
+fn derived_by(
+    branches: &[Descriptor<DescriptorPublicKey>],
+    txout: &TxOut,
+    output: &psbt::Output,
+) -> bool {
+    key_origins(&output.bip32_derivation, &output.tap_key_origins)
+        .filter_map(|(_, path)| match path.into_iter().last() {
+            Some(ChildNumber::Normal { index }) => Some(*index),
+            _ => None,
+        })
+        .any(|index| {
+            branches.iter().any(|branch| {
+                branch
+                    .at_derivation_index(index)
+                    .is_ok_and(|derived| derived.script_pubkey() == txout.script_pubkey)
+            })
+        })
+}
+
+/// Weight the input gains once signed, and whether it is spent with a witness. The witness
+/// item count is left to the transaction, as `max_weight_to_satisfy` does.
+fn satisfaction_weight(
+    input: &psbt::Input,
+    utxo: &TxOut,
+    descriptor: Option<&Descriptor<DescriptorPublicKey>>,
+) -> Option<(Weight, bool)> {
+    if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+        let script_sig = input
+            .final_script_sig
+            .as_ref()
+            .map_or(0, |script| script.len() as u64);
+        let witness = input
+            .final_script_witness
+            .as_ref()
+            .map_or(0, |witness| witness.size() as u64 - WITNESS_COUNT_WEIGHT);
+        return Some((
+            Weight::from_wu(script_sig * 4 + witness),
+            input.final_script_witness.is_some(),
+        ));
+    }
+    if let Some(descriptor) = descriptor {
+        let weight = descriptor.max_weight_to_satisfy().ok()?;
+        return Some((weight, descriptor.desc_type().segwit_version().is_some()));
+    }
+    let script = &utxo.script_pubkey;
+    let (weight, witness) = if script.is_p2wpkh() {
+        (P2WPKH_SATISFACTION_WEIGHT, true)
+    } else if script.is_p2tr() {
+        (P2TR_KEY_SATISFACTION_WEIGHT, true)
+    } else if script.is_p2sh()
+        && input
+            .redeem_script
+            .as_ref()
+            .is_some_and(|script| script.is_p2wpkh())
+    {
+        (
+            P2SH_P2WPKH_SCRIPT_SIG_WEIGHT + P2WPKH_SATISFACTION_WEIGHT,
+            true,
+        )
+    } else if script.is_p2pkh() {
+        (P2PKH_SATISFACTION_WEIGHT, false)
+    } else {
+        return None;
+    };
+    Some((Weight::from_wu(weight), witness))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use bitcoin::bip32::{Xpriv, Xpub};
+    use bitcoin::secp256k1::Secp256k1;
+    use bitcoin::{Network, Transaction, TxIn, Witness, transaction};
+
+    use super::*;
+
+    struct Wallet {
+        master: Xpriv,
+    }
+
+    impl Wallet {
+        fn new() -> Self {
+            Wallet {
+                master: Xpriv::new_master(Network::Testnet, &[1; 32]).unwrap(),
+            }
+        }
+
+        fn key(&self, path: &str) -> (PublicKey, KeySource) {
+            let secp = Secp256k1::new();
+            let path = DerivationPath::from_str(path).unwrap();
+            let key = self.master.derive_priv(&secp, &path).unwrap();
+            (
+                key.private_key.public_key(&secp),
+                (self.master.fingerprint(&secp), path),
+            )
+        }
+
+        fn policy(&self) -> WalletPolicy {
+            let secp = Secp256k1::new();
+            let path = DerivationPath::from_str("m/84'/1'/0'").unwrap();
+            let xpub = Xpub::from_priv(&secp, &self.master.derive_priv(&secp, &path).unwrap());
+            let fingerprint = self.master.fingerprint(&secp);
+            WalletPolicy::from_str(&format!("wpkh([{fingerprint}/84'/1'/0']{xpub}/<0;1>/*)"))
+                .unwrap()
+        }
+    }
+
+    fn p2wpkh(key: &PublicKey) -> ScriptBuf {
+        ScriptBuf::new_p2wpkh(&CompressedPublicKey(*key).wpubkey_hash())
+    }
+
+    /// A PSBT spending a coin of `wallet` to an external P2WSH output and a change output.
+    fn psbt(wallet: &Wallet, change_path: &str) -> Psbt {
+        let (input_key, input_origin) = wallet.key("m/84'/1'/0'/0/0");
+        let (change_key, change_origin) = wallet.key(change_path);
+        let tx = Transaction {
+            version: transaction::Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: ScriptBuf::new(),
+                sequence: Sequence::ENABLE_RBF_NO_LOCKTIME,
+                witness: Witness::new(),
+            }],
+            output: vec![
+                TxOut {
+                    value: Amount::from_sat(60_000),
+                    script_pubkey: ScriptBuf::new_p2wsh(&ScriptBuf::new().wscript_hash()),
+                },
+                TxOut {
+                    value: Amount::from_sat(39_000),
+                    script_pubkey: p2wpkh(&change_key),
+                },
+            ],
+        };
+        let mut psbt = Psbt::from_unsigned_tx(tx).unwrap();
+        psbt.inputs[0].witness_utxo = Some(TxOut {
+            value: Amount::from_sat(100_000),
+            script_pubkey: p2wpkh(&input_key),
+        });
+        psbt.inputs[0]
+            .bip32_derivation
+            .insert(input_key, input_origin);
+        psbt.outputs[1]
+            .bip32_derivation
+            .insert(change_key, change_origin);
+        psbt
+    }
+
+    #[test]
+    fn splits_recipients_from_change_and_computes_the_fee() {
+        let wallet = Wallet::new();
+        let preview = TxPreview::new(&psbt(&wallet, "m/84'/1'/0'/1/3"), None).unwrap();
+
+        assert_eq!(preview.recipients.len(), 1);
+        assert_eq!(preview.recipients[0].index, 0);
+        assert_eq!(preview.change.len(), 1);
+        assert_eq!(preview.change[0].index, 1);
+        assert_eq!(preview.fee, Some(Amount::from_sat(1_000)));
+        // 125 bytes of unsigned transaction, a P2WPKH witness and the segwit overhead.
+        assert_eq!(preview.estimated_vsize, Some(153));
+        assert!(preview.rbf);
+        assert_eq!(preview.locktime, None);
+
+        // An output under another account is not change.
+        let preview = TxPreview::new(&psbt(&wallet, "m/84'/1'/1'/1/3"), None).unwrap();
+        assert_eq!(preview.recipients.len(), 2);
+        assert!(preview.change.is_empty());
+    }
+
+    #[test]
+    fn spoofed_origin_is_not_change() {
+        let wallet = Wallet::new();
+        // Right account, but the output pays to a script the claimed key does not have.
+        let mut psbt = psbt(&wallet, "m/84'/1'/0'/1/3");
+        psbt.unsigned_tx.output[1].script_pubkey =
+            ScriptBuf::new_p2wsh(&ScriptBuf::new().wscript_hash());
+        let preview = TxPreview::new(&psbt, None).unwrap();
+        assert_eq!(preview.recipients.len(), 2);
+        assert!(preview.change.is_empty());
+    }
+
+    #[test]
+    fn policy_must_derive_the_change_script() {
+        let wallet = Wallet::new();
+        let policy = wallet.policy();
+        let preview = TxPreview::new(&psbt(&wallet, "m/84'/1'/0'/1/3"), Some(&policy)).unwrap();
+        assert_eq!(preview.change.len(), 1);
+        assert_eq!(preview.estimated_vsize, Some(153));
+
+        // Right account, but the output script is not the one the origin claims.
+        let mut psbt = psbt(&wallet, "m/84'/1'/0'/1/3");
+        psbt.unsigned_tx.output[1].script_pubkey = p2wpkh(&wallet.key("m/84'/1'/0'/1/4").0);
+        let preview = TxPreview::new(&psbt, Some(&policy)).unwrap();
+        assert!(preview.change.is_empty());
+    }
+
+    #[test]
+    fn unknown_input_amount_leaves_the_fee_unknown() {
+        let wallet = Wallet::new();
+        let mut psbt = psbt(&wallet, "m/84'/1'/0'/1/3");
+        psbt.inputs[0].witness_utxo = None;
+        let preview = TxPreview::new(&psbt, None).unwrap();
+        assert_eq!(preview.inputs[0].amount, None);
+        assert_eq!(preview.fee, None);
+        assert_eq!(preview.fee_rate(), None);
+    }
+}
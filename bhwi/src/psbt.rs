@@ -0,0 +1,680 @@
+//! BIP-370 version 2 PSBTs.
+//!
+//! Devices sign the version 0 data model of `bitcoin::psbt::Psbt`, which is what
+//! `Command::SignTx` carries. A [`VersionedPsbt`] is what callers hand over: a version 0
+//! PSBT, or a version 2 PSBT lowered to version 0 by building the unsigned transaction from
+//! its per-input and per-output fields. The version 2 fields with no version 0 counterpart
+//! are kept aside and put back when the signed PSBT is serialized, so callers get their
+//! PSBT back in the version they sent.
+
+use std::{fmt, str::FromStr};
+
+use base64ct::{Base64, Encoding};
+use bitcoin::{
+    Amount, OutPoint, ScriptBuf, Sequence, Transaction, TxIn, TxOut, Txid, Witness,
+    absolute::{LOCK_TIME_THRESHOLD, LockTime},
+    consensus::encode::{VarInt, deserialize, deserialize_partial, serialize},
+    psbt::Psbt,
+    transaction::Version,
+};
+
+use crate::common::Error;
+
+const MAGIC: &[u8] = b"psbt\xff";
+
+const PSBT_GLOBAL_UNSIGNED_TX: u8 = 0x00;
+const PSBT_GLOBAL_TX_VERSION: u8 = 0x02;
+const PSBT_GLOBAL_FALLBACK_LOCKTIME: u8 = 0x03;
+const PSBT_GLOBAL_INPUT_COUNT: u8 = 0x04;
+const PSBT_GLOBAL_OUTPUT_COUNT: u8 = 0x05;
+const PSBT_GLOBAL_TX_MODIFIABLE: u8 = 0x06;
+const PSBT_GLOBAL_VERSION: u8 = 0xfb;
+
+const PSBT_IN_PREVIOUS_TXID: u8 = 0x0e;
+const PSBT_IN_OUTPUT_INDEX: u8 = 0x0f;
+const PSBT_IN_SEQUENCE: u8 = 0x10;
+const PSBT_IN_REQUIRED_TIME_LOCKTIME: u8 = 0x11;
+const PSBT_IN_REQUIRED_HEIGHT_LOCKTIME: u8 = 0x12;
+
+const PSBT_OUT_AMOUNT: u8 = 0x03;
+const PSBT_OUT_SCRIPT: u8 = 0x04;
+
+const INPUTS_MODIFIABLE: u8 = 0x01;
+const OUTPUTS_MODIFIABLE: u8 = 0x02;
+const HAS_SIGHASH_SINGLE: u8 = 0x04;
+
+/// A PSBT in the version the caller sent it, 0 or 2.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VersionedPsbt {
+    psbt: Psbt,
+    v2: Option<V2Fields>,
+}
+
+/// Version 2 fields that the version 0 data model cannot hold.
+#[derive(Clone, Debug, PartialEq)]
+struct V2Fields {
+    fallback_locktime: Option<LockTime>,
+    tx_modifiable: Option<u8>,
+    /// Required time and height lock times of each input.
+    required_locktimes: Vec<(Option<u32>, Option<u32>)>,
+}
+
+impl VersionedPsbt {
+    pub fn deserialize(bytes: &[u8]) -> Result<Self, Error> {
+        let (global, _) = read_map(
+            bytes
+                .strip_prefix(MAGIC)
+                .ok_or_else(|| invalid("missing PSBT magic"))?,
+        )?;
+        match find(&global, PSBT_GLOBAL_VERSION) {
+            None => Ok(Self::from(
+                Psbt::deserialize(bytes).map_err(|e| Error::Serialization(e.to_string()))?,
+            )),
+            Some(version) => match read_u32(version)? {
+                0 => Ok(Self::from(
+                    Psbt::deserialize(bytes).map_err(|e| Error::Serialization(e.to_string()))?,
+                )),
+                2 => Self::deserialize_v2(&bytes[MAGIC.len()..]),
+                version => Err(invalid(&format!("unsupported PSBT version {version}"))),
+            },
+        }
+    }
+
+    fn deserialize_v2(bytes: &[u8]) -> Result<Self, Error> {
+        let (mut global, mut rest) = read_map(bytes)?;
+        let input_count = read_varint(required(&global, PSBT_GLOBAL_INPUT_COUNT)?)?;
+        let output_count = read_varint(required(&global, PSBT_GLOBAL_OUTPUT_COUNT)?)?;
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for _ in 0..input_count {
+            let (map, remaining) = read_map(rest)?;
+            inputs.push(map);
+            rest = remaining;
+        }
+        for _ in 0..output_count {
+            let (map, remaining) = read_map(rest)?;
+            outputs.push(map);
+            rest = remaining;
+        }
+        if !rest.is_empty() {
+            return Err(invalid("trailing bytes after the last output"));
+        }
+
+        let version = Version(read_u32(required(&global, PSBT_GLOBAL_TX_VERSION)?)? as i32);
+        let fallback_locktime = find(&global, PSBT_GLOBAL_FALLBACK_LOCKTIME)
+            .map(|value| read_u32(value).map(LockTime::from_consensus))
+            .transpose()?;
+        let tx_modifiable = find(&global, PSBT_GLOBAL_TX_MODIFIABLE)
+            .map(|value| match value {
+                [flags] => Ok(*flags),
+                _ => Err(invalid("invalid modifiable flags")),
+            })
+            .transpose()?;
+
+        let mut tx_inputs = Vec::new();
+        let mut required_locktimes = Vec::new();
+        for input in &inputs {
+            let txid: Txid = deserialize(required(input, PSBT_IN_PREVIOUS_TXID)?)
+                .map_err(|_| invalid("invalid previous txid"))?;
+            let vout = read_u32(required(input, PSBT_IN_OUTPUT_INDEX)?)?;
+            let sequence = find(input, PSBT_IN_SEQUENCE)
+                .map(read_u32)
+                .transpose()?
+                .map_or(Sequence::MAX, Sequence);
+            tx_inputs.push(TxIn {
+                previous_output: OutPoint { txid, vout },
+                script_sig: ScriptBuf::new(),
+                sequence,
+                witness: Witness::new(),
+            });
+            let time = find(input, PSBT_IN_REQUIRED_TIME_LOCKTIME)
+                .map(read_u32)
+                .transpose()?;
+            if time.is_some_and(|time| time < LOCK_TIME_THRESHOLD) {
+                return Err(invalid("required time lock time is a height"));
+            }
+            let height = find(input, PSBT_IN_REQUIRED_HEIGHT_LOCKTIME)
+                .map(read_u32)
+                .transpose()?;
+            if height.is_some_and(|height| height >= LOCK_TIME_THRESHOLD) {
+                return Err(invalid("required height lock time is a time"));
+            }
+            required_locktimes.push((time, height));
+        }
+        let mut tx_outputs = Vec::new();
+        for output in &outputs {
+            let amount: [u8; 8] = required(output, PSBT_OUT_AMOUNT)?
+                .try_into()
+                .map_err(|_| invalid("invalid output amount"))?;
+            tx_outputs.push(TxOut {
+                value: Amount::from_sat(u64::from_le_bytes(amount)),
+                script_pubkey: ScriptBuf::from_bytes(required(output, PSBT_OUT_SCRIPT)?.to_vec()),
+            });
+        }
+
+        let v2 = V2Fields {
+            fallback_locktime,
+            tx_modifiable,
+            required_locktimes,
+        };
+        let unsigned_tx = Transaction {
+            version,
+            lock_time: v2.lock_time()?,
+            input: tx_inputs,
+            output: tx_outputs,
+        };
+
+        global.retain(|(key, _)| {
+            !matches!(
+                key_type(key),
+                PSBT_GLOBAL_TX_VERSION
+                    | PSBT_GLOBAL_FALLBACK_LOCKTIME
+                    | PSBT_GLOBAL_INPUT_COUNT
+                    | PSBT_GLOBAL_OUTPUT_COUNT
+                    | PSBT_GLOBAL_TX_MODIFIABLE
+                    | PSBT_GLOBAL_VERSION
+            )
+        });
+        global.push((vec![PSBT_GLOBAL_UNSIGNED_TX], serialize(&unsigned_tx)));
+        for input in &mut inputs {
+            input.retain(|(key, _)| {
+                !matches!(
+                    key_type(key),
+                    PSBT_IN_PREVIOUS_TXID
+                        | PSBT_IN_OUTPUT_INDEX
+                        | PSBT_IN_SEQUENCE
+                        | PSBT_IN_REQUIRED_TIME_LOCKTIME
+                        | PSBT_IN_REQUIRED_HEIGHT_LOCKTIME
+                )
+            });
+        }
+        for output in &mut outputs {
+            output.retain(|(key, _)| !matches!(key_type(key), PSBT_OUT_AMOUNT | PSBT_OUT_SCRIPT));
+        }
+
+        let v0 = write_maps(
+            std::iter::once(&global)
+                .chain(&inputs)
+                .chain(&outputs)
+                .cloned(),
+        );
+        let psbt = Psbt::deserialize(&v0).map_err(|e| Error::Serialization(e.to_string()))?;
+        Ok(Self { psbt, v2: Some(v2) })
+    }
+
+    /// Serialize the PSBT in its version. The version 2 maps are rebuilt from the version 0
+    /// serialization, which holds one map per input and output of the unsigned transaction
+    /// as `deserialize` and `with_psbt` ensure, so reading it back cannot fail.
+    pub fn serialize(&self) -> Vec<u8> {
+        let Some(v2) = &self.v2 else {
+            return self.psbt.serialize();
+        };
+        let tx = &self.psbt.unsigned_tx;
+        let v0 = self.psbt.serialize();
+        let (mut global, mut rest) = read_map(&v0[MAGIC.len()..]).expect("serialized global map");
+        global.retain(|(key, _)| key_type(key) != PSBT_GLOBAL_UNSIGNED_TX);
+        global.push((
+            vec![PSBT_GLOBAL_TX_VERSION],
+            tx.version.0.to_le_bytes().to_vec(),
+        ));
+        if let Some(locktime) = v2.fallback_locktime {
+            global.push((vec![PSBT_GLOBAL_FALLBACK_LOCKTIME], serialize(&locktime)));
+        }
+        global.push((
+            vec![PSBT_GLOBAL_INPUT_COUNT],
+            serialize(&VarInt(tx.input.len() as u64)),
+        ));
+        global.push((
+            vec![PSBT_GLOBAL_OUTPUT_COUNT],
+            serialize(&VarInt(tx.output.len() as u64)),
+        ));
+        if let Some(flags) = tx_modifiable(&self.psbt, v2.tx_modifiable) {
+            global.push((vec![PSBT_GLOBAL_TX_MODIFIABLE], vec![flags]));
+        }
+        global.push((vec![PSBT_GLOBAL_VERSION], 2u32.to_le_bytes().to_vec()));
+
+        let mut maps = vec![global];
+        for (txin, (time, height)) in tx.input.iter().zip(&v2.required_locktimes) {
+            let (mut input, remaining) = read_map(rest).expect("serialized input map");
+            rest = remaining;
+            input.push((
+                vec![PSBT_IN_PREVIOUS_TXID],
+                serialize(&txin.previous_output.txid),
+            ));
+            input.push((
+                vec![PSBT_IN_OUTPUT_INDEX],
+                txin.previous_output.vout.to_le_bytes().to_vec(),
+            ));
+            input.push((vec![PSBT_IN_SEQUENCE], serialize(&txin.sequence)));
+            if let Some(time) = time {
+                input.push((
+                    vec![PSBT_IN_REQUIRED_TIME_LOCKTIME],
+                    time.to_le_bytes().to_vec(),
+                ));
+            }
+            if let Some(height) = height {
+                input.push((
+                    vec![PSBT_IN_REQUIRED_HEIGHT_LOCKTIME],
+                    height.to_le_bytes().to_vec(),
+                ));
+            }
+            maps.push(input);
+        }
+        for txout in &tx.output {
+            let (mut output, remaining) = read_map(rest).expect("serialized output map");
+            rest = remaining;
+            output.push((
+                vec![PSBT_OUT_AMOUNT],
+                txout.value.to_sat().to_le_bytes().to_vec(),
+            ));
+            output.push((vec![PSBT_OUT_SCRIPT], txout.script_pubkey.to_bytes()));
+            maps.push(output);
+        }
+        write_maps(maps)
+    }
+
+    /// BIP-174 version number: 0 or 2.
+    pub fn version(&self) -> u32 {
+        if self.v2.is_some() { 2 } else { 0 }
+    }
+
+    /// The PSBT in the version 0 data model devices sign.
+    pub fn psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+
+    pub fn into_psbt(self) -> Psbt {
+        self.psbt
+    }
+
+    /// Replace the PSBT with `psbt`, typically the one signed by a device, keeping the
+    /// version and the version 2 fields of `self`. Fails when `psbt` has other inputs than
+    /// those fields describe, or maps that do not match its unsigned transaction.
+    pub fn with_psbt(self, psbt: Psbt) -> Result<Self, Error> {
+        if let Some(v2) = &self.v2 {
+            if v2.required_locktimes.len() != psbt.inputs.len()
+                || psbt.inputs.len() != psbt.unsigned_tx.input.len()
+            {
+                return Err(invalid("signed PSBT inputs differ from the original ones"));
+            }
+            if psbt.outputs.len() != psbt.unsigned_tx.output.len() {
+                return Err(invalid("signed PSBT outputs differ from the original ones"));
+            }
+        }
+        Ok(Self { psbt, ..self })
+    }
+}
+
+impl V2Fields {
+    /// BIP-370 lock time determination: the greatest required lock time of the kind every
+    /// constrained input accepts, height when both are, or the fallback lock time.
+    fn lock_time(&self) -> Result<LockTime, Error> {
+        let constrained: Vec<_> = self
+            .required_locktimes
+            .iter()
+            .filter(|(time, height)| time.is_some() || height.is_some())
+            .collect();
+        if constrained.is_empty() {
+            return Ok(self.fallback_locktime.unwrap_or(LockTime::ZERO));
+        }
+        if constrained.iter().all(|(_, height)| height.is_some()) {
+            let height = constrained.iter().filter_map(|(_, h)| *h).max();
+            return Ok(LockTime::from_consensus(height.unwrap_or_default()));
+        }
+        if constrained.iter().all(|(time, _)| time.is_some()) {
+            let time = constrained.iter().filter_map(|(t, _)| *t).max();
+            return Ok(LockTime::from_consensus(time.unwrap_or_default()));
+        }
+        Err(invalid("inputs require incompatible lock times"))
+    }
+}
+
+/// BIP-370 modifiable flags of `psbt` once signed: a signature commits to all the inputs
+/// unless it is ANYONECANPAY, to all the outputs unless it is SIGHASH_NONE, and a
+/// SIGHASH_SINGLE signature pins its output to its input index.
+fn tx_modifiable(psbt: &Psbt, flags: Option<u8>) -> Option<u8> {
+    let sighashes: Vec<u32> = psbt
+        .inputs
+        .iter()
+        .flat_map(|input| {
+            input
+                .partial_sigs
+                .values()
+                .map(|signature| signature.sighash_type.to_u32())
+                .chain(
+                    input
+                        .tap_key_sig
+                        .map(|signature| signature.sighash_type as u32),
+                )
+                .chain(
+                    input
+                        .tap_script_sigs
+                        .values()
+                        .map(|signature| signature.sighash_type as u32),
+                )
+        })
+        .collect();
+    if sighashes.is_empty() {
+        return flags;
+    }
+    let mut modifiable = flags.unwrap_or(0);
+    for sighash in sighashes {
+        if sighash & 0x80 == 0 {
+            modifiable &= !INPUTS_MODIFIABLE;
+        }
+        match sighash & 0x7f {
+            0x02 => {}
+            0x03 => {
+                modifiable &= !OUTPUTS_MODIFIABLE;
+                modifiable |= HAS_SIGHASH_SINGLE;
+            }
+            _ => modifiable &= !OUTPUTS_MODIFIABLE,
+        }
+    }
+    // An absent field already reads as nothing modifiable.
+    (flags.is_some() || modifiable != 0).then_some(modifiable)
+}
+
+impl From<Psbt> for VersionedPsbt {
+    fn from(psbt: Psbt) -> Self {
+        Self { psbt, v2: None }
+    }
+}
+
+impl FromStr for VersionedPsbt {
+    type Err = Error;
+
+    /// Parse a base64 PSBT.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = Base64::decode_vec(s).map_err(|e| Error::Serialization(e.to_string()))?;
+        Self::deserialize(&bytes)
+    }
+}
+
+impl fmt::Display for VersionedPsbt {
+    /// Base64 encoding of the PSBT, in its version.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&Base64::encode_string(&self.serialize()))
+    }
+}
+
+/// Raw key (type byte and key data) and value of a map entry.
+type Pair = (Vec<u8>, Vec<u8>);
+
+fn invalid(reason: &str) -> Error {
+    Error::Serialization(format!("invalid PSBT: {reason}"))
+}
+
+fn key_type(key: &[u8]) -> u8 {
+    key[0]
+}
+
+fn find(map: &[Pair], type_value: u8) -> Option<&[u8]> {
+    map.iter()
+        .find(|(key, _)| key.as_slice() == [type_value])
+        .map(|(_, value)| value.as_slice())
+}
+
+fn required(map: &[Pair], type_value: u8) -> Result<&[u8], Error> {
+    find(map, type_value).ok_or_else(|| invalid(&format!("missing field {type_value:#04x}")))
+}
+
+fn read_u32(value: &[u8]) -> Result<u32, Error> {
+    value
+        .try_into()
+        .map(u32::from_le_bytes)
+        .map_err(|_| invalid("invalid 32-bit field"))
+}
+
+fn read_varint(value: &[u8]) -> Result<u64, Error> {
+    deserialize::<VarInt>(value)
+        .map(|count| count.0)
+        .map_err(|_| invalid("invalid count"))
+}
+
+/// Read one map up to its separator, returning it and the bytes following it.
+fn read_map(mut bytes: &[u8]) -> Result<(Vec<Pair>, &[u8]), Error> {
+    let mut map = Vec::new();
+    loop {
+        let (key, rest) = read_bytes(bytes)?;
+        if key.is_empty() {
+            return Ok((map, rest));
+        }
+        let (value, rest) = read_bytes(rest)?;
+        map.push((key.to_vec(), value.to_vec()));
+        bytes = rest;
+    }
+}
+
+/// Split a length prefixed byte string from the bytes following it.
+fn read_bytes(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let (len, consumed) =
+        deserialize_partial::<VarInt>(bytes).map_err(|_| invalid("truncated map"))?;
+    let rest = &bytes[consumed..];
+    if (rest.len() as u64) < len.0 {
+        return Err(invalid("truncated map"));
+    }
+    Ok(rest.split_at(len.0 as usize))
+}
+
+/// Serialize maps behind the magic, with the entries of each map sorted by key.
+fn write_maps(maps: impl IntoIterator<Item = Vec<Pair>>) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    for mut map in maps {
+        map.sort();
+        for (key, value) in map {
+            bytes.extend(serialize(&key));
+            bytes.extend(serialize(&value));
+        }
+        bytes.push(0x00);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        EcdsaSighashType, PublicKey, ecdsa,
+        hashes::Hash,
+        secp256k1::{Message, Secp256k1, SecretKey},
+    };
+
+    fn pair(key: &[u8], value: &[u8]) -> Pair {
+        (key.to_vec(), value.to_vec())
+    }
+
+    fn script() -> ScriptBuf {
+        ScriptBuf::new_p2wsh(&ScriptBuf::new().wscript_hash())
+    }
+
+    /// A two input, one output version 2 PSBT with a required height lock time on the
+    /// first input, a proprietary global field and an unknown input field.
+    fn v2_bytes(required_height: u32) -> Vec<u8> {
+        let txid = Txid::from_byte_array([1; 32]);
+        let global = vec![
+            pair(&[PSBT_GLOBAL_TX_VERSION], &2u32.to_le_bytes()),
+            pair(&[PSBT_GLOBAL_FALLBACK_LOCKTIME], &500u32.to_le_bytes()),
+            pair(&[PSBT_GLOBAL_INPUT_COUNT], &[2]),
+            pair(&[PSBT_GLOBAL_OUTPUT_COUNT], &[1]),
+            pair(&[PSBT_GLOBAL_TX_MODIFIABLE], &[0b11]),
+            pair(&[PSBT_GLOBAL_VERSION], &2u32.to_le_bytes()),
+            pair(b"\xfc\x04bhwi\x01", b"global"),
+        ];
+        let first = vec![
+            pair(&[PSBT_IN_PREVIOUS_TXID], &serialize(&txid)),
+            pair(&[PSBT_IN_OUTPUT_INDEX], &0u32.to_le_bytes()),
+            pair(&[PSBT_IN_SEQUENCE], &0xfffffffdu32.to_le_bytes()),
+            pair(
+                &[PSBT_IN_REQUIRED_HEIGHT_LOCKTIME],
+                &required_height.to_le_bytes(),
+            ),
+            pair(&[0x42], b"unknown"),
+        ];
+        let second = vec![
+            pair(&[PSBT_IN_PREVIOUS_TXID], &serialize(&txid)),
+            pair(&[PSBT_IN_OUTPUT_INDEX], &1u32.to_le_bytes()),
+            pair(&[PSBT_IN_SEQUENCE], &0xffffffffu32.to_le_bytes()),
+        ];
+        let output = vec![
+            pair(&[PSBT_OUT_AMOUNT], &1000u64.to_le_bytes()),
+            pair(&[PSBT_OUT_SCRIPT], script().as_bytes()),
+        ];
+        write_maps([global, first, second, output])
+    }
+
+    #[test]
+    fn v2_is_lowered_to_the_unsigned_transaction() {
+        let psbt = VersionedPsbt::deserialize(&v2_bytes(800_000)).unwrap();
+        assert_eq!(psbt.version(), 2);
+        let tx = &psbt.psbt().unsigned_tx;
+        assert_eq!(tx.version, Version::TWO);
+        assert_eq!(tx.lock_time, LockTime::from_consensus(800_000));
+        assert_eq!(tx.input.len(), 2);
+        assert_eq!(tx.input[0].sequence, Sequence(0xfffffffd));
+        assert_eq!(tx.input[1].previous_output.vout, 1);
+        assert_eq!(tx.output[0].value, Amount::from_sat(1000));
+        assert_eq!(tx.output[0].script_pubkey, script());
+        assert_eq!(psbt.psbt().inputs[0].unknown.len(), 1);
+        assert_eq!(psbt.psbt().proprietary.len(), 1);
+    }
+
+    #[test]
+    fn v2_round_trips_with_device_changes() {
+        let bytes = v2_bytes(800_000);
+        let psbt = VersionedPsbt::deserialize(&bytes).unwrap();
+        assert_eq!(psbt.serialize(), bytes);
+
+        let mut signed = psbt.psbt().clone();
+        signed.inputs[1].final_script_sig = Some(ScriptBuf::from_bytes(vec![0x51]));
+        let signed = psbt.with_psbt(signed).unwrap();
+        let reparsed = VersionedPsbt::from_str(&signed.to_string()).unwrap();
+        assert_eq!(reparsed, signed);
+        assert_eq!(
+            reparsed.psbt().inputs[1].final_script_sig,
+            Some(ScriptBuf::from_bytes(vec![0x51]))
+        );
+    }
+
+    #[test]
+    fn signatures_update_the_modifiable_flags() {
+        let secp = Secp256k1::new();
+        let secret_key = SecretKey::from_slice(&[1; 32]).unwrap();
+        let pubkey = PublicKey::new(secret_key.public_key(&secp));
+        let sign = |sighash_type| ecdsa::Signature {
+            signature: secp.sign_ecdsa(&Message::from_digest([2; 32]), &secret_key),
+            sighash_type,
+        };
+        let psbt = VersionedPsbt::deserialize(&v2_bytes(800_000)).unwrap();
+        let flags = |signed: &VersionedPsbt| {
+            let bytes = signed.serialize();
+            let (global, _) = read_map(&bytes[MAGIC.len()..]).unwrap();
+            find(&global, PSBT_GLOBAL_TX_MODIFIABLE).map(<[u8]>::to_vec)
+        };
+
+        let mut signed = psbt.psbt().clone();
+        signed.inputs[0]
+            .partial_sigs
+            .insert(pubkey, sign(EcdsaSighashType::All));
+        let signed = psbt.clone().with_psbt(signed).unwrap();
+        assert_eq!(flags(&signed), Some(vec![0b000]));
+        let reparsed = VersionedPsbt::deserialize(&signed.serialize()).unwrap();
+        assert_eq!(
+            reparsed.psbt().inputs[0].partial_sigs[&pubkey],
+            sign(EcdsaSighashType::All)
+        );
+        assert_eq!(reparsed.serialize(), signed.serialize());
+
+        let mut signed = psbt.psbt().clone();
+        signed.inputs[0]
+            .partial_sigs
+            .insert(pubkey, sign(EcdsaSighashType::SinglePlusAnyoneCanPay));
+        let signed = psbt.clone().with_psbt(signed).unwrap();
+        assert_eq!(flags(&signed), Some(vec![0b101]));
+
+        let mut signed = psbt.psbt().clone();
+        signed.inputs[1]
+            .partial_sigs
+            .insert(pubkey, sign(EcdsaSighashType::NonePlusAnyoneCanPay));
+        let signed = psbt.with_psbt(signed).unwrap();
+        assert_eq!(flags(&signed), Some(vec![0b011]));
+    }
+
+    #[test]
+    fn signed_psbt_must_keep_the_inputs() {
+        let psbt = VersionedPsbt::deserialize(&v2_bytes(800_000)).unwrap();
+        let mut signed = psbt.psbt().clone();
+        signed.inputs.pop();
+        signed.unsigned_tx.input.pop();
+        assert!(psbt.clone().with_psbt(signed).is_err());
+
+        let mut signed = psbt.psbt().clone();
+        signed.outputs.pop();
+        assert!(psbt.with_psbt(signed).is_err());
+    }
+
+    #[test]
+    fn v0_is_kept_as_is() {
+        let tx = Transaction {
+            version: Version::TWO,
+            lock_time: LockTime::ZERO,
+            input: vec![TxIn::default()],
+            output: vec![TxOut {
+                value: Amount::from_sat(1000),
+                script_pubkey: script(),
+            }],
+        };
+        let v0 = Psbt::from_unsigned_tx(tx).unwrap();
+        let psbt = VersionedPsbt::deserialize(&v0.serialize()).unwrap();
+        assert_eq!(psbt.version(), 0);
+        assert_eq!(psbt.serialize(), v0.serialize());
+        assert_eq!(psbt.to_string(), v0.to_string());
+    }
+
+    #[test]
+    fn lock_time_follows_bip370() {
+        let fields = |fallback: Option<u32>, required: Vec<(Option<u32>, Option<u32>)>| {
+            V2Fields {
+                fallback_locktime: fallback.map(LockTime::from_consensus),
+                tx_modifiable: None,
+                required_locktimes: required,
+            }
+            .lock_time()
+        };
+        assert_eq!(fields(None, vec![(None, None)]).unwrap(), LockTime::ZERO);
+        assert_eq!(
+            fields(Some(500), vec![(None, None)]).unwrap(),
+            LockTime::from_consensus(500)
+        );
+        assert_eq!(
+            fields(
+                Some(500),
+                vec![(Some(1_700_000_000), Some(10)), (None, Some(20))]
+            )
+            .unwrap(),
+            LockTime::from_consensus(20)
+        );
+        assert_eq!(
+            fields(
+                None,
+                vec![(Some(1_700_000_000), None), (Some(1_800_000_000), Some(5))]
+            )
+            .unwrap(),
+            LockTime::from_consensus(1_800_000_000)
+        );
+        assert!(fields(None, vec![(Some(1_700_000_000), None), (None, Some(5))]).is_err());
+    }
+
+    #[test]
+    fn malformed_v2_is_rejected() {
+        let mut bytes = v2_bytes(800_000);
+        bytes.truncate(bytes.len() - 10);
+        assert!(VersionedPsbt::deserialize(&bytes).is_err());
+        let mut bytes = v2_bytes(800_000);
+        bytes.push(0);
+        assert!(VersionedPsbt::deserialize(&bytes).is_err());
+        assert!(VersionedPsbt::deserialize(&v2_bytes(LOCK_TIME_THRESHOLD)).is_err());
+        assert!(VersionedPsbt::deserialize(b"psbt\xff\x02\xfb").is_err());
+        assert!(VersionedPsbt::deserialize(b"not a psbt").is_err());
+    }
+}
@@ -1897,7 +1897,9 @@ mod tests {
 
     fn set_ledger_automation(registers_wallet: bool) -> Result<()> {
         let automation = if registers_wallet {
-            serde_json::from_str(include_str!("../../ledger/automations/hwi_speculos.json"))?
+            serde_json::from_str(include_str!(
+                "../../../bhwi-async/src/emulator/speculos_approve.json"
+            ))?
         } else {
             serde_json::from_str(include_str!("../../ledger/automations/sign_psbt.json"))?
         };
@@ -54,6 +54,12 @@ interface AddressResult {
     address: string;
 }
 
+type DeviceEvent =
+    | { type: 'progress'; done: number; total: number }
+    | { type: 'awaiting_user_confirmation' }
+    | { type: 'pairing_code_available'; code: string }
+    | { type: 'pin_server_round_trip' };
+
 const isFirefox = navigator.userAgent.toLowerCase().includes('firefox');
 
 const App = () => {
@@ -82,6 +88,12 @@ const App = () => {
     const [processing, setProcessing] = useState(false);
     const [error, setError] = useState<string | null>(null);
     const [pairingCode, setPairingCode] = useState<string | null>(null);
+    const [deviceStatus, setDeviceStatus] = useState<string | null>(null);
+
+    // Device prompts only matter while a command runs.
+    useEffect(() => {
+        if (!processing) setDeviceStatus(null);
+    }, [processing]);
 
     const showError = (message: string) => {
         setError(message);
@@ -113,9 +125,22 @@ const App = () => {
                 setDevice(null);
             };
 
-            const onPairingCodeCallback = (code: string) => {
-                setPairingCode(code);
-            };
+            client.set_event_callback((event: DeviceEvent) => {
+                switch (event.type) {
+                    case 'progress':
+                        setDeviceStatus(`Working on the device: ${event.done}/${event.total}`);
+                        break;
+                    case 'awaiting_user_confirmation':
+                        setDeviceStatus('Confirm on your device');
+                        break;
+                    case 'pairing_code_available':
+                        setPairingCode(event.code);
+                        break;
+                    case 'pin_server_round_trip':
+                        setDeviceStatus('Contacting the Jade pin server');
+                        break;
+                }
+            });
 
             switch (type) {
                 case 'Coldcard':
@@ -128,7 +153,7 @@ const App = () => {
                     await client.connect_ledger(onCloseCallback);
                     break;
                 case 'BitBox02':
-                    await client.connect_bitbox(network ?? 'bitcoin', onCloseCallback, onPairingCodeCallback);
+                    await client.connect_bitbox(network ?? 'bitcoin', onCloseCallback);
                     break;
             }
 
@@ -305,6 +330,13 @@ const App = () => {
                 </div>
             )}
 
+            {deviceStatus && (
+                <div className="fixed bottom-4 right-4 z-40 flex items-center gap-3 bg-gray-800 border border-gray-700 rounded-lg shadow-xl px-4 py-3">
+                    <div className="h-4 w-4 rounded-full border-2 border-gray-500 border-t-white animate-spin" />
+                    <p className="text-sm">{deviceStatus}</p>
+                </div>
+            )}
+
             {pairingCode && (
                 <div className="fixed inset-0 z-50 flex items-center justify-center bg-black/70">
                     <div className="bg-gray-800 border border-gray-700 rounded-lg shadow-xl px-8 py-6 max-w-sm text-center">